@@ -0,0 +1,88 @@
+// Pooled vs freshly allocated `Response::json` serialization under load:
+//   cargo bench --features buffer-pool --bench json_buffer_pool
+//
+// Every thread builds and drops responses in a tight loop, the way a busy
+// server would; per-call latencies are merged and reported as percentiles.
+use high_performance_webserver::Response;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+const CALLS_PER_THREAD: usize = 200_000;
+
+#[derive(Serialize)]
+struct User {
+    id: u32,
+    name: String,
+    email: String,
+    tags: Vec<String>,
+}
+
+fn users(count: u32) -> Vec<User> {
+    (0..count)
+        .map(|id| User {
+            id,
+            name: format!("user {}", id),
+            email: format!("user{}@example.com", id),
+            tags: vec!["admin".to_string(), "beta".to_string()],
+        })
+        .collect()
+}
+
+// The unpooled path, as `Response::json` does without the feature
+fn naive(value: &[User]) -> Response {
+    let json = serde_json::to_string(value).unwrap();
+    Response::new()
+        .header("Content-Type", "application/json")
+        .body(json)
+}
+
+fn pooled(value: &[User]) -> Response {
+    Response::new().json(&value).unwrap()
+}
+
+fn run(label: &str, payload: &[User], threads: usize, build: fn(&[User]) -> Response) {
+    let mut latencies: Vec<Duration> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut latencies = Vec::with_capacity(CALLS_PER_THREAD);
+                    for _ in 0..CALLS_PER_THREAD {
+                        let start = Instant::now();
+                        // Dropping the response is when a pooled buffer returns
+                        drop(std::hint::black_box(build(payload)));
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    latencies.sort_unstable();
+
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    println!(
+        "{:<8} mean {:>9.2?}  p50 {:>9.2?}  p99 {:>9.2?}  p99.9 {:>9.2?}  max {:>9.2?}",
+        label,
+        mean,
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1],
+    );
+}
+
+fn main() {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    for count in [1, 20, 200] {
+        let payload = users(count);
+        let bytes = serde_json::to_vec(&payload).unwrap().len();
+        println!("{} users ({} bytes), {} threads", count, bytes, threads);
+        run("naive", &payload, threads, naive);
+        run("pooled", &payload, threads, pooled);
+    }
+}
@@ -0,0 +1,133 @@
+// The 404 path under load: a router miss, then the error formatted the way
+// the request log formats it.
+//   cargo bench --bench not_found
+//
+// A counting allocator reports allocations per 404 next to the latency
+// percentiles, since on a scanner-heavy server both add up.
+use high_performance_webserver::{Method, Response, Router};
+use hyper::{Body, Request};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const CALLS_PER_THREAD: usize = 200_000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn router() -> Router {
+    let mut router = Router::new();
+    for resource in ["users", "orders", "products", "invoices"] {
+        router = router
+            .get(format!("/api/{}", resource), |_req| async {
+                Ok(Response::new())
+            })
+            .get(format!("/api/{}/:id", resource), |_req| async {
+                Ok(Response::new())
+            })
+            .route(Method::POST, format!("/api/{}", resource), |_req| async {
+                Ok(Response::new())
+            });
+    }
+    router
+}
+
+// What scanners ask for
+const PATHS: [&str; 4] = [
+    "/wp-login.php",
+    "/.env",
+    "/api/users/1/../../../etc/passwd",
+    "/cgi-bin/luci/;stok=/locale?form=country&operation=write",
+];
+
+fn main() {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let router = router();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    // Requests are built up front so only the 404 itself is measured
+    let requests = |count: usize| -> Vec<Request<Body>> {
+        (0..count)
+            .map(|i| {
+                Request::get(PATHS[i % PATHS.len()])
+                    .body(Body::empty())
+                    .unwrap()
+            })
+            .collect()
+    };
+
+    let single = requests(CALLS_PER_THREAD);
+    let mut log = String::with_capacity(256);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for req in single {
+        let error = runtime.block_on(router.handle(req)).err().unwrap();
+        log.clear();
+        let _ = write!(log, "{}", error);
+        std::hint::black_box(&log);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{:.2} allocations per 404 (router miss + error message)",
+        allocations as f64 / CALLS_PER_THREAD as f64
+    );
+
+    let mut latencies: Vec<Duration> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let batch = requests(CALLS_PER_THREAD);
+                let router = &router;
+                scope.spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    let mut log = String::with_capacity(256);
+                    let mut latencies = Vec::with_capacity(CALLS_PER_THREAD);
+                    for req in batch {
+                        let start = Instant::now();
+                        let error = runtime.block_on(router.handle(req)).err().unwrap();
+                        log.clear();
+                        let _ = write!(log, "{}", error);
+                        drop(std::hint::black_box(error));
+                        latencies.push(start.elapsed());
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    latencies.sort_unstable();
+
+    let total: Duration = latencies.iter().sum();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{} threads: {:.0} 404s/s per thread  p50 {:>9.2?}  p99 {:>9.2?}  p99.9 {:>9.2?}  max {:>9.2?}",
+        threads,
+        latencies.len() as f64 / total.as_secs_f64(),
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.999),
+        latencies[latencies.len() - 1],
+    );
+}
@@ -0,0 +1,6 @@
+#[path = "src/build_env.rs"]
+mod build_env;
+
+fn main() {
+    build_env::emit();
+}
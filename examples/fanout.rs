@@ -0,0 +1,69 @@
+// Fans out to three upstream calls under a 300ms request timeout. The first
+// answers in time; when the deadline hits, the request's cancellation token
+// aborts the other two and the client gets 503.
+//
+//   cargo run --example fanout
+use high_performance_webserver::{fanout, Branch, RequestExt, Response, Router, Server};
+use hyper::{Body, Client, Request};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let router = Router::new().get("/dashboard", |req: Request<Body>| async move {
+        let results = fanout(
+            &req.cancellation_token(),
+            Duration::from_secs(5),
+            [
+                upstream("profile", Duration::from_millis(100)),
+                upstream("orders", Duration::from_secs(1)),
+                upstream("recommendations", Duration::from_secs(2)),
+            ],
+        )
+        .await;
+        let parts: Vec<String> = results.into_iter().filter_map(Branch::ok).collect();
+        Ok(Response::new().text(parts.join(", ")))
+    });
+
+    let addr: SocketAddr = "127.0.0.1:3001".parse()?;
+    let server = Server::new(addr)
+        .with_router(router)
+        .with_request_timeout(Duration::from_millis(300));
+    tokio::spawn(server.run());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let response = Client::new()
+        .get(format!("http://{}/dashboard", addr).parse()?)
+        .await?;
+    println!("GET /dashboard -> {}", response.status());
+    Ok(())
+}
+
+// Stands in for an HTTP call to another service
+async fn upstream(name: &'static str, latency: Duration) -> high_performance_webserver::Result<String> {
+    let call = Call { name, finished: false };
+    tokio::time::sleep(latency).await;
+    println!("{}: answered after {:?}", name, latency);
+    Ok(call.finish())
+}
+
+// Reports calls dropped before they finished
+struct Call {
+    name: &'static str,
+    finished: bool,
+}
+
+impl Call {
+    fn finish(mut self) -> String {
+        self.finished = true;
+        self.name.to_string()
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        if !self.finished {
+            println!("{}: aborted", self.name);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::response::body_allowed;
+use crate::stats::Stats;
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, StatusCode};
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+// Bytes of each body kept per event by default
+pub const DEFAULT_AUDIT_BODY_LIMIT: usize = 64 * 1024;
+
+// Events waiting for the sink before new ones are dropped
+pub const DEFAULT_AUDIT_CHANNEL_CAPACITY: usize = 1024;
+
+// Appended to a captured body that was cut off at the limit
+pub const TRUNCATION_MARKER: &[u8] = b"...[truncated]";
+
+pub const REDACTED: &str = "[REDACTED]";
+
+// Request / response capture for audit logging (`Server::with_audit`).
+//
+// For requests whose path passes the filter, the request body is copied as
+// the handler reads it and the response body as it is sent, up to
+// `max_body` bytes each; both still stream through unchanged. The event is
+// queued once the response body has been sent, or dropped by the client.
+//
+// Events go through a bounded channel to a background task that redacts
+// them and calls the sink one at a time. The request path never waits for
+// the sink: when the channel is full the event is discarded and counted in
+// `StatsSnapshot::audit_events_dropped`.
+//
+// Redaction replaces the value of every JSON object key listed in
+// `redact_fields` (compared case-insensitively, at any depth). A JSON body
+// that can't be parsed, e.g. because it was truncated, is withheld entirely
+// when redaction is configured. Other bodies are recorded as captured.
+pub struct Audit {
+    sink: Arc<dyn AuditSink>,
+    filter: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+    max_body: usize,
+    redact_fields: Vec<String>,
+    channel_capacity: usize,
+}
+
+pub trait AuditSink: Send + Sync + 'static {
+    fn record(&self, event: AuditEvent) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+impl<F, Fut> AuditSink for F
+where
+    F: Fn(AuditEvent) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn record(&self, event: AuditEvent) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(self(event))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub timestamp: SystemTime,
+    pub method: String,
+    // Path and query as sent
+    pub uri: String,
+    pub status: u16,
+    // Until the response body was sent (or abandoned)
+    pub duration: Duration,
+    pub request: AuditBody,
+    pub response: AuditBody,
+    // False when the client went away before the whole body was sent
+    pub response_complete: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditBody {
+    pub content_type: Option<String>,
+    // At most `max_body` bytes, followed by `TRUNCATION_MARKER` if truncated
+    pub data: Bytes,
+    // Bytes that passed through, captured or not
+    pub total_bytes: u64,
+    pub truncated: bool,
+}
+
+impl Audit {
+    // Audits every request until narrowed with `filter`
+    pub fn new<S>(sink: S) -> Self
+    where
+        S: AuditSink,
+    {
+        Self {
+            sink: Arc::new(sink),
+            filter: Arc::new(|_| true),
+            max_body: DEFAULT_AUDIT_BODY_LIMIT,
+            redact_fields: Vec::new(),
+            channel_capacity: DEFAULT_AUDIT_CHANNEL_CAPACITY,
+        }
+    }
+
+    // Only requests whose path satisfies `filter` are audited, e.g.
+    // `|path: &str| path.starts_with("/admin")`
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Arc::new(filter);
+        self
+    }
+
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    // JSON keys whose values are replaced with `[REDACTED]`, e.g.
+    // `&["password", "token"]`
+    pub fn redact_fields(mut self, fields: &[&str]) -> Self {
+        self.redact_fields = fields.iter().map(|f| f.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity.max(1);
+        self
+    }
+
+    // Starts the background task feeding the sink
+    pub(crate) fn start(&self, stats: Arc<Stats>) -> Auditor {
+        let (tx, mut rx) = mpsc::channel::<AuditEvent>(self.channel_capacity);
+        let sink = self.sink.clone();
+        let redact_fields = self.redact_fields.clone();
+        tokio::spawn(async move {
+            while let Some(mut event) = rx.recv().await {
+                if !redact_fields.is_empty() {
+                    redact_body(&mut event.request, &redact_fields);
+                    redact_body(&mut event.response, &redact_fields);
+                }
+                sink.record(event).await;
+            }
+        });
+
+        Auditor {
+            filter: self.filter.clone(),
+            max_body: self.max_body,
+            tx,
+            stats,
+        }
+    }
+}
+
+// The running side of an `Audit`, shared by all connections
+pub(crate) struct Auditor {
+    filter: Arc<dyn Fn(&str) -> bool + Send + Sync>,
+    max_body: usize,
+    tx: mpsc::Sender<AuditEvent>,
+    stats: Arc<Stats>,
+}
+
+// An audited request whose response hasn't been produced yet
+pub(crate) struct PendingAudit {
+    event: AuditEvent,
+    method: Method,
+    started: Instant,
+    request_capture: Arc<Mutex<Capture>>,
+    max_body: usize,
+    tx: mpsc::Sender<AuditEvent>,
+    stats: Arc<Stats>,
+}
+
+impl Auditor {
+    // Starts capturing the request body if the request is audited
+    pub(crate) fn begin(&self, req: Request<Body>) -> (Request<Body>, Option<PendingAudit>) {
+        if !(self.filter)(req.uri().path()) {
+            return (req, None);
+        }
+
+        let (parts, body) = req.into_parts();
+        let request_capture = Arc::new(Mutex::new(Capture::default()));
+        let capture = request_capture.clone();
+        let max_body = self.max_body;
+        let body = Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                capture.lock().unwrap().push(chunk, max_body);
+            }
+        }));
+
+        let pending = PendingAudit {
+            event: AuditEvent {
+                timestamp: SystemTime::now(),
+                method: parts.method.to_string(),
+                uri: parts
+                    .uri
+                    .path_and_query()
+                    .map(|pq| pq.as_str().to_string())
+                    .unwrap_or_else(|| parts.uri.path().to_string()),
+                status: 0,
+                duration: Duration::ZERO,
+                request: AuditBody {
+                    content_type: parts
+                        .headers
+                        .get(CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string),
+                    ..AuditBody::default()
+                },
+                response: AuditBody::default(),
+                response_complete: false,
+            },
+            method: parts.method.clone(),
+            started: Instant::now(),
+            request_capture,
+            max_body,
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+        };
+        (Request::from_parts(parts, body), Some(pending))
+    }
+}
+
+impl PendingAudit {
+    // Wraps the response body; the event is queued when it finishes or is
+    // dropped. The body loses its size hint, so the caller should declare
+    // Content-Length itself (see `exact_length`) and pass it here: hyper
+    // stops polling once that many bytes are out, so it's how completion
+    // is recognized.
+    pub(crate) fn finish(
+        mut self,
+        status: StatusCode,
+        content_type: Option<&str>,
+        content_length: Option<u64>,
+        body: Body,
+    ) -> Body {
+        self.event.status = status.as_u16();
+        self.event.response.content_type = content_type.map(str::to_string);
+        // Nothing will be sent (see `response::elide_body`)
+        if !body_allowed(&self.method, status) {
+            self.emit(Capture::default(), true);
+            return body;
+        }
+        Body::wrap_stream(ResponseTee {
+            body,
+            capture: Capture::default(),
+            content_length,
+            pending: Some(self),
+        })
+    }
+
+    fn emit(mut self, response: Capture, complete: bool) {
+        self.event.duration = self.started.elapsed();
+        self.event.response_complete = complete;
+        let request = std::mem::take(&mut *self.request_capture.lock().unwrap());
+        request.into_body(&mut self.event.request);
+        response.into_body(&mut self.event.response);
+
+        if self.tx.try_send(self.event).is_err() {
+            self.stats.audit_event_dropped();
+        }
+    }
+}
+
+// A body length worth declaring before the body is wrapped: exact, non-zero,
+// and for a status that carries a body
+pub(crate) fn exact_length(status: StatusCode, body: &Body) -> Option<u64> {
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return None;
+    }
+    HttpBody::size_hint(body).exact().filter(|&len| len > 0)
+}
+
+#[derive(Default)]
+pub(crate) struct Capture {
+    pub(crate) data: BytesMut,
+    pub(crate) total: u64,
+    pub(crate) truncated: bool,
+}
+
+impl Capture {
+    pub(crate) fn push(&mut self, chunk: &[u8], limit: usize) {
+        self.total += chunk.len() as u64;
+        let room = limit.saturating_sub(self.data.len());
+        if chunk.len() > room {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&chunk[..room.min(chunk.len())]);
+    }
+
+    pub(crate) fn into_body(mut self, body: &mut AuditBody) {
+        if self.truncated {
+            self.data.extend_from_slice(TRUNCATION_MARKER);
+        }
+        body.data = self.data.freeze();
+        body.total_bytes = self.total;
+        body.truncated = self.truncated;
+    }
+}
+
+struct ResponseTee {
+    body: Body,
+    capture: Capture,
+    content_length: Option<u64>,
+    pending: Option<PendingAudit>,
+}
+
+impl ResponseTee {
+    fn emit(&mut self, complete: bool) {
+        if let Some(pending) = self.pending.take() {
+            pending.emit(std::mem::take(&mut self.capture), complete);
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.body.is_end_stream() || self.content_length == Some(self.capture.total)
+    }
+}
+
+impl Stream for ResponseTee {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let limit = self.pending.as_ref().map_or(0, |p| p.max_body);
+                self.capture.push(chunk, limit);
+                if self.is_complete() {
+                    self.emit(true);
+                }
+            }
+            Poll::Ready(None) => self.emit(true),
+            Poll::Ready(Some(Err(_))) => self.emit(false),
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl Drop for ResponseTee {
+    fn drop(&mut self) {
+        let complete = self.is_complete();
+        self.emit(complete);
+    }
+}
+
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase())
+        .is_some_and(|ct| ct == "application/json" || ct.ends_with("+json"))
+}
+
+pub(crate) fn redact_body(body: &mut AuditBody, fields: &[String]) {
+    if body.data.is_empty() {
+        return;
+    }
+
+    match serde_json::from_slice::<Value>(&body.data) {
+        Ok(mut value) => {
+            if redact_value(&mut value, fields) {
+                body.data = Bytes::from(value.to_string());
+            }
+        }
+        Err(_) if is_json(body.content_type.as_deref()) => {
+            body.data = Bytes::from_static(b"[REDACTED: unparseable JSON]");
+        }
+        Err(_) => {}
+    }
+}
+
+// Returns whether anything was replaced
+fn redact_value(value: &mut Value, fields: &[String]) -> bool {
+    let mut redacted = false;
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if fields.iter().any(|f| key.eq_ignore_ascii_case(f)) {
+                    *value = Value::String(REDACTED.to_string());
+                    redacted = true;
+                } else {
+                    redacted |= redact_value(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                redacted |= redact_value(item, fields);
+            }
+        }
+        _ => {}
+    }
+    redacted
+}
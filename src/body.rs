@@ -0,0 +1,146 @@
+use crate::error::BodyError;
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use serde::de::DeserializeOwned;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+// Default cap on request body size, overridable per route
+pub const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+// Effective body size limit for one request. The server inserts it into the
+// request extensions with the global limit; the router lowers or raises it to
+// the matched route's `max_body_size` before the handler runs.
+#[derive(Clone)]
+pub(crate) struct BodyLimit {
+    limit: Arc<AtomicUsize>,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl BodyLimit {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit: Arc::new(AtomicUsize::new(limit)),
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn get(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    // Whether the body was cut off for exceeding the limit
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+// Enforces the `BodyLimit` on the bytes the handler actually reads: once
+// exceeded, the read fails and the server answers 413. The limit is looked
+// up as each chunk arrives, so a route's own `max_body_size` (set by the
+// router before the handler runs) applies to the whole body.
+//
+// Only bodies that could exceed a limit are wrapped: one with no
+// Content-Length, or a declared length above the server limit that a route
+// might raise. A declared length within the limit is enforced by hyper, and
+// the router rejects one above the route's limit before the handler runs.
+//
+// Nothing is read ahead of the handler. A body the handler ignores is left
+// to hyper, which discards what has already arrived before reusing an
+// HTTP/1.1 connection and closes the connection otherwise, so keep-alive
+// never sees the leftover bytes as the next request.
+pub(crate) fn limit_body(req: Request<Body>, limit: &BodyLimit) -> Request<Body> {
+    if req.body().is_end_stream() {
+        return req;
+    }
+    if content_length(&req).is_some_and(|length| length <= limit.get() as u64) {
+        return req;
+    }
+
+    let (parts, body) = req.into_parts();
+    let limited = futures::stream::unfold(
+        (body, limit.clone(), 0usize),
+        |(mut body, limit, received)| async move {
+            let chunk = match body.data().await? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some((Err(BodyReadError::Read(e)), (body, limit, received))),
+            };
+            let received = received + chunk.len();
+            if received > limit.get() {
+                debug!("Request body exceeds limit of {} bytes", limit.get());
+                limit.exceeded.store(true, Ordering::Relaxed);
+                return Some((Err(BodyReadError::TooLarge), (Body::empty(), limit, received)));
+            }
+            Some((Ok(chunk), (body, limit, received)))
+        },
+    );
+    Request::from_parts(parts, Body::wrap_stream(limited))
+}
+
+#[derive(Debug)]
+enum BodyReadError {
+    Read(hyper::Error),
+    TooLarge,
+}
+
+impl std::fmt::Display for BodyReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BodyReadError::Read(e) => e.fmt(f),
+            BodyReadError::TooLarge => f.write_str("request body exceeds the size limit"),
+        }
+    }
+}
+
+impl std::error::Error for BodyReadError {}
+
+// Declared Content-Length, if present and valid
+pub(crate) fn content_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get(hyper::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// The whole request body. A body cut off for exceeding the server's or the
+// route's size limit is `TooLarge` rather than a read error.
+pub async fn read_body(req: Request<Body>) -> Result<Bytes, BodyError> {
+    let limit = req.extensions().get::<BodyLimit>().cloned();
+    hyper::body::to_bytes(req.into_body()).await.map_err(|e| match limit {
+        Some(limit) if limit.exceeded() => BodyError::TooLarge { limit: limit.get() },
+        _ => BodyError::Read(e),
+    })
+}
+
+// The body as UTF-8 text, whatever the Content-Type says
+pub async fn read_text(req: Request<Body>) -> Result<String, BodyError> {
+    Ok(String::from_utf8(read_body(req).await?.into())?)
+}
+
+// The body parsed as JSON. The Content-Type must be `application/json` or a
+// `+json` type.
+pub async fn read_json<T>(req: Request<Body>) -> Result<T, BodyError>
+where
+    T: DeserializeOwned,
+{
+    let content_type = req.headers().get(hyper::header::CONTENT_TYPE);
+    let is_json = content_type
+        .and_then(|value| value.to_str().ok())
+        .and_then(crate::ContentType::parse)
+        .is_some_and(|content_type| content_type.is_json());
+    if !is_json {
+        return Err(BodyError::UnsupportedMediaType {
+            found: content_type.map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned()),
+            expected: "application/json".to_string(),
+        });
+    }
+    serde_json::from_slice(&read_body(req).await?).map_err(BodyError::Json)
+}
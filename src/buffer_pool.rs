@@ -0,0 +1,73 @@
+use bytes::Bytes;
+use serde::Serialize;
+use std::cell::RefCell;
+
+// Reused serialization buffers for `Response::json`, enabled with the
+// `buffer-pool` feature.
+//
+// Each thread keeps up to `POOL_SIZE` spare buffers. A response body
+// borrows one, and the buffer goes back to the pool of whichever thread
+// drops the body, i.e. once hyper has written it. Buffers that grew past
+// `MAX_POOLED_CAPACITY` are freed instead, so one huge response doesn't pin
+// its memory for good.
+
+// Spare buffers kept per thread
+pub const POOL_SIZE: usize = 64;
+
+// Largest buffer capacity returned to the pool
+pub const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+const INITIAL_CAPACITY: usize = 1024;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn to_json_bytes<T>(value: &T) -> serde_json::Result<Bytes>
+where
+    T: Serialize + ?Sized,
+{
+    let mut buffer = take();
+    match serde_json::to_writer(&mut buffer, value) {
+        Ok(()) => Ok(Bytes::from_owner(PooledBuffer(buffer))),
+        Err(e) => {
+            give_back(buffer);
+            Err(e)
+        }
+    }
+}
+
+fn take() -> Vec<u8> {
+    POOL.try_with(|pool| pool.borrow_mut().pop())
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| Vec::with_capacity(INITIAL_CAPACITY))
+}
+
+fn give_back(mut buffer: Vec<u8>) {
+    if buffer.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buffer.clear();
+    // The pool is already gone while the thread shuts down
+    let _ = POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < POOL_SIZE {
+            pool.push(buffer);
+        }
+    });
+}
+
+struct PooledBuffer(Vec<u8>);
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        give_back(std::mem::take(&mut self.0));
+    }
+}
@@ -0,0 +1,39 @@
+// Build-script side of `build_info`: emits the environment variables read by
+// the `build_info!` macro. Only uses std so it can be shared with build.rs.
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Call from a build script:
+//
+//     fn main() {
+//         high_performance_webserver::build_info::emit();
+//     }
+pub fn emit() {
+    if let Some(sha) = command_output("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=BUILD_GIT_SHA={}", sha);
+    }
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    if let Some(version) = command_output(&rustc, &["--version"]) {
+        println!("cargo:rustc-env=BUILD_RUSTC_VERSION={}", version);
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+
+    // Re-run when the checked-out commit changes rather than on every build
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_string())
+}
@@ -0,0 +1,59 @@
+use crate::Response;
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub use crate::build_env::emit;
+
+// Compile-time build metadata, captured with `build_info!()`. The git SHA,
+// rustc version and timestamp come from variables set by `build_info::emit()`
+// in the calling crate's build script and are `None` without it.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_sha: Option<&'static str>,
+    pub rustc_version: Option<&'static str>,
+    pub build_timestamp: Option<&'static str>,
+}
+
+impl BuildInfo {
+    // Build time as an HTTP date, if the timestamp was captured
+    pub fn built_at(&self) -> Option<String> {
+        let secs = self.build_timestamp?.parse().ok()?;
+        Some(httpdate::fmt_http_date(
+            UNIX_EPOCH + Duration::from_secs(secs),
+        ))
+    }
+
+    pub fn short_sha(&self) -> Option<&'static str> {
+        self.git_sha.map(|sha| &sha[..sha.len().min(12)])
+    }
+
+    pub(crate) fn to_response(&self) -> crate::Result<Response> {
+        #[derive(Serialize)]
+        struct VersionBody<'a> {
+            #[serde(flatten)]
+            info: &'a BuildInfo,
+            built_at: Option<String>,
+        }
+
+        Response::new().json(&VersionBody {
+            info: self,
+            built_at: self.built_at(),
+        })
+    }
+}
+
+// Expands in the calling crate, so name and version are that crate's
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::build_info::BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("BUILD_GIT_SHA"),
+            rustc_version: option_env!("BUILD_RUSTC_VERSION"),
+            build_timestamp: option_env!("BUILD_TIMESTAMP"),
+        }
+    };
+}
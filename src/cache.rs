@@ -0,0 +1,239 @@
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Shards per cache; caches smaller than this get one shard per entry
+const SHARDS: usize = 16;
+
+// Capacity-bounded map for small, hot lookups on the request path, such as
+// the encoding negotiated for an `Accept-Encoding` value. Keys are spread
+// over shards, each behind its own lock that is only held for a map lookup
+// or insert (never while computing a value), so concurrent requests rarely
+// wait on each other. A full shard evicts its least recently used entry;
+// shards are small, so finding it is a short scan.
+//
+// Hits, misses and evictions are counted in `CacheCounters`, reported per
+// cache in `StatsSnapshot::caches` once registered with
+// `Stats::register_cache`.
+pub(crate) struct LruCache<K, V> {
+    shards: Box<[Mutex<Shard<K, V>>]>,
+    shard_capacity: usize,
+    hasher: RandomState,
+    counters: Arc<CacheCounters>,
+}
+
+struct Shard<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    // Bumped on every access; an entry's `used` is the tick of its last one
+    tick: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    used: u64,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    // Holds at most `capacity` entries (at least one)
+    pub(crate) fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let shards = capacity.min(SHARDS);
+        Self {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        entries: HashMap::new(),
+                        tick: 0,
+                    })
+                })
+                .collect(),
+            shard_capacity: capacity / shards,
+            hasher: RandomState::new(),
+            counters: Arc::default(),
+        }
+    }
+
+    pub(crate) fn counters(&self) -> Arc<CacheCounters> {
+        self.counters.clone()
+    }
+
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.tick += 1;
+        let tick = shard.tick;
+        let value = shard.entries.get_mut(key).map(|entry| {
+            entry.used = tick;
+            entry.value.clone()
+        });
+        let counter = match value {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub(crate) fn insert(&self, key: K, value: V) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        shard.tick += 1;
+        let used = shard.tick;
+        if !shard.entries.contains_key(&key) && shard.entries.len() >= self.shard_capacity {
+            let oldest = shard
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                shard.entries.remove(&oldest);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+                self.counters.entries.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        if shard.entries.insert(key, Entry { value, used }).is_none() {
+            self.counters.entries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // The cached value for `key`, or `make`'s, which is then cached. `make`
+    // runs without any lock held; two requests missing the same key at once
+    // both compute it and the later insert wins.
+    pub(crate) fn get_or_insert_with<Q, F>(&self, key: &Q, make: F) -> V
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        F: FnOnce() -> V,
+    {
+        if let Some(value) = self.get(key) {
+            return value;
+        }
+        let value = make();
+        self.insert(key.to_owned(), value.clone());
+        value
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &Mutex<Shard<K, V>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[hash as usize % self.shards.len()]
+    }
+}
+
+// Live counters of one `LruCache`
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    entries: AtomicUsize,
+}
+
+impl CacheCounters {
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.entries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // Entries dropped to make room for new ones
+    pub evictions: u64,
+    // Entries held right now
+    pub entries: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TASKS: usize = 64;
+    const KEYS_PER_TASK: usize = 500;
+
+    fn stored(cache: &LruCache<String, usize>) -> usize {
+        cache.shards.iter().map(|shard| shard.lock().unwrap().entries.len()).sum()
+    }
+
+    // Every task writes its own keys and then reads all of them back while
+    // the others write theirs
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_inserts_are_never_lost() {
+        let cache = Arc::new(LruCache::new(TASKS * KEYS_PER_TASK * 4));
+        let tasks = (0..TASKS).map(|task| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                for i in 0..KEYS_PER_TASK {
+                    cache.insert(format!("{}/{}", task, i), task * KEYS_PER_TASK + i);
+                    tokio::task::yield_now().await;
+                }
+                for i in 0..KEYS_PER_TASK {
+                    assert_eq!(cache.get(&format!("{}/{}", task, i)), Some(task * KEYS_PER_TASK + i));
+                }
+            })
+        });
+        for task in futures::future::join_all(tasks).await {
+            task.unwrap();
+        }
+
+        let stats = cache.counters().snapshot();
+        let total = TASKS * KEYS_PER_TASK;
+        assert_eq!(stats.hits, total as u64);
+        assert_eq!(stats.misses, 0);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.entries, total);
+        assert_eq!(stored(&cache), total);
+    }
+
+    // Far more distinct keys than fit: the cache stays at its capacity and
+    // the counters agree with what it holds
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn memory_stays_bounded_under_contention() {
+        const CAPACITY: usize = 256;
+        let cache = Arc::new(LruCache::new(CAPACITY));
+        let tasks = (0..TASKS).map(|task| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                for i in 0..KEYS_PER_TASK {
+                    // Half the lookups repeat a key shared by every task
+                    let (key, expected) = match i % 2 {
+                        0 => (format!("shared/{}", i % 16), i % 16),
+                        _ => (format!("{}/{}", task, i), i),
+                    };
+                    let value = cache.get_or_insert_with(key.as_str(), || expected);
+                    assert_eq!(value, expected);
+                    assert!(stored(&cache) <= CAPACITY);
+                }
+            })
+        });
+        for task in futures::future::join_all(tasks).await {
+            task.unwrap();
+        }
+
+        let stats = cache.counters().snapshot();
+        assert_eq!(stats.hits + stats.misses, (TASKS * KEYS_PER_TASK) as u64);
+        assert!(stats.entries <= CAPACITY, "{} entries", stats.entries);
+        assert_eq!(stats.entries, stored(&cache));
+        // Every miss inserted a key; those not held any more were evicted
+        // (or replaced by a racing insert of the same key)
+        assert!(stats.evictions <= stats.misses - stats.entries as u64);
+        assert!(stats.evictions > 0);
+    }
+}
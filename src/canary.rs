@@ -0,0 +1,259 @@
+use crate::stats::{Histogram, HistogramSnapshot};
+use crate::{Handler, Response, Result};
+use hyper::{Body, Request};
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Response header naming the variant that served a request by default
+pub const DEFAULT_VARIANT_HEADER: &str = "X-Canary-Variant";
+
+// Splits one route's traffic between a stable handler and a canary, for
+// gradual rollouts:
+//   let search = canary(old_search, new_search, 0.05)
+//       .key(CanaryKey::Header("X-User-Id".into()));
+//   let ramp = search.handle();
+//   router.get("/search", search)
+//   ...
+//   ramp.set_weight(0.5);
+//
+// `weight` is the share of requests sent to the canary, 0.0 to 1.0. With a
+// header key, each key value is hashed to a fixed point in [0, 1) and goes to
+// the canary when that point is below the weight, so a given user stays on
+// one variant across requests (and across restarts; the hash isn't seeded).
+// Raising the weight only moves users from stable to canary, never back.
+// Requests without the header are assigned at random.
+//
+// Responses from either handler carry the variant (`stable` or `canary`)
+// in the `X-Canary-Variant` header; handler errors are passed on as they
+// are, so their error responses don't. `CanaryHandle::stats` counts
+// requests, errors and latency per variant so the two can be compared.
+pub fn canary<S, C>(stable: S, canary: C, weight: f64) -> Canary
+where
+    S: Handler,
+    C: Handler,
+{
+    Canary {
+        stable: Arc::new(stable),
+        canary: Arc::new(canary),
+        key: CanaryKey::Random,
+        header: Some(DEFAULT_VARIANT_HEADER.to_string()),
+        inner: Arc::new(Inner {
+            weight: AtomicU64::new(clamp(weight).to_bits()),
+            rng: AtomicU64::new(seed()),
+            variants: Default::default(),
+        }),
+    }
+}
+
+// What a request is assigned by
+#[derive(Debug, Clone)]
+pub enum CanaryKey {
+    // The value of this request header, such as a user or request ID
+    Header(String),
+    // Each request on its own; nothing is sticky
+    Random,
+}
+
+pub struct Canary {
+    stable: Arc<dyn Handler>,
+    canary: Arc<dyn Handler>,
+    key: CanaryKey,
+    header: Option<String>,
+    inner: Arc<Inner>,
+}
+
+// Runtime control over a `Canary` that has been added to a router
+#[derive(Clone)]
+pub struct CanaryHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryStats {
+    pub weight: f64,
+    pub stable: VariantStats,
+    pub canary: VariantStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VariantStats {
+    pub requests: u64,
+    // Handler errors and 5xx responses
+    pub errors: u64,
+    // Handler called -> response ready
+    pub latency: HistogramSnapshot,
+}
+
+struct Inner {
+    // f64 bits
+    weight: AtomicU64,
+    rng: AtomicU64,
+    // Stable, canary
+    variants: [VariantCounters; 2],
+}
+
+#[derive(Default)]
+struct VariantCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency: Histogram,
+}
+
+#[derive(Clone, Copy)]
+enum Variant {
+    Stable,
+    Canary,
+}
+
+impl Variant {
+    fn as_str(self) -> &'static str {
+        match self {
+            Variant::Stable => "stable",
+            Variant::Canary => "canary",
+        }
+    }
+}
+
+impl Canary {
+    pub fn key(mut self, key: CanaryKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    // Names the response header carrying the variant; None leaves responses
+    // unmarked
+    pub fn variant_header(mut self, name: Option<&str>) -> Self {
+        self.header = name.map(str::to_string);
+        self
+    }
+
+    pub fn handle(&self) -> CanaryHandle {
+        CanaryHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    fn choose(&self, req: &Request<Body>) -> Variant {
+        let point = match &self.key {
+            CanaryKey::Header(name) => match req.headers().get(name.as_str()) {
+                Some(value) => point(fnv1a(value.as_bytes())),
+                None => self.inner.roll(),
+            },
+            CanaryKey::Random => self.inner.roll(),
+        };
+        if point < self.inner.weight() {
+            Variant::Canary
+        } else {
+            Variant::Stable
+        }
+    }
+}
+
+impl Handler for Canary {
+    fn call(&self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        let variant = self.choose(&req);
+        let handler = match variant {
+            Variant::Stable => &self.stable,
+            Variant::Canary => &self.canary,
+        };
+        let started = Instant::now();
+        let handled = handler.call(req);
+        let header = self.header.clone();
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let result = handled.await;
+            inner.record(variant, &result, started.elapsed());
+            let response = result?;
+            Ok(match header {
+                Some(header) => response.header(header, variant.as_str()),
+                None => response,
+            })
+        })
+    }
+}
+
+impl CanaryHandle {
+    // Share of requests sent to the canary from now on, 0.0 to 1.0
+    pub fn set_weight(&self, weight: f64) {
+        self.inner
+            .weight
+            .store(clamp(weight).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.inner.weight()
+    }
+
+    pub fn stats(&self) -> CanaryStats {
+        let variant = |counters: &VariantCounters| VariantStats {
+            requests: counters.requests.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            latency: counters.latency.snapshot(),
+        };
+        CanaryStats {
+            weight: self.weight(),
+            stable: variant(&self.inner.variants[0]),
+            canary: variant(&self.inner.variants[1]),
+        }
+    }
+}
+
+impl Inner {
+    fn weight(&self) -> f64 {
+        f64::from_bits(self.weight.load(Ordering::Relaxed))
+    }
+
+    fn roll(&self) -> f64 {
+        let state = self
+            .rng
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        point(state)
+    }
+
+    fn record(&self, variant: Variant, result: &Result<Response>, elapsed: Duration) {
+        let counters = &self.variants[variant as usize];
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.latency.record(elapsed);
+        let failed = match result {
+            Ok(response) => response.status.is_server_error(),
+            Err(_) => true,
+        };
+        if failed {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn clamp(weight: f64) -> f64 {
+    if weight.is_nan() {
+        return 0.0;
+    }
+    weight.clamp(0.0, 1.0)
+}
+
+fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// The same value for the same key in every process
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// splitmix64's finalizer, scaled to [0, 1)
+fn point(mut z: u64) -> f64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
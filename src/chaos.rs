@@ -0,0 +1,289 @@
+use crate::pattern::{Pattern, RequestPath};
+use crate::Response;
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::{Body, StatusCode};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// Failure injection for exercising client retry and timeout handling. Only
+// compiled with the `chaos` feature, and rules only ever apply through a
+// `ChaosLayer` passed to `Server::with_chaos`; there's no config or
+// environment switch that turns it on.
+//
+// Each request is checked against the rules in order and the first rule
+// whose path matches and whose percentage roll succeeds fires. Every
+// injected fault is logged with a `chaos:` prefix, counted per kind (see
+// `ChaosHandle::injected`) and, where a response still goes out, marked with
+// an `X-Chaos-Fault` header.
+//
+// `ChaosLayer::seeded` makes the rolls reproducible: the same seed and the
+// same sequence of requests inject the same faults.
+
+pub const FAULT_HEADER: &str = "X-Chaos-Fault";
+
+#[derive(Debug, Clone)]
+pub enum Fault {
+    // Delay the request before the handler runs
+    Latency(Duration),
+    // Answer with this status instead of calling the handler
+    Status(StatusCode),
+    // Send part of the body, then abort the connection
+    DropConnection,
+    // End the body cleanly after this many bytes (Content-Length is removed)
+    Truncate(usize),
+}
+
+impl Fault {
+    fn kind(&self) -> usize {
+        match self {
+            Fault::Latency(_) => 0,
+            Fault::Status(_) => 1,
+            Fault::DropConnection => 2,
+            Fault::Truncate(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Latency(delay) => write!(f, "latency={}ms", delay.as_millis()),
+            Fault::Status(status) => write!(f, "status={}", status.as_u16()),
+            Fault::DropConnection => write!(f, "drop-connection"),
+            Fault::Truncate(bytes) => write!(f, "truncate={}", bytes),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ChaosRule {
+    fault: Fault,
+    path: Option<PathScope>,
+    percent: f64,
+}
+
+#[derive(Clone)]
+enum PathScope {
+    Prefix(String),
+    Pattern(Pattern),
+}
+
+impl ChaosRule {
+    // Applies to every request until narrowed with `path` / `percent`
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            path: None,
+            percent: 100.0,
+        }
+    }
+
+    // Route pattern syntax (`/users/:id<u32>`); a trailing `*` matches by
+    // prefix instead, e.g. `/api/*`
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(match path.strip_suffix('*') {
+            Some(prefix) => PathScope::Prefix(prefix.to_string()),
+            None => PathScope::Pattern(Pattern::parse(path)),
+        });
+        self
+    }
+
+    // Share of matching requests affected, 0 to 100
+    pub fn percent(mut self, percent: f64) -> Self {
+        self.percent = percent.clamp(0.0, 100.0);
+        self
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match &self.path {
+            None => true,
+            Some(PathScope::Prefix(prefix)) => path.starts_with(prefix.as_str()),
+            Some(PathScope::Pattern(pattern)) => pattern.matches(&RequestPath::new(path)).is_some(),
+        }
+    }
+}
+
+struct Inner {
+    rules: RwLock<Vec<ChaosRule>>,
+    rng: Mutex<u64>,
+    injected: [AtomicU64; 4],
+}
+
+#[derive(Clone)]
+pub struct ChaosLayer {
+    inner: Arc<Inner>,
+}
+
+// Runtime control over a running server's chaos rules
+#[derive(Clone)]
+pub struct ChaosHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Serialize)]
+pub struct InjectedFaults {
+    pub latency: u64,
+    pub status: u64,
+    pub drop_connection: u64,
+    pub truncate: u64,
+}
+
+impl ChaosLayer {
+    pub fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::seeded(seed)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                rules: RwLock::new(Vec::new()),
+                rng: Mutex::new(seed),
+                injected: Default::default(),
+            }),
+        }
+    }
+
+    pub fn rule(self, rule: ChaosRule) -> Self {
+        self.inner.rules.write().unwrap().push(rule);
+        self
+    }
+
+    pub fn handle(&self) -> ChaosHandle {
+        ChaosHandle {
+            inner: self.inner.clone(),
+        }
+    }
+
+    // Picks the fault for a request, if any, and records it
+    pub(crate) fn select(&self, method: &hyper::Method, path: &str) -> Option<Fault> {
+        let rules = self.inner.rules.read().unwrap();
+        let rule = rules
+            .iter()
+            .filter(|rule| rule.matches_path(path))
+            .find(|rule| self.roll() < rule.percent)?;
+
+        self.inner.injected[rule.fault.kind()].fetch_add(1, Ordering::Relaxed);
+        warn!("chaos: injecting {} into {} {}", rule.fault, method, path);
+        Some(rule.fault.clone())
+    }
+
+    // splitmix64, scaled to [0, 100)
+    fn roll(&self) -> f64 {
+        let mut state = self.inner.rng.lock().unwrap();
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64 * 100.0
+    }
+}
+
+impl Default for ChaosLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChaosHandle {
+    pub fn add_rule(&self, rule: ChaosRule) {
+        self.inner.rules.write().unwrap().push(rule);
+    }
+
+    pub fn set_rules(&self, rules: Vec<ChaosRule>) {
+        *self.inner.rules.write().unwrap() = rules;
+    }
+
+    pub fn clear(&self) {
+        self.inner.rules.write().unwrap().clear();
+    }
+
+    pub fn injected(&self) -> InjectedFaults {
+        let count = |kind: usize| self.inner.injected[kind].load(Ordering::Relaxed);
+        InjectedFaults {
+            latency: count(0),
+            status: count(1),
+            drop_connection: count(2),
+            truncate: count(3),
+        }
+    }
+}
+
+// Response replacing the handler's for `Fault::Status`
+pub(crate) fn status_response(status: StatusCode) -> Response {
+    Response::new()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header(FAULT_HEADER, format!("status={}", status.as_u16()))
+        .body(format!(
+            "{{\"error\": \"Injected fault: {}\"}}",
+            status.canonical_reason().unwrap_or("error")
+        ))
+}
+
+// Applies the body faults to the handler's response
+pub(crate) fn apply(fault: &Fault, mut response: Response) -> Response {
+    let cut = match fault {
+        Fault::DropConnection => {
+            let half = response.body.size_hint().lower() / 2;
+            Cut::Abort(half as usize)
+        }
+        Fault::Truncate(bytes) => {
+            response
+                .headers
+                .retain(|(key, _)| !key.eq_ignore_ascii_case("Content-Length"));
+            Cut::End(*bytes)
+        }
+        _ => return response.header(FAULT_HEADER, fault.to_string()),
+    };
+
+    let body = std::mem::replace(&mut response.body, Body::empty());
+    response
+        .header(FAULT_HEADER, fault.to_string())
+        .body(cut_body(body, cut))
+}
+
+#[derive(Clone, Copy)]
+enum Cut {
+    // Error out after this many bytes, which makes hyper drop the connection
+    Abort(usize),
+    // Finish normally after this many bytes
+    End(usize),
+}
+
+fn cut_body(body: Body, cut: Cut) -> Body {
+    let limit = match cut {
+        Cut::Abort(n) | Cut::End(n) => n,
+    };
+    let stream = futures::stream::unfold(Some((body, 0usize)), move |state| async move {
+        let (mut body, sent) = state?;
+        let remaining = limit - sent;
+        let data = if remaining == 0 { None } else { body.data().await };
+
+        match data {
+            Some(Ok(chunk)) => {
+                let chunk: Bytes = chunk.slice(..chunk.len().min(remaining));
+                let sent = sent + chunk.len();
+                Some((Ok(chunk), Some((body, sent))))
+            }
+            Some(Err(e)) => Some((Err(e.into()), None)),
+            None => match cut {
+                Cut::Abort(_) => {
+                    let e: Box<dyn std::error::Error + Send + Sync> =
+                        "chaos: connection dropped".into();
+                    Some((Err(e), None))
+                }
+                Cut::End(_) => None,
+            },
+        }
+    });
+    Body::wrap_stream(stream)
+}
@@ -0,0 +1,269 @@
+use crate::digest::DigestAlgorithm;
+use crate::layer::{Layer, Next};
+use crate::{BodyError, Response, Result, ServerError};
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, StatusCode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tracing::debug;
+
+// Request headers that separate coalesced requests by default. The
+// credentials are there so one user's response is never handed to another.
+pub const DEFAULT_COALESCE_VARY: &[&str] = &["Accept", "Accept-Language", "Authorization", "Cookie"];
+
+// Largest response body shared between coalesced requests by default
+pub const DEFAULT_MAX_SHARED_BODY: usize = 1024 * 1024;
+
+// Single-flight coalescing of identical GET/HEAD requests as a `Layer`, for
+// expensive endpoints hit by a thundering herd:
+//   router
+//       .get("/reports/daily", daily_report)
+//       .tag("coalesced")
+//       .layer_for_tag("coalesced", Coalesce::new())
+//
+// Requests are identical when they have the same method, path and query
+// and the same values for the `vary` headers. The first one runs the
+// handler; any identical request arriving while it runs waits for it and
+// gets a copy of its response (status, headers, body), or of its error.
+// Requests arriving afterwards run the handler again: nothing is cached.
+//
+// Only responses whose body has a known length up to `max_body` can be
+// copied. When the handler streams or returns more, each waiting request
+// runs the handler itself, as it also does if the first request is
+// cancelled before finishing.
+pub struct Coalesce {
+    in_flight: Arc<InFlight>,
+    vary: Arc<[String]>,
+    max_body: usize,
+}
+
+#[derive(Default)]
+struct InFlight {
+    flights: Mutex<HashMap<String, Flight>>,
+    next_id: AtomicU64,
+}
+
+struct Flight {
+    id: u64,
+    outcome: watch::Receiver<Option<Outcome>>,
+}
+
+#[derive(Clone)]
+enum Outcome {
+    Response(Arc<Snapshot>),
+    Error(Arc<ServerError>),
+    // Streamed or too large to copy
+    Unshared,
+}
+
+struct Snapshot {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    default_content_type: bool,
+    content_digest: Option<DigestAlgorithm>,
+}
+
+enum Role {
+    Lead(u64, watch::Sender<Option<Outcome>>),
+    Follow(watch::Receiver<Option<Outcome>>),
+}
+
+impl Coalesce {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::default(),
+            vary: DEFAULT_COALESCE_VARY.iter().map(|name| name.to_string()).collect(),
+            max_body: DEFAULT_MAX_SHARED_BODY,
+        }
+    }
+
+    // Replaces the default `DEFAULT_COALESCE_VARY`. Include every header the
+    // handler's response depends on.
+    pub fn vary(mut self, headers: &[&str]) -> Self {
+        self.vary = headers.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    async fn handle(
+        in_flight: Arc<InFlight>,
+        key: String,
+        max_body: usize,
+        req: Request<Body>,
+        next: Next,
+    ) -> Result<Response> {
+        let role = {
+            let mut flights = in_flight.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(flight) => Role::Follow(flight.outcome.clone()),
+                None => {
+                    let id = in_flight.next_id.fetch_add(1, Ordering::Relaxed);
+                    let (tx, rx) = watch::channel(None);
+                    flights.insert(key.clone(), Flight { id, outcome: rx });
+                    Role::Lead(id, tx)
+                }
+            }
+        };
+
+        let (id, tx) = match role {
+            Role::Lead(id, tx) => (id, tx),
+            Role::Follow(mut outcome) => {
+                // An error means the leader went away without an outcome
+                let outcome = outcome
+                    .wait_for(Option::is_some)
+                    .await
+                    .ok()
+                    .and_then(|outcome| outcome.clone());
+                return match outcome {
+                    Some(Outcome::Response(snapshot)) => Ok(snapshot.response()),
+                    Some(Outcome::Error(e)) => Err(replicate(&e)),
+                    Some(Outcome::Unshared) | None => {
+                        debug!("Running coalesced request {} on its own", key);
+                        next.run(req).await
+                    }
+                };
+            }
+        };
+
+        let _flight = Landing {
+            in_flight: in_flight.clone(),
+            key,
+            id,
+        };
+        match next.run(req).await {
+            Ok(response) => {
+                let (response, snapshot) = Snapshot::take(response, max_body).await?;
+                let outcome = match snapshot {
+                    Some(snapshot) => Outcome::Response(Arc::new(snapshot)),
+                    None => Outcome::Unshared,
+                };
+                tx.send_replace(Some(outcome));
+                Ok(response)
+            }
+            Err(e) => {
+                tx.send_replace(Some(Outcome::Error(Arc::new(replicate(&e)))));
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Default for Coalesce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Coalesce {
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return next.run(req);
+        }
+        let mut key = format!("{} {}", req.method(), req.uri());
+        for name in self.vary.iter() {
+            for value in req.headers().get_all(name.as_str()) {
+                key.push('\n');
+                key.push_str(name);
+                key.push(':');
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        Box::pin(Self::handle(self.in_flight.clone(), key, self.max_body, req, next))
+    }
+}
+
+// Ends the leader's flight, also when it fails or is cancelled; waiting
+// requests then see the sender dropped
+struct Landing {
+    in_flight: Arc<InFlight>,
+    key: String,
+    id: u64,
+}
+
+impl Drop for Landing {
+    fn drop(&mut self) {
+        let mut flights = self.in_flight.flights.lock().unwrap();
+        if flights.get(&self.key).is_some_and(|flight| flight.id == self.id) {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+impl Snapshot {
+    async fn take(mut response: Response, limit: usize) -> Result<(Response, Option<Self>)> {
+        match HttpBody::size_hint(&response.body).exact() {
+            Some(length) if length <= limit as u64 => {
+                let body = hyper::body::to_bytes(std::mem::take(&mut response.body)).await?;
+                response.body = Body::from(body.clone());
+                let snapshot = Self {
+                    status: response.status,
+                    headers: response.headers.clone(),
+                    body,
+                    default_content_type: response.default_content_type,
+                    content_digest: response.content_digest,
+                };
+                Ok((response, Some(snapshot)))
+            }
+            _ => Ok((response, None)),
+        }
+    }
+
+    fn response(&self) -> Response {
+        let mut response = Response::new().status(self.status).body(self.body.clone());
+        response.headers = self.headers.clone();
+        response.default_content_type = self.default_content_type;
+        response.content_digest = self.content_digest;
+        response
+    }
+}
+
+// A copy of the leader's error for each waiting request. Errors wrapping a
+// source that can't be cloned keep their message and status.
+fn replicate(error: &ServerError) -> ServerError {
+    match error {
+        ServerError::RouteNotFound { method, path } => ServerError::RouteNotFound {
+            method: method.clone(),
+            path: path.clone(),
+        },
+        ServerError::UriTooLong { length, limit } => ServerError::UriTooLong {
+            length: *length,
+            limit: *limit,
+        },
+        ServerError::PayloadTooLarge { limit } => ServerError::PayloadTooLarge { limit: *limit },
+        ServerError::BadGateway(message) => ServerError::BadGateway(message.clone()),
+        ServerError::BadRequest(message) => ServerError::BadRequest(message.clone()),
+        ServerError::Validation(errors) => ServerError::Validation(errors.clone()),
+        ServerError::Body(e) => match e {
+            BodyError::TooLarge { limit } => BodyError::TooLarge { limit: *limit }.into(),
+            BodyError::UnsupportedMediaType { found, expected } => BodyError::UnsupportedMediaType {
+                found: found.clone(),
+                expected: expected.clone(),
+            }
+            .into(),
+            BodyError::InvalidUtf8(e) => BodyError::InvalidUtf8(*e).into(),
+            BodyError::Json(_) | BodyError::Read(_) => ServerError::BadRequest(e.to_string()),
+        },
+        ServerError::TooManyRequests { reset, limit } => ServerError::TooManyRequests {
+            reset: *reset,
+            limit: *limit,
+        },
+        ServerError::ServiceUnavailable(message) => ServerError::ServiceUnavailable(message.clone()),
+        ServerError::Startup(message) => ServerError::Startup(message.clone()),
+        ServerError::Runtime(message) => ServerError::Runtime(message.clone()),
+        ServerError::Internal(message) => ServerError::Internal(message.clone()),
+        ServerError::Io(e) => ServerError::Io(std::io::Error::new(e.kind(), e.to_string())),
+        ServerError::Http(_) | ServerError::Hyper(_) | ServerError::Json(_) => {
+            ServerError::Internal(error.to_string())
+        }
+    }
+}
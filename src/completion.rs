@@ -0,0 +1,129 @@
+use crate::audit::exact_length;
+use crate::server::AccessLog;
+use bytes::Bytes;
+use futures::Stream;
+use hyper::header::{HeaderValue, CONTENT_LENGTH};
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// What became of a response once the server was done with it, passed to
+// `Response::on_complete` callbacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseOutcome {
+    // Body bytes handed to the connection. They were written to the socket,
+    // not necessarily read by the client.
+    pub bytes_sent: u64,
+    // The whole body went out; false when the client went away or the body
+    // stream failed part way
+    pub completed: bool,
+    // From when the server received the request
+    pub duration: Duration,
+}
+
+pub(crate) type CompletionFn = Box<dyn FnOnce(ResponseOutcome) + Send>;
+
+pub(crate) struct Completion {
+    pub(crate) callbacks: Vec<CompletionFn>,
+    pub(crate) started: Instant,
+    pub(crate) access_log: AccessLog,
+}
+
+impl Completion {
+    // Follows the body of `response` to its end; a body that is already
+    // empty completes right away
+    pub(crate) fn track(self, response: &mut hyper::Response<Body>) {
+        if response.body().is_end_stream() {
+            self.finish(0, true);
+            return;
+        }
+        // Wrapping the body hides its length, so it's declared up front
+        let content_length = exact_length(response.status(), response.body());
+        if let Some(len) = content_length {
+            response
+                .headers_mut()
+                .entry(CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok());
+        let body = std::mem::replace(response.body_mut(), Body::empty());
+        *response.body_mut() = Body::wrap_stream(CompletionBody {
+            body,
+            sent: 0,
+            content_length,
+            completion: Some(self),
+        });
+    }
+
+    fn finish(self, bytes_sent: u64, completed: bool) {
+        let outcome = ResponseOutcome {
+            bytes_sent,
+            completed,
+            duration: self.started.elapsed(),
+        };
+        self.access_log.write(bytes_sent, completed);
+        if self.callbacks.is_empty() {
+            return;
+        }
+        // Off the connection's task, so slow callbacks don't hold up the
+        // next request on it
+        let run = move || {
+            for callback in self.callbacks {
+                callback(outcome);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move { run() });
+            }
+            Err(_) => run(),
+        }
+    }
+}
+
+struct CompletionBody {
+    body: Body,
+    sent: u64,
+    content_length: Option<u64>,
+    completion: Option<Completion>,
+}
+
+impl CompletionBody {
+    fn finish(&mut self, completed: bool) {
+        if let Some(completion) = self.completion.take() {
+            completion.finish(self.sent, completed);
+        }
+    }
+}
+
+impl Stream for CompletionBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.sent += chunk.len() as u64;
+                if self.body.is_end_stream() || self.content_length == Some(self.sent) {
+                    self.finish(true);
+                }
+            }
+            Poll::Ready(None) => self.finish(true),
+            Poll::Ready(Some(Err(_))) => self.finish(false),
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+// hyper drops the body when the client goes away mid-response
+impl Drop for CompletionBody {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
@@ -0,0 +1,625 @@
+use crate::cache::{CacheCounters, LruCache};
+use crate::stats::Stats;
+use crate::Response;
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use hyper::{Body, HeaderMap, Method, StatusCode};
+use serde::Serialize;
+use std::sync::Arc;
+
+// Default smallest body worth compressing; below this the encoding overhead
+// usually outweighs the savings
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+// Distinct `Accept-Encoding` values whose negotiated outcome is remembered.
+// Browsers and HTTP libraries send only a handful between them; longer
+// values aren't cached, so odd clients can't flush the common ones out.
+const NEGOTIATION_CACHE_CAPACITY: usize = 256;
+const MAX_CACHED_ACCEPT_ENCODING: usize = 128;
+
+// A streaming content encoder (gzip, br, zstd, ...). The `gzip`, `br` and
+// `zstd` features provide `GzipEncoder`, `BrotliEncoder` and `ZstdEncoder`;
+// other codecs can wrap any compression library.
+//
+// `encode` is called once per body chunk and must return output the client
+// can decode up to that point (a sync flush), otherwise streamed chunks sit
+// in the encoder's buffer instead of reaching the client. `finish` is called
+// once after the last chunk.
+pub trait Encoder: Send + 'static {
+    fn encode(&mut self, chunk: &[u8]) -> std::io::Result<Bytes>;
+    fn finish(&mut self) -> std::io::Result<Bytes>;
+}
+
+pub type EncoderFactory = Arc<dyn Fn() -> Box<dyn Encoder> + Send + Sync>;
+
+// Builds an encoder at the given compression level
+pub type LeveledEncoderFactory = Arc<dyn Fn(i32) -> Box<dyn Encoder> + Send + Sync>;
+
+// Response compression, negotiated from `Accept-Encoding`.
+//
+// Bodies are never buffered: the encoder runs over the chunks as the handler
+// produces them, so streaming responses (`Response::channel`) stay streaming.
+// Some responses are always sent as-is:
+// - `text/event-stream`: SSE clients and proxies expect every event to
+//   arrive immediately, and per-event flushes leave little to compress
+// - responses that already have a `Content-Encoding`, or whose
+//   `Cache-Control` includes `no-transform`
+// - HEAD requests, 1xx/204/304 responses and known-length bodies smaller
+//   than `min_size`
+// Bodies of unknown length are compressed unless `compress_streams(false)`.
+//
+// The client's q-values decide, `identity` included; among equally
+// acceptable encodings the `prefer` order wins, then registration order. A
+// client that refuses identity (`identity;q=0`, or `*;q=0` without listing
+// identity) and accepts none of the registered encodings gets 406, unless
+// `reject_unacceptable(false)`; if it does accept one, that encoding is used
+// even below `min_size`. Each response's outcome is counted in
+// `StatsSnapshot::encodings`.
+#[derive(Serialize)]
+pub struct Compression {
+    codecs: Vec<Codec>,
+    preference: Vec<String>,
+    min_size: usize,
+    compress_streams: bool,
+    reject_unacceptable: bool,
+    #[serde(skip)]
+    negotiated: LruCache<String, Choice>,
+}
+
+#[derive(Serialize)]
+struct Codec {
+    name: String,
+    #[serde(skip)]
+    factory: LeveledEncoderFactory,
+    // None for codecs registered without a level
+    level: Option<i32>,
+}
+
+enum Negotiated<'a> {
+    // `required` when the client refuses identity
+    Encode { codec: &'a Codec, required: bool },
+    Identity,
+    NotAcceptable,
+}
+
+// `Negotiated` as cached, with the codec as an index into `codecs`
+#[derive(Clone, Copy)]
+enum Choice {
+    Encode { codec: usize, required: bool },
+    Identity,
+    NotAcceptable,
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            codecs: Vec::new(),
+            preference: Vec::new(),
+            min_size: DEFAULT_MIN_SIZE,
+            compress_streams: true,
+            reject_unacceptable: true,
+            negotiated: LruCache::new(NEGOTIATION_CACHE_CAPACITY),
+        }
+    }
+
+    // Every built-in encoder enabled by the crate's features, preferred in
+    // the order zstd, br, gzip, each at its default level. Without any of
+    // those features this is the same as `new`.
+    pub fn standard() -> Self {
+        let compression = Self::new();
+        #[cfg(feature = "zstd")]
+        let compression =
+            compression.encoding_with_level("zstd", ZstdEncoder::DEFAULT_LEVEL, ZstdEncoder::new);
+        #[cfg(feature = "br")]
+        let compression =
+            compression.encoding_with_level("br", BrotliEncoder::DEFAULT_LEVEL, BrotliEncoder::new);
+        #[cfg(feature = "gzip")]
+        let compression =
+            compression.encoding_with_level("gzip", GzipEncoder::DEFAULT_LEVEL, GzipEncoder::new);
+        compression.prefer(&["zstd", "br", "gzip"])
+    }
+
+    // Registers a codec under its `Content-Encoding` token
+    pub fn encoding<F, E>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn() -> E + Send + Sync + 'static,
+        E: Encoder,
+    {
+        self.codecs.push(Codec {
+            name: name.to_ascii_lowercase(),
+            factory: Arc::new(move |_| Box::new(factory()) as Box<dyn Encoder>),
+            level: None,
+        });
+        self
+    }
+
+    // Registers a codec whose factory takes a compression level, starting at
+    // `level`; change it later with `level`:
+    //   .encoding_with_level("zstd", 3, |level| ZstdEncoder::new(level))
+    pub fn encoding_with_level<F, E>(mut self, name: &str, level: i32, factory: F) -> Self
+    where
+        F: Fn(i32) -> E + Send + Sync + 'static,
+        E: Encoder,
+    {
+        self.codecs.push(Codec {
+            name: name.to_ascii_lowercase(),
+            factory: Arc::new(move |level| Box::new(factory(level)) as Box<dyn Encoder>),
+            level: Some(level),
+        });
+        self
+    }
+
+    // Panics unless `name` was registered with `encoding_with_level`
+    pub fn level(mut self, name: &str, level: i32) -> Self {
+        let codec = self
+            .codecs
+            .iter_mut()
+            .find(|codec| codec.name.eq_ignore_ascii_case(name))
+            .filter(|codec| codec.level.is_some())
+            .unwrap_or_else(|| panic!("`{}` is not an encoding registered with a level", name));
+        codec.level = Some(level);
+        self
+    }
+
+    // Server preference among encodings the client accepts equally, e.g.
+    // `&["zstd", "br", "gzip"]`. Unlisted encodings rank after the listed
+    // ones, in registration order.
+    pub fn prefer(mut self, order: &[&str]) -> Self {
+        self.preference = order.iter().map(|name| name.to_ascii_lowercase()).collect();
+        self
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    // Whether bodies of unknown length (streams) are compressed
+    pub fn compress_streams(mut self, enabled: bool) -> Self {
+        self.compress_streams = enabled;
+        self
+    }
+
+    // `false` sends identity to a client that refuses it but accepts nothing
+    // else, instead of 406
+    pub fn reject_unacceptable(mut self, reject: bool) -> Self {
+        self.reject_unacceptable = reject;
+        self
+    }
+
+    pub fn encodings(&self) -> impl Iterator<Item = &str> {
+        self.codecs.iter().map(|codec| codec.name.as_str())
+    }
+
+    // Hit/miss counters of the per-`Accept-Encoding` negotiation cache
+    pub(crate) fn cache_counters(&self) -> Arc<CacheCounters> {
+        self.negotiated.counters()
+    }
+
+    fn negotiate(&self, headers: &HeaderMap) -> Negotiated<'_> {
+        let values: Vec<&str> = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let choice = match values.as_slice() {
+            [value] if value.len() <= MAX_CACHED_ACCEPT_ENCODING => self
+                .negotiated
+                .get_or_insert_with(*value, || self.choose(&values)),
+            _ => self.choose(&values),
+        };
+        match choice {
+            Choice::Encode { codec, required } => Negotiated::Encode {
+                codec: &self.codecs[codec],
+                required,
+            },
+            Choice::Identity => Negotiated::Identity,
+            Choice::NotAcceptable => Negotiated::NotAcceptable,
+        }
+    }
+
+    fn choose(&self, accept_encoding: &[&str]) -> Choice {
+        let accepted: Vec<(String, f32)> = accept_encoding
+            .iter()
+            .flat_map(|v| v.split(','))
+            .filter_map(parse_coding)
+            .collect();
+
+        let listed = |name: &str| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding == name)
+                .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+                .map(|(_, q)| *q)
+        };
+        // Identity is acceptable unless excluded explicitly
+        let identity = listed("identity").unwrap_or(1.0);
+
+        let mut best: Option<(usize, f32, usize)> = None;
+        for (index, codec) in self.codecs.iter().enumerate() {
+            let q = listed(&codec.name).unwrap_or(0.0);
+            let rank = self
+                .preference
+                .iter()
+                .position(|name| *name == codec.name)
+                .unwrap_or(self.preference.len() + index);
+            let better = best.is_none_or(|(_, best_q, best_rank)| {
+                q > best_q || (q == best_q && rank < best_rank)
+            });
+            if q > 0.0 && better {
+                best = Some((index, q, rank));
+            }
+        }
+
+        match best {
+            Some((codec, q, _)) if q >= identity => Choice::Encode {
+                codec,
+                required: identity <= 0.0,
+            },
+            _ if identity > 0.0 => Choice::Identity,
+            Some((codec, _, _)) => Choice::Encode {
+                codec,
+                required: true,
+            },
+            None => Choice::NotAcceptable,
+        }
+    }
+
+    // `route_compress` is the matched route's `compress` setting, which
+    // overrides `min_size` and `compress_streams` in either direction.
+    // `body_len` is the body's exact length if known; `None` means streamed.
+    pub(crate) fn apply(
+        &self,
+        method: &Method,
+        request_headers: &HeaderMap,
+        route_compress: Option<bool>,
+        body_len: Option<u64>,
+        stats: &Stats,
+        response: Response,
+    ) -> Response {
+        if route_compress == Some(false) || !self.can_compress(method, &response) {
+            return response;
+        }
+
+        // Caches must key on Accept-Encoding even when this client got identity
+        let mut response = response.vary("Accept-Encoding");
+
+        let (codec, required) = match self.negotiate(request_headers) {
+            Negotiated::Encode { codec, required } => (codec, required),
+            Negotiated::NotAcceptable if self.reject_unacceptable => {
+                stats.response_encoded("none");
+                return not_acceptable(response, self.encodings());
+            }
+            Negotiated::Identity | Negotiated::NotAcceptable => {
+                stats.response_encoded("identity");
+                return response;
+            }
+        };
+        // A client that refuses identity gets the encoding regardless of size
+        if !required && !self.worth_compressing(route_compress, body_len) {
+            stats.response_encoded("identity");
+            return response;
+        }
+
+        stats.response_encoded(&codec.name);
+        let body = std::mem::replace(&mut response.body, Body::empty());
+        response
+            .headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(CONTENT_LENGTH.as_str()));
+        let encoder = (codec.factory)(codec.level.unwrap_or_default());
+        response
+            .header(CONTENT_ENCODING.as_str(), codec.name.as_str())
+            .body(encode_body(body, encoder))
+    }
+
+    // Whether the response may be transformed at all
+    fn can_compress(&self, method: &Method, response: &Response) -> bool {
+        if self.codecs.is_empty() || method == Method::HEAD {
+            return false;
+        }
+
+        let status = response.status_code();
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+
+        if response.has_header(CONTENT_ENCODING.as_str()) || response.content_digest.is_some() {
+            return false;
+        }
+
+        let is_event_stream = response
+            .header_value(CONTENT_TYPE.as_str())
+            .and_then(|ct| ct.split(';').next())
+            .is_some_and(|ct| ct.trim().eq_ignore_ascii_case("text/event-stream"));
+        if is_event_stream {
+            return false;
+        }
+
+        let no_transform = response
+            .header_values(CACHE_CONTROL.as_str())
+            .flat_map(|v| v.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        !no_transform
+    }
+
+    fn worth_compressing(&self, route_compress: Option<bool>, body_len: Option<u64>) -> bool {
+        if route_compress == Some(true) {
+            return true;
+        }
+
+        match body_len {
+            Some(length) => length >= self.min_size as u64,
+            None => self.compress_streams,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The handler consumes the request, so negotiation input is kept aside
+// The built-in encoders write into a `Vec` and hand over what the flush
+// after each chunk produced
+#[cfg(any(feature = "gzip", feature = "br", feature = "zstd"))]
+fn take_output(buf: &mut Vec<u8>) -> Bytes {
+    Bytes::from(std::mem::take(buf))
+}
+
+// `gzip`, levels 0 to 9
+#[cfg(feature = "gzip")]
+pub struct GzipEncoder(flate2::write::GzEncoder<Vec<u8>>);
+
+#[cfg(feature = "gzip")]
+impl GzipEncoder {
+    pub const DEFAULT_LEVEL: i32 = 6;
+
+    pub fn new(level: i32) -> Self {
+        let level = flate2::Compression::new(level.clamp(0, 9) as u32);
+        Self(flate2::write::GzEncoder::new(Vec::new(), level))
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Encoder for GzipEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        use std::io::Write;
+        self.0.write_all(chunk)?;
+        // A deflate sync flush
+        self.0.flush()?;
+        Ok(take_output(self.0.get_mut()))
+    }
+
+    fn finish(&mut self) -> std::io::Result<Bytes> {
+        self.0.try_finish()?;
+        Ok(take_output(self.0.get_mut()))
+    }
+}
+
+// `br`, levels 0 to 11. The default is lower than brotli's own 11, which is
+// meant for precompressed assets rather than per-response work.
+#[cfg(feature = "br")]
+pub struct BrotliEncoder(Option<brotli::CompressorWriter<Vec<u8>>>);
+
+#[cfg(feature = "br")]
+impl BrotliEncoder {
+    pub const DEFAULT_LEVEL: i32 = 4;
+
+    pub fn new(level: i32) -> Self {
+        let quality = level.clamp(0, 11) as u32;
+        Self(Some(brotli::CompressorWriter::new(Vec::new(), 4096, quality, 22)))
+    }
+}
+
+#[cfg(feature = "br")]
+impl Encoder for BrotliEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        use std::io::Write;
+        let Some(writer) = self.0.as_mut() else {
+            return Ok(Bytes::new());
+        };
+        writer.write_all(chunk)?;
+        writer.flush()?;
+        Ok(take_output(writer.get_mut()))
+    }
+
+    fn finish(&mut self) -> std::io::Result<Bytes> {
+        // Consuming the writer ends the stream
+        Ok(self.0.take().map_or_else(Bytes::new, |writer| Bytes::from(writer.into_inner())))
+    }
+}
+
+// `zstd`, levels as the zstd library defines them (1 to 22, or negative
+// for faster modes); out-of-range levels are clamped
+#[cfg(feature = "zstd")]
+pub struct ZstdEncoder(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+#[cfg(feature = "zstd")]
+impl ZstdEncoder {
+    pub const DEFAULT_LEVEL: i32 = 3;
+
+    pub fn new(level: i32) -> Self {
+        let range = zstd::compression_level_range();
+        let level = level.clamp(*range.start(), *range.end());
+        // Only fails when zstd can't allocate its context
+        Self(zstd::stream::write::Encoder::new(Vec::new(), level).expect("zstd encoder"))
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Encoder for ZstdEncoder {
+    fn encode(&mut self, chunk: &[u8]) -> std::io::Result<Bytes> {
+        use std::io::Write;
+        self.0.write_all(chunk)?;
+        self.0.flush()?;
+        Ok(take_output(self.0.get_mut()))
+    }
+
+    fn finish(&mut self) -> std::io::Result<Bytes> {
+        self.0.do_finish()?;
+        Ok(take_output(self.0.get_mut()))
+    }
+}
+
+pub(crate) fn capture_accept_encoding(headers: &HeaderMap) -> HeaderMap {
+    let mut captured = HeaderMap::new();
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        captured.append(ACCEPT_ENCODING, value.clone());
+    }
+    captured
+}
+
+// 406 for a client that accepts neither identity nor any registered encoding
+fn not_acceptable<'a>(response: Response, encodings: impl Iterator<Item = &'a str>) -> Response {
+    let vary: Vec<String> = response.header_values(VARY.as_str()).map(str::to_string).collect();
+    let mut rejection = Response::new()
+        .status(StatusCode::NOT_ACCEPTABLE)
+        .header("Content-Type", "application/json")
+        .body(
+            serde_json::json!({
+                "error": "no acceptable content coding",
+                "available": encodings.collect::<Vec<_>>(),
+            })
+            .to_string(),
+        );
+    for value in vary {
+        rejection = rejection.append_header(VARY.as_str(), value);
+    }
+    rejection
+}
+
+// `gzip;q=0.8` -> ("gzip", 0.8). Elements with a malformed q-value are
+// dropped rather than guessed at.
+fn parse_coding(item: &str) -> Option<(String, f32)> {
+    let mut parts = item.split(';');
+    let coding = parts.next()?.trim().to_ascii_lowercase();
+    if coding.is_empty() {
+        return None;
+    }
+
+    let mut q = 1.0;
+    for param in parts {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("q") {
+            q = parse_qvalue(value.trim())?;
+        }
+    }
+    Some((coding, q))
+}
+
+// RFC 9110 `qvalue`: 0 to 1 with at most three decimals
+fn parse_qvalue(value: &str) -> Option<f32> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    let valid = match int {
+        "0" => frac.len() <= 3 && frac.bytes().all(|b| b.is_ascii_digit()),
+        "1" => frac.len() <= 3 && frac.bytes().all(|b| b == b'0'),
+        _ => false,
+    };
+    if !valid {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn encode_body(body: Body, encoder: Box<dyn Encoder>) -> Body {
+    let stream = futures::stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            match body.data().await {
+                Some(Ok(chunk)) => match encoder.encode(&chunk) {
+                    // Skip empty output so the client never sees empty chunks
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Some((Ok(out), Some((body, encoder)))),
+                    Err(e) => return Some((Err(e.into()), None)),
+                },
+                Some(Err(e)) => {
+                    let e: Box<dyn std::error::Error + Send + Sync> = e.into();
+                    return Some((Err(e), None));
+                }
+                None => {
+                    return match encoder.finish() {
+                        Ok(out) => Some((Ok(out), None)),
+                        Err(e) => Some((Err(e.into()), None)),
+                    }
+                }
+            }
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+#[cfg(all(test, feature = "gzip", feature = "br", feature = "zstd"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Everything `compressed` decodes to, even if the stream isn't finished
+    fn decode(name: &str, compressed: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        let complete = match name {
+            "gzip" => flate2::read::GzDecoder::new(compressed).read_to_end(&mut out),
+            "br" => brotli::Decompressor::new(compressed, 4096).read_to_end(&mut out),
+            "zstd" => zstd::stream::read::Decoder::new(compressed)
+                .unwrap()
+                .read_to_end(&mut out),
+            _ => unreachable!(),
+        }
+        .is_ok();
+        (out, complete)
+    }
+
+    #[test]
+    fn every_chunk_is_decodable_on_arrival() {
+        let chunks: Vec<Vec<u8>> = (0..5)
+            .map(|i| format!("event {} {}\n", i, "payload ".repeat(i * 50)).into_bytes())
+            .collect();
+        let encoders: [(&str, Box<dyn Encoder>); 3] = [
+            ("gzip", Box::new(GzipEncoder::new(GzipEncoder::DEFAULT_LEVEL))),
+            ("br", Box::new(BrotliEncoder::new(BrotliEncoder::DEFAULT_LEVEL))),
+            ("zstd", Box::new(ZstdEncoder::new(ZstdEncoder::DEFAULT_LEVEL))),
+        ];
+
+        for (name, mut encoder) in encoders {
+            let mut sent = Vec::new();
+            let mut expected = Vec::new();
+            for chunk in &chunks {
+                sent.extend_from_slice(&encoder.encode(chunk).unwrap());
+                expected.extend_from_slice(chunk);
+                assert_eq!(decode(name, &sent).0, expected, "{name}");
+            }
+            sent.extend_from_slice(&encoder.finish().unwrap());
+            assert_eq!(decode(name, &sent), (expected, true), "{name}");
+        }
+    }
+
+    #[test]
+    fn levels_are_clamped() {
+        for level in [i32::MIN, -1, 0, 100, i32::MAX] {
+            for (name, mut encoder) in [
+                ("gzip", Box::new(GzipEncoder::new(level)) as Box<dyn Encoder>),
+                ("br", Box::new(BrotliEncoder::new(level))),
+                ("zstd", Box::new(ZstdEncoder::new(level))),
+            ] {
+                let mut sent = encoder.encode(b"hello").unwrap().to_vec();
+                sent.extend_from_slice(&encoder.finish().unwrap());
+                assert_eq!(decode(name, &sent), (b"hello".to_vec(), true), "{name} at {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn standard_registers_the_enabled_codecs() {
+        let compression = Compression::standard();
+        assert_eq!(compression.encodings().collect::<Vec<_>>(), ["zstd", "br", "gzip"]);
+    }
+}
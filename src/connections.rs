@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+// Tracks open connections per client IP. A limit of 0 means unlimited. With
+// IPv6 bucketing enabled, all addresses in the same /64 share one counter,
+// since a single client usually controls a whole /64.
+//
+// Excess connections are refused at accept time rather than queued, so the
+// limiter never adds a wait to a request's `ServerTiming`.
+pub(crate) struct IpLimiter {
+    limit: AtomicUsize,
+    bucket_ipv6: AtomicBool,
+    open: Mutex<HashMap<IpAddr, usize>>,
+    rejected: Mutex<HashMap<IpAddr, u64>>,
+    rejected_total: AtomicU64,
+}
+
+// Released when the connection's service is dropped
+pub(crate) struct IpGuard {
+    limiter: Arc<IpLimiter>,
+    key: IpAddr,
+}
+
+impl IpLimiter {
+    pub(crate) fn new(limit: Option<usize>, bucket_ipv6: bool) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit.unwrap_or(0)),
+            bucket_ipv6: AtomicBool::new(bucket_ipv6),
+            open: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(HashMap::new()),
+            rejected_total: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_bucket_ipv6(&self, enabled: bool) {
+        self.bucket_ipv6.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    pub(crate) fn rejected_total(&self) -> u64 {
+        self.rejected_total.load(Ordering::Relaxed)
+    }
+
+    fn key(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V6(v6) if self.bucket_ipv6.load(Ordering::Relaxed) => {
+                let prefix = u128::from(v6) & !((1u128 << 64) - 1);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+            ip => ip,
+        }
+    }
+
+    pub(crate) fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpGuard> {
+        let key = self.key(ip);
+        let limit = self.limit();
+
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(key).or_insert(0);
+        if limit.is_some_and(|limit| *count >= limit) {
+            if *count == 0 {
+                open.remove(&key);
+            }
+            drop(open);
+            self.rejected_total.fetch_add(1, Ordering::Relaxed);
+            *self.rejected.lock().unwrap().entry(key).or_insert(0) += 1;
+            return None;
+        }
+        *count += 1;
+
+        Some(IpGuard {
+            limiter: self.clone(),
+            key,
+        })
+    }
+
+    // Logs the clients rejected most often since the last call
+    pub(crate) fn log_offenders(&self, top: usize) {
+        let rejected = std::mem::take(&mut *self.rejected.lock().unwrap());
+        if rejected.is_empty() {
+            return;
+        }
+
+        let mut offenders: Vec<_> = rejected.into_iter().collect();
+        offenders.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let summary = offenders
+            .iter()
+            .take(top)
+            .map(|(ip, count)| format!("{} ({})", ip, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        warn!(
+            "Per-IP connection limit rejected {} client(s); top offenders: {}",
+            offenders.len(),
+            summary
+        );
+    }
+}
+
+impl Drop for IpGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.key);
+            }
+        }
+    }
+}
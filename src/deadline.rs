@@ -0,0 +1,141 @@
+use crate::timing::ServerTiming;
+use crate::{Response, Result, ServerError};
+use bytes::Bytes;
+use futures::Stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::Sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+// `Server::with_request_timeout`, the timeout for requests whose route and
+// router set none
+#[derive(Clone, Copy)]
+pub(crate) struct ServerTimeout(pub(crate) Duration);
+
+pub(crate) fn server_timeout(req: &Request<Body>) -> Option<Duration> {
+    req.extensions().get::<ServerTimeout>().map(|timeout| timeout.0)
+}
+
+// When the response to `req` has to be complete: `timeout` after the server
+// received it, or after now for requests that didn't come through the server
+pub(crate) fn deadline(req: &Request<Body>, timeout: Duration) -> Instant {
+    let received = req
+        .extensions()
+        .get::<ServerTiming>()
+        .map(ServerTiming::started)
+        .unwrap_or_else(Instant::now);
+    received + timeout
+}
+
+// Runs the handler (with its layers) against the deadline. Past it the
+// handler future is dropped, the request's `cancellation_token` cancelled
+// and the client gets a 503 instead.
+pub(crate) async fn race<F>(
+    deadline: Instant,
+    timeout: Duration,
+    cancellation: Option<CancellationToken>,
+    handler: F,
+) -> Result<Response>
+where
+    F: Future<Output = Result<Response>>,
+{
+    match tokio::time::timeout_at(deadline.into(), handler).await {
+        Ok(result) => result,
+        Err(_) => {
+            if let Some(cancellation) = cancellation {
+                cancellation.cancel();
+            }
+            Err(ServerError::ServiceUnavailable(format!(
+                "response timed out after {:?}",
+                timeout
+            )))
+        }
+    }
+}
+
+// `handler` called with `req`, raced against `timeout` if there is one
+pub(crate) async fn run<H, F>(
+    req: Request<Body>,
+    timeout: Option<Duration>,
+    handler: H,
+) -> Result<Response>
+where
+    H: FnOnce(Request<Body>) -> F,
+    F: Future<Output = Result<Response>>,
+{
+    let Some(timeout) = timeout else {
+        return handler(req).await;
+    };
+    let deadline = deadline(&req, timeout);
+    let cancellation = req.extensions().get::<CancellationToken>().cloned();
+    race(deadline, timeout, cancellation, handler(req)).await
+}
+
+// Cuts the body off at the deadline. The status and headers are already on
+// the wire by then, so the failure can't be reported as a status: the body
+// stream errors, which makes hyper close the connection (HTTP/1.1) or reset
+// the stream (HTTP/2), and the client sees a truncated response rather than
+// a complete one. hyper only polls the body when it can write more, so a
+// client that stops reading is cut off once it reads again, not before.
+pub(crate) fn limit_body(
+    body: Body,
+    deadline: Instant,
+    timeout: Duration,
+    cancellation: CancellationToken,
+    label: String,
+) -> Body {
+    Body::wrap_stream(DeadlineBody {
+        body,
+        sleep: Box::pin(tokio::time::sleep_until(deadline.into())),
+        timeout,
+        cancellation,
+        label,
+        expired: false,
+    })
+}
+
+struct DeadlineBody {
+    body: Body,
+    sleep: Pin<Box<Sleep>>,
+    timeout: Duration,
+    cancellation: CancellationToken,
+    // `METHOD path` for the log line
+    label: String,
+    expired: bool,
+}
+
+impl Stream for DeadlineBody {
+    type Item = std::result::Result<Bytes, std::io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.expired {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.body).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => return Poll::Ready(Some(Ok(chunk))),
+            Poll::Ready(Some(Err(e))) => {
+                return Poll::Ready(Some(Err(std::io::Error::other(e))))
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => {}
+        }
+        if self.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.expired = true;
+        self.cancellation.cancel();
+        warn!(
+            "{} - response timed out after {:?} while sending the body; connection aborted",
+            self.label, self.timeout
+        );
+        Poll::Ready(Some(Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "response timed out",
+        ))))
+    }
+}
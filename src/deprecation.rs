@@ -0,0 +1,105 @@
+use crate::Response;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, StatusCode};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+// How often a deprecated route that is still being called logs a warning
+pub const DEPRECATION_WARNING_INTERVAL: Duration = Duration::from_secs(300);
+
+// Deprecation signals for a route (`Router::deprecated`). Every response
+// from the route carries
+//   Deprecation: true
+//   Sunset: <date>                                  (RFC 8594)
+//   Link: <link>; rel="successor-version"           (when `link` is set)
+// With `gone_after_sunset`, requests after `date` get 410 Gone and a JSON
+// body pointing at `link` instead of reaching the handler.
+#[derive(Debug, Clone)]
+pub struct Sunset {
+    pub date: SystemTime,
+    pub link: Option<String>,
+    pub gone_after_sunset: bool,
+}
+
+impl Sunset {
+    pub fn new(date: SystemTime) -> Self {
+        Self {
+            date,
+            link: None,
+            gone_after_sunset: false,
+        }
+    }
+
+    pub fn link(mut self, link: impl Into<String>) -> Self {
+        self.link = Some(link.into());
+        self
+    }
+
+    pub fn gone_after_sunset(mut self, enabled: bool) -> Self {
+        self.gone_after_sunset = enabled;
+        self
+    }
+
+    pub fn is_past(&self) -> bool {
+        SystemTime::now() >= self.date
+    }
+
+    pub(crate) fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(
+            HeaderName::from_static("deprecation"),
+            HeaderValue::from_static("true"),
+        );
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(self.date)) {
+            headers.insert(HeaderName::from_static("sunset"), value);
+        }
+        if let Some(link) = &self.link {
+            let link = format!("<{}>; rel=\"successor-version\"", link);
+            if let Ok(value) = HeaderValue::from_str(&link) {
+                headers.append(hyper::header::LINK, value);
+            }
+        }
+    }
+
+    pub(crate) fn gone_response(&self) -> Response {
+        let body = serde_json::json!({
+            "error": "This endpoint has been retired",
+            "sunset": httpdate::fmt_http_date(self.date),
+            "successor": self.link,
+        });
+        Response::new()
+            .status(StatusCode::GONE)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+    }
+}
+
+// Per-route usage of a deprecated route, for the periodic warning
+#[derive(Debug, Default)]
+pub(crate) struct DeprecationUsage {
+    since_warning: AtomicU64,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl DeprecationUsage {
+    pub(crate) fn record(self: &Arc<Self>, method: &str, path: &str, sunset: &Sunset) {
+        let calls = self.since_warning.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|at| at.elapsed() < DEPRECATION_WARNING_INTERVAL) {
+            return;
+        }
+        *last_warning = Some(Instant::now());
+        drop(last_warning);
+
+        self.since_warning.fetch_sub(calls, Ordering::Relaxed);
+        warn!(
+            "Deprecated route {} {} still receiving traffic ({} call(s) since last warning); sunset {}",
+            method,
+            path,
+            calls,
+            httpdate::fmt_http_date(sunset.date)
+        );
+    }
+}
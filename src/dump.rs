@@ -0,0 +1,162 @@
+use crate::audit::Capture;
+use crate::{Response, Result};
+use bytes::Bytes;
+use futures::Stream;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE};
+use hyper::{Body, Request};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::debug;
+
+// Bytes of each body logged per request by default
+pub const DEFAULT_DEBUG_BODY_LIMIT: usize = 4 * 1024;
+
+// Credentials are never logged, even for debug routes
+pub(crate) const MASKED: &str = "[MASKED]";
+
+// Request/response dumps for routes marked `Router::debug` (or tagged with a
+// `Router::debug_tag`), logged at debug level under this module's target:
+//   RUST_LOG=high_performance_webserver::dump=debug
+//
+// The request line and headers are logged when routing matches, the request
+// body as far as the handler read it, the response status and headers when
+// the handler returns, and the response body once it has been sent. Bodies are cut off after the route's limit; the byte
+// count is always the full length. The response is the handler's, before
+// compression and the server's default headers.
+pub(crate) fn request(route: &str, req: Request<Body>, limit: usize) -> Request<Body> {
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or("<binary>")));
+    debug!(
+        route,
+        "> {} {} {:?} {}",
+        req.method(),
+        req.uri(),
+        req.version(),
+        format_headers(headers)
+    );
+    let (parts, body) = req.into_parts();
+    let body = Body::wrap_stream(DumpBody {
+        body,
+        capture: Capture::default(),
+        limit,
+        route: route.to_string(),
+        direction: '>',
+        logged: false,
+    });
+    Request::from_parts(parts, body)
+}
+
+pub(crate) async fn response(route: &str, result: Result<Response>, limit: usize) -> Result<Response> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            debug!(route, "< error: {}", e);
+            return Err(e);
+        }
+    };
+    debug!(
+        route,
+        "< {} {}",
+        response.status,
+        format_headers(response.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())))
+    );
+
+    // A body already in memory is read here so it keeps its length, which
+    // later layers rely on; only streamed bodies are wrapped
+    if HttpBody::size_hint(&response.body).exact().is_some() {
+        let body = hyper::body::to_bytes(std::mem::take(&mut response.body)).await?;
+        let mut capture = Capture::default();
+        capture.push(&body, limit);
+        log_body(route, '<', &capture);
+        response.body = Body::from(body);
+    } else {
+        response.body = Body::wrap_stream(DumpBody {
+            body: std::mem::take(&mut response.body),
+            capture: Capture::default(),
+            limit,
+            route: route.to_string(),
+            direction: '<',
+            logged: false,
+        });
+    }
+    Ok(response)
+}
+
+pub(crate) fn is_masked(name: &str) -> bool {
+    [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE]
+        .iter()
+        .any(|m| m.as_str().eq_ignore_ascii_case(name))
+}
+
+fn format_headers<'a>(headers: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    headers
+        .map(|(name, value)| {
+            if is_masked(name) {
+                format!("{}: {}", name, MASKED)
+            } else {
+                format!("{}: {}", name, value)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn log_body(route: &str, direction: char, capture: &Capture) {
+    if capture.total == 0 {
+        return;
+    }
+    debug!(
+        route,
+        "{} body ({} bytes{}): {}",
+        direction,
+        capture.total,
+        if capture.truncated { ", truncated" } else { "" },
+        String::from_utf8_lossy(&capture.data).escape_debug()
+    );
+}
+
+struct DumpBody {
+    body: Body,
+    capture: Capture,
+    limit: usize,
+    route: String,
+    direction: char,
+    logged: bool,
+}
+
+impl DumpBody {
+    fn log(&mut self) {
+        if !std::mem::replace(&mut self.logged, true) {
+            log_body(&self.route, self.direction, &self.capture);
+        }
+    }
+}
+
+impl Stream for DumpBody {
+    type Item = std::result::Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let limit = self.limit;
+                self.capture.push(chunk, limit);
+                if self.body.is_end_stream() {
+                    self.log();
+                }
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.log(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl Drop for DumpBody {
+    fn drop(&mut self) {
+        self.log();
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::{Arc, Mutex};
+
+// Resources a handler wants the client to start fetching early, as `Link`
+// header values: `</app.css>; rel=preload; as=style`. The server puts one in
+// every request's extensions when `Server::with_early_hints(true)` (see
+// `RequestExt::send_early_hints`).
+//
+// hyper 0.14 has no way to send an informational response from a server,
+// over HTTP/1 or HTTP/2, so no `103 Early Hints` goes out from here. The
+// links are added to the final response as `Link` headers instead, which
+// is what CDNs that support Early Hints (Cloudflare, Fastly) build their
+// 103s from on later requests; browsers also act on `rel=preload` there.
+#[derive(Debug, Clone, Default)]
+pub struct EarlyHints {
+    links: Arc<Mutex<Vec<String>>>,
+}
+
+impl EarlyHints {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, link: impl Into<String>) {
+        let link = link.into();
+        let mut links = self.links.lock().unwrap();
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+
+    pub fn links(&self) -> Vec<String> {
+        self.links.lock().unwrap().clone()
+    }
+
+    pub(crate) fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.links.lock().unwrap())
+    }
+}
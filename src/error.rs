@@ -1,3 +1,7 @@
+use crate::router::Method;
+use crate::validation::ValidationErrors;
+use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, ServerError>;
@@ -16,12 +20,46 @@ pub enum ServerError {
     #[error("JSON parsing error: {0}")]
     Json(#[from] serde_json::Error),
     
+    // Scanners make 404s the hottest error path, so this variant stays cheap:
+    // the method is a plain enum and the path is its only allocation.
     #[error("Route not found: {method} {path}")]
-    RouteNotFound { method: String, path: String },
+    RouteNotFound { method: Method, path: Arc<str> },
+    
+    #[error("URI too long: {length} bytes exceeds limit of {limit}")]
+    UriTooLong { length: usize, limit: usize },
+    
+    #[error("Payload too large: body exceeds limit of {limit} bytes")]
+    PayloadTooLarge { limit: usize },
+    
+    #[error("Bad gateway: {0}")]
+    BadGateway(String),
     
     #[error("Bad request: {0}")]
     BadRequest(String),
     
+    // Reading or decoding the request body failed; the status depends on
+    // why (see `BodyError`)
+    #[error("{0}")]
+    Body(#[from] BodyError),
+    
+    // Answered 422 with the errors by field (see `ValidationErrors`)
+    #[error("Validation failed: {0}")]
+    Validation(ValidationErrors),
+    
+    // `limit` is set for a spent quota (see `Quota`), which adds the
+    // X-Quota-* headers to the 429
+    #[error("Too many requests: retry after {}", httpdate::fmt_http_date(*reset))]
+    TooManyRequests { reset: SystemTime, limit: Option<u64> },
+    
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+    
+    #[error("Startup check failed: {0}")]
+    Startup(String),
+    
+    #[error("Runtime error: {0}")]
+    Runtime(String),
+    
     #[error("Internal server error: {0}")]
     Internal(String),
 }
@@ -30,8 +68,83 @@ impl ServerError {
     pub fn status_code(&self) -> hyper::StatusCode {
         match self {
             ServerError::RouteNotFound { .. } => hyper::StatusCode::NOT_FOUND,
+            ServerError::UriTooLong { .. } => hyper::StatusCode::URI_TOO_LONG,
+            ServerError::PayloadTooLarge { .. } => hyper::StatusCode::PAYLOAD_TOO_LARGE,
             ServerError::BadRequest(_) => hyper::StatusCode::BAD_REQUEST,
+            ServerError::Body(e) => e.status_code(),
+            ServerError::Validation(_) => hyper::StatusCode::UNPROCESSABLE_ENTITY,
+            ServerError::TooManyRequests { .. } => hyper::StatusCode::TOO_MANY_REQUESTS,
+            ServerError::BadGateway(_) => hyper::StatusCode::BAD_GATEWAY,
+            ServerError::ServiceUnavailable(_) => hyper::StatusCode::SERVICE_UNAVAILABLE,
             _ => hyper::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
-} 
\ No newline at end of file
+}
+
+// Why a request body couldn't be read as what the handler wanted. Returned
+// by `read_body`, `read_text` and `read_json`; with `?` in a handler it
+// becomes the matching error response:
+//   TooLarge                413 Payload Too Large
+//   UnsupportedMediaType    415 Unsupported Media Type
+//   Json, InvalidUtf8       400 Bad Request
+//   Read                    400 Bad Request: the client broke the body off
+//                           mid-stream, and is usually gone already
+//
+// `String::from_utf8` and `str::from_utf8` errors convert too, so decoding
+// body bytes by hand gets the same 400. A `serde_json::Error` converted
+// straight into a `ServerError` is still a 500, as it's the server's fault
+// when serializing a response fails.
+#[derive(Error, Debug)]
+pub enum BodyError {
+    #[error("Payload too large: body exceeds limit of {limit} bytes")]
+    TooLarge { limit: usize },
+    
+    // `expected` lists what the handler accepts
+    #[error("Unsupported media type {}: expected {expected}", found.as_deref().unwrap_or("(none)"))]
+    UnsupportedMediaType { found: Option<String>, expected: String },
+    
+    #[error("Malformed JSON body: {0}")]
+    Json(serde_json::Error),
+    
+    #[error("Body is not valid UTF-8: {0}")]
+    InvalidUtf8(std::str::Utf8Error),
+    
+    #[error("Reading the body failed: {0}")]
+    Read(hyper::Error),
+}
+
+impl BodyError {
+    pub fn status_code(&self) -> hyper::StatusCode {
+        match self {
+            BodyError::TooLarge { .. } => hyper::StatusCode::PAYLOAD_TOO_LARGE,
+            BodyError::UnsupportedMediaType { .. } => hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            BodyError::Json(_) | BodyError::InvalidUtf8(_) | BodyError::Read(_) => {
+                hyper::StatusCode::BAD_REQUEST
+            }
+        }
+    }
+}
+
+impl From<std::str::Utf8Error> for BodyError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        BodyError::InvalidUtf8(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BodyError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        BodyError::InvalidUtf8(e.utf8_error())
+    }
+}
+
+impl From<std::str::Utf8Error> for ServerError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        ServerError::Body(e.into())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for ServerError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        ServerError::Body(e.into())
+    }
+}
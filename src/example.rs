@@ -0,0 +1,457 @@
+// The demo application the binary serves, as a library function so the
+// integration tests run exactly what `main` does. It doubles as a reference
+// for wiring up routes: typed parameters, per-route settings, deprecation,
+// streaming, upgrades and request-scoped logging.
+use crate::{EndpointGuard, RequestExt, Response, Result, Router, ServerError, Sunset};
+use hyper::{Body, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::Instrument;
+
+#[derive(Serialize, Deserialize)]
+pub struct User {
+    pub id: u32,
+    pub name: String,
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: T,
+    pub message: String,
+}
+
+// The demo server's routes, listed by `main` at startup
+pub fn example_app() -> Router {
+    Router::new()
+        .get("/", home_handler)
+        .get("/health", health_handler)
+        .get("/users", get_users_handler)
+        .cache_ttl(Duration::from_secs(30))
+        .get("/users/:id<u32>", get_user_handler)
+        // Old path kept alive until clients move over
+        .get("/v1/users", get_users_handler)
+        .deprecated(
+            Sunset::new(UNIX_EPOCH + Duration::from_secs(1_814_313_600))
+                .link("/users")
+                .gone_after_sunset(true),
+        )
+        .post("/users", create_user_handler)
+        .max_body_size(64 * 1024)
+        .consumes(&["application/json"])
+        .produces("application/json")
+        .get("/api/stats", stats_handler)
+        .get("/async-demo", async_demo_handler)
+        .get("/progress", progress_handler)
+        .compress(false)
+        .get("/me", me_handler)
+        .get("/echo", echo_upgrade_handler)
+        .with_version_endpoint(
+            "/version",
+            crate::build_info!(),
+            EndpointGuard::ip_allowlist(["127.0.0.1", "::1"]),
+        )
+        .with_merge_slashes(true)
+}
+
+async fn home_handler(_req: Request<Body>) -> Result<Response> {
+    let html = r#"
+    <!DOCTYPE html>
+    <html>
+    <head>
+        <title>High-Performance Web Server</title>
+        <style>
+            body { font-family: Arial, sans-serif; margin: 40px; background: #f5f5f5; }
+            .container { max-width: 800px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }
+            h1 { color: #333; text-align: center; }
+            .endpoint { background: #f8f9fa; padding: 15px; margin: 10px 0; border-radius: 5px; border-left: 4px solid #007bff; }
+            .method { font-weight: bold; color: #007bff; }
+            .feature { background: #e8f5e8; padding: 10px; margin: 5px 0; border-radius: 5px; }
+        </style>
+    </head>
+    <body>
+        <div class="container">
+            <h1>🚀 High-Performance Web Server</h1>
+            <p>Built with Rust, featuring async I/O and HTTP/2 support!</p>
+            
+            <h2>🔥 Key Features</h2>
+            <div class="feature">⚡ Async I/O with Tokio runtime</div>
+            <div class="feature">🌐 HTTP/2 and HTTP/1.1 support</div>
+            <div class="feature">🛣️ Flexible routing system</div>
+            <div class="feature">📊 JSON API responses</div>
+            <div class="feature">🎯 High-performance architecture</div>
+            
+            <h2>📋 API Endpoints</h2>
+            <div class="endpoint">
+                <span class="method">GET</span> /health - Health check
+            </div>
+            <div class="endpoint">
+                <span class="method">GET</span> /users - List all users
+            </div>
+            <div class="endpoint">
+                <span class="method">GET</span> /users/:id - Get specific user
+            </div>
+            <div class="endpoint">
+                <span class="method">POST</span> /users - Create new user
+            </div>
+            <div class="endpoint">
+                <span class="method">GET</span> /api/stats - Server statistics
+            </div>
+            <div class="endpoint">
+                <span class="method">GET</span> /async-demo - Async operation demo
+            </div>
+            <div class="endpoint">
+                <span class="method">GET</span> /progress - Streamed progress of a background job
+            </div>
+        </div>
+    </body>
+    </html>
+    "#;
+
+    Ok(Response::new().html(html))
+}
+
+async fn health_handler(_req: Request<Body>) -> Result<Response> {
+    let response = ApiResponse {
+        success: true,
+        data: "Server is healthy and running!",
+        message: "All systems operational".to_string(),
+    };
+
+    Response::new().json(&response)
+}
+
+async fn get_users_handler(_req: Request<Body>) -> Result<Response> {
+    let users = vec![
+        User {
+            id: 1,
+            name: "Alice Johnson".to_string(),
+            email: "alice@example.com".to_string(),
+        },
+        User {
+            id: 2,
+            name: "Bob Smith".to_string(),
+            email: "bob@example.com".to_string(),
+        },
+        User {
+            id: 3,
+            name: "Carol Davis".to_string(),
+            email: "carol@example.com".to_string(),
+        },
+    ];
+
+    let response = ApiResponse {
+        success: true,
+        data: users,
+        message: "Users retrieved successfully".to_string(),
+    };
+
+    Response::new().json(&response)
+}
+
+async fn get_user_handler(req: Request<Body>) -> Result<Response> {
+    // The route only matches numeric ids, so parsing can't fail here
+    let id = req
+        .context()
+        .and_then(|ctx| ctx.parse_param::<u32>("id"))
+        .unwrap_or_default();
+
+    let user = User {
+        id,
+        name: "Alice Johnson".to_string(),
+        email: "alice@example.com".to_string(),
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: user,
+        message: "User retrieved successfully".to_string(),
+    };
+
+    Response::new().json(&response)
+}
+
+async fn create_user_handler(_req: Request<Body>) -> Result<Response> {
+    // In a real application, you would parse the request body
+    let user = User {
+        id: 4,
+        name: "New User".to_string(),
+        email: "newuser@example.com".to_string(),
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: user,
+        message: "User created successfully".to_string(),
+    };
+
+    Response::new()
+        .status(StatusCode::CREATED)
+        .json(&response)
+}
+
+// Stand-in for an auth layer: the bearer token is the user ID
+fn authenticate(req: &Request<Body>) -> Option<u32> {
+    req.headers()
+        .get(hyper::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?
+        .parse()
+        .ok()
+}
+
+async fn me_handler(req: Request<Body>) -> Result<Response> {
+    let Some(user_id) = authenticate(&req) else {
+        return Ok(Response::new()
+            .status(StatusCode::UNAUTHORIZED)
+            .text("Missing or invalid bearer token"));
+    };
+
+    // From here on every log line for this request carries user_id, including
+    // the access-log line written once the response is sent
+    let log = req.log_context().cloned();
+    if let Some(log) = &log {
+        log.record("user_id", user_id);
+    }
+    tracing::info!("Loading profile");
+
+    // Spawned work only joins the request's span when handed it explicitly
+    let lookup = async move {
+        tracing::info!("Looking up user record");
+        User {
+            id: user_id,
+            name: format!("User {}", user_id),
+            email: format!("user{}@example.com", user_id),
+        }
+    };
+    let user = match &log {
+        Some(log) => tokio::spawn(lookup.instrument(log.span().clone())).await,
+        None => tokio::spawn(lookup).await,
+    }
+    .map_err(|e| ServerError::Internal(e.to_string()))?;
+
+    Response::new().json(&ApiResponse {
+        success: true,
+        data: user,
+        message: "Authenticated user".to_string(),
+    })
+}
+
+// Switches the connection to a trivial protocol that echoes every byte back
+async fn echo_upgrade_handler(
+    mut req: Request<Body>,
+) -> Result<Response> {
+    let requested = req
+        .headers()
+        .get(hyper::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("echo"));
+    if !requested {
+        return Ok(Response::new()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header("Upgrade", "echo")
+            .text("This endpoint requires `Upgrade: echo`"));
+    }
+
+    Ok(Response::upgrade(&mut req, "echo", |io| async move {
+        let (mut reader, mut writer) = tokio::io::split(io);
+        if let Err(e) = tokio::io::copy(&mut reader, &mut writer).await {
+            tracing::debug!("Echo connection ended: {}", e);
+        }
+    }))
+}
+
+async fn stats_handler(req: Request<Body>) -> Result<Response> {
+    #[derive(Serialize)]
+    struct ServerStats {
+        uptime: String,
+        memory_usage: String,
+        active_connections: usize,
+        total_requests: u64,
+        http2_enabled: bool,
+        deprecated_routes: std::collections::BTreeMap<String, u64>,
+    }
+
+    let counters = req.stats().map(|stats| stats.snapshot());
+    let stats = ServerStats {
+        uptime: "Running".to_string(),
+        memory_usage: "Optimized".to_string(),
+        active_connections: counters.as_ref().map_or(0, |c| c.active_connections),
+        total_requests: counters.as_ref().map_or(0, |c| c.total_requests),
+        http2_enabled: true,
+        deprecated_routes: counters
+            .map(|c| c.deprecated_routes)
+            .unwrap_or_default(),
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: stats,
+        message: "Server statistics retrieved".to_string(),
+    };
+
+    Response::new().json(&response)
+}
+
+async fn async_demo_handler(_req: Request<Body>) -> Result<Response> {
+    // Simulate an async operation
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+    #[derive(Serialize)]
+    struct AsyncResult {
+        operation: String,
+        duration_ms: u64,
+        result: String,
+    }
+
+    let result = AsyncResult {
+        operation: "Async computation".to_string(),
+        duration_ms: 100,
+        result: "Completed successfully with async I/O!".to_string(),
+    };
+
+    let response = ApiResponse {
+        success: true,
+        data: result,
+        message: "Async operation completed".to_string(),
+    };
+
+    Response::new().json(&response)
+}
+
+async fn progress_handler(_req: Request<Body>) -> Result<Response> {
+    let (tx, response) = Response::channel::<std::io::Error>();
+
+    // The job runs in its own task; the response streams while it works
+    tokio::spawn(async move {
+        for percent in (0..=100).step_by(10) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+            let line = format!("progress: {}%\n", percent);
+            if tx.send(Ok(line.into())).await.is_err() {
+                // Client went away, stop working
+                return;
+            }
+        }
+    });
+
+    Ok(response.header("Content-Type", "text/plain"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_handler;
+
+    #[tokio::test]
+    async fn home_page() {
+        assert_handler(home_handler)
+            .expect_status(200)
+            .expect_header("Content-Type", "text/html")
+            .expect_body_contains("High-Performance Web Server")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn health() {
+        assert_handler(health_handler)
+            .expect_status(200)
+            .expect_json_path("$.success", true)
+            .expect_json_path("$.message", "All systems operational")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn list_users() {
+        assert_handler(get_users_handler)
+            .path("/users")
+            .expect_status(200)
+            .expect_json_path("$.data[0].name", "Alice Johnson")
+            .expect_json_path("$.data[2].email", "carol@example.com")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn get_user_by_id() {
+        assert_handler(get_user_handler)
+            .path("/users/42")
+            .with_param("id", "42")
+            .expect_status(200)
+            .expect_json_path("$.data.id", 42)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn create_user() {
+        assert_handler(create_user_handler)
+            .method(hyper::Method::POST)
+            .path("/users")
+            .with_json(&serde_json::json!({ "name": "New User" }))
+            .expect_status(201)
+            .expect_header("Content-Type", "application/json")
+            .expect_json_path("$.data.name", "New User")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn me_needs_a_bearer_token() {
+        assert_handler(me_handler)
+            .path("/me")
+            .expect_status(401)
+            .expect_body_contains("bearer token")
+            .await;
+        assert_handler(me_handler)
+            .path("/me")
+            .with_header("Authorization", "Bearer nobody")
+            .expect_status(401)
+            .await;
+        assert_handler(me_handler)
+            .path("/me")
+            .with_header("Authorization", "Bearer 7")
+            .expect_status(200)
+            .expect_json_path("$.data.id", 7)
+            .expect_json_path("$.data.email", "user7@example.com")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn echo_requires_an_upgrade() {
+        assert_handler(echo_upgrade_handler)
+            .path("/echo")
+            .expect_status(426)
+            .expect_header("Upgrade", "echo")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn stats_outside_a_server() {
+        // No `Stats` in the request, so the counters read zero
+        assert_handler(stats_handler)
+            .path("/api/stats")
+            .expect_status(200)
+            .expect_json_path("$.data.total_requests", 0)
+            .expect_json_path("$.data.http2_enabled", true)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn async_demo() {
+        assert_handler(async_demo_handler)
+            .path("/async-demo")
+            .expect_status(200)
+            .expect_json_path("$.data.duration_ms", 100)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn progress_streams_to_completion() {
+        assert_handler(progress_handler)
+            .path("/progress")
+            .expect_status(200)
+            .expect_header("Content-Type", "text/plain")
+            .expect_body_contains("progress: 0%\nprogress: 10%\n")
+            .expect_body_contains("progress: 100%\n")
+            .await;
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{Result, ServerError};
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// How one branch of a `fanout` ended
+#[derive(Debug)]
+pub enum Branch<T> {
+    Done(T),
+    Failed(ServerError),
+    TimedOut,
+    // The token was cancelled first
+    Cancelled,
+}
+
+impl<T> Branch<T> {
+    pub fn ok(self) -> Option<T> {
+        match self {
+            Branch::Done(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self, Branch::Done(_))
+    }
+}
+
+// Runs `branches` concurrently, each on its own task, and returns how each
+// ended, in the order given. A branch is abandoned when it runs past
+// `branch_timeout`, and every unfinished branch is abandoned as soon as
+// `token` is cancelled or the `fanout` future itself is dropped, so with a
+// request's token upstream calls stop with the request:
+//   let results = fanout(
+//       &req.cancellation_token(),
+//       Duration::from_millis(500),
+//       [fetch("profile"), fetch("orders"), fetch("recommendations")],
+//   )
+//   .await;
+// A failed or slow branch doesn't affect the others, so the caller decides
+// what partial results are good enough. See `examples/fanout.rs`.
+pub async fn fanout<I, F, T>(token: &CancellationToken, branch_timeout: Duration, branches: I) -> Vec<Branch<T>>
+where
+    I: IntoIterator<Item = F>,
+    F: Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let token = token.child_token();
+    let _cancel_on_drop = token.clone().drop_guard();
+    let tasks: Vec<_> = branches
+        .into_iter()
+        .map(|branch| {
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    biased;
+                    _ = token.cancelled() => Branch::Cancelled,
+                    result = tokio::time::timeout(branch_timeout, branch) => match result {
+                        Ok(Ok(value)) => Branch::Done(value),
+                        Ok(Err(e)) => Branch::Failed(e),
+                        Err(_) => Branch::TimedOut,
+                    },
+                }
+            })
+        })
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        outcomes.push(task.await.unwrap_or_else(|e| {
+            Branch::Failed(ServerError::Internal(format!("fan-out branch failed: {}", e)))
+        }));
+    }
+    outcomes
+}
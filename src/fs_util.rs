@@ -0,0 +1,114 @@
+use crate::{Result, ServerError};
+use std::path::{Path, PathBuf};
+
+// Path handling for handlers that serve files, shared with `StaticFiles`.
+//
+// `safe_join` maps a request path onto a directory without letting it
+// escape: it works on the still percent-encoded path (`req.uri().path()` or
+// `CatchAll::raw`), splits it on '/' and only then decodes each segment, so
+// an encoded slash (`%2F`) can't smuggle in a separator and `%2e%2e` is
+// caught like `..`. Refused, with a 400:
+//   - `..` segments
+//   - NUL bytes, backslashes and ':' in a segment, which covers Windows
+//     drive (`C:`) and UNC (`\\server\share`) prefixes
+//   - escapes that don't decode to UTF-8
+// Empty and `.` segments are skipped, so a leading '/' stays under `root`.
+//
+// The result is only checked lexically: a symlink below `root` can still
+// point outside it. Pass it through `resolve_within` before opening it when
+// the tree may contain links you don't control.
+//   let requested = req.uri().path().trim_start_matches("/files/");
+//   let path = fs_util::safe_join(&root, requested)?;
+//   match fs_util::resolve_within(&root, &path).await? {
+//       Some(path) => Response::file_range(&path, req.headers()).await,
+//       None => Ok(Response::new().status(StatusCode::NOT_FOUND)),
+//   }
+pub fn safe_join(root: impl AsRef<Path>, requested: &str) -> Result<PathBuf> {
+    let segments = requested
+        .split('/')
+        .map(|segment| {
+            crate::percent::decode(segment).ok_or_else(|| rejected(requested, "invalid percent-encoding"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    join_segments(root.as_ref(), segments.iter().map(String::as_str))
+        .map_err(|reason| rejected(requested, reason))
+}
+
+// `safe_join` for segments that are already decoded, e.g. from
+// `CatchAll::segments`. A segment still holding a '/' is refused rather
+// than split.
+pub(crate) fn join_segments<'a>(
+    root: &Path,
+    segments: impl Iterator<Item = &'a str>,
+) -> std::result::Result<PathBuf, &'static str> {
+    let mut path = root.to_path_buf();
+    for segment in segments {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err("parent directory segment"),
+            _ if segment.contains('\0') => return Err("NUL byte"),
+            _ if segment.contains(['/', '\\', ':']) => return Err("separator or drive prefix in segment"),
+            _ => path.push(segment),
+        }
+    }
+    Ok(path)
+}
+
+// `path` with symlinks resolved, or None when it doesn't exist or resolves
+// to somewhere outside `root` (also resolved); answer both with a 404 so the
+// response doesn't reveal whether a link target exists.
+pub async fn resolve_within(
+    root: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+) -> std::io::Result<Option<PathBuf>> {
+    let real = match tokio::fs::canonicalize(path).await {
+        Ok(real) => real,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let root = tokio::fs::canonicalize(root).await?;
+    Ok(real.starts_with(&root).then_some(real))
+}
+
+// Content-Type for a file, guessed from its extension (case-insensitive);
+// text types carry `charset=utf-8`, unknown ones are
+// `application/octet-stream`
+pub fn mime_for_path(path: impl AsRef<Path>) -> &'static str {
+    let extension = path
+        .as_ref()
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn rejected(requested: &str, reason: &str) -> ServerError {
+    ServerError::BadRequest(format!("unsafe path `{}`: {}", requested.escape_debug(), reason))
+}
@@ -0,0 +1,174 @@
+use crate::handler::RequestExt;
+use crate::{Method, Response, ServerError};
+use hyper::{Body, Request, StatusCode};
+use std::net::IpAddr;
+
+// Who may call an endpoint the crate provides (`Router::with_version_endpoint`,
+// `Server::with_debug_config_endpoint`). Mounting one requires a guard, so
+// exposing it publicly is always a visible decision:
+//   EndpointGuard::bearer_token("s3cret")
+//   EndpointGuard::ip_allowlist(["10.0.0.0/8", "127.0.0.1"])
+//   EndpointGuard::ip_allowlist(["10.0.0.0/8"]).and(EndpointGuard::bearer_token("s3cret"))
+//   EndpointGuard::allow_public()
+//
+// Refused requests get the server's ordinary 404, so the endpoint isn't
+// advertised; `deny_status` picks another status (a 401 carries
+// `WWW-Authenticate: Bearer` when a token is required). The client address
+// is the peer of the connection: proxy headers such as X-Forwarded-For are
+// ignored, and requests served outside `Server` (see `router_service`) have
+// no address and fail an IP check.
+//
+// Another way to keep these endpoints private is a second `Server` bound to
+// an internal address, with its own router and `allow_public()`.
+#[derive(Debug, Clone)]
+pub struct EndpointGuard {
+    token: Option<String>,
+    networks: Option<Vec<IpNetwork>>,
+    deny_status: StatusCode,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl EndpointGuard {
+    // Requires `Authorization: Bearer <token>`
+    pub fn bearer_token(token: impl Into<String>) -> Self {
+        Self {
+            token: Some(token.into()),
+            ..Self::allow_public()
+        }
+    }
+
+    // Requires the client address to be in one of `networks`, given in CIDR
+    // notation (`10.0.0.0/8`, `fd00::/8`) or as single addresses. Panics on
+    // an entry that doesn't parse.
+    pub fn ip_allowlist<I, S>(networks: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let networks = networks
+            .into_iter()
+            .map(|network| {
+                let network = network.as_ref();
+                IpNetwork::parse(network)
+                    .unwrap_or_else(|| panic!("invalid IP network `{}` in allowlist", network))
+            })
+            .collect();
+        Self {
+            networks: Some(networks),
+            ..Self::allow_public()
+        }
+    }
+
+    // No restriction
+    pub fn allow_public() -> Self {
+        Self {
+            token: None,
+            networks: None,
+            deny_status: StatusCode::NOT_FOUND,
+        }
+    }
+
+    // Requires both guards to pass, with IP allowlists merged; `self`'s deny
+    // status is kept
+    pub fn and(mut self, other: EndpointGuard) -> Self {
+        if other.token.is_some() {
+            self.token = other.token;
+        }
+        if let Some(networks) = other.networks {
+            self.networks
+                .get_or_insert_with(Vec::new)
+                .extend(networks);
+        }
+        self
+    }
+
+    pub fn deny_status(mut self, status: StatusCode) -> Self {
+        self.deny_status = status;
+        self
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.token.is_none() && self.networks.is_none()
+    }
+
+    // None when the request may proceed; otherwise what to answer instead
+    pub(crate) fn refuse(&self, req: &Request<Body>) -> Option<crate::Result<Response>> {
+        if self.allows(req) {
+            return None;
+        }
+        if self.deny_status == StatusCode::NOT_FOUND {
+            return Some(Err(ServerError::RouteNotFound {
+                method: Method::from(req.method()),
+                path: req.uri().path().into(),
+            }));
+        }
+        let response = Response::new().status(self.deny_status);
+        if self.deny_status == StatusCode::UNAUTHORIZED && self.token.is_some() {
+            return Some(Ok(response.header("WWW-Authenticate", "Bearer")));
+        }
+        Some(Ok(response))
+    }
+
+    fn allows(&self, req: &Request<Body>) -> bool {
+        if let Some(networks) = &self.networks {
+            let Some(addr) = req.remote_addr() else {
+                return false;
+            };
+            // An IPv4 client on a dual-stack socket shows up as `::ffff:a.b.c.d`
+            let ip = addr.ip().to_canonical();
+            if !networks.iter().any(|network| network.contains(ip)) {
+                return false;
+            }
+        }
+        if let Some(token) = &self.token {
+            let presented = crate::headers::bearer_token(req.headers());
+            if !presented.is_some_and(|presented| constant_time_eq(presented, token)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl IpNetwork {
+    fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.trim(), Some(prefix.trim().parse::<u8>().ok()?)),
+            None => (value.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Doesn't stop at the first differing byte, so response timing says
+// nothing about how much of a guessed token was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
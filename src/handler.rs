@@ -1,9 +1,15 @@
+use crate::response::IntoResponse;
 use crate::{Response, Result};
+use futures::TryFutureExt;
 use hyper::{Body, Request};
 use std::future::Future;
 use std::pin::Pin;
 
-pub type HandlerFn = Box<
+// How routes store handlers. It's an `Arc` (it used to be a `Box`) so that
+// cloning a `Route` or `Router` shares the handler instead of requiring
+// closures to be `Clone`; state captured by a handler is shared by every
+// clone.
+pub type HandlerFn = std::sync::Arc<
     dyn Fn(Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>>
         + Send
         + Sync,
@@ -13,13 +19,16 @@ pub trait Handler: Send + Sync + 'static {
     fn call(&self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
 }
 
-impl<F, Fut> Handler for F
+// Function and closure handlers may return `Result<T>` for any
+// `T: IntoResponse`, such as a `StreamBody`
+impl<F, Fut, T> Handler for F
 where
     F: Fn(Request<Body>) -> Fut + Send + Sync + 'static,
-    Fut: Future<Output = Result<Response>> + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+    T: IntoResponse + 'static,
 {
     fn call(&self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
-        Box::pin(self(req))
+        Box::pin(self(req).map_ok(IntoResponse::into_response))
     }
 }
 
@@ -27,14 +36,123 @@ where
 #[macro_export]
 macro_rules! handler {
     ($func:expr) => {
-        Box::new($func) as $crate::HandlerFn
+        std::sync::Arc::new($func) as $crate::HandlerFn
     };
 }
 
-// Request context with path parameters
+// Method, URI, version and headers of a request as received. With
+// `Server::with_request_parts(true)` the server puts one in every request's
+// extensions before routing (see `RequestExt::parts`), so a handler that
+// consumes the request for its body can still refer to it afterwards:
+//   let parts = req.parts().cloned();
+//   let body = hyper::body::to_bytes(req.into_body()).await?;
+// Clones share the headers.
+#[derive(Debug, Clone)]
+pub struct RequestParts {
+    pub method: hyper::Method,
+    pub uri: hyper::Uri,
+    pub version: hyper::Version,
+    pub headers: std::sync::Arc<hyper::HeaderMap>,
+}
+
+impl RequestParts {
+    pub fn from_request(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: original_uri(req).clone(),
+            version: req.version(),
+            headers: std::sync::Arc::new(req.headers().clone()),
+        }
+    }
+}
+
+// The request URI as received, stashed in the extensions by whatever first
+// replaces it (dot-segment resolution, `Normalize`, an internal rewrite)
+#[derive(Clone)]
+struct OriginalUri(hyper::Uri);
+
+// Call before replacing `req`'s URI; only the first call records anything
+pub(crate) fn keep_original_uri(req: &mut Request<Body>) {
+    if req.extensions().get::<OriginalUri>().is_none() {
+        let uri = req.uri().clone();
+        req.extensions_mut().insert(OriginalUri(uri));
+    }
+}
+
+pub(crate) fn original_uri(req: &Request<Body>) -> &hyper::Uri {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map_or(req.uri(), |original| &original.0)
+}
+
+// Request context with path parameters.
+//
+// Decoding rules:
+// - `raw_path()` / `raw_query()` are exactly what the client sent, still
+//   percent-encoded. Use these when computing request signatures.
+// - `decoded_path()` percent-decodes the whole path in one go, so an encoded
+//   `%2F` becomes `/` and is indistinguishable from a separator.
+// - Routing matches the raw path segment by segment; `params` hold each
+//   captured segment percent-decoded individually (an encoded `%2F` stays
+//   inside one parameter).
+// - A trailing catch-all (`*path`) is captured as a parameter with its
+//   segments decoded and joined by '/'; `catch_all()` also keeps the
+//   remainder as sent and the decoded segments one by one.
+// - `query` holds form-style decoded pairs (`+` is a space).
+//   `decoded_query()` decodes the whole string the same way.
+// - A path or query with malformed percent-encoding (`%zz`, or escapes that
+//   aren't UTF-8) never reaches a handler: the router answers 400.
 pub struct RequestContext {
     pub params: std::collections::HashMap<String, String>,
     pub query: std::collections::HashMap<String, String>,
+    raw_path: String,
+    raw_query: Option<String>,
+    catch_all: Option<CatchAll>,
+    route: Option<String>,
+    locale: Option<String>,
+}
+
+// What a trailing `*name` segment matched. For `/static/*path`:
+//   /static/css/site%20v2.css  raw `css/site%20v2.css`, segments `css`, `site v2.css`
+//   /static/a%2Fb              raw `a%2Fb`, one segment `a/b`
+//   /static/ or /static        raw ``, no segments
+// A trailing slash doesn't add an empty segment; empty segments inside the
+// remainder (`a//b`) are kept unless the router merges slashes.
+#[derive(Debug, Clone)]
+pub struct CatchAll {
+    raw: String,
+    segments: Vec<String>,
+}
+
+impl CatchAll {
+    // `request` holds the raw segments after the pattern's prefix; None if
+    // one fails to decode
+    pub(crate) fn from_segments(request: &[&str]) -> Option<Self> {
+        let mut segments = request
+            .iter()
+            .map(|segment| crate::percent::decode(segment))
+            .collect::<Option<Vec<_>>>()?;
+        if segments.last().is_some_and(String::is_empty) {
+            segments.pop();
+        }
+        Some(Self {
+            raw: request.join("/"),
+            segments,
+        })
+    }
+
+    // Still percent-encoded, without a leading '/'
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
 }
 
 impl RequestContext {
@@ -42,14 +160,280 @@ impl RequestContext {
         Self {
             params: std::collections::HashMap::new(),
             query: std::collections::HashMap::new(),
+            raw_path: String::new(),
+            raw_query: None,
+            catch_all: None,
+            route: None,
+            locale: None,
+        }
+    }
+
+    // `raw_*` from the URI as received, `query` from the one being routed
+    pub(crate) fn for_request(req: &Request<Body>) -> Self {
+        let query = req.uri().query().map(parse_query).unwrap_or_default();
+        let original = original_uri(req);
+
+        Self {
+            params: std::collections::HashMap::new(),
+            query,
+            raw_path: original.path().to_string(),
+            raw_query: original.query().map(str::to_string),
+            catch_all: None,
+            route: None,
+            locale: None,
         }
     }
 
+    pub(crate) fn set_catch_all(&mut self, catch_all: Option<CatchAll>) {
+        self.catch_all = catch_all;
+    }
+
+    pub(crate) fn set_route(&mut self, pattern: &str) {
+        self.route = Some(pattern.to_string());
+    }
+
+    // The registered pattern that matched, e.g. `/orders/:id`
+    pub fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub(crate) fn set_locale(&mut self, locale: &str) {
+        self.locale = Some(locale.to_string());
+    }
+
+    // The locale negotiated for this request; None unless the router has
+    // `with_locales`
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    pub fn raw_path(&self) -> &str {
+        &self.raw_path
+    }
+
+    pub fn raw_query(&self) -> Option<&str> {
+        self.raw_query.as_deref()
+    }
+
+    // None if the path contains invalid percent-encoding or non-UTF-8 bytes
+    pub fn decoded_path(&self) -> Option<String> {
+        crate::percent::decode(&self.raw_path)
+    }
+
+    pub fn decoded_query(&self) -> Option<String> {
+        crate::percent::decode_query_component(self.raw_query.as_deref()?)
+    }
+
     pub fn param(&self, key: &str) -> Option<&String> {
         self.params.get(key)
     }
 
+    // None unless the route ends in a catch-all
+    pub fn catch_all(&self) -> Option<&CatchAll> {
+        self.catch_all.as_ref()
+    }
+
+    // Decoded segments of the catch-all remainder; empty without one
+    pub fn catch_all_segments(&self) -> impl Iterator<Item = &str> {
+        self.catch_all.iter().flat_map(CatchAll::segments)
+    }
+
     pub fn query_param(&self, key: &str) -> Option<&String> {
         self.query.get(key)
     }
-} 
\ No newline at end of file
+
+    // Parses a path parameter, e.g. `ctx.parse_param::<u64>("id")`
+    pub fn parse_param<T: std::str::FromStr>(&self, key: &str) -> Option<T> {
+        self.params.get(key).and_then(|v| v.parse().ok())
+    }
+}
+
+// The router inserts a `RequestContext` into the request extensions as soon
+// as a route matches, before any `Layer` runs, so middleware can make
+// decisions on path parameters (`req.route_params()`, `req.param("id")`) and
+// the matched pattern (`context().route()`) just like the handler.
+pub trait RequestExt {
+    fn context(&self) -> Option<&RequestContext>;
+
+    // Server counters; present on requests dispatched by `Server`
+    fn stats(&self) -> Option<&std::sync::Arc<crate::Stats>>;
+
+    // Per-request logging fields; present on requests dispatched by `Server`
+    fn log_context(&self) -> Option<&crate::LogContext>;
+
+    // Start time and phase timings; present on requests dispatched by `Server`
+    fn timing(&self) -> Option<&crate::ServerTiming>;
+
+    // The request as received; present when `Server::with_request_parts` is on
+    fn parts(&self) -> Option<&RequestParts>;
+
+    // Peer address of the connection; present on requests accepted by `Server`
+    fn remote_addr(&self) -> Option<std::net::SocketAddr>;
+
+    // Present when `Server::with_early_hints` is on
+    fn early_hints(&self) -> Option<&crate::EarlyHints>;
+
+    // Cancelled when the request times out (`Server::with_request_timeout`,
+    // `Router::response_timeout`), the client goes away before the response is ready, or the server
+    // begins shutting down. Pass it to sub-tasks the handler spawns so they
+    // stop with the request; see `fanout`. Outside a `Server` this is a
+    // token nothing cancels.
+    fn cancellation_token(&self) -> crate::CancellationToken;
+
+    // Empty when the header is absent
+    fn accept_language(&self) -> crate::AcceptLanguage;
+
+    // One of the router's `with_locales`, picked from `Accept-Language`
+    // with the fallback applied; None when the router has no locales
+    fn locale(&self) -> Option<&str>;
+
+    // None when absent or not a `type/subtype`
+    fn content_type(&self) -> Option<crate::ContentType>;
+
+    fn user_agent(&self) -> Option<crate::UserAgent>;
+
+    // `Authorization: Bearer <token>`. Only reads the header: checking the
+    // token is up to the caller (or an `EndpointGuard`).
+    fn bearer_token(&self) -> Option<&str>;
+
+    // An API key sent in `header`, e.g. `req.api_key("X-API-Key")`
+    fn api_key(&self, header: &str) -> Option<&str>;
+
+    // Parsed `If-Modified-Since` / `If-Unmodified-Since`; None when absent
+    // or malformed, which the RFC says to treat the same. Lets a handler
+    // skip loading a resource that hasn't changed:
+    //   if let Some(since) = req.if_modified_since() { ... }
+    fn if_modified_since(&self) -> Option<std::time::SystemTime>;
+
+    fn if_unmodified_since(&self) -> Option<std::time::SystemTime>;
+
+    // Evaluates every precondition header against the resource's current
+    // validators (see `preconditions::evaluate`). Writes must check this
+    // before changing anything; for GET/HEAD the server already applies it
+    // to 200 responses.
+    fn preconditions(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<std::time::SystemTime>,
+    ) -> crate::preconditions::Decision;
+
+    fn param(&self, key: &str) -> Option<&str> {
+        self.context()?.param(key).map(String::as_str)
+    }
+
+    // Asks for `links` (`Link` header values, e.g.
+    // `</app.css>; rel=preload; as=style`) to be announced to the client; see
+    // `EarlyHints` for how they are delivered. Returns false when early
+    // hints are off.
+    fn send_early_hints(&self, links: &[&str]) -> bool {
+        let Some(hints) = self.early_hints() else {
+            return false;
+        };
+        for link in links {
+            hints.push(*link);
+        }
+        true
+    }
+
+    // Captured path parameters, decoded; None before routing
+    fn route_params(&self) -> Option<&std::collections::HashMap<String, String>> {
+        self.context().map(|context| &context.params)
+    }
+}
+
+impl RequestExt for Request<Body> {
+    fn context(&self) -> Option<&RequestContext> {
+        self.extensions().get::<RequestContext>()
+    }
+
+    fn stats(&self) -> Option<&std::sync::Arc<crate::Stats>> {
+        self.extensions().get::<std::sync::Arc<crate::Stats>>()
+    }
+
+    fn log_context(&self) -> Option<&crate::LogContext> {
+        self.extensions().get::<crate::LogContext>()
+    }
+
+    fn timing(&self) -> Option<&crate::ServerTiming> {
+        self.extensions().get::<crate::ServerTiming>()
+    }
+
+    fn parts(&self) -> Option<&RequestParts> {
+        self.extensions().get::<RequestParts>()
+    }
+
+    fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        self.extensions().get::<std::net::SocketAddr>().copied()
+    }
+
+    fn early_hints(&self) -> Option<&crate::EarlyHints> {
+        self.extensions().get::<crate::EarlyHints>()
+    }
+
+    fn cancellation_token(&self) -> crate::CancellationToken {
+        self.extensions()
+            .get::<crate::CancellationToken>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn accept_language(&self) -> crate::AcceptLanguage {
+        crate::AcceptLanguage::from_headers(self.headers())
+    }
+
+    fn locale(&self) -> Option<&str> {
+        self.context().and_then(RequestContext::locale)
+    }
+
+    fn content_type(&self) -> Option<crate::ContentType> {
+        crate::ContentType::from_headers(self.headers())
+    }
+
+    fn user_agent(&self) -> Option<crate::UserAgent> {
+        crate::UserAgent::from_headers(self.headers())
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        crate::headers::bearer_token(self.headers())
+    }
+
+    fn api_key(&self, header: &str) -> Option<&str> {
+        crate::headers::api_key(self.headers(), header)
+    }
+
+    fn if_modified_since(&self) -> Option<std::time::SystemTime> {
+        crate::preconditions::parse_http_date(self.headers(), hyper::header::IF_MODIFIED_SINCE)
+    }
+
+    fn if_unmodified_since(&self) -> Option<std::time::SystemTime> {
+        crate::preconditions::parse_http_date(self.headers(), hyper::header::IF_UNMODIFIED_SINCE)
+    }
+
+    fn preconditions(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<std::time::SystemTime>,
+    ) -> crate::preconditions::Decision {
+        crate::preconditions::evaluate(self.method(), self.headers(), etag, last_modified)
+    }
+} 
+
+fn parse_query(query: &str) -> std::collections::HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Some((
+                crate::percent::decode_query_component(key)?,
+                crate::percent::decode_query_component(value)?,
+            ))
+        })
+        .collect()
+}
+
+impl Default for RequestContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,424 @@
+// Typed views of common request headers (see `RequestExt::accept_language`,
+// `content_type`, `user_agent`, `bearer_token` and `api_key`). The parsers never fail: malformed parts
+// of a header are skipped, and an absent header yields an empty value or
+// `None`.
+use hyper::header::{ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
+use hyper::HeaderMap;
+
+// `Accept-Language`, most preferred first. Ranges with q=0 ("not
+// acceptable") are dropped; equal q-values keep the client's order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcceptLanguage {
+    languages: Vec<LanguageRange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageRange {
+    // Lowercased, e.g. `en-us`, or `*`
+    pub tag: String,
+    pub q: f32,
+}
+
+impl AcceptLanguage {
+    pub fn parse(value: &str) -> Self {
+        let mut languages: Vec<LanguageRange> = value
+            .split(',')
+            .filter_map(parse_language_range)
+            .filter(|range| range.q > 0.0)
+            .collect();
+        // Stable, so ties stay in header order
+        languages.sort_by(|a, b| b.q.total_cmp(&a.q));
+        Self { languages }
+    }
+
+    // All `Accept-Language` header lines, combined
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = headers
+            .get_all(ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        Self::parse(&value)
+    }
+
+    pub fn languages(&self) -> &[LanguageRange] {
+        &self.languages
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.languages.is_empty()
+    }
+
+    // The best of `available` for this client, or `None` if nothing is
+    // acceptable (fall back to your default). For each range in preference
+    // order, an available tag matches when it is the range itself, starts
+    // with it (`en` accepts `en-GB`), or is a prefix of it (`de-CH` accepts
+    // `de`); `*` takes the first available tag. Comparison ignores case.
+    pub fn pick<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        for range in &self.languages {
+            if range.tag == "*" {
+                return available.first().copied();
+            }
+            let found = available
+                .iter()
+                .find(|tag| tag.eq_ignore_ascii_case(&range.tag))
+                .or_else(|| available.iter().find(|tag| is_subtag_of(tag, &range.tag)))
+                .or_else(|| available.iter().find(|tag| is_subtag_of(&range.tag, tag)));
+            if let Some(tag) = found {
+                return Some(tag);
+            }
+        }
+        None
+    }
+}
+
+// The locales an application serves, for negotiating one per request with
+// `Router::with_locales`:
+//   Locales::new(&["en", "de", "fr-CA"], "en")
+// Tags are matched as in `AcceptLanguage::pick`; clients that accept none of
+// them (or send no `Accept-Language`) get `fallback`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Locales {
+    available: Vec<String>,
+    fallback: String,
+}
+
+impl Locales {
+    pub fn new(available: &[&str], fallback: &str) -> Self {
+        Self {
+            available: available.iter().map(|tag| tag.to_string()).collect(),
+            fallback: fallback.to_string(),
+        }
+    }
+
+    pub fn available(&self) -> impl Iterator<Item = &str> {
+        self.available.iter().map(String::as_str)
+    }
+
+    pub fn fallback(&self) -> &str {
+        &self.fallback
+    }
+
+    // One of `available` as registered, or the fallback
+    pub fn negotiate(&self, accept: &AcceptLanguage) -> &str {
+        let available: Vec<&str> = self.available().collect();
+        accept.pick(&available).unwrap_or(&self.fallback)
+    }
+}
+
+// Whether `tag` is `prefix` plus more subtags, e.g. `en-GB` of `en`
+fn is_subtag_of(tag: &str, prefix: &str) -> bool {
+    tag.len() > prefix.len()
+        && tag.as_bytes()[prefix.len()] == b'-'
+        && tag
+            .get(..prefix.len())
+            .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+}
+
+fn parse_language_range(item: &str) -> Option<LanguageRange> {
+    let mut parts = item.split(';');
+    let tag = parts.next()?.trim();
+    let valid = tag == "*"
+        || (!tag.is_empty()
+            && tag.split('-').all(|subtag| {
+                !subtag.is_empty() && subtag.len() <= 8 && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            }));
+    if !valid {
+        return None;
+    }
+
+    let q = parts.find_map(|p| {
+        let (name, value) = p.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("q").then(|| value.trim())
+    });
+    let q = match q {
+        Some(q) => parse_qvalue(q)?,
+        None => 1.0,
+    };
+    Some(LanguageRange {
+        tag: tag.to_ascii_lowercase(),
+        q,
+    })
+}
+
+// RFC 7231 `qvalue`: `0` or `1` with up to three decimals, at most 1.000
+fn parse_qvalue(value: &str) -> Option<f32> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    let valid = matches!(int, "0" | "1")
+        && frac.len() <= 3
+        && frac.bytes().all(|b| b.is_ascii_digit())
+        && (int == "0" || frac.bytes().all(|b| b == b'0'));
+    if !valid {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// `Content-Type` with its parameters, e.g.
+// `multipart/form-data; boundary="abc"`. Type, subtype and parameter names
+// are lowercased; values are unquoted but otherwise kept as sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    kind: String,
+    subtype: String,
+    params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (essence, mut rest) = value.split_once(';').unwrap_or((value, ""));
+        let (kind, subtype) = essence.trim().split_once('/')?;
+        let (kind, subtype) = (kind.trim(), subtype.trim());
+        if !is_token(kind) || !is_token(subtype) {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        loop {
+            rest = rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+            if rest.is_empty() {
+                break;
+            }
+            let Some((name, after)) = rest.split_once('=') else {
+                break;
+            };
+            let (value, remaining) = match after.strip_prefix('"') {
+                Some(quoted) => parse_quoted(quoted),
+                None => {
+                    let end = after.find(';').unwrap_or(after.len());
+                    (after[..end].trim().to_string(), &after[end..])
+                }
+            };
+            let name = name.trim();
+            if is_token(name) {
+                params.push((name.to_ascii_lowercase(), value));
+            }
+            rest = remaining;
+        }
+
+        Some(Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params,
+        })
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        Self::parse(headers.get(CONTENT_TYPE)?.to_str().ok()?)
+    }
+
+    // `type/subtype`, e.g. `application/json`
+    pub fn mime_type(&self) -> String {
+        format!("{}/{}", self.kind, self.subtype)
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn subtype(&self) -> &str {
+        &self.subtype
+    }
+
+    // First value of the parameter; names ignore case
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    pub fn boundary(&self) -> Option<&str> {
+        self.param("boundary")
+    }
+
+    // `application/json` and any `+json` type
+    pub fn is_json(&self) -> bool {
+        (self.kind == "application" && self.subtype == "json") || self.subtype.ends_with("+json")
+    }
+}
+
+// Reads a quoted-string whose opening quote is already consumed; returns the
+// unescaped value and what follows the closing quote (or nothing if it's
+// missing)
+fn parse_quoted(input: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return (value, &input[i + 1..]),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            _ => value.push(c),
+        }
+    }
+    (value, "")
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+// Substrings (lowercase) that mark automated clients: crawlers, link
+// preview fetchers, monitoring and common HTTP libraries
+const BOT_MARKERS: &[&str] = &[
+    "bot", "crawl", "spider", "slurp", "mediapartners", "facebookexternalhit",
+    "headlesschrome", "lighthouse", "curl/", "wget/", "python-requests",
+    "python-urllib", "go-http-client", "okhttp", "java/", "libwww-perl",
+    "httpclient", "axios/", "node-fetch",
+];
+
+// `User-Agent`, kept raw, with a rough guess at whether it's automated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent {
+    raw: String,
+}
+
+impl UserAgent {
+    pub fn new(raw: impl Into<String>) -> Self {
+        Self { raw: raw.into() }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let raw = headers.get(USER_AGENT)?;
+        Some(Self::new(String::from_utf8_lossy(raw.as_bytes())))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    // Heuristic only: true for well-known crawler and HTTP-library agents
+    // and for blank ones. Anything can claim to be a browser, so don't use
+    // this for access control.
+    pub fn is_bot(&self) -> bool {
+        let lower = self.raw.to_ascii_lowercase();
+        lower.trim().is_empty() || BOT_MARKERS.iter().any(|marker| lower.contains(marker))
+    }
+}
+
+// The token of `Authorization: Bearer <token>`, borrowed from the header.
+// The scheme is matched case-insensitively; None for other schemes and
+// for an empty token.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?.trim();
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
+    }
+    Some(token.trim_start()).filter(|token| !token.is_empty())
+}
+
+// The value of an API key header such as `X-API-Key`, trimmed, borrowed
+// from the header; None when absent, empty or not visible ASCII
+pub fn api_key<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?.trim();
+    Some(value).filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(value: &str) -> Vec<(String, f32)> {
+        AcceptLanguage::parse(value)
+            .languages()
+            .iter()
+            .map(|range| (range.tag.clone(), range.q))
+            .collect()
+    }
+
+    #[test]
+    fn accept_language_parsing() {
+        let cases: &[(&str, &[(&str, f32)])] = &[
+            ("", &[]),
+            ("en", &[("en", 1.0)]),
+            ("EN-gb", &[("en-gb", 1.0)]),
+            // Sorted by q, ties in header order
+            ("fr;q=0.5, de, en;q=0.8, it", &[("de", 1.0), ("it", 1.0), ("en", 0.8), ("fr", 0.5)]),
+            ("da, en-gb;q=0.8, en;q=0.7", &[("da", 1.0), ("en-gb", 0.8), ("en", 0.7)]),
+            ("*;q=0.1, en", &[("en", 1.0), ("*", 0.1)]),
+            // q=0 is "not acceptable"
+            ("en;q=0, de;q=0.000", &[]),
+            ("de; Q=0.5 ,en", &[("en", 1.0), ("de", 0.5)]),
+            ("de;q=1.000, en;q=0.001", &[("de", 1.0), ("en", 0.001)]),
+            // Malformed q-values drop their range
+            ("de;q=abc, en", &[("en", 1.0)]),
+            ("de;q=1.5, en", &[("en", 1.0)]),
+            ("de;q=-0.5, en", &[("en", 1.0)]),
+            ("de;q=0.5000, en", &[("en", 1.0)]),
+            ("de;q=1e0, en", &[("en", 1.0)]),
+            ("de;q=.5, en", &[("en", 1.0)]),
+            ("de;q=, en", &[("en", 1.0)]),
+            ("de;q=1.001, en", &[("en", 1.0)]),
+            // Malformed tags too
+            ("en_US, d e, toolongsubtag, -en, en-, fr", &[("fr", 1.0)]),
+            (",,en,,", &[("en", 1.0)]),
+        ];
+        for (value, expected) in cases {
+            let expected: Vec<(String, f32)> =
+                expected.iter().map(|(tag, q)| (tag.to_string(), *q)).collect();
+            assert_eq!(tags(value), expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn accept_language_pick() {
+        let available = ["en", "en-GB", "de", "fr-CA"];
+        let cases: &[(&str, Option<&str>)] = &[
+            ("", None),
+            ("de", Some("de")),
+            ("DE", Some("de")),
+            ("en-gb", Some("en-GB")),
+            // A range accepts longer tags starting with it...
+            ("fr", Some("fr-CA")),
+            // ...and an available tag that is a prefix of it
+            ("de-CH", Some("de")),
+            ("en-US", Some("en")),
+            // An exact match wins over prefixes
+            ("en", Some("en")),
+            ("it, fr-ca;q=0.3, de;q=0.5", Some("de")),
+            ("it;q=0.9, es", None),
+            ("*", Some("en")),
+            ("it, *;q=0.1", Some("en")),
+            ("de;q=0.2, *;q=0.5", Some("en")),
+            // Not acceptable, and nothing else is
+            ("de;q=0", None),
+            ("de;q=bogus", None),
+            ("de;q=bogus, fr", Some("fr-CA")),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(AcceptLanguage::parse(value).pick(&available), *expected, "{value:?}");
+        }
+        assert_eq!(AcceptLanguage::parse("de").pick(&[]), None);
+    }
+
+    #[test]
+    fn header_lines_combine() {
+        let mut headers = HeaderMap::new();
+        headers.append(ACCEPT_LANGUAGE, "fr;q=0.4".parse().unwrap());
+        headers.append(ACCEPT_LANGUAGE, "de;q=0.6, en;q=0.2".parse().unwrap());
+        let accept = AcceptLanguage::from_headers(&headers);
+        assert_eq!(accept.pick(&["en", "fr"]), Some("fr"));
+        assert_eq!(accept.pick(&["en", "fr", "de"]), Some("de"));
+
+        let locales = Locales::new(&["en", "fr"], "en");
+        assert_eq!(locales.negotiate(&accept), "fr");
+        assert_eq!(locales.negotiate(&AcceptLanguage::default()), "en");
+    }
+}
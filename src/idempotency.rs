@@ -0,0 +1,369 @@
+use crate::layer::{Layer, Next};
+use crate::{Response, Result, ServerError};
+use bytes::Bytes;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, StatusCode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+// Set on responses replayed from the store
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// Larger (or streamed) responses are sent but not stored, so a retry runs
+// the handler again
+pub const DEFAULT_MAX_CACHED_BODY: usize = 1024 * 1024;
+pub const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// How often a request waiting on an in-flight duplicate checks the store
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+// Idempotency-Key handling as a `Layer`, for routes where a retried request
+// must not run twice (payments, order creation):
+//   router
+//       .route(Method::POST, "/payments", create_payment)
+//       .tag("idempotent")
+//       .layer_for_tag("idempotent", Idempotency::new())
+//
+// The first POST/PUT/PATCH/DELETE carrying a given key runs the handler and
+// its response (status, headers, body) is stored for `ttl`; later requests
+// with the same key, method and path get that response back, marked with
+// `Idempotent-Replayed: true`, without reaching the handler. Requests
+// without the header pass through unless `required` is set, and safe
+// methods are never cached.
+//
+// A duplicate arriving while the first request is still running gets 409
+// Conflict, or with `wait_for_in_flight` waits up to the given time for the
+// first to finish. Handler errors, 5xx responses, streamed bodies and bodies
+// over `max_body` aren't stored, so the client can retry those.
+//
+// Keys are scoped by method and path only: when keys come from untrusted
+// clients, put this behind authentication and have the client derive keys
+// per account (or include the account in the path).
+pub struct Idempotency {
+    store: Arc<dyn IdempotencyStore>,
+    ttl: Duration,
+    max_body: usize,
+    wait: Option<Duration>,
+    required: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Clone)]
+pub enum Reservation {
+    // The caller now owns the key and must `complete` or `release` it
+    Reserved,
+    InFlight,
+    Completed(CachedResponse),
+}
+
+// Where responses are kept. `reserve` must claim a key atomically, so that
+// of two concurrent requests with the same key exactly one gets `Reserved`;
+// a shared store (e.g. Redis `SET NX PX`) makes this hold across instances.
+// Entries, in flight or completed, expire after `ttl`.
+pub trait IdempotencyStore: Send + Sync + 'static {
+    fn reserve(&self, key: &str, ttl: Duration) -> Pin<Box<dyn Future<Output = Result<Reservation>> + Send>>;
+
+    fn complete(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+    // Drops an in-flight reservation so the key can be used again
+    fn release(&self, key: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+}
+
+impl Idempotency {
+    // Backed by a `MemoryStore`, which is per process
+    pub fn new() -> Self {
+        Self::with_store(MemoryStore::new())
+    }
+
+    pub fn with_store<S>(store: S) -> Self
+    where
+        S: IdempotencyStore,
+    {
+        Self {
+            store: Arc::new(store),
+            ttl: DEFAULT_IDEMPOTENCY_TTL,
+            max_body: DEFAULT_MAX_CACHED_BODY,
+            wait: None,
+            required: false,
+        }
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    pub fn wait_for_in_flight(mut self, timeout: Duration) -> Self {
+        self.wait = Some(timeout);
+        self
+    }
+
+    // Rejects unsafe requests without an Idempotency-Key with 400
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    async fn handle(
+        store: Arc<dyn IdempotencyStore>,
+        settings: Settings,
+        req: Request<Body>,
+        next: Next,
+    ) -> Result<Response> {
+        if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+            return next.run(req).await;
+        }
+        let key = match req.headers().get(IDEMPOTENCY_KEY_HEADER) {
+            None if settings.required => {
+                return Err(ServerError::BadRequest(format!(
+                    "missing {} header",
+                    IDEMPOTENCY_KEY_HEADER
+                )));
+            }
+            None => return next.run(req).await,
+            Some(value) => match value.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => key,
+                _ => {
+                    return Err(ServerError::BadRequest(format!(
+                        "{} must be 1-{} visible ASCII characters",
+                        IDEMPOTENCY_KEY_HEADER, MAX_IDEMPOTENCY_KEY_LENGTH
+                    )));
+                }
+            },
+        };
+        let store_key = format!("{} {} {}", req.method(), req.uri().path(), key);
+
+        let started = Instant::now();
+        loop {
+            match store.reserve(&store_key, settings.ttl).await? {
+                Reservation::Reserved => break,
+                Reservation::Completed(cached) => {
+                    debug!("Replaying stored response for {}", store_key);
+                    return Ok(replay(cached));
+                }
+                Reservation::InFlight => {
+                    let waited_enough = settings
+                        .wait
+                        .is_none_or(|wait| started.elapsed() + WAIT_POLL_INTERVAL > wait);
+                    if waited_enough {
+                        return Ok(conflict());
+                    }
+                    tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+                }
+            }
+        }
+
+        // Released if the handler fails, panics or the client goes away
+        let mut claim = Claim {
+            store: store.clone(),
+            key: Some(store_key),
+        };
+        let response = next.run(req).await?;
+        if response.status.is_server_error() {
+            return Ok(response);
+        }
+
+        let (response, body) = buffer_body(response, settings.max_body).await?;
+        if let Some(body) = body {
+            let cached = CachedResponse {
+                status: response.status,
+                headers: response.headers.clone(),
+                body,
+            };
+            let key = claim.key.take().unwrap_or_default();
+            store.complete(&key, cached, settings.ttl).await?;
+        }
+        Ok(response)
+    }
+}
+
+impl Default for Idempotency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Settings {
+    ttl: Duration,
+    max_body: usize,
+    wait: Option<Duration>,
+    required: bool,
+}
+
+impl Layer for Idempotency {
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        let settings = Settings {
+            ttl: self.ttl,
+            max_body: self.max_body,
+            wait: self.wait,
+            required: self.required,
+        };
+        Box::pin(Self::handle(self.store.clone(), settings, req, next))
+    }
+}
+
+struct Claim {
+    store: Arc<dyn IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = store.release(&key).await {
+                debug!("Failed to release idempotency key {}: {}", key, e);
+            }
+        });
+    }
+}
+
+fn replay(cached: CachedResponse) -> Response {
+    let mut response = Response::new().status(cached.status).body(cached.body);
+    response.headers = cached.headers;
+    response.header(IDEMPOTENT_REPLAYED_HEADER, "true")
+}
+
+fn conflict() -> Response {
+    Response::new()
+        .status(StatusCode::CONFLICT)
+        .header("Content-Type", "application/json")
+        .body(
+            serde_json::json!({
+                "error": "a request with this Idempotency-Key is still being processed"
+            })
+            .to_string(),
+        )
+}
+
+// Only bodies of known length are stored; a streamed body (SSE, a file
+// download) could take arbitrarily long to read and goes out uncached
+async fn buffer_body(mut response: Response, limit: usize) -> Result<(Response, Option<Bytes>)> {
+    match response.body.size_hint().exact() {
+        Some(length) if length <= limit as u64 => {
+            let body = hyper::body::to_bytes(std::mem::take(&mut response.body)).await?;
+            response.body = Body::from(body.clone());
+            Ok((response, Some(body)))
+        }
+        _ => Ok((response, None)),
+    }
+}
+
+// An in-process store. Expired entries are pruned by `reserve`, at most
+// once per `PRUNE_INTERVAL`.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct MemoryState {
+    entries: HashMap<String, Entry>,
+    last_prune: Option<Instant>,
+}
+
+struct Entry {
+    expires: Instant,
+    response: Option<CachedResponse>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Includes entries that have expired but not been pruned yet
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl IdempotencyStore for MemoryStore {
+    fn reserve(&self, key: &str, ttl: Duration) -> Pin<Box<dyn Future<Output = Result<Reservation>> + Send>> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state
+            .last_prune
+            .is_none_or(|last| now.duration_since(last) >= PRUNE_INTERVAL)
+        {
+            state.entries.retain(|_, entry| entry.expires > now);
+            state.last_prune = Some(now);
+        }
+        let reservation = match state.entries.get(key) {
+            Some(entry) if entry.expires > now => match &entry.response {
+                Some(response) => Reservation::Completed(response.clone()),
+                None => Reservation::InFlight,
+            },
+            _ => {
+                state.entries.insert(
+                    key.to_string(),
+                    Entry {
+                        expires: now + ttl,
+                        response: None,
+                    },
+                );
+                Reservation::Reserved
+            }
+        };
+        Box::pin(futures::future::ready(Ok(reservation)))
+    }
+
+    fn complete(
+        &self,
+        key: &str,
+        response: CachedResponse,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        self.state.lock().unwrap().entries.insert(
+            key.to_string(),
+            Entry {
+                expires: Instant::now() + ttl,
+                response: Some(response),
+            },
+        );
+        Box::pin(futures::future::ready(Ok(())))
+    }
+
+    fn release(&self, key: &str) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.response.is_none())
+        {
+            state.entries.remove(key);
+        }
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
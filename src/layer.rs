@@ -0,0 +1,67 @@
+use crate::{HandlerFn, Response, Result};
+use hyper::{Body, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+// Middleware around a route's handler, attached to tagged routes with
+// `Router::layer_for_tag`. A layer receives the request and a `Next` running
+// the rest of the chain (the remaining layers, then the handler), and may
+// answer on its own instead:
+//   router.layer_for_tag("internal", |req: Request<Body>, next: Next| async move {
+//       if !is_trusted(&req) {
+//           return Ok(Response::new().status(StatusCode::FORBIDDEN));
+//       }
+//       next.run(req).await
+//   })
+//
+// Layers run after routing, so the path parameters and matched pattern are
+// already in the request (see `RequestExt`). An ownership check on
+// `/orders/:id` needs nothing from the handler:
+//   |req: Request<Body>, next: Next| async move {
+//       let order = req.param("id").and_then(|id| id.parse().ok());
+//       if !order.is_some_and(|order| owns(&req, order)) {
+//           return Ok(Response::new().status(StatusCode::FORBIDDEN));
+//       }
+//       next.run(req).await
+//   }
+pub trait Layer: Send + Sync + 'static {
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
+}
+
+impl<F, Fut> Layer for F
+where
+    F: Fn(Request<Body>, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response>> + Send + 'static,
+{
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        Box::pin(self(req, next))
+    }
+}
+
+pub struct Next {
+    // Outermost first
+    layers: Vec<Arc<dyn Layer>>,
+    position: usize,
+    handler: HandlerFn,
+}
+
+impl Next {
+    pub(crate) fn new(layers: Vec<Arc<dyn Layer>>, handler: HandlerFn) -> Self {
+        Self {
+            layers,
+            position: 0,
+            handler,
+        }
+    }
+
+    pub fn run(mut self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        match self.layers.get(self.position).cloned() {
+            Some(layer) => {
+                self.position += 1;
+                layer.call(req, self)
+            }
+            None => (self.handler)(req),
+        }
+    }
+}
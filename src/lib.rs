@@ -1,11 +1,106 @@
 pub mod router;
 pub mod server;
 pub mod handler;
+pub mod layer;
+pub mod idempotency;
+pub mod quota;
+pub mod coalesce;
+pub mod fanout;
+pub mod canary;
+pub mod transform;
+pub mod headers;
 pub mod error;
+pub mod validation;
 pub mod response;
+pub mod completion;
+pub mod pattern;
+pub mod preconditions;
+pub mod stats;
+pub mod build_info;
+pub mod normalize;
+pub mod rewrite;
+pub mod log_context;
+pub mod timing;
+pub mod compression;
+pub mod audit;
+pub mod trap;
+pub mod deprecation;
+pub mod guard;
+pub mod early_hints;
+pub mod digest;
+pub mod proxy;
+pub mod query;
+pub mod static_files;
+pub mod fs_util;
+pub mod preflight;
+pub mod runtime;
+pub mod sse;
+pub mod example;
+mod build_env;
+mod dump;
+mod body;
+mod cache;
+mod deadline;
+mod connections;
+mod recover;
+mod range;
+mod media_type;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+// Also built for the crate's own unit tests
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "buffer-pool")]
+pub mod buffer_pool;
+mod percent;
+mod queue;
 
-pub use router::{Router, Route, Method};
-pub use server::Server;
-pub use handler::{Handler, HandlerFn};
-pub use error::{ServerError, Result};
-pub use response::Response; 
\ No newline at end of file
+pub use router::{Router, Route, Method, RouteDiagnostic, RouteInfo, RouteIssue};
+pub use body::{read_body, read_json, read_text, DEFAULT_MAX_BODY_SIZE};
+pub use router::DEFAULT_MAX_PATH_SEGMENTS;
+pub use dump::DEFAULT_DEBUG_BODY_LIMIT;
+pub use server::{
+    router_service, RouteCheck, RouterHandle, RouterService, Server, ServerConfig, ServerHandle,
+};
+pub use handler::{CatchAll, Handler, HandlerFn, RequestContext, RequestExt, RequestParts};
+pub use layer::{Layer, Next};
+pub use idempotency::{CachedResponse, Idempotency, IdempotencyStore, MemoryStore, Reservation};
+pub use coalesce::Coalesce;
+pub use fanout::{fanout, Branch};
+pub use canary::{canary, Canary, CanaryHandle, CanaryKey, CanaryStats, VariantStats};
+pub use transform::{BodyTransform, ChunkTransform};
+pub use completion::ResponseOutcome;
+pub use tokio_util::sync::CancellationToken;
+pub use quota::{Exceeded, MemoryQuotaStore, Quota, QuotaPeriod, QuotaStore, QuotaUsage, Remaining};
+pub use headers::{AcceptLanguage, ContentType, LanguageRange, Locales, UserAgent};
+pub use error::{BodyError, ServerError, Result};
+pub use validation::ValidationErrors;
+pub use response::{IntoResponse, Response, RetryAfter, StreamBody};
+pub use stats::{HistogramSnapshot, Stats, StatsSnapshot, TagStats};
+pub use cache::CacheStats;
+pub use log_context::LogContext;
+pub use timing::{ServerTiming, TimingEntry};
+pub use compression::{Compression, Encoder};
+#[cfg(feature = "gzip")]
+pub use compression::GzipEncoder;
+#[cfg(feature = "br")]
+pub use compression::BrotliEncoder;
+#[cfg(feature = "zstd")]
+pub use compression::ZstdEncoder;
+pub use audit::{Audit, AuditBody, AuditEvent, AuditSink};
+pub use trap::{CapturedRequest, RequestTraps, TrapFilter, TrapInfo};
+pub use deprecation::Sunset;
+pub use guard::EndpointGuard;
+pub use early_hints::EarlyHints;
+pub use digest::{ContentDigest, DigestAlgorithm};
+pub use proxy::Proxy;
+pub use query::{Query, QueryConfig, QueryMode};
+pub use static_files::StaticFiles;
+pub use preflight::Preflight;
+pub use queue::QueuePolicy;
+pub use runtime::{RuntimeConfig, ServerRuntime};
+pub use sse::{Event, Sse};
+#[cfg(feature = "chaos")]
+pub use chaos::{ChaosHandle, ChaosLayer, ChaosRule, Fault};
+pub use normalize::{DotSegments, Normalize, NormalizeMode};
+pub use rewrite::{RewriteRule, RewriteRuleInfo, RewriteTable}; 
\ No newline at end of file
@@ -0,0 +1,72 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::field::{self, Empty};
+use tracing::Span;
+
+// Fields attached to one request's logs. The server opens a `request` span
+// around each request and puts a `LogContext` for it in the request
+// extensions (see `RequestExt::log_context`).
+//
+// `record` adds the field to the span, so every later log line emitted
+// inside it carries the field, and to the access-log summary line written
+// once the response body has been sent (with `bytes_sent`, and `aborted`
+// when it was cut short). Tracing spans can't gain new field names after
+// creation, so recorded fields are appended to the span's `ctx` field as
+// `key=value`.
+//
+// The span follows the request future across await points. Work moved to a
+// spawned task only keeps it when the span is propagated explicitly:
+// `tokio::spawn(work.instrument(ctx.span().clone()))`.
+#[derive(Clone)]
+pub struct LogContext {
+    span: Span,
+    fields: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl LogContext {
+    pub(crate) fn new(method: &hyper::Method, path: &str) -> Self {
+        Self {
+            span: tracing::info_span!("request", method = %method, path = %path, ctx = Empty),
+            fields: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Recording a key again replaces its value in the summary line
+    pub fn record<V>(&self, key: &str, value: V)
+    where
+        V: fmt::Display,
+    {
+        let value = value.to_string();
+        self.span
+            .record("ctx", field::display(format_args!("{}={}", key, value)));
+
+        let mut fields = self.fields.lock().unwrap();
+        match fields.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => *existing = value,
+            None => fields.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.fields
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+}
+
+// Recorded fields as ` key=value` pairs, ready to append to a log message
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in self.fields.lock().unwrap().iter() {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+// Media type parsing for Content-Type / Accept style values. Parameters
+// (`; charset=utf-8`) are dropped and names are compared case-insensitively.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MediaType {
+    pub(crate) kind: String,
+    pub(crate) subtype: String,
+}
+
+impl MediaType {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let essence = value.split(';').next()?.trim();
+        let (kind, subtype) = essence.split_once('/')?;
+        let (kind, subtype) = (kind.trim(), subtype.trim());
+        if kind.is_empty() || subtype.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+        })
+    }
+
+    // `self` is a pattern such as `image/*` or `*/*`
+    pub(crate) fn matches(&self, other: &MediaType) -> bool {
+        (self.kind == "*" || self.kind == other.kind)
+            && (self.subtype == "*" || self.subtype == other.subtype)
+    }
+}
@@ -0,0 +1,284 @@
+use crate::Response;
+use hyper::header::{HeaderValue, HOST, LOCATION};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalizeMode {
+    // Route the canonical form directly
+    Rewrite,
+    // Answer with a redirect to the canonical form (301 for GET/HEAD, 308
+    // otherwise so the method and body survive)
+    Redirect,
+}
+
+// URL canonicalization applied by the router before matching. Every rule is
+// off by default. The rules are idempotent, so a redirect target never
+// redirects again.
+//
+// Host handling (lowercasing and dropping `:80` / `:443`) only rewrites the
+// Host header used for comparisons; it never triggers a redirect on its own.
+#[derive(Debug, Clone)]
+pub struct Normalize {
+    mode: NormalizeMode,
+    merge_slashes: bool,
+    strip_index_html: bool,
+    lowercase_path: bool,
+    normalize_host: bool,
+    strip_query_params: Vec<String>,
+}
+
+impl Normalize {
+    pub fn new() -> Self {
+        Self {
+            mode: NormalizeMode::Rewrite,
+            merge_slashes: false,
+            strip_index_html: false,
+            lowercase_path: false,
+            normalize_host: false,
+            strip_query_params: Vec::new(),
+        }
+    }
+
+    pub fn mode(mut self, mode: NormalizeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    // `//users///1` -> `/users/1`
+    pub fn merge_slashes(mut self, enabled: bool) -> Self {
+        self.merge_slashes = enabled;
+        self
+    }
+
+    // `/docs/index.html` -> `/docs/`
+    pub fn strip_index_html(mut self, enabled: bool) -> Self {
+        self.strip_index_html = enabled;
+        self
+    }
+
+    // Risky: also lowercases path parameters and breaks case-sensitive routes
+    pub fn lowercase_path(mut self, enabled: bool) -> Self {
+        self.lowercase_path = enabled;
+        self
+    }
+
+    pub fn normalize_host(mut self, enabled: bool) -> Self {
+        self.normalize_host = enabled;
+        self
+    }
+
+    // Query parameters to drop. A trailing `*` matches by prefix, e.g. `utm_*`.
+    pub fn strip_query_params<I, S>(mut self, params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.strip_query_params = params.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // Rewrites the request in place, or returns the redirect to send instead
+    pub(crate) fn apply(&self, req: &mut Request<Body>) -> Option<Response> {
+        if self.normalize_host {
+            normalize_host_header(req);
+        }
+
+        let path = self.canonical_path(req.uri().path());
+        let query = req.uri().query().map(|q| self.canonical_query(q));
+        let original_query = req.uri().query().map(str::to_string);
+        if path == req.uri().path() && query == original_query {
+            return None;
+        }
+
+        let mut target = path;
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            target.push('?');
+            target.push_str(&query);
+        }
+
+        match self.mode {
+            NormalizeMode::Redirect => {
+                let status = if req.method() == Method::GET || req.method() == Method::HEAD {
+                    StatusCode::MOVED_PERMANENTLY
+                } else {
+                    StatusCode::PERMANENT_REDIRECT
+                };
+                Some(
+                    Response::new()
+                        .status(status)
+                        .header(LOCATION.as_str(), target),
+                )
+            }
+            NormalizeMode::Rewrite => {
+                crate::handler::keep_original_uri(req);
+                let mut parts = req.uri().clone().into_parts();
+                parts.path_and_query = PathAndQuery::from_maybe_shared(target).ok();
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+                None
+            }
+        }
+    }
+
+    fn canonical_path(&self, path: &str) -> String {
+        let mut path = if self.merge_slashes {
+            merge_slashes(path)
+        } else {
+            path.to_string()
+        };
+
+        if self.strip_index_html && path.ends_with("/index.html") {
+            path.truncate(path.len() - "index.html".len());
+        }
+
+        if self.lowercase_path {
+            path = path.to_lowercase();
+        }
+
+        path
+    }
+
+    fn canonical_query(&self, query: &str) -> String {
+        if self.strip_query_params.is_empty() {
+            return query.to_string();
+        }
+
+        query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or("");
+                !self
+                    .strip_query_params
+                    .iter()
+                    .any(|rule| match rule.strip_suffix('*') {
+                        Some(prefix) => key.starts_with(prefix),
+                        None => key == rule,
+                    })
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// What the server does with `.` and `..` path segments (including their
+// percent-encoded forms `%2e`, `%2e%2e`) before anything looks at the path
+// (`Server::with_dot_segments`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DotSegments {
+    // Resolve them as RFC 3986 section 5.2.4 describes: `/users/../admin`
+    // is served as `/admin`, and `..` at the root stays at the root
+    Resolve,
+    // Answer 400 to any request whose path contains one
+    Reject,
+}
+
+// `segment` without its leading `.` or `%2e`
+fn strip_dot(segment: &str) -> Option<&str> {
+    if let Some(rest) = segment.strip_prefix('.') {
+        return Some(rest);
+    }
+    segment
+        .get(..3)
+        .filter(|prefix| prefix.eq_ignore_ascii_case("%2e"))
+        .map(|_| &segment[3..])
+}
+
+fn is_dot(segment: &str) -> bool {
+    strip_dot(segment) == Some("")
+}
+
+fn is_dot_dot(segment: &str) -> bool {
+    strip_dot(segment).is_some_and(is_dot)
+}
+
+pub(crate) fn has_dot_segments(path: &str) -> bool {
+    (path.contains('.') || path.contains('%'))
+        && path.split('/').any(|segment| is_dot(segment) || is_dot_dot(segment))
+}
+
+// `path` with its dot-segments removed; None when it has none. A trailing
+// dot-segment leaves a trailing slash (`/a/b/..` -> `/a/`), and empty
+// segments are kept like any other.
+pub(crate) fn remove_dot_segments(path: &str) -> Option<String> {
+    if !has_dot_segments(path) {
+        return None;
+    }
+    let mut output: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.strip_prefix('/').unwrap_or(path).split('/') {
+        trailing_slash = false;
+        if is_dot(segment) {
+            trailing_slash = true;
+        } else if is_dot_dot(segment) {
+            output.pop();
+            trailing_slash = true;
+        } else {
+            output.push(segment);
+        }
+    }
+    let mut resolved = String::with_capacity(path.len());
+    for segment in &output {
+        resolved.push('/');
+        resolved.push_str(segment);
+    }
+    if trailing_slash || resolved.is_empty() {
+        resolved.push('/');
+    }
+    Some(resolved)
+}
+
+// Replaces the path of `req`'s URI, keeping the rest of it
+pub(crate) fn set_path(req: &mut Request<Body>, path: &str) {
+    crate::handler::keep_original_uri(req);
+    let target = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = PathAndQuery::from_maybe_shared(target).ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
+pub(crate) fn merge_slashes(path: &str) -> String {
+    let mut merged = String::with_capacity(path.len());
+    let mut previous_slash = false;
+    for c in path.chars() {
+        if c == '/' && previous_slash {
+            continue;
+        }
+        previous_slash = c == '/';
+        merged.push(c);
+    }
+    merged
+}
+
+fn normalize_host_header(req: &mut Request<Body>) {
+    let Some(host) = req.headers().get(HOST).and_then(|h| h.to_str().ok()) else {
+        return;
+    };
+
+    let mut canonical = host.to_ascii_lowercase();
+    for default_port in [":80", ":443"] {
+        if let Some(stripped) = canonical.strip_suffix(default_port) {
+            canonical = stripped.to_string();
+            break;
+        }
+    }
+
+    if canonical != host {
+        if let Ok(value) = HeaderValue::from_str(&canonical) {
+            req.headers_mut().insert(HOST, value);
+        }
+    }
+}
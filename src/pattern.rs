@@ -0,0 +1,595 @@
+use crate::handler::CatchAll;
+use std::fmt;
+use std::sync::Arc;
+
+pub type Matcher = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+pub(crate) type Params = Vec<(String, String)>;
+
+// Route patterns are split on '/' into segments. A segment is either static
+// text or a parameter `:name`, optionally constrained as `:name<constraint>`.
+// Constraints are a builtin (`int`, `u64`, `i64`, `u32`, `i32`, `uuid`,
+// `alpha`, `alnum`), a character class such as `[a-z0-9_-]+`, or the name of
+// a custom matcher registered with `Router::matcher`.
+//
+// A final segment `*name` is a catch-all: it matches the rest of the path,
+// zero or more segments, and captures them joined by '/'. It ranks below
+// every other kind of segment.
+#[derive(Clone)]
+pub(crate) enum Segment {
+    Static(String),
+    Param {
+        name: String,
+        constraint: Option<Constraint>,
+    },
+    CatchAll(String),
+}
+
+#[derive(Clone)]
+pub(crate) enum Constraint {
+    Builtin(Builtin),
+    Class(CharClass),
+    Custom {
+        name: String,
+        matcher: Option<Matcher>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Builtin {
+    Int,
+    U64,
+    I64,
+    U32,
+    I32,
+    Uuid,
+    Alpha,
+    Alnum,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CharClass {
+    ranges: Vec<(char, char)>,
+    allow_empty: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct Pattern {
+    pub(crate) segments: Vec<Segment>,
+}
+
+impl Pattern {
+    pub(crate) fn parse(path: &str) -> Self {
+        let segments = split_path(path)
+            .map(|segment| parse_segment(path, segment))
+            .collect::<Vec<_>>();
+
+        let last = segments.len() - 1;
+        if segments[..last]
+            .iter()
+            .any(|segment| matches!(segment, Segment::CatchAll(_)))
+        {
+            panic!(
+                "invalid route pattern `{}`: a catch-all must be the last segment",
+                path
+            );
+        }
+
+        Self { segments }
+    }
+
+    // Parameter names that appear more than once (reported by `Router::check`)
+    pub(crate) fn duplicate_params(&self) -> Vec<&str> {
+        let mut seen: Vec<&str> = Vec::new();
+        let mut duplicates = Vec::new();
+        for segment in &self.segments {
+            if let Segment::Param { name, .. } | Segment::CatchAll(name) = segment {
+                if seen.contains(&name.as_str()) {
+                    duplicates.push(name.as_str());
+                }
+                seen.push(name);
+            }
+        }
+        duplicates
+    }
+
+    pub(crate) fn has_empty_segment(&self) -> bool {
+        self.segments.len() > 1
+            && self
+                .segments
+                .iter()
+                .any(|segment| matches!(segment, Segment::Static(text) if text.is_empty()))
+    }
+
+    // Whether every request matched by `other` is also matched by `self`
+    pub(crate) fn covers(&self, other: &Pattern) -> bool {
+        if let Some(prefix) = self.catch_all_prefix() {
+            // Everything under the prefix, including other catch-alls
+            return other.segments.len() >= prefix.len()
+                && prefix
+                    .iter()
+                    .zip(&other.segments)
+                    .all(|(a, b)| a.covers(b));
+        }
+        if other.catch_all_prefix().is_some() {
+            return false;
+        }
+
+        self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(&other.segments)
+                .all(|(a, b)| a.covers(b))
+    }
+
+    // Returns the captured (name, value) pairs if the request path matches.
+    // Captured values are percent-decoded; segments that fail to decode never match.
+    pub(crate) fn matches(&self, request: &RequestPath<'_>) -> Option<Params> {
+        self.match_segments(request, false)
+    }
+
+    // Static segments compare ASCII case-insensitively; captures keep the
+    // request's case
+    pub(crate) fn matches_ignore_case(&self, request: &RequestPath<'_>) -> Option<Params> {
+        self.match_segments(request, true)
+    }
+
+    // What the trailing catch-all matched in `path`, which the pattern must
+    // match; None if the pattern has no catch-all
+    pub(crate) fn catch_all(&self, path: &str) -> Option<CatchAll> {
+        let prefix = self.catch_all_prefix()?.len();
+        let request: Vec<&str> = split_path(path).collect();
+        CatchAll::from_segments(request.get(prefix..)?)
+    }
+
+    // The segments before a trailing catch-all, if the pattern ends in one
+    fn catch_all_prefix(&self) -> Option<&[Segment]> {
+        match self.segments.last() {
+            Some(Segment::CatchAll(_)) => Some(&self.segments[..self.segments.len() - 1]),
+            _ => None,
+        }
+    }
+
+    // Walks the path's segments in place, statics first, so a miss (the
+    // common case for scanners) allocates nothing
+    fn match_segments(&self, request: &RequestPath<'_>, ignore_case: bool) -> Option<Params> {
+        let segments = match self.catch_all_prefix() {
+            Some(prefix) if request.len >= prefix.len() => prefix,
+            None if request.len == self.segments.len() => &self.segments[..],
+            _ => return None,
+        };
+
+        for (segment, value) in segments.iter().zip(request.segments()) {
+            if let Segment::Static(text) = segment {
+                let equal = if ignore_case {
+                    text.eq_ignore_ascii_case(value)
+                } else {
+                    text == value
+                };
+                if !equal {
+                    return None;
+                }
+            }
+        }
+        let mut request = request.segments();
+        let mut params = Vec::new();
+        for (segment, value) in segments.iter().zip(request.by_ref()) {
+            match segment {
+                Segment::Static(_) => {}
+                Segment::Param { name, constraint } => {
+                    let decoded = crate::percent::decode(value)?;
+                    if let Some(constraint) = constraint {
+                        if !constraint.matches(&decoded) {
+                            return None;
+                        }
+                    }
+                    params.push((name.clone(), decoded));
+                }
+                Segment::CatchAll(_) => unreachable!("catch-all is always the last segment"),
+            }
+        }
+
+        if let Some(Segment::CatchAll(name)) = self.segments.last() {
+            // Each segment is decoded on its own, as for parameters
+            let rest = request
+                .map(crate::percent::decode)
+                .collect::<Option<Vec<_>>>()?;
+            params.push((name.clone(), rest.join("/")));
+        }
+
+        Some(params)
+    }
+
+    // Precedence rank: a pattern without a catch-all beats one with, since a
+    // catch-all can match zero segments (`/assets` beats `/assets/*path`).
+    // Then compared segment by segment, static beats constrained parameters,
+    // which beat unconstrained parameters, which beat a catch-all.
+    pub(crate) fn rank(&self) -> Vec<u8> {
+        let has_catch_all = matches!(self.segments.last(), Some(Segment::CatchAll(_)));
+        std::iter::once(u8::from(!has_catch_all))
+            .chain(self.segments.iter().map(|segment| match segment {
+                Segment::Static(_) => 3,
+                Segment::Param {
+                    constraint: Some(_),
+                    ..
+                } => 2,
+                Segment::Param {
+                    constraint: None, ..
+                } => 1,
+                Segment::CatchAll(_) => 0,
+            }))
+            .collect()
+    }
+}
+
+impl Segment {
+    fn covers(&self, other: &Segment) -> bool {
+        match (self, other) {
+            (Segment::Static(a), Segment::Static(b)) => a == b,
+            (Segment::Static(_), _) => false,
+            (Segment::CatchAll(_), _) | (_, Segment::CatchAll(_)) => false,
+            (Segment::Param { constraint, .. }, Segment::Static(text)) => {
+                constraint.as_ref().is_none_or(|c| c.matches(text))
+            }
+            (Segment::Param { constraint: a, .. }, Segment::Param { constraint: b, .. }) => {
+                match (a, b) {
+                    (None, _) => true,
+                    (Some(a), Some(b)) => a.same_as(b),
+                    (Some(_), None) => false,
+                }
+            }
+        }
+    }
+}
+
+impl Constraint {
+    // Custom matchers are known by name; a router has one matcher per name
+    fn same_as(&self, other: &Constraint) -> bool {
+        match (self, other) {
+            (Constraint::Builtin(a), Constraint::Builtin(b)) => a == b,
+            (Constraint::Class(a), Constraint::Class(b)) => a == b,
+            (Constraint::Custom { name: a, .. }, Constraint::Custom { name: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Constraint::Builtin(builtin) => builtin.matches(value),
+            Constraint::Class(class) => class.matches(value),
+            Constraint::Custom { matcher, .. } => matcher.as_ref().is_some_and(|m| m(value)),
+        }
+    }
+}
+
+impl fmt::Debug for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Constraint::Builtin(builtin) => write!(f, "{:?}", builtin),
+            Constraint::Class(class) => write!(f, "{:?}", class),
+            Constraint::Custom { name, .. } => write!(f, "Custom({})", name),
+        }
+    }
+}
+
+impl Builtin {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(Builtin::Int),
+            "u64" => Some(Builtin::U64),
+            "i64" => Some(Builtin::I64),
+            "u32" => Some(Builtin::U32),
+            "i32" => Some(Builtin::I32),
+            "uuid" => Some(Builtin::Uuid),
+            "alpha" => Some(Builtin::Alpha),
+            "alnum" => Some(Builtin::Alnum),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Builtin::Int | Builtin::I64 => value.parse::<i64>().is_ok(),
+            Builtin::U64 => value.parse::<u64>().is_ok(),
+            Builtin::U32 => value.parse::<u32>().is_ok(),
+            Builtin::I32 => value.parse::<i32>().is_ok(),
+            Builtin::Uuid => is_uuid(value),
+            Builtin::Alpha => !value.is_empty() && value.chars().all(|c| c.is_ascii_alphabetic()),
+            Builtin::Alnum => !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric()),
+        }
+    }
+}
+
+impl CharClass {
+    // Parses `[...]+` or `[...]*`, where the brackets hold single characters
+    // and `a-z` style ranges. A trailing '-' inside the brackets is literal.
+    fn parse(spec: &str) -> Option<Self> {
+        let (body, allow_empty) = if let Some(body) = spec.strip_suffix('+') {
+            (body, false)
+        } else if let Some(body) = spec.strip_suffix('*') {
+            (body, true)
+        } else {
+            return None;
+        };
+
+        let inner: Vec<char> = body.strip_prefix('[')?.strip_suffix(']')?.chars().collect();
+        if inner.is_empty() {
+            return None;
+        }
+
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < inner.len() {
+            let start = inner[i];
+            if i + 2 < inner.len() && inner[i + 1] == '-' {
+                let end = inner[i + 2];
+                if end < start {
+                    return None;
+                }
+                ranges.push((start, end));
+                i += 3;
+            } else {
+                ranges.push((start, start));
+                i += 1;
+            }
+        }
+
+        Some(Self {
+            ranges,
+            allow_empty,
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        (self.allow_empty || !value.is_empty())
+            && value
+                .chars()
+                .all(|c| self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi))
+    }
+}
+
+pub(crate) fn split_path(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+// A request path with its segment count, worked out once per request rather
+// than once per candidate pattern
+pub(crate) struct RequestPath<'a> {
+    path: &'a str,
+    len: usize,
+}
+
+impl<'a> RequestPath<'a> {
+    pub(crate) fn new(path: &'a str) -> Self {
+        Self {
+            path,
+            len: split_path(path).count(),
+        }
+    }
+
+    fn segments(&self) -> std::str::Split<'a, char> {
+        split_path(self.path)
+    }
+}
+
+fn parse_segment(path: &str, segment: &str) -> Segment {
+    if let Some(name) = segment.strip_prefix('*') {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            panic!(
+                "invalid route pattern `{}`: bad catch-all name in `{}`",
+                path, segment
+            );
+        }
+        return Segment::CatchAll(name.to_string());
+    }
+
+    let Some(param) = segment.strip_prefix(':') else {
+        return Segment::Static(segment.to_string());
+    };
+
+    let (name, constraint) = match param.find('<') {
+        Some(open) => {
+            let spec = param[open + 1..].strip_suffix('>').unwrap_or_else(|| {
+                panic!(
+                    "invalid route pattern `{}`: unterminated constraint in `{}`",
+                    path, segment
+                )
+            });
+            (&param[..open], Some(parse_constraint(path, spec)))
+        }
+        None => (param, None),
+    };
+
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        panic!(
+            "invalid route pattern `{}`: bad parameter name in `{}`",
+            path, segment
+        );
+    }
+
+    Segment::Param {
+        name: name.to_string(),
+        constraint,
+    }
+}
+
+fn parse_constraint(path: &str, spec: &str) -> Constraint {
+    if spec.starts_with('[') {
+        return CharClass::parse(spec)
+            .map(Constraint::Class)
+            .unwrap_or_else(|| {
+                panic!(
+                    "invalid route pattern `{}`: bad character class `{}`",
+                    path, spec
+                )
+            });
+    }
+
+    if let Some(builtin) = Builtin::from_name(spec) {
+        return Constraint::Builtin(builtin);
+    }
+
+    if spec.is_empty() || !spec.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        panic!(
+            "invalid route pattern `{}`: bad constraint `{}`",
+            path, spec
+        );
+    }
+
+    // Resolved against the router's registered matchers when the route is added
+    Constraint::Custom {
+        name: spec.to_string(),
+        matcher: None,
+    }
+}
+
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36
+        && value.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(pattern: &str, path: &str) -> Option<Params> {
+        Pattern::parse(pattern).matches(&RequestPath::new(path))
+    }
+
+    fn accepts(pattern: &str, value: &str) -> bool {
+        capture(pattern, &format!("/{}", value)).is_some()
+    }
+
+    #[test]
+    #[should_panic(expected = "bad parameter name in `:`")]
+    fn rejects_an_empty_parameter_name() {
+        Pattern::parse("/users/:");
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated constraint in `:id<int`")]
+    fn rejects_an_unterminated_constraint() {
+        Pattern::parse("/users/:id<int");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad character class `[z-a]+`")]
+    fn rejects_a_backwards_range() {
+        Pattern::parse("/tags/:tag<[z-a]+>");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad character class `[a-z]`")]
+    fn rejects_a_class_without_repetition() {
+        Pattern::parse("/tags/:tag<[a-z]>");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad constraint `not-a-name`")]
+    fn rejects_a_bad_constraint_name() {
+        Pattern::parse("/users/:id<not-a-name>");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad catch-all name in `*`")]
+    fn rejects_an_empty_catch_all_name() {
+        Pattern::parse("/files/*");
+    }
+
+    #[test]
+    #[should_panic(expected = "a catch-all must be the last segment")]
+    fn rejects_a_catch_all_before_the_end() {
+        Pattern::parse("/files/*path/raw");
+    }
+
+    #[test]
+    fn builtin_constraints() {
+        assert!(accepts(":v<int>", "-42") && !accepts(":v<int>", "4x"));
+        assert!(accepts(":v<i64>", "-9223372036854775808"));
+        assert!(accepts(":v<u64>", "18446744073709551615") && !accepts(":v<u64>", "-1"));
+        assert!(accepts(":v<u32>", "4294967295") && !accepts(":v<u32>", "4294967296"));
+        assert!(accepts(":v<i32>", "-2147483648") && !accepts(":v<i32>", "2147483648"));
+        assert!(accepts(":v<uuid>", "67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!accepts(":v<uuid>", "67e55044-10b1-426f-9247-bb680e5fe0c"));
+        assert!(accepts(":v<alpha>", "abcXYZ") && !accepts(":v<alpha>", "abc1"));
+        assert!(accepts(":v<alnum>", "abc123") && !accepts(":v<alnum>", "abc-123"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(accepts(":v<[a-z0-9_-]+>", "my-slug_2"));
+        assert!(!accepts(":v<[a-z0-9_-]+>", "My-Slug"));
+        assert!(!accepts(":v<[a-z]+>", ""));
+        assert!(accepts(":v<[a-z]*>", ""));
+        // Checked after percent-decoding
+        assert!(accepts(":v<[a-z ]+>", "two%20words"));
+    }
+
+    #[test]
+    fn custom_constraints_match_through_their_matcher() {
+        let mut pattern = Pattern::parse("/:v<even>");
+        // Unresolved, it matches nothing
+        assert!(pattern.matches(&RequestPath::new("/4")).is_none());
+
+        let Segment::Param {
+            constraint: Some(Constraint::Custom { matcher, .. }),
+            ..
+        } = &mut pattern.segments[0]
+        else {
+            panic!("expected a custom constraint");
+        };
+        *matcher = Some(Arc::new(|v: &str| v.parse::<u32>().is_ok_and(|n| n % 2 == 0)));
+        assert!(pattern.matches(&RequestPath::new("/4")).is_some());
+        assert!(pattern.matches(&RequestPath::new("/5")).is_none());
+    }
+
+    #[test]
+    fn captures_are_decoded() {
+        assert_eq!(
+            capture("/users/:id/files/*path", "/users/a%20b/files/x/y%2Fz"),
+            Some(vec![
+                ("id".to_string(), "a b".to_string()),
+                ("path".to_string(), "x/y/z".to_string()),
+            ])
+        );
+        assert_eq!(capture("/users/:id", "/users/%zz"), None);
+    }
+
+    #[test]
+    fn same_as_compares_structurally() {
+        let constraint = |pattern: &str| match Pattern::parse(pattern).segments.remove(0) {
+            Segment::Param {
+                constraint: Some(constraint),
+                ..
+            } => constraint,
+            _ => panic!("expected a constrained parameter"),
+        };
+
+        assert!(constraint(":a<int>").same_as(&constraint(":b<int>")));
+        assert!(!constraint(":a<int>").same_as(&constraint(":a<u64>")));
+        assert!(constraint(":a<[a-z]+>").same_as(&constraint(":b<[a-z]+>")));
+        assert!(!constraint(":a<[a-z]+>").same_as(&constraint(":a<[a-z]*>")));
+        assert!(constraint(":a<slug>").same_as(&constraint(":b<slug>")));
+        assert!(!constraint(":a<slug>").same_as(&constraint(":a<sku>")));
+        // A custom matcher named like a builtin's debug output is still custom
+        assert!(!constraint(":a<Int>").same_as(&constraint(":a<int>")));
+    }
+
+    #[test]
+    fn rank_orders_static_constrained_unconstrained_catch_all() {
+        let rank = |pattern: &str| Pattern::parse(pattern).rank();
+
+        assert!(rank("/users/me") > rank("/users/:id<int>"));
+        assert!(rank("/users/:id<int>") > rank("/users/:id"));
+        assert!(rank("/users/:id") > rank("/users/*rest"));
+        // Compared from the first segment on
+        assert!(rank("/users/:id") > rank("/:kind/me"));
+        // Any pattern without a catch-all beats one with
+        assert!(rank("/assets") > rank("/assets/*path"));
+        assert!(rank("/:a/:b") > rank("/assets/css/*path"));
+    }
+}
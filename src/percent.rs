@@ -0,0 +1,103 @@
+// Percent-encoding helpers shared by routing, query parsing and headers.
+// Decoding fails (returns None) on truncated or non-hex escapes and on
+// byte sequences that don't form valid UTF-8.
+
+pub(crate) fn decode(input: &str) -> Option<String> {
+    decode_bytes(input.as_bytes(), false)
+}
+
+// Query components additionally treat '+' as a space
+pub(crate) fn decode_query_component(input: &str) -> Option<String> {
+    decode_bytes(input.as_bytes(), true)
+}
+
+fn decode_bytes(input: &[u8], plus_as_space: bool) -> Option<String> {
+    if !input
+        .iter()
+        .any(|&b| b == b'%' || (plus_as_space && b == b'+'))
+    {
+        return std::str::from_utf8(input).ok().map(str::to_string);
+    }
+
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' => {
+                let hi = hex_value(*input.get(i + 1)?)?;
+                let lo = hex_value(*input.get(i + 2)?)?;
+                out.push(hi << 4 | lo);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(out).ok()
+}
+
+// RFC 5987 `value-chars`: everything but `attr-char` is escaped, as UTF-8
+pub(crate) fn encode_rfc5987(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            out.push(b as char);
+        } else {
+            push_escaped(&mut out, b);
+        }
+    }
+    out
+}
+
+// RFC 3986 `pchar`s pass through; everything else, '/' and '%' included,
+// is escaped
+pub(crate) fn encode_path_segment(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for &b in input.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&b) {
+            out.push(b as char);
+        } else {
+            push_escaped(&mut out, b);
+        }
+    }
+    out
+}
+
+fn push_escaped(out: &mut String, b: u8) {
+    out.push('%');
+    out.push(char::from(b"0123456789ABCDEF"[usize::from(b >> 4)]));
+    out.push(char::from(b"0123456789ABCDEF"[usize::from(b & 0xf)]));
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode("/users/%34%32").as_deref(), Some("/users/42"));
+        assert_eq!(decode("a+b").as_deref(), Some("a+b"));
+        assert_eq!(decode_query_component("a+b%2Bc").as_deref(), Some("a b+c"));
+        assert_eq!(decode("caf%c3%A9").as_deref(), Some("caf\u{e9}"));
+        for bad in ["%zz", "%4", "%", "a%g1", "%C3%28", "caf%C3", "%FF"] {
+            assert_eq!(decode(bad), None, "{bad}");
+            assert_eq!(decode_query_component(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,512 @@
+// Conditional request evaluation (RFC 7232 section 6).
+//
+// Precedence, as specified:
+// 1. If-Match (strong comparison) — failure is 412.
+// 2. Otherwise If-Unmodified-Since — failure is 412.
+// 3. If-None-Match (weak comparison) — a match is 304 for GET/HEAD, 412 otherwise.
+// 4. Otherwise, for GET/HEAD only, If-Modified-Since — not modified is 304.
+//
+// Dates that fail to parse are ignored, as the RFC requires, and so is an
+// If-Modified-Since in the future. Last-Modified is compared at one-second
+// resolution, since that is all an HTTP date carries.
+use crate::Response;
+use hyper::header::{self, HeaderMap};
+use hyper::{Method, StatusCode};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+impl Decision {
+    // The short-circuit response for this decision, if any
+    pub fn response(self) -> Option<Response> {
+        match self {
+            Decision::Proceed => None,
+            Decision::NotModified => Some(Response::new().status(StatusCode::NOT_MODIFIED)),
+            Decision::PreconditionFailed => {
+                Some(Response::new().status(StatusCode::PRECONDITION_FAILED))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    // Parses `"abc"` or `W/"abc"`. The weakness prefix is case-sensitive and
+    // the opaque part may only hold visible ASCII other than `"`, or
+    // non-ASCII (RFC 7232 `etagc`).
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if !tag.chars().all(|c| c == '!' || ('#'..='~').contains(&c) || !c.is_ascii()) {
+            return None;
+        }
+        Some(Self {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+
+    // The comparisons of RFC 7232 section 2.3.2:
+    //   W/"1" vs W/"1": weak match, no strong match
+    //   W/"1" vs "1":   weak match, no strong match
+    //   "1"   vs "1":   both match
+    //   W/"1" vs W/"2": neither
+    //
+    // Both must be strong and byte-identical
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    // Opaque tags equal, weakness ignored
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/\"{}\"", self.tag)
+        } else {
+            write!(f, "\"{}\"", self.tag)
+        }
+    }
+}
+
+// The value of an If-Match / If-None-Match header. Malformed entries
+// (including values that aren't UTF-8) never match anything, but the header
+// still counts as present, so a garbled If-Match fails rather than being
+// ignored.
+enum TagList {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl TagList {
+    fn from_headers(headers: &HeaderMap, name: header::HeaderName) -> Option<Self> {
+        let mut tags = Vec::new();
+        let mut present = false;
+        for value in headers.get_all(name) {
+            present = true;
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            if value.trim() == "*" {
+                return Some(TagList::Any);
+            }
+            tags.extend(split_tags(value).filter_map(EntityTag::parse));
+        }
+        present.then_some(TagList::Tags(tags))
+    }
+
+    fn matches(&self, current: Option<&EntityTag>, strong: bool) -> bool {
+        match (self, current) {
+            (TagList::Any, current) => current.is_some(),
+            (TagList::Tags(_), None) => false,
+            (TagList::Tags(tags), Some(current)) => tags.iter().any(|tag| {
+                if strong {
+                    tag.strong_eq(current)
+                } else {
+                    tag.weak_eq(current)
+                }
+            }),
+        }
+    }
+}
+
+// Splits a comma-separated list of entity tags; commas are legal inside the
+// quoted part so a plain split isn't enough.
+fn split_tags(value: &str) -> impl Iterator<Item = &str> {
+    let mut rest = value;
+    std::iter::from_fn(move || {
+        let mut in_quotes = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '"' => in_quotes = !in_quotes,
+                ',' if !in_quotes => {
+                    let item = &rest[..i];
+                    rest = &rest[i + 1..];
+                    return Some(item.trim());
+                }
+                _ => {}
+            }
+        }
+        if rest.trim().is_empty() {
+            None
+        } else {
+            let item = rest.trim();
+            rest = "";
+            Some(item)
+        }
+    })
+}
+
+pub fn parse_http_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    let value = headers.get(name)?.to_str().ok()?;
+    httpdate::parse_http_date(value).ok()
+}
+
+// HTTP dates have one-second resolution, so comparisons drop sub-second parts
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => UNIX_EPOCH + Duration::from_secs(since.as_secs()),
+        Err(_) => time,
+    }
+}
+
+// `current_etag` is the representation's ETag in header form (`"abc"` or
+// `W/"abc"`); `None` means the resource has no ETag (or doesn't exist, for `*`).
+pub fn evaluate(
+    method: &Method,
+    headers: &HeaderMap,
+    current_etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Decision {
+    let current = current_etag.and_then(EntityTag::parse);
+    let last_modified = last_modified.map(truncate_to_secs);
+    let safe = *method == Method::GET || *method == Method::HEAD;
+
+    if let Some(if_match) = TagList::from_headers(headers, header::IF_MATCH) {
+        if !if_match.matches(current.as_ref(), true) {
+            return Decision::PreconditionFailed;
+        }
+    } else if let Some(since) = parse_http_date(headers, header::IF_UNMODIFIED_SINCE) {
+        if last_modified.is_some_and(|modified| modified > since) {
+            return Decision::PreconditionFailed;
+        }
+    }
+
+    if let Some(if_none_match) = TagList::from_headers(headers, header::IF_NONE_MATCH) {
+        if if_none_match.matches(current.as_ref(), false) {
+            return if safe {
+                Decision::NotModified
+            } else {
+                Decision::PreconditionFailed
+            };
+        }
+    } else if safe {
+        // A date in the future is invalid and ignored
+        let since = parse_http_date(headers, header::IF_MODIFIED_SINCE)
+            .filter(|since| *since <= SystemTime::now());
+        if let Some(since) = since {
+            if last_modified.is_some_and(|modified| modified <= since) {
+                return Decision::NotModified;
+            }
+        }
+    }
+
+    Decision::Proceed
+}
+
+// Conditional GET support used by the server: the precondition headers of
+// a GET/HEAD request are captured before dispatch, then checked against the
+// ETag / Last-Modified headers the handler put on a 200 response.
+pub(crate) fn capture_conditions(method: &Method, headers: &HeaderMap) -> Option<HeaderMap> {
+    if *method != Method::GET && *method != Method::HEAD {
+        return None;
+    }
+
+    let mut conditions = HeaderMap::new();
+    for name in [
+        header::IF_MATCH,
+        header::IF_UNMODIFIED_SINCE,
+        header::IF_NONE_MATCH,
+        header::IF_MODIFIED_SINCE,
+    ] {
+        for value in headers.get_all(&name) {
+            conditions.append(name.clone(), value.clone());
+        }
+    }
+    (!conditions.is_empty()).then_some(conditions)
+}
+
+pub(crate) fn apply_conditional_get(
+    method: &Method,
+    conditions: &HeaderMap,
+    response: Response,
+) -> Response {
+    if response.status != StatusCode::OK {
+        return response;
+    }
+
+    let last_modified = response
+        .header_value("Last-Modified")
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    let decision = evaluate(
+        method,
+        conditions,
+        response.header_value("ETag"),
+        last_modified,
+    );
+
+    match decision {
+        Decision::Proceed => response,
+        // The server drops the body, keeping its length as Content-Length
+        Decision::NotModified => response.status(StatusCode::NOT_MODIFIED),
+        Decision::PreconditionFailed => Response::new().status(StatusCode::PRECONDITION_FAILED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const ETAG: &str = "\"v2\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    fn date(offset: i64) -> String {
+        let time = if offset < 0 {
+            modified() - Duration::from_secs(offset.unsigned_abs())
+        } else {
+            modified() + Duration::from_secs(offset as u64)
+        };
+        httpdate::fmt_http_date(time)
+    }
+
+    type Case = (&'static str, Method, Vec<(header::HeaderName, String)>, Decision);
+
+    fn headers(pairs: &[(header::HeaderName, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn rfc7232_precedence() {
+        use header::{IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE};
+        use Decision::*;
+
+        let before = date(-3600);
+        let after = date(3600);
+        let cases: Vec<Case> = vec![
+            ("no conditions", Method::GET, vec![], Proceed),
+            // If-Match decides alone; If-Unmodified-Since is not consulted
+            (
+                "if-match hit beats stale if-unmodified-since",
+                Method::PUT,
+                vec![(IF_MATCH, ETAG.into()), (IF_UNMODIFIED_SINCE, before.clone())],
+                Proceed,
+            ),
+            (
+                "if-match miss beats fresh if-unmodified-since",
+                Method::PUT,
+                vec![(IF_MATCH, "\"v1\"".into()), (IF_UNMODIFIED_SINCE, after.clone())],
+                PreconditionFailed,
+            ),
+            (
+                "if-unmodified-since alone fails",
+                Method::PUT,
+                vec![(IF_UNMODIFIED_SINCE, before.clone())],
+                PreconditionFailed,
+            ),
+            (
+                "if-unmodified-since alone passes",
+                Method::PUT,
+                vec![(IF_UNMODIFIED_SINCE, after.clone())],
+                Proceed,
+            ),
+            // If-None-Match decides alone; If-Modified-Since is not consulted
+            (
+                "if-none-match hit beats stale if-modified-since",
+                Method::GET,
+                vec![(IF_NONE_MATCH, ETAG.into()), (IF_MODIFIED_SINCE, before.clone())],
+                NotModified,
+            ),
+            (
+                "if-none-match miss beats fresh if-modified-since",
+                Method::GET,
+                vec![(IF_NONE_MATCH, "\"v1\"".into()), (IF_MODIFIED_SINCE, after.clone())],
+                Proceed,
+            ),
+            (
+                "if-modified-since alone, not modified",
+                Method::GET,
+                vec![(IF_MODIFIED_SINCE, after.clone())],
+                NotModified,
+            ),
+            (
+                "if-modified-since alone, modified",
+                Method::GET,
+                vec![(IF_MODIFIED_SINCE, before.clone())],
+                Proceed,
+            ),
+            // A failed If-Match wins over a matching If-None-Match
+            (
+                "if-match checked before if-none-match",
+                Method::GET,
+                vec![(IF_MATCH, "\"v1\"".into()), (IF_NONE_MATCH, ETAG.into())],
+                PreconditionFailed,
+            ),
+            // 304 only for safe methods
+            ("if-none-match on GET", Method::GET, vec![(IF_NONE_MATCH, ETAG.into())], NotModified),
+            ("if-none-match on HEAD", Method::HEAD, vec![(IF_NONE_MATCH, ETAG.into())], NotModified),
+            (
+                "if-none-match on POST",
+                Method::POST,
+                vec![(IF_NONE_MATCH, ETAG.into())],
+                PreconditionFailed,
+            ),
+            (
+                "if-none-match on DELETE",
+                Method::DELETE,
+                vec![(IF_NONE_MATCH, "*".into())],
+                PreconditionFailed,
+            ),
+            (
+                "if-modified-since ignored on POST",
+                Method::POST,
+                vec![(IF_MODIFIED_SINCE, after.clone())],
+                Proceed,
+            ),
+            // Malformed or future dates are ignored
+            (
+                "malformed if-unmodified-since",
+                Method::PUT,
+                vec![(IF_UNMODIFIED_SINCE, "yesterday".into())],
+                Proceed,
+            ),
+            (
+                "malformed if-modified-since",
+                Method::GET,
+                vec![(IF_MODIFIED_SINCE, "Tue, 32 Foo 2020 25:00:00 GMT".into())],
+                Proceed,
+            ),
+            (
+                "future if-modified-since",
+                Method::GET,
+                vec![(IF_MODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT".into())],
+                Proceed,
+            ),
+            (
+                "malformed if-unmodified-since doesn't mask if-none-match",
+                Method::GET,
+                vec![(IF_UNMODIFIED_SINCE, "garbage".into()), (IF_NONE_MATCH, ETAG.into())],
+                NotModified,
+            ),
+        ];
+
+        for (name, method, pairs, expected) in cases {
+            let got = evaluate(&method, &headers(&pairs), Some(ETAG), Some(modified()));
+            assert_eq!(got, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn dates_compare_at_second_resolution() {
+        let headers = headers(&[(header::IF_MODIFIED_SINCE, date(0))]);
+        let modified = modified() + Duration::from_millis(750);
+        assert_eq!(
+            evaluate(&Method::GET, &headers, None, Some(modified)),
+            Decision::NotModified
+        );
+    }
+
+    #[test]
+    fn entity_tag_parse() {
+        assert_eq!(EntityTag::parse("\"abc\""), Some(EntityTag::strong("abc")));
+        assert_eq!(EntityTag::parse(" W/\"abc\" "), Some(EntityTag::weak("abc")));
+        assert_eq!(EntityTag::parse("\"\""), Some(EntityTag::strong("")));
+        assert_eq!(EntityTag::parse("\"caf\u{e9}\""), Some(EntityTag::strong("caf\u{e9}")));
+        for bad in ["abc", "\"abc", "abc\"", "w/\"abc\"", "W/abc", "\"a\"b\"", "\"a b\"", "*"] {
+            assert_eq!(EntityTag::parse(bad), None, "{bad}");
+        }
+        assert_eq!(EntityTag::weak("x").to_string(), "W/\"x\"");
+        assert_eq!(EntityTag::strong("x").to_string(), "\"x\"");
+    }
+
+    #[test]
+    fn entity_tag_comparison() {
+        let (w1, s1) = (EntityTag::weak("1"), EntityTag::strong("1"));
+        let (w2, s2) = (EntityTag::weak("2"), EntityTag::strong("2"));
+        // RFC 7232 section 2.3.2 table
+        assert!(!w1.strong_eq(&w1) && w1.weak_eq(&w1));
+        assert!(!w1.strong_eq(&s1) && w1.weak_eq(&s1));
+        assert!(!s1.strong_eq(&w1) && s1.weak_eq(&w1));
+        assert!(s1.strong_eq(&s1) && s1.weak_eq(&s1));
+        assert!(!w1.strong_eq(&w2) && !w1.weak_eq(&w2));
+        assert!(!s1.strong_eq(&s2) && !s1.weak_eq(&s2));
+    }
+
+    #[test]
+    fn tag_lists_and_wildcards() {
+        use header::{IF_MATCH, IF_NONE_MATCH};
+        use Decision::*;
+
+        let cases: Vec<(header::HeaderName, &str, Option<&str>, Decision)> = vec![
+            (IF_MATCH, "*", Some("\"a\""), Proceed),
+            (IF_MATCH, "*", None, PreconditionFailed),
+            (IF_MATCH, "\"x\", \"a\", \"y\"", Some("\"a\""), Proceed),
+            (IF_MATCH, "\"x\", \"y\"", Some("\"a\""), PreconditionFailed),
+            // If-Match uses the strong comparison
+            (IF_MATCH, "W/\"a\"", Some("\"a\""), PreconditionFailed),
+            (IF_MATCH, "\"a\"", Some("W/\"a\""), PreconditionFailed),
+            // Commas inside the quotes don't split the list
+            (IF_MATCH, "\"x\", \"a,b\"", Some("\"a,b\""), Proceed),
+            // A garbled list is present but matches nothing
+            (IF_MATCH, "a, b", Some("\"a\""), PreconditionFailed),
+            (IF_NONE_MATCH, "*", Some("\"a\""), NotModified),
+            (IF_NONE_MATCH, "*", None, Proceed),
+            (IF_NONE_MATCH, "\"x\", W/\"a\"", Some("\"a\""), NotModified),
+            (IF_NONE_MATCH, "\"x\",\"y\"", Some("\"a\""), Proceed),
+            // If-None-Match uses the weak comparison
+            (IF_NONE_MATCH, "\"a\"", Some("W/\"a\""), NotModified),
+        ];
+
+        for (name, value, current, expected) in cases {
+            let headers = headers(&[(name.clone(), value.into())]);
+            assert_eq!(
+                evaluate(&Method::GET, &headers, current, None),
+                expected,
+                "{name}: {value} against {current:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tag_lists_span_repeated_headers() {
+        let headers = headers(&[
+            (header::IF_NONE_MATCH, "\"x\"".into()),
+            (header::IF_NONE_MATCH, "\"y\", \"a\"".into()),
+        ]);
+        assert_eq!(
+            evaluate(&Method::GET, &headers, Some("\"a\""), None),
+            Decision::NotModified
+        );
+    }
+
+}
@@ -0,0 +1,97 @@
+use crate::Method;
+use bytes::Bytes;
+use hyper::{Body, Request, StatusCode};
+
+// Largest part of an unexpected response body quoted in the startup error
+const QUOTED_BODY_BYTES: usize = 200;
+
+// A synthetic request run against the router before the server binds its
+// listener (`Server::preflight`). It goes through the same dispatch path as
+// real traffic, so it warms route caches, lazily initialized state and
+// anything the handler touches. Without `expect_status`, any 2xx passes.
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    method: Method,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    expect_status: Option<StatusCode>,
+}
+
+impl Preflight {
+    // `path` may include a query string
+    pub fn new(method: Method, path: impl Into<String>) -> Self {
+        Self {
+            method,
+            path: path.into(),
+            headers: Vec::new(),
+            body: Bytes::new(),
+            expect_status: None,
+        }
+    }
+
+    pub fn get(path: impl Into<String>) -> Self {
+        Self::new(Method::GET, path)
+    }
+
+    pub fn post(path: impl Into<String>, body: impl Into<Bytes>) -> Self {
+        Self::new(Method::POST, path).body(body)
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Panics on a status code outside 100..=999
+    pub fn expect_status(mut self, status: u16) -> Self {
+        self.expect_status = Some(
+            StatusCode::from_u16(status)
+                .unwrap_or_else(|_| panic!("invalid preflight status {}", status)),
+        );
+        self
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+
+    pub(crate) fn request(&self) -> crate::Result<Request<Body>> {
+        let mut request = Request::builder()
+            .method(hyper::Method::from(self.method.clone()))
+            .uri(self.path.as_str());
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        Ok(request.body(Body::from(self.body.clone()))?)
+    }
+
+    // `Err` describes the mismatch
+    pub(crate) fn verify(&self, status: StatusCode, body: &[u8]) -> Result<(), String> {
+        let passed = match self.expect_status {
+            Some(expected) => status == expected,
+            None => status.is_success(),
+        };
+        if passed {
+            return Ok(());
+        }
+
+        let expected = match self.expect_status {
+            Some(expected) => expected.as_u16().to_string(),
+            None => "2xx".to_string(),
+        };
+        let quoted = String::from_utf8_lossy(&body[..body.len().min(QUOTED_BODY_BYTES)]);
+        Err(format!(
+            "{} returned {}, expected {}; body: {:?}",
+            self.describe(),
+            status.as_u16(),
+            expected,
+            quoted
+        ))
+    }
+}
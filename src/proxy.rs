@@ -0,0 +1,187 @@
+use crate::handler::Handler;
+use crate::{Response, Result, ServerError};
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::http::uri::{PathAndQuery, Uri};
+use hyper::{Body, Client, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 32;
+pub const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Reverse proxy handler: `.get("/status", Proxy::new("http://10.0.0.2:8080"))`
+// forwards the request path and query to the upstream.
+//
+// Upstream connections come from a pooled `hyper::Client` created on first
+// use and shared by every request through this handler; pass your own with
+// `Proxy::with_client` to share one pool between several proxies. Request
+// and response bodies are streamed through without buffering, and trailers
+// travel with the body where the protocol supports them (HTTP/2).
+//
+// Hop-by-hop headers (RFC 7230 section 6.1, plus any named in `Connection`)
+// are dropped in both directions. The upstream sees its own Host, with the
+// original in `X-Forwarded-Host`. Connection failures are 502 Bad Gateway.
+pub struct Proxy {
+    upstream: Uri,
+    strip_prefix: Option<String>,
+    pool_max_idle_per_host: usize,
+    pool_idle_timeout: Duration,
+    preserve_header_case: bool,
+    client: OnceLock<Client<HttpConnector, Body>>,
+}
+
+impl Proxy {
+    // Panics if `upstream` isn't an absolute `http://host[:port]` URI
+    pub fn new(upstream: &str) -> Self {
+        let upstream: Uri = upstream
+            .parse()
+            .unwrap_or_else(|e| panic!("Invalid proxy upstream `{}`: {}", upstream, e));
+        if upstream.scheme().is_none() || upstream.authority().is_none() {
+            panic!("Proxy upstream `{}` must be an absolute URI", upstream);
+        }
+
+        Self {
+            upstream,
+            strip_prefix: None,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            preserve_header_case: false,
+            client: OnceLock::new(),
+        }
+    }
+
+    // Uses an existing client (and its connection pool); the pool settings
+    // below are then ignored
+    pub fn with_client(upstream: &str, client: Client<HttpConnector, Body>) -> Self {
+        let proxy = Self::new(upstream);
+        let _ = proxy.client.set(client);
+        proxy
+    }
+
+    // Idle connections kept open per upstream host
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = max;
+        self
+    }
+
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    // Writes request header names upstream in the case the client sent
+    // them, which needs `Server::http1_preserve_header_case` to record it
+    pub fn http1_preserve_header_case(mut self, enabled: bool) -> Self {
+        self.preserve_header_case = enabled;
+        self
+    }
+
+    // `/api/users` with prefix `/api` is forwarded as `/users`
+    pub fn strip_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.strip_prefix = Some(prefix.into());
+        self
+    }
+
+    fn client(&self) -> Client<HttpConnector, Body> {
+        self.client
+            .get_or_init(|| {
+                Client::builder()
+                    .pool_max_idle_per_host(self.pool_max_idle_per_host)
+                    .pool_idle_timeout(self.pool_idle_timeout)
+                    .http1_preserve_header_case(self.preserve_header_case)
+                    .build_http()
+            })
+            .clone()
+    }
+
+    fn upstream_uri(&self, uri: &Uri) -> Result<Uri> {
+        let path = uri.path();
+        let path = match &self.strip_prefix {
+            Some(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some("") => "/",
+                Some(rest) => rest,
+                None => path,
+            },
+            None => path,
+        };
+
+        let base = self.upstream.path().trim_end_matches('/');
+        let mut target = format!("{}{}", base, path);
+        if let Some(query) = uri.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+
+        let mut parts = self.upstream.clone().into_parts();
+        parts.path_and_query =
+            Some(PathAndQuery::from_maybe_shared(target).map_err(hyper::http::Error::from)?);
+        Ok(Uri::from_parts(parts).map_err(hyper::http::Error::from)?)
+    }
+}
+
+impl Handler for Proxy {
+    fn call(&self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        let client = self.client();
+        let uri = self.upstream_uri(req.uri());
+
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            parts.uri = uri?;
+            parts.version = hyper::Version::HTTP_11;
+
+            let original_host = parts.headers.remove(header::HOST);
+            strip_hop_by_hop(&mut parts.headers);
+            if let Some(host) = original_host {
+                parts
+                    .headers
+                    .insert(HeaderName::from_static("x-forwarded-host"), host);
+            }
+
+            let upstream = client
+                .request(Request::from_parts(parts, body))
+                .await
+                .map_err(|e| ServerError::BadGateway(e.to_string()))?;
+
+            let (mut parts, body) = upstream.into_parts();
+            strip_hop_by_hop(&mut parts.headers);
+
+            let mut response = Response::new().status(parts.status).body(body);
+            for (name, value) in parts.headers.iter() {
+                response = response.append_header(
+                    name.as_str(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                );
+            }
+            Ok(response)
+        })
+    }
+}
+
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Headers the sender listed in `Connection` are hop-by-hop as well
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in HOP_BY_HOP.iter().chain(listed.iter()) {
+        headers.remove(name);
+    }
+}
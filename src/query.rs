@@ -0,0 +1,664 @@
+use crate::ServerError;
+use hyper::{Body, Request};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use std::fmt;
+use std::ops::Deref;
+
+pub const DEFAULT_MAX_QUERY_DEPTH: usize = 5;
+
+// How `Query<T>` reads the query string, set with `Router::with_query_config`.
+//
+// `Flat` takes every key literally, like `serde_urlencoded`, except that a
+// repeated key (`?tag=a&tag=b`) fills a `Vec`. `Structured` additionally
+// understands brackets the way `serde_qs` does:
+//   ids[]=1&ids[]=2             -> ids: Vec<u32>
+//   filter[status]=open         -> filter: a struct or map
+//   items[0][name]=a            -> items: Vec<Item>, ordered by index
+// Keys are percent-decoded before brackets are parsed, so `ids%5B%5D=1` is
+// the same as `ids[]=1`. Keys nested deeper than `max_depth` are rejected.
+//
+// With `strict`, parameters that don't correspond to a struct field and
+// pairs that fail to decode are errors; otherwise they are ignored.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryConfig {
+    pub mode: QueryMode,
+    pub max_depth: usize,
+    pub strict: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryMode {
+    Flat,
+    Structured,
+}
+
+impl QueryConfig {
+    pub fn flat() -> Self {
+        Self {
+            mode: QueryMode::Flat,
+            max_depth: DEFAULT_MAX_QUERY_DEPTH,
+            strict: false,
+        }
+    }
+
+    pub fn structured() -> Self {
+        Self {
+            mode: QueryMode::Structured,
+            ..Self::flat()
+        }
+    }
+
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self::flat()
+    }
+}
+
+// Query string deserialized into `T`:
+//   let Query(page) = Query::<Pagination>::from_request(&req)?;
+// The mode comes from the router that dispatched the request (the default
+// `QueryConfig` otherwise). Errors are 400 Bad Request naming the offending
+// key path, e.g. `filter[status]`.
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> Query<T> {
+    pub fn from_request(req: &Request<Body>) -> crate::Result<Self> {
+        let config = req
+            .extensions()
+            .get::<QueryConfig>()
+            .copied()
+            .unwrap_or_default();
+        Ok(Self(from_query(req.uri().query().unwrap_or(""), &config)?))
+    }
+}
+
+impl<T> Deref for Query<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+pub fn from_query<T: DeserializeOwned>(query: &str, config: &QueryConfig) -> Result<T, QueryError> {
+    let root = parse(query, config)?;
+    T::deserialize(NodeDeserializer {
+        node: Node::Map(root),
+        path: String::new(),
+        strict: config.strict,
+    })
+}
+
+#[derive(Debug)]
+pub struct QueryError {
+    // `None` until the error has bubbled up to the node it belongs to
+    path: Option<String>,
+    field: Option<&'static str>,
+    message: String,
+}
+
+impl QueryError {
+    fn new(path: &str, message: impl Into<String>) -> Self {
+        Self {
+            path: Some(path.to_string()),
+            field: None,
+            message: message.into(),
+        }
+    }
+
+    // The key path the error refers to, e.g. `items[0][name]`
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("")
+    }
+
+    fn at(mut self, path: &str) -> Self {
+        if self.path.is_none() {
+            self.path = Some(match self.field {
+                Some(field) => child_path(path, field),
+                None => path.to_string(),
+            });
+        }
+        self
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path() {
+            "" => write!(f, "invalid query: {}", self.message),
+            path => write!(f, "invalid query parameter `{}`: {}", path, self.message),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl de::Error for QueryError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self {
+            path: None,
+            field: None,
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Self {
+            path: None,
+            field: Some(field),
+            message: "missing field".to_string(),
+        }
+    }
+}
+
+impl From<QueryError> for ServerError {
+    fn from(error: QueryError) -> Self {
+        ServerError::BadRequest(error.to_string())
+    }
+}
+
+enum Node {
+    Value(String),
+    List(Vec<Node>),
+    // Insertion ordered; numeric keys make it usable as a list
+    Map(Vec<(String, Node)>),
+}
+
+fn child_path(parent: &str, key: &str) -> String {
+    if parent.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}[{}]", parent, key)
+    }
+}
+
+fn parse(query: &str, config: &QueryConfig) -> Result<Vec<(String, Node)>, QueryError> {
+    let mut root = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (raw_key, raw_value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decoded = crate::percent::decode_query_component(raw_key)
+            .zip(crate::percent::decode_query_component(raw_value));
+        let Some((key, value)) = decoded else {
+            if config.strict {
+                return Err(QueryError::new(raw_key, "invalid percent-encoding"));
+            }
+            continue;
+        };
+
+        let (name, subkeys) = match config.mode {
+            QueryMode::Flat => (key.as_str(), Vec::new()),
+            QueryMode::Structured => split_key(&key),
+        };
+        if subkeys.len() > config.max_depth {
+            return Err(QueryError::new(
+                &key,
+                format!("nested deeper than {} levels", config.max_depth),
+            ));
+        }
+        insert(&mut root, name, &subkeys, value, &key)?;
+    }
+    Ok(root)
+}
+
+// `a[b][]` -> ("a", ["b", ""]). Keys that aren't well-formed bracket
+// expressions are taken literally.
+fn split_key(key: &str) -> (&str, Vec<&str>) {
+    let Some(open) = key.find('[') else {
+        return (key, Vec::new());
+    };
+    if open == 0 || !key.ends_with(']') {
+        return (key, Vec::new());
+    }
+
+    let mut subkeys = Vec::new();
+    let mut rest = &key[open..];
+    while !rest.is_empty() {
+        let close = match rest.strip_prefix('[').and_then(|r| r.find(']')) {
+            Some(close) => close + 1,
+            None => return (key, Vec::new()),
+        };
+        let subkey = &rest[1..close];
+        if subkey.contains('[') {
+            return (key, Vec::new());
+        }
+        subkeys.push(subkey);
+        rest = &rest[close + 1..];
+    }
+    (&key[..open], subkeys)
+}
+
+fn insert(
+    entries: &mut Vec<(String, Node)>,
+    name: &str,
+    subkeys: &[&str],
+    value: String,
+    key: &str,
+) -> Result<(), QueryError> {
+    let index = match entries.iter().position(|(k, _)| k == name) {
+        Some(index) => index,
+        None if subkeys.is_empty() => {
+            entries.push((name.to_string(), Node::Value(value)));
+            return Ok(());
+        }
+        None => {
+            let node = match subkeys[0] {
+                "" => Node::List(Vec::new()),
+                _ => Node::Map(Vec::new()),
+            };
+            entries.push((name.to_string(), node));
+            entries.len() - 1
+        }
+    };
+    let node = &mut entries[index].1;
+
+    match (subkeys.first(), node) {
+        (None | Some(&""), node) => {
+            if subkeys.len() > 1 {
+                return Err(QueryError::new(key, "`[]` is only allowed at the end of a key"));
+            }
+            match node {
+                Node::List(items) => items.push(Node::Value(value)),
+                Node::Value(first) => {
+                    let first = std::mem::take(first);
+                    *node = Node::List(vec![Node::Value(first), Node::Value(value)]);
+                }
+                Node::Map(_) => {
+                    return Err(QueryError::new(key, "used both as a value and as a map"));
+                }
+            }
+            Ok(())
+        }
+        (Some(subkey), Node::Map(children)) => {
+            insert(children, subkey, &subkeys[1..], value, key)
+        }
+        (Some(_), _) => Err(QueryError::new(key, "used both as a value and as a map")),
+    }
+}
+
+struct NodeDeserializer {
+    node: Node,
+    path: String,
+    strict: bool,
+}
+
+impl NodeDeserializer {
+    fn single(self) -> Result<(String, String), QueryError> {
+        match self.node {
+            Node::Value(value) => Ok((value, self.path)),
+            Node::List(mut items) if items.len() == 1 => match items.pop() {
+                Some(Node::Value(value)) => Ok((value, self.path)),
+                _ => Err(QueryError::new(&self.path, "expected a single value")),
+            },
+            _ => Err(QueryError::new(&self.path, "expected a single value")),
+        }
+    }
+
+    fn into_list(self) -> Result<Vec<NodeDeserializer>, QueryError> {
+        let (path, strict) = (self.path, self.strict);
+        let items: Vec<(String, Node)> = match self.node {
+            Node::Value(value) => vec![("0".to_string(), Node::Value(value))],
+            Node::List(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(i, node)| (i.to_string(), node))
+                .collect(),
+            Node::Map(entries) => {
+                let mut indexed = Vec::with_capacity(entries.len());
+                for (key, node) in entries {
+                    match key.parse::<usize>() {
+                        Ok(index) => indexed.push((index, key, node)),
+                        Err(_) => return Err(QueryError::new(&path, "expected a list")),
+                    }
+                }
+                indexed.sort_by_key(|(index, _, _)| *index);
+                indexed.into_iter().map(|(_, key, node)| (key, node)).collect()
+            }
+        };
+
+        Ok(items
+            .into_iter()
+            .map(|(key, node)| NodeDeserializer {
+                node,
+                path: child_path(&path, &key),
+                strict,
+            })
+            .collect())
+    }
+
+    fn into_map(self) -> Result<MapAccess, QueryError> {
+        match self.node {
+            Node::Map(entries) => Ok(MapAccess {
+                path: self.path,
+                strict: self.strict,
+                entries: entries.into_iter(),
+                value: None,
+            }),
+            _ => Err(QueryError::new(&self.path, "expected a map")),
+        }
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+                let (value, path) = self.single()?;
+                let parsed = value
+                    .parse()
+                    .map_err(|e| QueryError::new(&path, format!("{} (got `{}`)", e, value)))?;
+                visitor.$visit::<QueryError>(parsed).map_err(|e| e.at(&path))
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for NodeDeserializer {
+    type Error = QueryError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let path = self.path.clone();
+        match self.node {
+            Node::Value(value) => visitor.visit_string(value),
+            Node::List(_) => visitor.visit_seq(SeqAccess(self.into_list()?.into_iter())),
+            Node::Map(_) => visitor.visit_map(self.into_map()?),
+        }
+        .map_err(|e| e.at(&path))
+    }
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    // Besides `true` / `false`, accepts what HTML checkboxes and flags send
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let (value, path) = self.single()?;
+        let parsed = match value.as_str() {
+            "true" | "1" | "on" | "yes" => true,
+            "false" | "0" | "off" | "no" => false,
+            _ => return Err(QueryError::new(&path, format!("expected a boolean (got `{}`)", value))),
+        };
+        visitor.visit_bool::<QueryError>(parsed).map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let (value, path) = self.single()?;
+        visitor.visit_string::<QueryError>(value).map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let (value, path) = self.single()?;
+        visitor.visit_byte_buf::<QueryError>(value.into_bytes()).map_err(|e| e.at(&path))
+    }
+
+    // A key that is present is always `Some`; absent fields are `None`
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let path = self.path.clone();
+        visitor.visit_some(self).map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        let path = self.path.clone();
+        visitor.visit_newtype_struct(self).map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let path = self.path.clone();
+        visitor
+            .visit_seq(SeqAccess(self.into_list()?.into_iter()))
+            .map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, QueryError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        let path = self.path.clone();
+        visitor.visit_map(self.into_map()?).map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        if self.strict {
+            if let Node::Map(entries) = &self.node {
+                if let Some((key, _)) = entries.iter().find(|(key, _)| !fields.contains(&key.as_str())) {
+                    return Err(QueryError::new(&child_path(&self.path, key), "unknown field"));
+                }
+            }
+        }
+        self.deserialize_map(visitor)
+    }
+
+    // Unit variants only, named by the value: `?sort=asc`
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, QueryError> {
+        let (value, path) = self.single()?;
+        visitor
+            .visit_enum(IntoDeserializer::<QueryError>::into_deserializer(value))
+            .map_err(|e| e.at(&path))
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, QueryError> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess(std::vec::IntoIter<NodeDeserializer>);
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = QueryError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, QueryError> {
+        self.0.next().map(|item| seed.deserialize(item)).transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapAccess {
+    path: String,
+    strict: bool,
+    entries: std::vec::IntoIter<(String, Node)>,
+    value: Option<NodeDeserializer>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = QueryError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, QueryError> {
+        let Some((key, node)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(NodeDeserializer {
+            node,
+            path: child_path(&self.path, &key),
+            strict: self.strict,
+        });
+        seed.deserialize(IntoDeserializer::<QueryError>::into_deserializer(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, QueryError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(de::Error::custom("value requested before key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Item {
+        name: String,
+        qty: u32,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Order {
+        items: Vec<Item>,
+        #[serde(default)]
+        meta: BTreeMap<String, BTreeMap<String, String>>,
+    }
+
+    fn request(query: &str, config: Option<QueryConfig>) -> Request<Body> {
+        let mut req = Request::get(format!("/orders?{}", query))
+            .body(Body::empty())
+            .unwrap();
+        if let Some(config) = config {
+            req.extensions_mut().insert(config);
+        }
+        req
+    }
+
+    fn structured<T: DeserializeOwned>(query: &str) -> crate::Result<T> {
+        Query::from_request(&request(query, Some(QueryConfig::structured()))).map(|q| q.0)
+    }
+
+    fn item(name: &str, qty: u32) -> Item {
+        Item {
+            name: name.to_string(),
+            qty,
+        }
+    }
+
+    #[test]
+    fn arrays_of_structs_follow_the_indices() {
+        let order: Order =
+            structured("items[1][name]=b&items[0][name]=a&items[1][qty]=2&items[0][qty]=1")
+                .unwrap();
+        assert_eq!(order.items, vec![item("a", 1), item("b", 2)]);
+        assert!(order.meta.is_empty());
+
+        // Indices order the items but needn't be contiguous
+        let order: Order =
+            structured("items[10][name]=c&items[10][qty]=3&items[2][name]=b&items[2][qty]=2")
+                .unwrap();
+        assert_eq!(order.items, vec![item("b", 2), item("c", 3)]);
+    }
+
+    #[test]
+    fn nested_maps() {
+        let order: Order = structured(
+            "items[0][name]=a&items[0][qty]=1&meta[ship][city]=Oslo&meta[ship][zip]=0150&meta[bill][city]=Bergen",
+        )
+        .unwrap();
+        assert_eq!(order.meta["ship"]["city"], "Oslo");
+        assert_eq!(order.meta["ship"]["zip"], "0150");
+        assert_eq!(order.meta["bill"]["city"], "Bergen");
+
+        let maps: HashMap<String, HashMap<String, Vec<u32>>> =
+            structured("a[x][]=1&a[x][]=2&b[y][]=3").unwrap();
+        assert_eq!(maps["a"]["x"], vec![1, 2]);
+        assert_eq!(maps["b"]["y"], vec![3]);
+    }
+
+    #[test]
+    fn percent_encoded_brackets() {
+        let order: Order = structured(
+            "items%5B0%5D%5Bname%5D=caf%C3%A9&items%5B0%5D%5Bqty%5D=4&meta%5Bship%5D%5Bcity%5D=Oslo",
+        )
+        .unwrap();
+        assert_eq!(order.items, vec![item("caf\u{e9}", 4)]);
+        assert_eq!(order.meta["ship"]["city"], "Oslo");
+
+        let ids: HashMap<String, Vec<u32>> = structured("a%5B0%5D=5&a%5b1%5d=6").unwrap();
+        assert_eq!(ids["a"], vec![5, 6]);
+    }
+
+    #[test]
+    fn errors_name_the_key_path() {
+        let err = structured::<Order>("items[0][name]=a&items[0][qty]=many").unwrap_err();
+        let message = err.to_string();
+        assert_eq!(err.status_code(), hyper::StatusCode::BAD_REQUEST);
+        assert!(message.contains("items[0][qty]"), "{message}");
+    }
+
+    #[test]
+    fn flat_without_a_router_config() {
+        // Brackets are only special in structured mode
+        let flat: HashMap<String, String> =
+            Query::from_request(&request("a%5B0%5D=1", None)).unwrap().0;
+        assert_eq!(flat["a[0]"], "1");
+    }
+}
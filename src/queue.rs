@@ -0,0 +1,188 @@
+use crate::stats::Stats;
+use crate::ServerError;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+// What happens to a request arriving when every processing slot is busy and
+// the queue is full (`Server::request_queue`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum QueuePolicy {
+    // The new request gets 503 right away
+    RejectNewest,
+    // The request that has waited longest gets 503 and the new one takes
+    // its place, which bounds how long a queued request can wait
+    RejectOldest,
+    // The new request waits for room in the queue, holding up its
+    // connection; nothing is rejected
+    Block,
+}
+
+// Admission control in front of the handlers: at most `max_running`
+// requests are processed at once and up to `depth` more wait in FIFO
+// order. A request whose client goes away while queued gives up its place.
+pub(crate) struct RequestQueue {
+    max_running: usize,
+    depth: usize,
+    policy: QueuePolicy,
+    state: Mutex<State>,
+    stats: Arc<Stats>,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    next_id: u64,
+    queued: VecDeque<Waiter>,
+    // `Block` requests waiting for room in the queue
+    blocked: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    id: u64,
+    admit: oneshot::Sender<bool>,
+}
+
+// A processing slot, given back on drop
+pub(crate) struct Permit {
+    queue: Arc<RequestQueue>,
+}
+
+impl RequestQueue {
+    pub(crate) fn new(max_running: usize, depth: usize, policy: QueuePolicy, stats: Arc<Stats>) -> Self {
+        Self {
+            max_running: max_running.max(1),
+            depth,
+            policy,
+            state: Mutex::new(State::default()),
+            stats,
+        }
+    }
+
+    pub(crate) async fn acquire(self: &Arc<Self>) -> Result<Permit, ServerError> {
+        let (id, admitted) = {
+            let mut state = self.state.lock().unwrap();
+            if state.running < self.max_running {
+                state.running += 1;
+                return Ok(Permit { queue: self.clone() });
+            }
+
+            let (tx, rx) = oneshot::channel();
+            state.next_id += 1;
+            let waiter = Waiter {
+                id: state.next_id,
+                admit: tx,
+            };
+            if state.queued.len() < self.depth {
+                state.queued.push_back(waiter);
+            } else {
+                match self.policy {
+                    QueuePolicy::RejectNewest => {
+                        self.stats.queue_rejected();
+                        return Err(overloaded());
+                    }
+                    QueuePolicy::RejectOldest => {
+                        // With a zero-depth queue there is no one to evict
+                        let Some(oldest) = state.queued.pop_front() else {
+                            self.stats.queue_rejected();
+                            return Err(overloaded());
+                        };
+                        let _ = oldest.admit.send(false);
+                        state.queued.push_back(waiter);
+                    }
+                    QueuePolicy::Block => state.blocked.push_back(waiter),
+                }
+            }
+            self.publish_depth(&state);
+            (state.next_id, rx)
+        };
+
+        let started = Instant::now();
+        let mut waiting = Waiting {
+            queue: self.clone(),
+            id,
+            admitted,
+        };
+        let admitted = (&mut waiting.admitted).await.unwrap_or(false);
+        self.stats.record_queue_wait(started.elapsed());
+        // Settled: nothing left for `Waiting` to clean up
+        waiting.id = 0;
+        if admitted {
+            Ok(Permit { queue: self.clone() })
+        } else {
+            self.stats.queue_rejected();
+            Err(overloaded())
+        }
+    }
+
+    // Hands the slot of a finished request to the next one in line
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let Some(waiter) = state.queued.pop_front() else {
+                state.running -= 1;
+                break;
+            };
+            if waiter.admit.send(true).is_ok() {
+                break;
+            }
+        }
+        if state.queued.len() < self.depth {
+            if let Some(blocked) = state.blocked.pop_front() {
+                state.queued.push_back(blocked);
+            }
+        }
+        self.publish_depth(&state);
+    }
+
+    // A queued request went away before it was admitted or rejected
+    fn abandon(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.queued.retain(|waiter| waiter.id != id);
+        state.blocked.retain(|waiter| waiter.id != id);
+        if state.queued.len() < self.depth {
+            if let Some(blocked) = state.blocked.pop_front() {
+                state.queued.push_back(blocked);
+            }
+        }
+        self.publish_depth(&state);
+    }
+
+    fn publish_depth(&self, state: &State) {
+        self.stats
+            .set_queue_depth(state.queued.len() + state.blocked.len());
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+struct Waiting {
+    queue: Arc<RequestQueue>,
+    // 0 once the wait has been settled
+    id: u64,
+    admitted: oneshot::Receiver<bool>,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.id == 0 {
+            return;
+        }
+        // Admitted just before the client went away: pass the slot on
+        if let Ok(true) = self.admitted.try_recv() {
+            self.queue.release();
+        } else {
+            self.queue.abandon(self.id);
+        }
+    }
+}
+
+fn overloaded() -> ServerError {
+    ServerError::ServiceUnavailable("request queue is full".to_string())
+}
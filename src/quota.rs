@@ -0,0 +1,362 @@
+use crate::layer::{Layer, Next};
+use crate::response::RetryAfter;
+use crate::{Response, Result, ServerError};
+use hyper::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::{Body, HeaderMap, Request, StatusCode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const QUOTA_LIMIT_HEADER: &str = "X-Quota-Limit";
+pub const QUOTA_REMAINING_HEADER: &str = "X-Quota-Remaining";
+// Unix time, in seconds, at which the quota refills
+pub const QUOTA_RESET_HEADER: &str = "X-Quota-Reset";
+
+// Long-term usage quotas per API key, as a `Layer`. Unlike rate limiting,
+// which smooths out bursts, a quota caps the total spent per period
+// (a calendar month by default):
+//   router
+//       .get("/search", search)
+//       .tag("metered")
+//       .post("/reports", build_report)
+//       .tag("metered")
+//       .quota_cost(5)
+//       .layer_for_tag("metered", require_api_key)
+//       .layer_for_tag("metered", Quota::new(MemoryQuotaStore::new(10_000), api_key))
+//
+// Each request consumes its route's `Router::quota_cost` (1 unless set)
+// from the key `key` returns, and the response reports what is left in
+// X-Quota-Limit / X-Quota-Remaining / X-Quota-Reset. A request the quota
+// can't cover gets 429 (`ServerError::TooManyRequests`) with Retry-After
+// and the same headers, without reaching the handler.
+//
+// Only authenticated requests may spend a key's quota, or anyone knowing a
+// key could exhaust it. Attach `Quota` after the authentication layer so
+// rejected requests never get here, and have `key` return the
+// authenticated identity rather than a raw header; requests it returns
+// None for pass through unmetered. As a safety net, a 401 or 403 from
+// further in (a later layer or the handler) gives the cost back.
+pub struct Quota {
+    store: Arc<dyn QuotaStore>,
+    key: KeyFn,
+}
+
+type KeyFn = Arc<dyn Fn(&Request<Body>) -> Option<String> + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Remaining {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Exceeded {
+    pub limit: u64,
+    pub reset: SystemTime,
+}
+
+// Where usage is counted. `consume` must check and deduct atomically, so
+// that concurrent requests can't overspend; a shared store (e.g. a Redis
+// script) makes this hold across instances. A request whose cost exceeds
+// what is left consumes nothing.
+//
+// Store failures are the store's to handle: return `Remaining` to fail
+// open, or `Exceeded` to fail closed.
+pub trait QuotaStore: Send + Sync + 'static {
+    fn consume(&self, key: &str, cost: u64) -> Pin<Box<dyn Future<Output = std::result::Result<Remaining, Exceeded>> + Send>>;
+
+    // Gives back a cost consumed for a request that turned out not to count
+    fn refund(&self, key: &str, cost: u64) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+// Per-route cost, inserted into the request extensions by the router
+#[derive(Clone, Copy)]
+pub(crate) struct QuotaCost(pub(crate) u64);
+
+impl Quota {
+    pub fn new<S, K>(store: S, key: K) -> Self
+    where
+        S: QuotaStore,
+        K: Fn(&Request<Body>) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            store: Arc::new(store),
+            key: Arc::new(key),
+        }
+    }
+
+    async fn handle(store: Arc<dyn QuotaStore>, key: Option<String>, req: Request<Body>, next: Next) -> Result<Response> {
+        let Some(key) = key else {
+            return next.run(req).await;
+        };
+        let cost = req.extensions().get::<QuotaCost>().map_or(1, |cost| cost.0);
+        let remaining = store.consume(&key, cost).await.map_err(|exceeded| {
+            ServerError::TooManyRequests {
+                reset: exceeded.reset,
+                limit: Some(exceeded.limit),
+            }
+        })?;
+
+        let result = next.run(req).await;
+        let status = match &result {
+            Ok(response) => response.status,
+            Err(e) => e.status_code(),
+        };
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            store.refund(&key, cost).await;
+            return result;
+        }
+        result.map(|response| {
+            response
+                .header(QUOTA_LIMIT_HEADER, remaining.limit.to_string())
+                .header(QUOTA_REMAINING_HEADER, remaining.remaining.to_string())
+                .header(QUOTA_RESET_HEADER, unix_seconds(remaining.reset).to_string())
+        })
+    }
+}
+
+impl Layer for Quota {
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        let key = (self.key)(&req);
+        Box::pin(Self::handle(self.store.clone(), key, req, next))
+    }
+}
+
+// Headers for a `TooManyRequests` error response
+pub(crate) fn rejection_headers(headers: &mut HeaderMap, reset: SystemTime, limit: Option<u64>) {
+    let wait = RetryAfter::Delay(reset.duration_since(SystemTime::now()).unwrap_or_default());
+    if let Ok(value) = HeaderValue::from_str(&wait.to_string()) {
+        headers.insert(RETRY_AFTER, value);
+    }
+    if let Some(limit) = limit {
+        headers.insert(HeaderName::from_static("x-quota-limit"), HeaderValue::from(limit));
+        headers.insert(HeaderName::from_static("x-quota-remaining"), HeaderValue::from(0));
+        headers.insert(
+            HeaderName::from_static("x-quota-reset"),
+            HeaderValue::from(unix_seconds(reset)),
+        );
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// When quotas refill
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    // At the start of each calendar month, UTC
+    Monthly,
+    // Fixed windows of this length, counted from the Unix epoch
+    Every(Duration),
+}
+
+impl QuotaPeriod {
+    fn next_reset(self, now: SystemTime) -> SystemTime {
+        let secs = unix_seconds(now);
+        match self {
+            QuotaPeriod::Monthly => {
+                let (year, month) = year_month(secs / 86_400);
+                let (year, month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                UNIX_EPOCH + Duration::from_secs(days_from_civil(year, month) * 86_400)
+            }
+            QuotaPeriod::Every(period) => {
+                let period = period.as_secs().max(1);
+                UNIX_EPOCH + Duration::from_secs((secs / period + 1) * period)
+            }
+        }
+    }
+}
+
+// Calendar conversions for days since 1970-01-01 (H. Hinnant's algorithms)
+fn year_month(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month)
+}
+
+// Days from 1970-01-01 to the first of `month`
+fn days_from_civil(year: u64, month: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// One key's usage in the current period, as saved by a `persist_every` hook
+#[derive(Debug, Clone)]
+pub struct QuotaUsage {
+    pub key: String,
+    pub used: u64,
+    pub reset: SystemTime,
+}
+
+type PersistHook = Arc<dyn Fn(Vec<QuotaUsage>) + Send + Sync>;
+
+// An in-process store: every key gets `limit` per period, or its own limit
+// from `limit_for`. Usage lives in memory; to survive restarts, save it
+// with a `persist_every` hook and load it back with `restore`.
+#[derive(Clone)]
+pub struct MemoryQuotaStore {
+    inner: Arc<MemoryInner>,
+}
+
+struct MemoryInner {
+    limit: u64,
+    limits: HashMap<String, u64>,
+    period: QuotaPeriod,
+    usage: Mutex<HashMap<String, QuotaUsage>>,
+    persist: Option<(Duration, PersistHook)>,
+    // Usage changed since the last persist
+    dirty: AtomicBool,
+    persisting: AtomicBool,
+}
+
+impl MemoryQuotaStore {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            inner: Arc::new(MemoryInner {
+                limit,
+                limits: HashMap::new(),
+                period: QuotaPeriod::Monthly,
+                usage: Mutex::new(HashMap::new()),
+                persist: None,
+                dirty: AtomicBool::new(false),
+                persisting: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    // Builder methods below must be called before the store is cloned
+    fn configure(&mut self) -> &mut MemoryInner {
+        Arc::get_mut(&mut self.inner).expect("MemoryQuotaStore configured after it was cloned")
+    }
+
+    pub fn period(mut self, period: QuotaPeriod) -> Self {
+        self.configure().period = period;
+        self
+    }
+
+    // A different limit for one key, e.g. a customer on a larger plan
+    pub fn limit_for(mut self, key: &str, limit: u64) -> Self {
+        self.configure().limits.insert(key.to_string(), limit);
+        self
+    }
+
+    // Calls `hook` with a `snapshot` every `interval` while usage keeps
+    // changing. The task starts with the first request and stops once the
+    // store is dropped, so the store can be built outside a runtime.
+    pub fn persist_every<F>(mut self, interval: Duration, hook: F) -> Self
+    where
+        F: Fn(Vec<QuotaUsage>) + Send + Sync + 'static,
+    {
+        self.configure().persist = Some((interval, Arc::new(hook)));
+        self
+    }
+
+    // Loads saved usage, e.g. at startup; entries whose period has ended
+    // are skipped
+    pub fn restore<I>(&self, usages: I)
+    where
+        I: IntoIterator<Item = QuotaUsage>,
+    {
+        let now = SystemTime::now();
+        let mut usage = self.inner.usage.lock().unwrap();
+        for entry in usages.into_iter().filter(|entry| entry.reset > now) {
+            usage.insert(entry.key.clone(), entry);
+        }
+    }
+
+    // Usage of every key in its current period
+    pub fn snapshot(&self) -> Vec<QuotaUsage> {
+        self.inner.snapshot()
+    }
+
+    fn start_persisting(&self) {
+        let Some((interval, hook)) = self.inner.persist.clone() else {
+            return;
+        };
+        if self.inner.persisting.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let inner: Weak<MemoryInner> = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                if inner.dirty.swap(false, Ordering::Relaxed) {
+                    hook(inner.snapshot());
+                }
+            }
+        });
+    }
+}
+
+impl MemoryInner {
+    fn snapshot(&self) -> Vec<QuotaUsage> {
+        let now = SystemTime::now();
+        let mut usage = self.usage.lock().unwrap();
+        usage.retain(|_, entry| entry.reset > now);
+        usage.values().cloned().collect()
+    }
+}
+
+impl QuotaStore for MemoryQuotaStore {
+    fn consume(&self, key: &str, cost: u64) -> Pin<Box<dyn Future<Output = std::result::Result<Remaining, Exceeded>> + Send>> {
+        self.start_persisting();
+        let inner = &self.inner;
+        let limit = inner.limits.get(key).copied().unwrap_or(inner.limit);
+        let now = SystemTime::now();
+        let mut usage = inner.usage.lock().unwrap();
+        let entry = usage.entry(key.to_string()).or_insert_with(|| QuotaUsage {
+            key: key.to_string(),
+            used: 0,
+            reset: now,
+        });
+        if entry.reset <= now {
+            entry.used = 0;
+            entry.reset = inner.period.next_reset(now);
+        }
+
+        let outcome = match entry.used.checked_add(cost) {
+            Some(used) if used <= limit => {
+                entry.used = used;
+                inner.dirty.store(true, Ordering::Relaxed);
+                Ok(Remaining {
+                    limit,
+                    remaining: limit - used,
+                    reset: entry.reset,
+                })
+            }
+            _ => Err(Exceeded {
+                limit,
+                reset: entry.reset,
+            }),
+        };
+        Box::pin(futures::future::ready(outcome))
+    }
+
+    fn refund(&self, key: &str, cost: u64) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        if let Some(entry) = self.inner.usage.lock().unwrap().get_mut(key) {
+            entry.used = entry.used.saturating_sub(cost);
+            self.inner.dirty.store(true, Ordering::Relaxed);
+        }
+        Box::pin(futures::future::ready(()))
+    }
+}
@@ -0,0 +1,500 @@
+use crate::preconditions::{evaluate, Decision, EntityTag};
+use crate::Response;
+use bytes::Bytes;
+use hyper::header::{self, HeaderMap};
+use hyper::{Body, Method, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tracing::warn;
+
+// Byte range requests (RFC 7233) over any seekable source.
+//
+// A single range is served as 206. A multi-range request gets the full 200,
+// which the RFC allows, unless the caller supplied the representation's
+// Content-Type: then it is answered with a `multipart/byteranges` 206 whose
+// parts are streamed from the source, with the Content-Length computed up
+// front. More than `MAX_RANGES` ranges, or overlapping ones, still get the
+// full 200. Ranges past the end are dropped; a syntactically invalid Range
+// header is ignored, while a valid one with nothing satisfiable is 416. If-Range must
+// strongly match the current ETag, or exactly equal Last-Modified when given
+// as a date; otherwise the whole representation is sent.
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Beyond this a multi-range request is served in full
+pub const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RangeRequest {
+    Full,
+    // Inclusive byte offsets
+    Partial(u64, u64),
+    // Two or more, in the order requested
+    Multiple(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+pub(crate) struct Validators {
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    pub(crate) const NONE: Validators = Validators {
+        etag: None,
+        last_modified: None,
+    };
+
+    // A strong ETag for a file snapshot, from its length and mtime
+    pub(crate) fn for_file(len: u64, modified: Option<SystemTime>) -> Self {
+        let nanos = modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Self {
+            etag: Some(format!("\"{:x}-{:x}\"", len, nanos)),
+            last_modified: modified,
+        }
+    }
+}
+
+// With `content_type`, responses with a body carry it and multi-range
+// requests get `multipart/byteranges`
+pub(crate) fn respond<R>(
+    reader: R,
+    len: u64,
+    headers: &HeaderMap,
+    validators: Validators,
+    content_type: Option<&str>,
+) -> Response
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let mut response = Response::new().header("Accept-Ranges", "bytes");
+    if let Some(etag) = &validators.etag {
+        response = response.header("ETag", etag.as_str());
+    }
+    if let Some(modified) = validators.last_modified {
+        response = response.header("Last-Modified", httpdate::fmt_http_date(modified));
+    }
+
+    if validators.etag.is_some() || validators.last_modified.is_some() {
+        let decision = evaluate(
+            &Method::GET,
+            headers,
+            validators.etag.as_deref(),
+            validators.last_modified,
+        );
+        match decision {
+            Decision::Proceed => {}
+            Decision::NotModified => return response.status(StatusCode::NOT_MODIFIED),
+            Decision::PreconditionFailed => {
+                return response.status(StatusCode::PRECONDITION_FAILED)
+            }
+        }
+    }
+
+    let range = match headers.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if if_range_matches(headers, &validators) => parse_range(range, len),
+        _ => RangeRequest::Full,
+    };
+
+    let (range, content_type) = match (range, content_type) {
+        (RangeRequest::Multiple(_), None) => (RangeRequest::Full, None),
+        (RangeRequest::Multiple(ranges), Some(content_type)) => {
+            return multipart(response, reader, len, &ranges, content_type, &boundary());
+        }
+        (range, content_type) => (range, content_type),
+    };
+    if let Some(content_type) = content_type.filter(|_| range != RangeRequest::Unsatisfiable) {
+        response = response.header("Content-Type", content_type);
+    }
+
+    match range {
+        RangeRequest::Full | RangeRequest::Multiple(_) => response
+            .header("Content-Length", len.to_string())
+            .body(read_window(reader, 0, len)),
+        RangeRequest::Partial(start, end) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+            .header("Content-Length", (end - start + 1).to_string())
+            .body(read_window(reader, start, end - start + 1)),
+        RangeRequest::Unsatisfiable => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("Content-Range", format!("bytes */{}", len)),
+    }
+}
+
+// Each part is framed as
+//   \r\n--<boundary>\r\n
+//   Content-Type: <content_type>\r\n
+//   Content-Range: bytes <start>-<end>/<len>\r\n
+//   \r\n
+//   <bytes>
+// and the body ends with `\r\n--<boundary>--\r\n`
+fn multipart<R>(
+    response: Response,
+    reader: R,
+    len: u64,
+    ranges: &[(u64, u64)],
+    content_type: &str,
+    boundary: &str,
+) -> Response
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    let mut pieces = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    let mut length = 0;
+    for &(start, end) in ranges {
+        let head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, len
+        );
+        length += head.len() as u64 + (end - start + 1);
+        pieces.push_back(Piece::Literal(Bytes::from(head)));
+        pieces.push_back(Piece::Window {
+            start,
+            count: end - start + 1,
+        });
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    length += tail.len() as u64;
+    pieces.push_back(Piece::Literal(Bytes::from(tail)));
+
+    response
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .header("Content-Length", length.to_string())
+        .body(read_pieces(reader, pieces))
+}
+
+// Unpredictable enough not to occur in the content by accident
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let state = RandomState::new();
+    format!(
+        "{:016x}{:016x}",
+        state.hash_one((count, 0u8)),
+        state.hash_one((count, 1u8))
+    )
+}
+
+fn if_range_matches(headers: &HeaderMap, validators: &Validators) -> bool {
+    let Some(if_range) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        let current = validators.etag.as_deref().and_then(EntityTag::parse);
+        return match (EntityTag::parse(if_range), current) {
+            (Some(expected), Some(current)) => expected.strong_eq(&current),
+            _ => false,
+        };
+    }
+
+    match (
+        httpdate::parse_http_date(if_range),
+        validators.last_modified,
+    ) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date)
+        }
+        _ => false,
+    }
+}
+
+fn parse_range(value: &str, len: u64) -> RangeRequest {
+    let value = value.trim();
+    let Some(spec) = value
+        .get(..6)
+        .filter(|unit| unit.eq_ignore_ascii_case("bytes="))
+        .map(|_| value[6..].trim())
+    else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in spec.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+        match parse_spec(spec, len) {
+            Spec::Invalid => return RangeRequest::Full,
+            Spec::Unsatisfiable => {}
+            Spec::Range(start, end) => ranges.push((start, end)),
+        }
+    }
+
+    match ranges.len() {
+        0 if count == 0 => RangeRequest::Full,
+        0 => RangeRequest::Unsatisfiable,
+        1 => RangeRequest::Partial(ranges[0].0, ranges[0].1),
+        _ => {
+            let mut sorted = ranges.clone();
+            sorted.sort_unstable();
+            if sorted.windows(2).any(|pair| pair[1].0 <= pair[0].1) {
+                return RangeRequest::Full;
+            }
+            RangeRequest::Multiple(ranges)
+        }
+    }
+}
+
+enum Spec {
+    Invalid,
+    Unsatisfiable,
+    Range(u64, u64),
+}
+
+fn parse_spec(spec: &str, len: u64) -> Spec {
+    let Some((first, last)) = spec.split_once('-') else {
+        return Spec::Invalid;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix range: the last N bytes
+        let Ok(suffix) = last.parse::<u64>() else {
+            return Spec::Invalid;
+        };
+        if suffix == 0 || len == 0 {
+            return Spec::Unsatisfiable;
+        }
+        return Spec::Range(len.saturating_sub(suffix), len - 1);
+    }
+
+    let Ok(start) = first.parse::<u64>() else {
+        return Spec::Invalid;
+    };
+    let end = if last.is_empty() {
+        None
+    } else {
+        match last.parse::<u64>() {
+            Ok(end) if end >= start => Some(end),
+            _ => return Spec::Invalid,
+        }
+    };
+
+    if start >= len {
+        return Spec::Unsatisfiable;
+    }
+    let end = end.map_or(len - 1, |end| end.min(len - 1));
+    Spec::Range(start, end)
+}
+
+// Streams `count` bytes starting at `start`. A seek or read error ends the
+// body with an error, so the client sees a truncated transfer rather than a
+// short but seemingly complete one.
+fn read_window<R>(reader: R, start: u64, count: u64) -> Body
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    read_pieces(reader, VecDeque::from([Piece::Window { start, count }]))
+}
+
+enum Piece {
+    Literal(Bytes),
+    Window { start: u64, count: u64 },
+}
+
+// Streams the pieces in order, reading windows from `reader` a chunk at a
+// time, with the same error handling as `read_window`
+fn read_pieces<R>(reader: R, pieces: VecDeque<Piece>) -> Body
+where
+    R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+{
+    struct State<R> {
+        reader: R,
+        pieces: VecDeque<Piece>,
+        // Within the window at the front of `pieces`
+        offset: u64,
+        remaining: u64,
+        seeked: bool,
+    }
+
+    let state = State {
+        reader,
+        pieces,
+        offset: 0,
+        remaining: 0,
+        seeked: false,
+    };
+
+    let stream = futures::stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            let (start, count) = match state.pieces.pop_front()? {
+                Piece::Literal(bytes) => return Some((Ok(bytes), Some(state))),
+                Piece::Window { start, count } => (start, count),
+            };
+            if !state.seeked {
+                state.offset = start;
+                state.remaining = count;
+                state.seeked = true;
+                if count > 0 {
+                    if let Err(e) = state.reader.seek(SeekFrom::Start(start)).await {
+                        warn!("Range body seek to byte {} failed: {}", start, e);
+                        return Some((Err(e), None));
+                    }
+                }
+            }
+            if state.remaining == 0 {
+                state.seeked = false;
+                continue;
+            }
+            state.pieces.push_front(Piece::Window { start, count });
+            break;
+        }
+
+        let mut buf = vec![0; READ_CHUNK_SIZE.min(state.remaining as usize)];
+        match state.reader.read(&mut buf).await {
+            Ok(0) => {
+                let e = std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "source ended before the declared length",
+                );
+                warn!("Range body read failed at byte {}: {}", state.offset, e);
+                Some((Err(e), None))
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                state.offset += n as u64;
+                state.remaining -= n as u64;
+                Some((Ok(Bytes::from(buf)), Some(state)))
+            }
+            Err(e) => {
+                warn!("Range body read failed at byte {}: {}", state.offset, e);
+                Some((Err(e), None))
+            }
+        }
+    });
+    Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use std::io::Cursor;
+
+    const CONTENT: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn ranged(range: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        headers
+    }
+
+    async fn body_of(response: Response) -> Vec<u8> {
+        hyper::body::to_bytes(response.body).await.unwrap().to_vec()
+    }
+
+    fn serve(range: &str) -> Response {
+        respond(
+            Cursor::new(CONTENT),
+            CONTENT.len() as u64,
+            &ranged(range),
+            Validators::NONE,
+            Some("text/plain"),
+        )
+    }
+
+    #[tokio::test]
+    async fn multipart_body_is_byte_exact() {
+        let response = multipart(
+            Response::new(),
+            Cursor::new(CONTENT),
+            CONTENT.len() as u64,
+            &[(30, 35), (0, 1), (10, 12)],
+            "text/plain",
+            "BOUNDARY",
+        );
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.header_value("Content-Type"),
+            Some("multipart/byteranges; boundary=BOUNDARY")
+        );
+        let length: usize = response.header_value("Content-Length").unwrap().parse().unwrap();
+
+        let expected = concat!(
+            "\r\n--BOUNDARY\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Range: bytes 30-35/36\r\n",
+            "\r\n",
+            "uvwxyz",
+            "\r\n--BOUNDARY\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Range: bytes 0-1/36\r\n",
+            "\r\n",
+            "01",
+            "\r\n--BOUNDARY\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Range: bytes 10-12/36\r\n",
+            "\r\n",
+            "abc",
+            "\r\n--BOUNDARY--\r\n",
+        );
+        let body = body_of(response).await;
+        assert_eq!(String::from_utf8(body.clone()).unwrap(), expected);
+        assert_eq!(length, body.len());
+    }
+
+    #[tokio::test]
+    async fn content_length_matches_the_body_sent() {
+        let response = serve("bytes=0-0, 5-9, -3");
+        let content_type = response.header_value("Content-Type").unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        assert_eq!(boundary.len(), 32);
+        assert!(boundary.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let length: usize = response.header_value("Content-Length").unwrap().parse().unwrap();
+        let body = body_of(response).await;
+        assert_eq!(length, body.len());
+        assert!(body.ends_with(format!("\r\n--{}--\r\n", boundary).as_bytes()));
+    }
+
+    #[test]
+    fn boundaries_differ_per_response() {
+        assert_ne!(boundary(), boundary());
+    }
+
+    #[tokio::test]
+    async fn too_many_ranges_collapse_to_full_response() {
+        let specs: Vec<String> = (0..=MAX_RANGES as u64)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect();
+        let response = serve(&format!("bytes={}", specs.join(",")));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.header_value("Content-Type"), Some("text/plain"));
+        assert_eq!(body_of(response).await, CONTENT);
+
+        // Exactly the limit is still multipart
+        let response = serve(&format!("bytes={}", specs[..MAX_RANGES].join(",")));
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn overlapping_ranges_collapse_to_full_response() {
+        for range in ["bytes=0-5, 5-9", "bytes=10-20, 0-10", "bytes=0-9, -30", "bytes=3-4, 0-"] {
+            let response = serve(range);
+            assert_eq!(response.status, StatusCode::OK, "{range}");
+            assert_eq!(
+                response.header_value("Content-Length"),
+                Some(CONTENT.len().to_string().as_str())
+            );
+            assert_eq!(body_of(response).await, CONTENT, "{range}");
+        }
+    }
+}
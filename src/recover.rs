@@ -0,0 +1,141 @@
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::task::Poll;
+
+// Handler panics are caught per request and turned into a 500, so one bad
+// handler can't take the connection (or the worker thread) down with it.
+//
+// `catch_unwind` only hands back the payload, so a process-wide panic hook
+// records the location and backtrace into a thread-local while a handler is
+// being polled. The panic unwinds on the same thread that polls the future,
+// which lets `catch_panic` pick the details up right after. Panics outside
+// handlers still go to the previously installed hook.
+//
+// Requires `panic = "unwind"`; with `abort` the process exits as usual.
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static CAPTURED: RefCell<Option<Captured>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+struct Captured {
+    location: Option<String>,
+    backtrace: Backtrace,
+}
+
+// What a caught handler panic left behind, for the error log
+pub(crate) struct PanicReport {
+    message: String,
+    location: Option<String>,
+    // Only when enabled through `RUST_BACKTRACE` / `RUST_LIB_BACKTRACE`
+    backtrace: Option<Backtrace>,
+}
+
+impl PanicReport {
+    fn new(payload: Box<dyn std::any::Any + Send>) -> Self {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+
+        let captured = CAPTURED.with(|c| c.borrow_mut().take());
+        let (location, backtrace) = match captured {
+            Some(Captured {
+                location,
+                backtrace,
+            }) => {
+                let backtrace = match backtrace.status() {
+                    BacktraceStatus::Captured => Some(backtrace),
+                    _ => None,
+                };
+                (location, backtrace)
+            }
+            None => (None, None),
+        };
+
+        Self {
+            message,
+            location,
+            backtrace,
+        }
+    }
+
+    pub(crate) fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+}
+
+impl fmt::Display for PanicReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "'{}' at {}", self.message, location),
+            None => write!(f, "'{}'", self.message),
+        }
+    }
+}
+
+pub(crate) fn install_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(Cell::get) {
+                previous(info);
+                return;
+            }
+
+            let location = info
+                .location()
+                .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+            CAPTURED.with(|c| {
+                *c.borrow_mut() = Some(Captured {
+                    location,
+                    backtrace: Backtrace::capture(),
+                })
+            });
+        }));
+    });
+}
+
+// Marks the current thread as polling a handler until dropped
+struct Catching {
+    previous: bool,
+}
+
+impl Catching {
+    fn enter() -> Self {
+        Self {
+            previous: CATCHING.with(|c| c.replace(true)),
+        }
+    }
+}
+
+impl Drop for Catching {
+    fn drop(&mut self) {
+        CATCHING.with(|c| c.set(self.previous));
+    }
+}
+
+pub(crate) async fn catch_panic<F>(future: F) -> Result<F::Output, PanicReport>
+where
+    F: Future,
+{
+    let mut future = Box::pin(future);
+    std::future::poll_fn(move |cx| {
+        let _catching = Catching::enter();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(PanicReport::new(payload))),
+        }
+    })
+    .await
+}
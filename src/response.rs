@@ -1,19 +1,56 @@
-use hyper::{Body, StatusCode};
+use bytes::Bytes;
+use crate::completion::{CompletionFn, ResponseOutcome};
+use crate::digest::DigestAlgorithm;
+use crate::fs_util;
+use crate::preconditions::EntityTag;
+use crate::ServerError;
+use crate::range::{self, Validators};
+use crate::transform::{ChunkTransform, TransformStream};
+use futures::{Stream, StreamExt, TryStreamExt};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, HeaderMap, Method, Request, StatusCode};
 use serde::Serialize;
-use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
 
+// Buffered chunks between a `Response::channel` producer and the connection
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 16;
+
+// Headers are kept in insertion order and may repeat (e.g. `Set-Cookie`).
+// `header` replaces every existing value for the name, compared
+// case-insensitively; `append_header` adds another value.
+//
+// The Content-Type set by `text`, `html` and `json` is only a default: an
+// explicit `header("Content-Type", ..)` wins whether it comes before or
+// after the helper.
 pub struct Response {
-    status: StatusCode,
-    headers: HashMap<String, String>,
-    body: Body,
+    pub(crate) status: StatusCode,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Body,
+    // The current Content-Type came from a helper rather than `header`
+    pub(crate) default_content_type: bool,
+    // Set by `with_content_digest`; the digest is computed by the server
+    pub(crate) content_digest: Option<DigestAlgorithm>,
+    // Added with `on_complete`
+    pub(crate) on_complete: Vec<CompletionFn>,
 }
 
 impl Response {
     pub fn new() -> Self {
         Self {
             status: StatusCode::OK,
-            headers: HashMap::new(),
+            headers: Vec::new(),
             body: Body::empty(),
+            default_content_type: false,
+            content_digest: None,
+            on_complete: Vec::new(),
         }
     }
 
@@ -27,7 +64,25 @@ impl Response {
         K: Into<String>,
         V: Into<String>,
     {
-        self.headers.insert(key.into(), value.into());
+        let key = key.into();
+        if key.eq_ignore_ascii_case("Content-Type") {
+            self.default_content_type = false;
+        }
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        self.headers.push((key, value.into()));
+        self
+    }
+
+    pub fn append_header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let key = key.into();
+        if key.eq_ignore_ascii_case("Content-Type") {
+            self.default_content_type = false;
+        }
+        self.headers.push((key, value.into()));
         self
     }
 
@@ -39,40 +94,498 @@ impl Response {
         self
     }
 
+    // Replaces any earlier Cache-Control, including the one `no_cache` sets
+    pub fn cache_control<V>(self, value: V) -> Self
+    where
+        V: Into<String>,
+    {
+        self.header("Cache-Control", value)
+    }
+
+    // Forbids caching anywhere: `Cache-Control: no-store, no-cache,
+    // must-revalidate`, plus `Pragma: no-cache` and `Expires: 0` for HTTP/1.0
+    // caches. A later `cache_control` wins, since caches that understand
+    // Cache-Control ignore Pragma and Expires when it is present.
+    pub fn no_cache(self) -> Self {
+        self.cache_control("no-store, no-cache, must-revalidate")
+            .header("Pragma", "no-cache")
+            .header("Expires", "0")
+    }
+
+    // Formatted as an HTTP date, so sub-second precision is dropped. With
+    // conditional GET enabled, the server answers a GET/HEAD whose
+    // If-Modified-Since is not older with 304.
+    pub fn last_modified(self, time: SystemTime) -> Self {
+        self.header("Last-Modified", httpdate::fmt_http_date(time))
+    }
+
+    // `Retry-After` on a 503 or 429, as a delay or as the moment to come
+    // back: `.retry_after(Duration::from_secs(120))` sends `120`,
+    // `.retry_after(window_end)` with a `SystemTime` sends an HTTP date, so
+    // every client told to wait for the same maintenance window returns at
+    // the same moment.
+    pub fn retry_after(self, retry: impl Into<RetryAfter>) -> Self {
+        self.header("Retry-After", retry.into().to_string())
+    }
+
+    // e.g. `.etag(&EntityTag::weak("v42"))`
+    pub fn etag(self, etag: &EntityTag) -> Self {
+        self.header("ETag", etag.to_string())
+    }
+
+    // `Content-Language: <lang>`, plus `Vary: Accept-Language` so caches
+    // keep one copy per language
+    pub fn content_language(self, lang: &str) -> Self {
+        self.header("Content-Language", lang).vary("Accept-Language")
+    }
+
+    // Adds `name` to `Vary` unless it is already listed (or `Vary: *`)
+    pub(crate) fn vary(self, name: &str) -> Self {
+        let varies = self
+            .header_values("Vary")
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(name));
+        if varies {
+            self
+        } else {
+            self.append_header("Vary", name)
+        }
+    }
+
+    // Tells the client to drop cookie `name`: an empty value with
+    // `Max-Age=0` and an `Expires` in the past, appended as its own
+    // `Set-Cookie`. Browsers only delete a cookie whose name, path and
+    // domain all match the one they hold, so pass the `Path` it was set
+    // with, and use `remove_domain_cookie` if it was set with a `Domain`.
+    pub fn remove_cookie(self, name: &str, path: &str) -> Self {
+        self.append_header("Set-Cookie", cookie_removal(name, path, None))
+    }
+
+    pub fn remove_domain_cookie(self, name: &str, path: &str, domain: &str) -> Self {
+        self.append_header("Set-Cookie", cookie_removal(name, path, Some(domain)))
+    }
+
+    // Runs the body through `transform` as it is sent, streamed or not; the
+    // response goes out without a Content-Length. See `BodyTransform` for
+    // when to prefer `map_body_buffered`.
+    pub fn map_body<T: ChunkTransform>(self, transform: T) -> Self {
+        self.map_boxed_body(Box::new(transform))
+    }
+
+    pub(crate) fn map_boxed_body(mut self, transform: Box<dyn ChunkTransform>) -> Self {
+        self.headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(CONTENT_LENGTH.as_str()));
+        self.body = Body::wrap_stream(TransformStream {
+            body: std::mem::take(&mut self.body),
+            transform,
+            finished: false,
+        });
+        self
+    }
+
+    // Reads the whole body, streamed or not, and replaces it with
+    // `transform`'s output, which is sent with an exact Content-Length
+    pub async fn map_body_buffered<F>(mut self, transform: F) -> crate::Result<Self>
+    where
+        F: FnOnce(Bytes) -> Bytes,
+    {
+        let body = hyper::body::to_bytes(std::mem::take(&mut self.body)).await?;
+        self.headers
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(CONTENT_LENGTH.as_str()));
+        self.body = Body::from(transform(body));
+        Ok(self)
+    }
+
+    // RFC 9530 `Content-Digest` over the body as sent, e.g.
+    // `sha-256=:X48E9q...=:`. It is computed once the handler returns, so
+    // the body may be set before or after. A body with a fixed length is
+    // hashed and sent uncompressed (the digest would otherwise have to cover
+    // the compressed bytes); a streamed body gets no digest.
+    pub fn with_content_digest(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.content_digest = Some(algorithm);
+        self
+    }
+
+    // Runs `callback` once the server is done with this response: after the
+    // last body byte was written, or when the client went away or the body
+    // failed part way (`completed: false`). Useful for recording bytes
+    // actually delivered or firing analytics:
+    //   Response::file(&path, req.headers()).await?.on_complete(move |outcome| {
+    //       downloads.record(&path, outcome.bytes_sent, outcome.completed)
+    //   })
+    // Callbacks run on a spawned task, so they don't hold up the connection,
+    // but they shouldn't block. Only responses a handler returns through the
+    // server run them; error responses and copies handed out by `Coalesce`
+    // or `Idempotency` don't.
+    pub fn on_complete<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(ResponseOutcome) + Send + 'static,
+    {
+        self.on_complete.push(Box::new(callback));
+        self
+    }
+
+    // `text/plain`, with the server's default charset if one is configured
+    // (see `Server::default_charset`)
     pub fn text<S>(self, text: S) -> Self
     where
         S: Into<String>,
     {
-        self.header("Content-Type", "text/plain")
+        self.with_default_content_type("text/plain")
             .body(Body::from(text.into()))
     }
 
+    // Overrides the server's default charset for this response only. The
+    // body is sent as given; `charset` just labels its encoding.
+    pub fn text_with_charset<B>(self, text: B, charset: &str) -> Self
+    where
+        B: Into<Body>,
+    {
+        self.with_default_content_type(format!("text/plain; charset={}", charset))
+            .body(text)
+    }
+
     pub fn html<S>(self, html: S) -> Self
     where
         S: Into<String>,
     {
-        self.header("Content-Type", "text/html")
+        self.with_default_content_type("text/html")
             .body(Body::from(html.into()))
     }
 
+    pub fn html_with_charset<B>(self, html: B, charset: &str) -> Self
+    where
+        B: Into<Body>,
+    {
+        self.with_default_content_type(format!("text/html; charset={}", charset))
+            .body(html)
+    }
+
+    // With the `buffer-pool` feature the JSON is written into a pooled
+    // buffer (see `buffer_pool`) instead of a fresh allocation
     pub fn json<T>(self, value: &T) -> crate::Result<Self>
     where
         T: Serialize,
     {
+        #[cfg(feature = "buffer-pool")]
+        let json = crate::buffer_pool::to_json_bytes(value)?;
+        #[cfg(not(feature = "buffer-pool"))]
         let json = serde_json::to_string(value)?;
         Ok(self
-            .header("Content-Type", "application/json")
+            .with_default_content_type("application/json")
             .body(Body::from(json)))
     }
 
-    pub(crate) fn into_hyper_response(self) -> crate::Result<hyper::Response<Body>> {
-        let mut response = hyper::Response::builder().status(self.status);
+    // `json` for a body built dynamically, e.g. with `serde_json::json!`. A
+    // `Value` always serializes (its map keys are strings), so there's no
+    // error to handle.
+    pub fn json_value(self, value: serde_json::Value) -> Self {
+        self.with_default_content_type("application/json")
+            .body(Body::from(value.to_string()))
+    }
 
-        for (key, value) in self.headers {
-            response = response.header(key, value);
+    // A JSON array written item by item as `items` produces them, so a large
+    // export never has to be collected into a Vec:
+    //   Response::new().json_array_stream(db.rows().map_ok(Row::into_dto))
+    // Items are pulled only as fast as the client reads (see `StreamBody`),
+    // and the items ready at once go out together as one chunk. An `Err`
+    // item, or one that fails to serialize, is logged and aborts the body:
+    // the array can't be closed validly by then, so the client sees a
+    // truncated response rather than a valid-looking partial one.
+    pub fn json_array_stream<S, T, E>(self, items: S) -> Self
+    where
+        S: Stream<Item = std::result::Result<T, E>> + Send + 'static,
+        T: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        self.with_default_content_type("application/json")
+            .body(json_stream(items, true))
+    }
+
+    // `json_array_stream` as newline-delimited JSON (`application/x-ndjson`),
+    // one item per line, for clients that can read it. A body aborted by an
+    // error still ends with complete lines, so the client can keep what it
+    // got; pick it when the request's Accept allows.
+    pub fn ndjson_stream<S, T, E>(self, items: S) -> Self
+    where
+        S: Stream<Item = std::result::Result<T, E>> + Send + 'static,
+        T: Serialize + Send + 'static,
+        E: fmt::Display + Send + 'static,
+    {
+        self.with_default_content_type("application/x-ndjson")
+            .body(json_stream(items, false))
+    }
+
+    // Leaves a Content-Type set through `header` alone
+    fn with_default_content_type<V>(self, value: V) -> Self
+    where
+        V: Into<String>,
+    {
+        let explicit = !self.default_content_type && self.has_header("Content-Type");
+        if explicit {
+            return self;
         }
+        let mut response = self.header("Content-Type", value);
+        response.default_content_type = true;
+        response
+    }
 
-        Ok(response.body(self.body)?)
+    // Adds `; charset=` to a helper-set `text/*` type that has none
+    pub(crate) fn apply_default_charset(&mut self, charset: &str) {
+        if !self.default_content_type {
+            return;
+        }
+        let Some((_, value)) = self
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case("Content-Type"))
+        else {
+            return;
+        };
+        if value.starts_with("text/") && !value.to_ascii_lowercase().contains("charset=") {
+            value.push_str("; charset=");
+            value.push_str(charset);
+        }
+    }
+
+    // A file download: `Content-Disposition: attachment` with `filename`
+    // as the suggested name. `body` may be buffered bytes or a stream
+    // (`Body::wrap_stream`, `Response::channel`'s body).
+    //
+    // The name is reduced to its last path component and stripped of
+    // control characters, so it can't inject headers or point elsewhere. A
+    // non-ASCII name is sent twice: as an ASCII `filename` fallback with
+    // `_` replacing what can't be represented, and exactly as
+    // `filename*=UTF-8''...` (RFC 5987) for clients that understand it.
+    pub fn download<B>(filename: &str, content_type: &str, body: B) -> Self
+    where
+        B: Into<Body>,
+    {
+        Self::new()
+            .header("Content-Type", content_type)
+            .header("Content-Disposition", content_disposition(filename))
+            .body(body)
+    }
+
+    // Streaming response fed from another task. Each `send().await` waits for
+    // room in a bounded buffer, so a slow client slows the producer down.
+    // Dropping the sender ends the body cleanly, sending an `Err` aborts it,
+    // and once the client goes away further sends fail so the producer can stop.
+    pub fn channel<E>() -> (mpsc::Sender<std::result::Result<Bytes, E>>, Self)
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+    {
+        Self::channel_with_capacity(DEFAULT_CHANNEL_CAPACITY)
+    }
+
+    pub fn channel_with_capacity<E>(
+        capacity: usize,
+    ) -> (mpsc::Sender<std::result::Result<Bytes, E>>, Self)
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(capacity);
+        let stream = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        });
+        (tx, Self::new().body(Body::wrap_stream(stream)))
+    }
+
+    // Streams the body from a synchronous `Read` (a compressor's output, a
+    // blocking client library) in chunks of up to `chunk_size` bytes. The
+    // reads run on tokio's blocking pool, never on a runtime worker, and
+    // wait for a slow client like `channel` does; the blocking thread is
+    // held until the reader is exhausted or the client goes away. A read
+    // error is logged and aborts the body. Must be called on the runtime.
+    pub fn with_body_from_reader<R>(self, mut reader: R, chunk_size: usize) -> Self
+    where
+        R: std::io::Read + Send + 'static,
+    {
+        let (tx, response) = Self::channel_with_capacity::<std::io::Error>(DEFAULT_CHANNEL_CAPACITY);
+        let mut buf = vec![0; chunk_size.max(1)];
+        tokio::task::spawn_blocking(move || {
+            let mut offset = 0u64;
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => Ok(Bytes::copy_from_slice(&buf[..n])),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        warn!("Reader body failed at byte {}: {}", offset, e);
+                        Err(e)
+                    }
+                };
+                let failed = chunk.is_err();
+                offset += chunk.as_ref().map_or(0, |chunk| chunk.len() as u64);
+                // Fails once the client has gone away
+                if tx.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        self.body(response.body)
+    }
+
+    // A response that is only a status line and headers, such as
+    // 101 Switching Protocols. Any body set later is not sent for 1xx.
+    pub fn with_status_line_only(status: StatusCode) -> Self {
+        Self::new().status(status)
+    }
+
+    // Accepts a protocol upgrade (WebSocket, h2c, ...): answers
+    // 101 Switching Protocols with `Connection: upgrade` and
+    // `Upgrade: <protocol>`, and once that response has been written hands
+    // the raw connection to `on_upgrade` in a new task.
+    //
+    // The `Upgraded` IO implements `AsyncRead + AsyncWrite`; any bytes the
+    // client sent right after its request headers are replayed from it
+    // first. The connection closes when `on_upgrade` drops it. HTTP/2
+    // connections can't be upgraded, so `on_upgrade` is never called for
+    // them (the failure is logged).
+    pub fn upgrade<F, Fut>(req: &mut Request<Body>, protocol: &str, on_upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let pending = hyper::upgrade::on(req);
+        tokio::spawn(async move {
+            match pending.await {
+                Ok(upgraded) => on_upgrade(upgraded).await,
+                Err(e) => debug!("Connection upgrade failed: {}", e),
+            }
+        });
+
+        Self::with_status_line_only(StatusCode::SWITCHING_PROTOCOLS)
+            .header("Connection", "upgrade")
+            .header("Upgrade", protocol)
+    }
+
+    // `file_multirange` with the Content-Type from `fs_util::mime_for_path`.
+    // `path` is opened as given: build it from request input with
+    // `fs_util::safe_join`, never by pushing the raw request path.
+    pub async fn file<P>(path: P, request_headers: &HeaderMap) -> crate::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let content_type = fs_util::mime_for_path(&path);
+        Self::file_multirange(path, request_headers, content_type).await
+    }
+
+    // Serves a file with Range / If-Range and conditional request support:
+    // 200, 206, 304, 412 or 416 as appropriate. The ETag and Last-Modified
+    // come from the file's length and modification time. Content-Type is
+    // left to the caller.
+    pub async fn file_range<P>(path: P, request_headers: &HeaderMap) -> crate::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = tokio::fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+        let validators = Validators::for_file(metadata.len(), metadata.modified().ok());
+        Ok(range::respond(file, metadata.len(), request_headers, validators, None))
+    }
+
+    // Like `file_range` for any seekable source of `len` bytes. Without
+    // validators an If-Range header can never match, so it always yields the
+    // full 200; set ETag / Last-Modified yourself to get automatic 304s.
+    pub fn reader_range<R>(reader: R, len: u64, request_headers: &HeaderMap) -> Self
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+    {
+        range::respond(reader, len, request_headers, Validators::NONE, None)
+    }
+
+    // Like `file_range`, but also sets `content_type` and answers requests
+    // for several ranges (`Range: bytes=0-99,200-299`) with a streamed
+    // `multipart/byteranges` 206 instead of the whole file
+    pub async fn file_multirange<P>(
+        path: P,
+        request_headers: &HeaderMap,
+        content_type: &str,
+    ) -> crate::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = tokio::fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+        let validators = Validators::for_file(metadata.len(), metadata.modified().ok());
+        Ok(range::respond(
+            file,
+            metadata.len(),
+            request_headers,
+            validators,
+            Some(content_type),
+        ))
+    }
+
+    pub fn reader_multirange<R>(
+        reader: R,
+        len: u64,
+        request_headers: &HeaderMap,
+        content_type: &str,
+    ) -> Self
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+    {
+        range::respond(
+            reader,
+            len,
+            request_headers,
+            Validators::NONE,
+            Some(content_type),
+        )
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    // Header names are compared case-insensitively; returns the first value
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn has_header(&self, name: &str) -> bool {
+        self.header_value(name).is_some()
+    }
+
+    // Headers whose name or value can't be sent (e.g. a value containing
+    // `\r\n`) are skipped with a warning, or with `strict` fail the whole
+    // conversion with an error naming the header
+    pub(crate) fn into_hyper_response(self, strict: bool) -> crate::Result<hyper::Response<Body>> {
+        let mut response = hyper::Response::new(self.body);
+        *response.status_mut() = self.status;
+
+        let headers = response.headers_mut();
+        for (key, value) in self.headers {
+            match (HeaderName::try_from(key.as_str()), HeaderValue::try_from(value.as_str())) {
+                (Ok(name), Ok(value)) => {
+                    headers.append(name, value);
+                }
+                _ if strict => {
+                    return Err(ServerError::Internal(format!(
+                        "invalid response header {:?}",
+                        truncate(&key)
+                    )));
+                }
+                _ => warn!(
+                    "Skipping invalid response header {:?}: {:?}",
+                    truncate(&key),
+                    truncate(&value)
+                ),
+            }
+        }
+        Ok(response)
     }
 }
 
@@ -80,4 +593,270 @@ impl Default for Response {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+} 
+
+// What a handler's `Ok` value can be: a function or closure handler may
+// return `Result<T>` for any `T: IntoResponse`, not just `Result<Response>`:
+//   async fn hello(_req: Request<Body>) -> Result<&'static str> { Ok("hello") }
+//   async fn export(_req: Request<Body>) -> Result<StreamBody> { Ok(StreamBody::new(rows())) }
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+// `text/plain`
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::new().text(self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Response::new().text(self)
+    }
+}
+
+// A 200 whose body is a stream of chunks, sent with chunked encoding (or
+// HTTP/2 DATA frames) as they are produced:
+//   let lines = futures::stream::iter(rows).map(|row| Ok::<_, io::Error>(Bytes::from(row)));
+//   Ok(StreamBody::new(lines).content_type("text/csv"))
+//
+// The content type defaults to `application/octet-stream`; `content_type`
+// or an explicit `header("Content-Type", ..)` on the converted response
+// replaces it.
+//
+// Backpressure: the stream is polled for the next chunk only once the
+// previous one has been handed to the connection, so a slow client slows
+// the producer down and no more than a chunk or so is buffered per
+// response. The stream is dropped when the client goes away. An `Err` item
+// aborts the body; the client sees a truncated response, as the status
+// line is already sent.
+pub struct StreamBody {
+    body: Body,
+    content_type: Option<String>,
+}
+
+impl StreamBody {
+    pub fn new<S, O, E>(stream: S) -> Self
+    where
+        S: Stream<Item = std::result::Result<O, E>> + Send + 'static,
+        O: Into<Bytes> + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        Self {
+            body: Body::wrap_stream(stream.map_ok(Into::<Bytes>::into)),
+            content_type: None,
+        }
+    }
+
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+}
+
+impl IntoResponse for StreamBody {
+    fn into_response(self) -> Response {
+        let content_type = self
+            .content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        Response::new()
+            .with_default_content_type(content_type)
+            .body(self.body)
+    }
+}
+
+impl From<StreamBody> for Response {
+    fn from(body: StreamBody) -> Self {
+        body.into_response()
+    }
+}
+
+// Value of a `Retry-After` header (RFC 9110 section 10.2.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfter {
+    // Delta-seconds, rounded up so clients never come back early
+    Delay(Duration),
+    // IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`; sub-second
+    // precision is dropped
+    At(SystemTime),
+}
+
+impl From<Duration> for RetryAfter {
+    fn from(delay: Duration) -> Self {
+        RetryAfter::Delay(delay)
+    }
+}
+
+impl From<SystemTime> for RetryAfter {
+    fn from(time: SystemTime) -> Self {
+        RetryAfter::At(time)
+    }
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryAfter::Delay(delay) => write!(f, "{}", delay.as_secs_f64().ceil() as u64),
+            RetryAfter::At(time) => f.write_str(&httpdate::fmt_http_date(*time)),
+        }
+    }
+}
+
+// Most items serialized into one chunk of a JSON stream
+const JSON_STREAM_BATCH: usize = 64;
+
+// A JSON array (`array`) or NDJSON body from `items`
+fn json_stream<S, T, E>(items: S, array: bool) -> Body
+where
+    S: Stream<Item = std::result::Result<T, E>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    E: fmt::Display + Send + 'static,
+{
+    let batches = items.ready_chunks(JSON_STREAM_BATCH).boxed();
+    // Items written so far; None once the body has ended
+    let chunks = futures::stream::unfold(Some((batches, 0u64)), move |state| async move {
+        let (mut batches, mut written) = state?;
+        let mut buf = Vec::new();
+        if array && written == 0 {
+            buf.push(b'[');
+        }
+        let batch = match batches.next().await {
+            Some(batch) => batch,
+            None => {
+                if array {
+                    buf.push(b']');
+                }
+                return Some((vec![Ok(Bytes::from(buf))], None));
+            }
+        };
+        for item in batch {
+            let start = buf.len();
+            if array && written > 0 {
+                buf.push(b',');
+            }
+            let serialized = item
+                .map_err(|e| e.to_string())
+                .and_then(|item| serde_json::to_writer(&mut buf, &item).map_err(|e| e.to_string()));
+            if let Err(e) = serialized {
+                warn!("JSON stream aborted after {} items: {}", written, e);
+                // Whatever was complete before the failed item still goes out
+                buf.truncate(start);
+                let error = std::io::Error::other(e);
+                return Some((vec![Ok(Bytes::from(buf)), Err(error)], None));
+            }
+            if !array {
+                buf.push(b'\n');
+            }
+            written += 1;
+        }
+        Some((vec![Ok(Bytes::from(buf))], Some((batches, written))))
+    });
+    Body::wrap_stream(chunks.flat_map(futures::stream::iter))
+}
+
+fn content_disposition(filename: &str) -> String {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let name = match name.trim() {
+        "" | "." | ".." => "download",
+        name => name,
+    };
+
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    if fallback == name {
+        format!("attachment; filename=\"{}\"", name)
+    } else {
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            fallback,
+            crate::percent::encode_rfc5987(name)
+        )
+    }
+}
+
+// Browsers ignore a `__Secure-` or `__Host-` cookie without `Secure`, the
+// deletion included
+fn cookie_removal(name: &str, path: &str, domain: Option<&str>) -> String {
+    let mut cookie = format!(
+        "{}=; Path={}; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+        name, path
+    );
+    if let Some(domain) = domain {
+        cookie.push_str("; Domain=");
+        cookie.push_str(domain);
+    }
+    if name.starts_with("__Secure-") || name.starts_with("__Host-") {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+// Invalid header names and values are logged only in part
+const LOGGED_HEADER_CHARS: usize = 64;
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(LOGGED_HEADER_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// Whether a response to `method` with `status` may carry content. Responses
+// to HEAD and 1xx / 204 / 304 responses never do (RFC 9110 §6.4.1).
+pub(crate) fn body_allowed(method: &Method, status: StatusCode) -> bool {
+    *method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+}
+
+// Drops the body of a response that can't have one, whatever the handler
+// supplied:
+// - 1xx and 204 also lose Content-Length and Transfer-Encoding, which they
+//   must not send
+// - HEAD and 304 keep their headers. Without a Content-Length they get the
+//   length of the dropped body when it was known and non-empty, i.e. what
+//   a GET / 200 would have sent.
+// Returns whether the dropped body might have had content.
+pub(crate) fn elide_body(method: &Method, response: &mut hyper::Response<Body>) -> bool {
+    let status = response.status();
+    if body_allowed(method, status) {
+        return false;
+    }
+
+    let body = std::mem::replace(response.body_mut(), Body::empty());
+    let len = HttpBody::size_hint(&body).exact();
+    let headers = response.headers_mut();
+    if status.is_informational() || status == StatusCode::NO_CONTENT {
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(TRANSFER_ENCODING);
+    } else {
+        if let Some(len) = len.filter(|&len| len > 0) {
+            headers
+                .entry(CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        // hyper only keeps a 304's Content-Length when the body's length is
+        // unknown; it still sends nothing
+        if headers.contains_key(CONTENT_LENGTH) {
+            *response.body_mut() =
+                Body::wrap_stream(futures::stream::empty::<std::io::Result<Bytes>>());
+        }
+    }
+    len != Some(0)
+}
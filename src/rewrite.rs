@@ -0,0 +1,323 @@
+use crate::pattern::{Pattern, RequestPath};
+use crate::{Response, Result, ServerError};
+use hyper::header::LOCATION;
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Request, StatusCode, Uri};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, warn};
+
+// Internal rewrites one request may go through before it is refused as a loop
+pub const DEFAULT_MAX_REWRITES: usize = 8;
+
+// Redirects and internal rewrites evaluated before routing, e.g. when
+// migrating a legacy site (`Router::rewrites`):
+//   RewriteTable::new()
+//       .rule(RewriteRule::exact("/about.php", "/about"))
+//       .rule(RewriteRule::prefix("/docs/v1", "/docs/v2").redirect(StatusCode::FOUND))
+//       .rule(RewriteRule::pattern("/blog/:year/:slug", "/posts/:slug"))
+//       .rule(RewriteRule::prefix("/api/v1", "/api/v2").internal())
+//
+// The first matching rule wins. A redirect (301 unless `redirect` says
+// otherwise) is answered right away; an internal rewrite replaces the request
+// URI and the table is evaluated again, up to `max_rewrites` times, after
+// which the request fails with 500 rather than looping. The query string is
+// carried over to the target unless `drop_query` is set. Rules see the path
+// after `Normalize` has been applied.
+pub struct RewriteTable {
+    rules: Vec<RewriteRule>,
+    max_rewrites: usize,
+}
+
+pub struct RewriteRule {
+    from: From,
+    to: String,
+    action: Action,
+    preserve_query: bool,
+    hits: AtomicU64,
+}
+
+enum From {
+    Exact(String),
+    // Matches the prefix itself and anything below it, at a '/' boundary
+    Prefix(String),
+    Pattern(String, Pattern),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Redirect(StatusCode),
+    Internal,
+}
+
+// One rule as listed by `Router::rewrite_rules` and the debug endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct RewriteRuleInfo {
+    // `exact`, `prefix` or `pattern`
+    pub kind: &'static str,
+    pub from: String,
+    pub to: String,
+    // The redirect status, or None for an internal rewrite
+    pub status: Option<u16>,
+    pub preserve_query: bool,
+    pub hits: u64,
+}
+
+impl RewriteTable {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_rewrites: DEFAULT_MAX_REWRITES,
+        }
+    }
+
+    pub fn rule(mut self, rule: RewriteRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules<I>(mut self, rules: I) -> Self
+    where
+        I: IntoIterator<Item = RewriteRule>,
+    {
+        self.rules.extend(rules);
+        self
+    }
+
+    pub fn max_rewrites(mut self, max: usize) -> Self {
+        self.max_rewrites = max;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn info(&self) -> Vec<RewriteRuleInfo> {
+        self.rules.iter().map(RewriteRule::info).collect()
+    }
+
+    pub(crate) fn patterns_mut(&mut self) -> impl Iterator<Item = (&str, &mut Pattern)> {
+        self.rules.iter_mut().filter_map(|rule| match &mut rule.from {
+            From::Pattern(path, pattern) => Some((path.as_str(), pattern)),
+            _ => None,
+        })
+    }
+
+    // Rewrites the request in place, or returns the redirect to send instead
+    pub(crate) fn apply(&self, req: &mut Request<Body>, ignore_case: bool) -> Result<Option<Response>> {
+        let original = req.uri().path().to_string();
+        for _ in 0..=self.max_rewrites {
+            let Some((rule, target)) = self.find(req.uri().path(), ignore_case) else {
+                return Ok(None);
+            };
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            let target = match req.uri().query() {
+                Some(query) if rule.preserve_query => {
+                    let separator = if target.contains('?') { '&' } else { '?' };
+                    format!("{}{}{}", target, separator, query)
+                }
+                _ => target,
+            };
+
+            match rule.action {
+                Action::Redirect(status) => {
+                    return Ok(Some(
+                        Response::new()
+                            .status(status)
+                            .header(LOCATION.as_str(), target),
+                    ));
+                }
+                Action::Internal => {
+                    debug!("Rewrote {} to {}", req.uri().path(), target);
+                    crate::handler::keep_original_uri(req);
+                    let mut parts = req.uri().clone().into_parts();
+                    parts.path_and_query = Some(
+                        PathAndQuery::from_maybe_shared(target).map_err(hyper::http::Error::from)?,
+                    );
+                    *req.uri_mut() = Uri::from_parts(parts).map_err(hyper::http::Error::from)?;
+                }
+            }
+        }
+
+        warn!(
+            "Rewrite chain for {} exceeded {} rewrites (now at {})",
+            original,
+            self.max_rewrites,
+            req.uri().path()
+        );
+        Err(ServerError::Internal(format!(
+            "more than {} rewrites",
+            self.max_rewrites
+        )))
+    }
+
+    fn find(&self, path: &str, ignore_case: bool) -> Option<(&RewriteRule, String)> {
+        self.rules
+            .iter()
+            .find_map(|rule| rule.target(path, ignore_case).map(|target| (rule, target)))
+    }
+}
+
+impl Default for RewriteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RewriteRule {
+    // Only `from` itself
+    pub fn exact(from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self::new(From::Exact(from.into()), to.into())
+    }
+
+    // `from` and everything below it, with the remainder appended to `to`:
+    // prefix `/docs/v1` to `/docs/v2` sends `/docs/v1/intro` to
+    // `/docs/v2/intro`, but leaves `/docs/v10` alone
+    pub fn prefix(from: impl Into<String>, to: impl Into<String>) -> Self {
+        let from = from.into();
+        let from = match from.trim_end_matches('/') {
+            "" => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+        Self::new(From::Prefix(from), to.into())
+    }
+
+    // A route-style pattern; `:name` and `*name` in `to` are replaced with
+    // the captured values, percent-encoded again. Panics like a route would
+    // on an invalid pattern.
+    pub fn pattern(from: impl Into<String>, to: impl Into<String>) -> Self {
+        let from = from.into();
+        let pattern = Pattern::parse(&from);
+        Self::new(From::Pattern(from, pattern), to.into())
+    }
+
+    fn new(from: From, to: String) -> Self {
+        Self {
+            from,
+            to,
+            action: Action::Redirect(StatusCode::MOVED_PERMANENTLY),
+            preserve_query: true,
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    // 301, 302, 303, 307 or 308; panics on anything else
+    pub fn redirect(mut self, status: StatusCode) -> Self {
+        if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+            panic!("rewrite rule for `{}`: {} is not a redirect status", self.to, status);
+        }
+        self.action = Action::Redirect(status);
+        self
+    }
+
+    // Routes the request to the target path without a round trip to the
+    // client. Panics unless the target is a path.
+    pub fn internal(mut self) -> Self {
+        if !self.to.starts_with('/') {
+            panic!("internal rewrite target `{}` must be a path", self.to);
+        }
+        self.action = Action::Internal;
+        self
+    }
+
+    pub fn drop_query(mut self) -> Self {
+        self.preserve_query = false;
+        self
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn info(&self) -> RewriteRuleInfo {
+        let (kind, from) = match &self.from {
+            From::Exact(from) => ("exact", from.clone()),
+            From::Prefix(from) => ("prefix", from.clone()),
+            From::Pattern(from, _) => ("pattern", from.clone()),
+        };
+        RewriteRuleInfo {
+            kind,
+            from,
+            to: self.to.clone(),
+            status: match self.action {
+                Action::Redirect(status) => Some(status.as_u16()),
+                Action::Internal => None,
+            },
+            preserve_query: self.preserve_query,
+            hits: self.hits(),
+        }
+    }
+
+    fn target(&self, path: &str, ignore_case: bool) -> Option<String> {
+        let equal = |a: &str, b: &str| {
+            if ignore_case {
+                a.eq_ignore_ascii_case(b)
+            } else {
+                a == b
+            }
+        };
+        match &self.from {
+            From::Exact(from) => equal(from, path).then(|| self.to.clone()),
+            From::Prefix(from) => {
+                let rest = if from == "/" {
+                    path
+                } else {
+                    let head = path.get(..from.len())?;
+                    let rest = &path[from.len()..];
+                    if !equal(head, from) || !(rest.is_empty() || rest.starts_with('/')) {
+                        return None;
+                    }
+                    rest
+                };
+                let to = self.to.trim_end_matches('/');
+                Some(match (to, rest) {
+                    ("", "") => "/".to_string(),
+                    (to, "") => to.to_string(),
+                    (to, rest) => format!("{}/{}", to, rest.trim_start_matches('/')),
+                })
+            }
+            From::Pattern(_, pattern) => {
+                let request = RequestPath::new(path);
+                let params = if ignore_case {
+                    pattern.matches_ignore_case(&request)?
+                } else {
+                    pattern.matches(&request)?
+                };
+                Some(substitute(&self.to, &params))
+            }
+        }
+    }
+}
+
+// Replaces `:name` / `*name` segments of `template` with captured values
+fn substitute(template: &str, params: &[(String, String)]) -> String {
+    let lookup = |name: &str| {
+        params
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    template
+        .split('/')
+        .map(|segment| {
+            if let Some(value) = segment.strip_prefix(':').and_then(lookup) {
+                crate::percent::encode_path_segment(value)
+            } else if let Some(value) = segment.strip_prefix('*').and_then(lookup) {
+                value
+                    .split('/')
+                    .map(crate::percent::encode_path_segment)
+                    .collect::<Vec<_>>()
+                    .join("/")
+            } else {
+                segment.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
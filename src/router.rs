@@ -1,7 +1,29 @@
-use crate::{Handler, HandlerFn, Response, Result, ServerError};
+use crate::body::{content_length, BodyLimit};
+use crate::build_info::BuildInfo;
+use crate::deadline;
+use crate::deprecation::{DeprecationUsage, Sunset};
+use crate::dump::{self, DEFAULT_DEBUG_BODY_LIMIT};
+use crate::guard::EndpointGuard;
+use crate::handler::RequestContext;
+use crate::headers::{AcceptLanguage, Locales};
+use crate::layer::{Layer, Next};
+use crate::media_type::MediaType;
+use crate::normalize::{merge_slashes, Normalize};
+use crate::pattern::{Constraint, Matcher, Params, Pattern, RequestPath, Segment};
+use crate::query::QueryConfig;
+use crate::quota::QuotaCost;
+use crate::rewrite::{RewriteRuleInfo, RewriteTable};
+use crate::static_files::StaticFiles;
+use crate::timing::ServerTiming;
+use crate::{Handler, HandlerFn, Response, Result, ServerError, Stats};
 use hyper::{Body, Method as HttpMethod, Request};
+use std::collections::HashMap;
+use std::fmt;
 use std::future::Future;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Method {
@@ -29,10 +51,63 @@ impl From<&HttpMethod> for Method {
     }
 }
 
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::PATCH => "PATCH",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<Method> for HttpMethod {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::GET => HttpMethod::GET,
+            Method::POST => HttpMethod::POST,
+            Method::PUT => HttpMethod::PUT,
+            Method::DELETE => HttpMethod::DELETE,
+            Method::PATCH => HttpMethod::PATCH,
+            Method::HEAD => HttpMethod::HEAD,
+            Method::OPTIONS => HttpMethod::OPTIONS,
+        }
+    }
+}
+
+// Path patterns support parameters: `/users/:id` captures any segment, while
+// `/users/:id<u64>` or `/files/:name<[a-z0-9_-]+>` only match when the
+// segment satisfies the constraint (see `pattern` for the syntax). Invalid
+// patterns panic at registration.
+#[derive(Clone)]
 pub struct Route {
     method: Method,
     path: String,
+    pattern: Pattern,
     handler: HandlerFn,
+    max_body_size: Option<usize>,
+    compress: Option<bool>,
+    cache_ttl: Option<Duration>,
+    consumes: Vec<String>,
+    produces: Option<String>,
+    sunset: Option<Sunset>,
+    deprecation_usage: Arc<DeprecationUsage>,
+    tags: Vec<String>,
+    debug_body_limit: Option<usize>,
+    sensitive: bool,
+    quota_cost: Option<u64>,
+    name: Option<String>,
+    response_timeout: Option<Duration>,
 }
 
 impl Route {
@@ -40,98 +115,1056 @@ impl Route {
     where
         H: Handler,
     {
-        let handler_fn = Box::new(move |req: Request<Body>| {
+        let handler_fn: HandlerFn = Arc::new(move |req: Request<Body>| {
             Box::pin(handler.call(req)) as Pin<Box<dyn Future<Output = Result<Response>> + Send>>
         });
 
+        let path = path.into();
         Self {
             method,
-            path: path.into(),
+            pattern: Pattern::parse(&path),
+            path,
             handler: handler_fn,
+            max_body_size: None,
+            compress: None,
+            cache_ttl: None,
+            consumes: Vec::new(),
+            produces: None,
+            sunset: None,
+            deprecation_usage: Arc::default(),
+            tags: Vec::new(),
+            debug_body_limit: None,
+            sensitive: false,
+            quota_cost: None,
+            name: None,
+            response_timeout: None,
+        }
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn max_body_size(&self) -> Option<usize> {
+        self.max_body_size
+    }
+
+    pub fn compress(&self) -> Option<bool> {
+        self.compress
+    }
+
+    pub fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl
+    }
+
+    pub fn consumes(&self) -> &[String] {
+        &self.consumes
+    }
+
+    pub fn produces(&self) -> Option<&str> {
+        self.produces.as_deref()
+    }
+
+    pub fn sunset(&self) -> Option<&Sunset> {
+        self.sunset.as_ref()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    // Set when the route was marked with `Router::debug`
+    pub fn debug_body_limit(&self) -> Option<usize> {
+        self.debug_body_limit
+    }
+
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+    }
+
+    // Set with `Router::quota_cost`
+    pub fn quota_cost(&self) -> Option<u64> {
+        self.quota_cost
+    }
+
+    // Set with `Router::name`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // Set with `Router::response_timeout`
+    pub fn response_timeout(&self) -> Option<Duration> {
+        self.response_timeout
+    }
+
+    fn info(&self) -> RouteInfo {
+        RouteInfo {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            compress: self.compress,
+            cache_ttl: self.cache_ttl,
+            produces: self.produces.clone(),
+            sunset: self.sunset.clone(),
+            tags: self.tags.clone(),
+            name: self.name.clone(),
+            response_timeout: self.response_timeout,
         }
     }
 }
 
+// What the server's response layers need to know about the matched route.
+// Route settings live next to the route definition rather than in path
+// strings on each layer, and take precedence over layer-wide config.
+#[derive(Debug, Clone)]
+pub struct RouteInfo {
+    pub method: Method,
+    // The registered pattern, e.g. `/users/:id`
+    pub path: String,
+    pub compress: Option<bool>,
+    pub cache_ttl: Option<Duration>,
+    pub produces: Option<String>,
+    pub sunset: Option<Sunset>,
+    pub tags: Vec<String>,
+    pub name: Option<String>,
+    // The route's own timeout, or else the router's default, or else
+    // `Server::with_request_timeout`
+    pub response_timeout: Option<Duration>,
+}
+
+// Slot the server puts in the request extensions before routing. The router
+// fills it in on a match, so the info is still around after the handler has
+// consumed the request.
+#[derive(Clone, Default)]
+pub(crate) struct MatchedRoute(Arc<OnceLock<RouteInfo>>);
+
+impl MatchedRoute {
+    pub(crate) fn get(&self) -> Option<&RouteInfo> {
+        self.0.get()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteIssue {
+    // Same method and identical pattern registered more than once
+    Duplicate,
+    // Every request this route matches is claimed by another route first
+    Shadowed { by: String },
+    // A parameter name appears twice in one pattern; only the last capture survives
+    DuplicateParam(String),
+    // The pattern contains `//` or a trailing `/`
+    EmptySegment,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteDiagnostic {
+    pub method: Method,
+    pub path: String,
+    pub issue: RouteIssue,
+}
+
+impl fmt::Display for RouteDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: ", self.method, self.path)?;
+        match &self.issue {
+            RouteIssue::Duplicate => write!(f, "duplicate route"),
+            RouteIssue::Shadowed { by } => write!(f, "unreachable, shadowed by {}", by),
+            RouteIssue::DuplicateParam(name) => write!(f, "parameter `{}` declared twice", name),
+            RouteIssue::EmptySegment => write!(f, "pattern contains an empty segment"),
+        }
+    }
+}
+
+// Requests whose path has more segments than this are refused by default
+// (see `Router::with_max_path_segments`)
+pub const DEFAULT_MAX_PATH_SEGMENTS: usize = 256;
+
+// Cloning is cheap: handlers, layers and host routers are shared, not
+// copied. Clones also share the counters behind them (deprecated route
+// calls, rewrite rule hits), so the same table can serve several servers or
+// tests and still be reported once.
+#[derive(Clone)]
 pub struct Router {
     routes: Vec<Route>,
+    matchers: HashMap<String, Matcher>,
+    build_info: Option<BuildInfo>,
+    normalize: Option<Normalize>,
+    rewrites: Option<Arc<RewriteTable>>,
+    hosts: Vec<(String, Arc<Router>)>,
+    fallback: Option<HandlerFn>,
+    merge_slashes: bool,
+    case_insensitive: bool,
+    allow_missing_content_type: bool,
+    query_config: QueryConfig,
+    locales: Option<Arc<Locales>>,
+    default_response_timeout: Option<Duration>,
+    max_path_segments: usize,
+    tag_layers: Vec<(String, Arc<dyn Layer>)>,
+    debug_tags: Vec<String>,
 }
 
 impl Router {
     pub fn new() -> Self {
         Self {
             routes: Vec::new(),
+            matchers: HashMap::new(),
+            build_info: None,
+            normalize: None,
+            rewrites: None,
+            hosts: Vec::new(),
+            fallback: None,
+            merge_slashes: false,
+            case_insensitive: false,
+            allow_missing_content_type: false,
+            query_config: QueryConfig::default(),
+            locales: None,
+            default_response_timeout: None,
+            max_path_segments: DEFAULT_MAX_PATH_SEGMENTS,
+            tag_layers: Vec::new(),
+            debug_tags: Vec::new(),
         }
     }
 
-    pub fn get<H>(mut self, path: impl Into<String>, handler: H) -> Self
+    // Registers a named constraint usable as `:param<name>` in routes added
+    // after this call.
+    pub fn matcher<F>(mut self, name: impl Into<String>, matcher: F) -> Self
     where
-        H: Handler,
+        F: Fn(&str) -> bool + Send + Sync + 'static,
     {
-        self.routes.push(Route::new(Method::GET, path, handler));
+        self.matchers.insert(name.into(), Arc::new(matcher));
         self
     }
 
-    pub fn post<H>(mut self, path: impl Into<String>, handler: H) -> Self
+    pub fn get<H>(self, path: impl Into<String>, handler: H) -> Self
     where
         H: Handler,
     {
-        self.routes.push(Route::new(Method::POST, path, handler));
-        self
+        self.route(Method::GET, path, handler)
     }
 
-    pub fn put<H>(mut self, path: impl Into<String>, handler: H) -> Self
+    pub fn post<H>(self, path: impl Into<String>, handler: H) -> Self
     where
         H: Handler,
     {
-        self.routes.push(Route::new(Method::PUT, path, handler));
-        self
+        self.route(Method::POST, path, handler)
     }
 
-    pub fn delete<H>(mut self, path: impl Into<String>, handler: H) -> Self
+    pub fn put<H>(self, path: impl Into<String>, handler: H) -> Self
     where
         H: Handler,
     {
-        self.routes.push(Route::new(Method::DELETE, path, handler));
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn delete<H>(self, path: impl Into<String>, handler: H) -> Self
+    where
+        H: Handler,
+    {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    pub fn patch<H>(self, path: impl Into<String>, handler: H) -> Self
+    where
+        H: Handler,
+    {
+        self.route(Method::PATCH, path, handler)
+    }
+
+    // Serves the files under `root` at `prefix`: `.mount_static("/assets",
+    // "./public")` maps `/assets/css/site.css` to `./public/css/site.css`.
+    // This registers GET `<prefix>/*path` with a `StaticFiles` handler, so
+    // per-route settings such as `.cache_ttl(...)` can follow it.
+    //
+    // The catch-all ranks below every other pattern, so any other GET route
+    // under the prefix (`/assets/manifest.json`, `/assets/:name`) takes
+    // precedence over the files, regardless of registration order.
+    pub fn mount_static(self, prefix: &str, root: impl Into<PathBuf>) -> Self {
+        self.mount_static_with(prefix, StaticFiles::new(root))
+    }
+
+    // `mount_static` with a configured handler, e.g. a different index file
+    pub fn mount_static_with(self, prefix: &str, files: StaticFiles) -> Self {
+        let pattern = format!("{}/*path", prefix.trim_end_matches('/'));
+        self.get(pattern, files)
+    }
+
+    pub fn route<H>(mut self, method: Method, path: impl Into<String>, handler: H) -> Self
+    where
+        H: Handler,
+    {
+        self.add_route(method, path, handler);
         self
     }
 
-    pub fn patch<H>(mut self, path: impl Into<String>, handler: H) -> Self
+    // Non-consuming registration for route tables built at runtime, e.g.
+    // from plugins. To collect handlers of different types first, build a
+    // `Route` for each and `add` / `extend` them.
+    pub fn add_route<H>(&mut self, method: Method, path: impl Into<String>, handler: H) -> &mut Self
     where
         H: Handler,
     {
-        self.routes.push(Route::new(Method::PATCH, path, handler));
+        self.push(Route::new(method, path, handler));
         self
     }
 
-    pub fn route<H>(mut self, method: Method, path: impl Into<String>, handler: H) -> Self
+    pub fn add(&mut self, route: Route) -> &mut Self {
+        self.push(route);
+        self
+    }
+
+    // Takes over the routes of a router built elsewhere, e.g. by a module,
+    // after this one's. Tag layers and `debug_tag`s of both then cover the
+    // tagged routes of both; `other`'s router-wide settings (fallback, hosts,
+    // normalization, ...) are dropped.
+    pub fn merge(mut self, other: Router) -> Self {
+        // Custom matchers were resolved against `other`'s registry already
+        self.routes.extend(other.routes);
+        self.tag_layers.extend(other.tag_layers);
+        for tag in other.debug_tags {
+            if !self.debug_tags.contains(&tag) {
+                self.debug_tags.push(tag);
+            }
+        }
+        self
+    }
+
+    // Per-route settings apply to the most recently added route:
+    // `.post("/upload", h).max_body_size(50 * 1024 * 1024)`
+    fn last_route(&mut self, setting: &str) -> &mut Route {
+        self.routes
+            .last_mut()
+            .unwrap_or_else(|| panic!("`{}` called before any route was added", setting))
+    }
+
+    // Overrides the server-wide body size limit for the last added route
+    pub fn max_body_size(mut self, limit: usize) -> Self {
+        self.last_route("max_body_size").max_body_size = Some(limit);
+        self
+    }
+
+    // `false` never compresses the last added route's responses; `true`
+    // compresses them even when below `Compression::min_size` or streamed.
+    // SSE and already-encoded responses are still never compressed.
+    pub fn compress(mut self, enabled: bool) -> Self {
+        self.last_route("compress").compress = Some(enabled);
+        self
+    }
+
+    // Successful responses from the last added route get
+    // `Cache-Control: max-age=<ttl>` unless the handler set Cache-Control
+    // itself. Takes precedence over a `Cache-Control` default header.
+    pub fn cache_ttl(mut self, ttl: Duration) -> Self {
+        self.last_route("cache_ttl").cache_ttl = Some(ttl);
+        self
+    }
+
+    // Overrides `with_default_response_timeout` and
+    // `Server::with_request_timeout` for the last added route, e.g. a longer
+    // one for an export endpoint
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.last_route("response_timeout").response_timeout = Some(timeout);
+        self
+    }
+
+    // Media types the last added route accepts as request bodies, e.g.
+    // `&["image/png", "image/*"]`. Parameters such as charset are ignored.
+    // Other types are rejected with 415 before the handler runs, listing the
+    // accepted types in `Accept-Post` (`Accept-Patch` for PATCH).
+    pub fn consumes(mut self, media_types: &[&str]) -> Self {
+        for media_type in media_types {
+            if MediaType::parse(media_type).is_none() {
+                panic!("Invalid media type `{}` in `consumes`", media_type);
+            }
+        }
+        self.last_route("consumes").consumes = media_types.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    // Media type the last added route responds with. Informational only:
+    // it is exposed through `Route::produces` and `RouteInfo`.
+    pub fn produces(mut self, media_type: &str) -> Self {
+        self.last_route("produces").produces = Some(media_type.to_string());
+        self
+    }
+
+    // Marks the last added route as deprecated; see `Sunset` for the headers
+    // added and the optional 410 after the sunset date. Calls are counted in
+    // `StatsSnapshot::deprecated_routes` and logged periodically.
+    pub fn deprecated(mut self, sunset: Sunset) -> Self {
+        self.last_route("deprecated").sunset = Some(sunset);
+        self
+    }
+
+    // A logical name for the last added route, e.g. `.name("get_user")`,
+    // reported in `RouteInfo` and `Server::with_route_header`
+    pub fn name(mut self, name: &str) -> Self {
+        self.last_route("name").name = Some(name.to_string());
+        self
+    }
+
+    // Labels the last added route, e.g. `.tag("internal")`; a route can have
+    // several tags. Tags select routes for `layer_for_tag`, group the
+    // per-tag counters in `StatsSnapshot::tags` and are listed in
+    // `Route::tags` / `RouteInfo`.
+    pub fn tag(mut self, tag: &str) -> Self {
+        let route = self.last_route("tag");
+        if !route.has_tag(tag) {
+            route.tags.push(tag.to_string());
+        }
+        self
+    }
+
+    // Runs `layer` around the handler of every route tagged `tag`, whether
+    // the route was added before or after this call. A route with several
+    // tagged layers runs them in the order they were registered, the first
+    // one outermost. Layers only see requests that matched a route and
+    // passed its body size and `consumes` checks.
+    pub fn layer_for_tag<L>(mut self, tag: &str, layer: L) -> Self
+    where
+        L: Layer,
+    {
+        self.tag_layers.push((tag.to_string(), Arc::new(layer)));
+        self
+    }
+
+    // Logs the last added route's requests and responses, headers and
+    // bodies, at debug level (see `dump`), without turning anything on for
+    // other routes. Bodies are cut off after `DEFAULT_DEBUG_BODY_LIMIT`
+    // bytes unless `debug_body_limit` says otherwise.
+    pub fn debug(mut self) -> Self {
+        let route = self.last_route("debug");
+        route.debug_body_limit.get_or_insert(DEFAULT_DEBUG_BODY_LIMIT);
+        self
+    }
+
+    pub fn debug_body_limit(mut self, bytes: usize) -> Self {
+        self.last_route("debug_body_limit").debug_body_limit = Some(bytes);
+        self
+    }
+
+    // As `debug`, for every route tagged `tag`
+    pub fn debug_tag(mut self, tag: &str) -> Self {
+        self.debug_tags.push(tag.to_string());
+        self
+    }
+
+    // Never dumps the last added route's traffic, even when `debug` or a
+    // `debug_tag` covers it; for routes handling credentials or personal data
+    pub fn sensitive(mut self) -> Self {
+        self.last_route("sensitive").sensitive = true;
+        self
+    }
+
+    // Units of quota the last added route consumes per request under a
+    // `Quota` layer; 1 by default
+    pub fn quota_cost(mut self, cost: u64) -> Self {
+        self.last_route("quota_cost").quota_cost = Some(cost);
+        self
+    }
+
+    // Whether a request with a body but no Content-Type passes a `consumes`
+    // guard. Off by default, so such requests get 415.
+    pub fn allow_missing_content_type(mut self, allow: bool) -> Self {
+        self.allow_missing_content_type = allow;
+        self
+    }
+
+    fn push(&mut self, mut route: Route) {
+        self.resolve_matchers(&route.path, &mut route.pattern);
+        self.routes.push(route);
+    }
+
+    fn resolve_matchers(&self, path: &str, pattern: &mut Pattern) {
+        for segment in &mut pattern.segments {
+            if let Segment::Param {
+                constraint: Some(Constraint::Custom { name, matcher }),
+                ..
+            } = segment
+            {
+                match self.matchers.get(name.as_str()) {
+                    Some(m) => *matcher = Some(m.clone()),
+                    None => panic!(
+                        "invalid route pattern `{}`: unknown matcher `{}`",
+                        path, name
+                    ),
+                }
+            }
+        }
+    }
+
+    // Redirects and internal rewrites checked before routing, after
+    // `with_normalize`; see `RewriteTable`. Custom matchers in rule patterns
+    // must be registered before this call. Replaces any earlier table.
+    pub fn rewrites(mut self, mut table: RewriteTable) -> Self {
+        for (path, pattern) in table.patterns_mut() {
+            self.resolve_matchers(path, pattern);
+        }
+        self.rewrites = Some(Arc::new(table));
+        self
+    }
+
+    // The rewrite rules with their hit counts, in evaluation order
+    pub fn rewrite_rules(&self) -> Vec<RewriteRuleInfo> {
+        self.rewrites
+            .as_ref()
+            .map_or_else(Vec::new, |table| table.info())
+    }
+
+    pub(crate) fn rewrite_table(&self) -> Option<Arc<RewriteTable>> {
+        self.rewrites.clone()
+    }
+
+    // Virtual hosting: requests for `host` are handled entirely by `router`,
+    // with its own routes, normalization, rewrites and layers.
+    //   Router::new()
+    //       .host("api.example.com", api)
+    //       .host("*.example.com", tenants)
+    //       .get("/", landing)
+    //
+    // The host comes from the Host header, or the `:authority` of an HTTP/2
+    // request, compared case-insensitively and without the port. Exact hosts
+    // are tried before wildcards, and `*.example.com` matches any subdomain
+    // but not `example.com` itself. A request whose host matches nothing
+    // falls through to this router's own routes, so leave those empty to
+    // answer unknown hosts with 404. This router's `with_normalize` runs
+    // before the host is looked up; its rewrites only apply to fall-through
+    // requests.
+    pub fn host(mut self, host: &str, router: Router) -> Self {
+        self.hosts
+            .push((host.trim_end_matches('.').to_ascii_lowercase(), Arc::new(router)));
+        self
+    }
+
+    // Handles every request no route matches, instead of answering 404. With
+    // a `Proxy` this puts the router in front of an existing server, so
+    // endpoints can move over one at a time while everything else is still
+    // served by the old one:
+    //   Router::new()
+    //       .get("/users/:id", get_user)
+    //       .fallback_service(Proxy::new("http://legacy.internal:8080"))
+    //
+    // The fallback gets the request as the router saw it: after
+    // `with_normalize`, rewrites and the server's dot-segment resolution, so
+    // the path forwarded upstream is the canonical one. Anything the server
+    // itself does still applies (body size limit, timeouts, request guard,
+    // compression, default headers); route options and tag layers don't. The
+    // proxy streams both bodies through, drops hop-by-hop headers and passes
+    // the original Host as `X-Forwarded-Host` (see `Proxy`). A method a
+    // matching path doesn't handle falls back too, as there are no 405s.
+    //
+    // Host routers keep their own fallback; a request for an unknown host
+    // reaches this router's routes and then its fallback.
+    pub fn fallback_service<H>(mut self, handler: H) -> Self
     where
         H: Handler,
     {
-        self.routes.push(Route::new(method, path, handler));
+        self.fallback = Some(Arc::new(move |req: Request<Body>| {
+            Box::pin(handler.call(req)) as Pin<Box<dyn Future<Output = Result<Response>> + Send>>
+        }));
         self
     }
 
-    pub async fn handle(&self, req: Request<Body>) -> Result<Response> {
+    fn host_router(&self, req: &Request<Body>) -> Option<&Arc<Router>> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        let host = request_host(req)?;
+        self.hosts
+            .iter()
+            .find(|(pattern, _)| *pattern == host)
+            .or_else(|| {
+                self.hosts.iter().find(|(pattern, _)| {
+                    pattern.strip_prefix("*.").is_some_and(|domain| {
+                        host.strip_suffix(domain)
+                            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+                    })
+                })
+            })
+            .map(|(_, router)| router)
+    }
+
+    // URL canonicalization applied before any route is matched
+    pub fn with_normalize(mut self, normalize: Normalize) -> Self {
+        self.normalize = Some(normalize);
+        self
+    }
+
+    // Off by default: `/users//1` doesn't match `/users/1` and is a 404.
+    // When enabled, repeated slashes are collapsed for matching only; the
+    // request URI and `RequestContext::raw_path` keep the path as sent.
+    // For a redirect to the canonical path instead, use `Normalize`.
+    pub fn with_merge_slashes(mut self, enabled: bool) -> Self {
+        self.merge_slashes = enabled;
+        self
+    }
+
+    // Off by default, so `/Users` doesn't match `/users`. When enabled,
+    // static segments match regardless of ASCII case while parameters are
+    // captured, and the request logged, exactly as sent. Useful when
+    // migrating from case-insensitive servers such as IIS.
+    pub fn with_case_insensitive(mut self, enabled: bool) -> Self {
+        self.case_insensitive = enabled;
+        self
+    }
+
+    // How `Query<T>` deserializes the query string of requests routed here;
+    // flat, lenient parsing by default
+    pub fn with_query_config(mut self, config: QueryConfig) -> Self {
+        self.query_config = config;
+        self
+    }
+
+    // Negotiates a locale for every routed request from `Accept-Language`,
+    // available to layers and handlers as `req.locale()`. Pair the response
+    // with `Response::content_language`.
+    pub fn with_locales(mut self, locales: Locales) -> Self {
+        self.locales = Some(Arc::new(locales));
+        self
+    }
+
+    // Ceiling on the whole response to each routed request, counted from
+    // when the server received it until the last body byte is written;
+    // unlike the connection timeouts it bounds a single exchange. Routes can
+    // override it with `.response_timeout(...)`, and host routers use their
+    // own default. It overrides `Server::with_request_timeout`, which applies
+    // only where neither the route nor its router sets a timeout.
+    //
+    // While the handler (with its layers) is still running, hitting it drops
+    // the handler, cancels the request's `cancellation_token` and answers
+    // 503. Once the handler has returned, the status and headers go out and
+    // can no longer change: a body still streaming at the deadline is cut
+    // off, closing the connection (HTTP/1.1) or resetting the stream
+    // (HTTP/2), so the client sees a truncated response, and the token is
+    // cancelled for whatever is producing the body.
+    pub fn with_default_response_timeout(mut self, timeout: Duration) -> Self {
+        self.default_response_timeout = Some(timeout);
+        self
+    }
+
+    // Requests whose path has more than `max` segments are answered 400
+    // before normalization, rewrites or matching look at them, bounding the
+    // work a pathological URL can cause. Empty segments (`a//b`) count.
+    // Host routers apply their own limit once the host is resolved.
+    pub fn with_max_path_segments(mut self, max: usize) -> Self {
+        self.max_path_segments = max;
+        self
+    }
+
+    // Serves `info` as JSON on GET `path` (e.g. `/version`) to requests that
+    // pass `guard`. The server also publishes it through `Stats`. To stamp
+    // the SHA on every response, pass it to `Server::with_default_headers`.
+    pub fn with_version_endpoint(
+        mut self,
+        path: impl Into<String>,
+        info: BuildInfo,
+        guard: EndpointGuard,
+    ) -> Self {
+        let response_info = info.clone();
+        self.build_info = Some(info);
+        self.get(path, move |req: Request<Body>| {
+            let response = match guard.refuse(&req) {
+                Some(refusal) => refusal,
+                None => response_info.to_response(),
+            };
+            async move { response }
+        })
+    }
+
+    pub fn build_info(&self) -> Option<&BuildInfo> {
+        self.build_info.as_ref()
+    }
+
+    // Registered routes, in registration order
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn routes_tagged<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = &'a Route> + 'a {
+        self.routes.iter().filter(move |route| route.has_tag(tag))
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    // Static analysis of the route table under the matching precedence rules
+    pub fn check(&self) -> Vec<RouteDiagnostic> {
+        let mut diagnostics = Vec::new();
+        let diagnostic = |route: &Route, issue| RouteDiagnostic {
+            method: route.method.clone(),
+            path: route.path.clone(),
+            issue,
+        };
+
+        for (i, route) in self.routes.iter().enumerate() {
+            for name in route.pattern.duplicate_params() {
+                diagnostics.push(diagnostic(
+                    route,
+                    RouteIssue::DuplicateParam(name.to_string()),
+                ));
+            }
+            if route.pattern.has_empty_segment() {
+                diagnostics.push(diagnostic(route, RouteIssue::EmptySegment));
+            }
+
+            let rank = route.pattern.rank();
+            for (j, other) in self.routes.iter().enumerate() {
+                if i == j || other.method != route.method {
+                    continue;
+                }
+                if other.path == route.path {
+                    if j < i {
+                        diagnostics.push(diagnostic(route, RouteIssue::Duplicate));
+                        break;
+                    }
+                    continue;
+                }
+                let other_rank = other.pattern.rank();
+                let wins = other_rank > rank || (other_rank == rank && j < i);
+                if wins && other.pattern.covers(&route.pattern) {
+                    diagnostics.push(diagnostic(
+                        route,
+                        RouteIssue::Shadowed {
+                            by: other.path.clone(),
+                        },
+                    ));
+                    break;
+                }
+            }
+        }
+
+        diagnostics
+    }
+
+    // The server runs this on the path as received, before resolving dot
+    // segments; `handle` repeats it for routers used on their own
+    pub(crate) fn check_path_segments(&self, path: &str) -> Result<()> {
+        check_path_segments(path, self.max_path_segments)
+    }
+
+    pub async fn handle(&self, mut req: Request<Body>) -> Result<Response> {
+        check_path_segments(req.uri().path(), self.max_path_segments)?;
+        check_percent_encoding(req.uri())?;
+        if let Some(normalize) = &self.normalize {
+            if let Some(redirect) = normalize.apply(&mut req) {
+                return Ok(redirect);
+            }
+        }
+        if let Some(router) = self.host_router(&req) {
+            return Box::pin(router.handle(req)).await;
+        }
+        if let Some(rewrites) = &self.rewrites {
+            if let Some(redirect) = rewrites.apply(&mut req, self.case_insensitive)? {
+                return Ok(redirect);
+            }
+        }
+
         let method = Method::from(req.method());
         let path = req.uri().path();
+        let merged;
+        let match_path = if self.merge_slashes && path.contains("//") {
+            merged = merge_slashes(path);
+            merged.as_str()
+        } else {
+            path
+        };
 
-        // Simple path matching for now - can be enhanced with parameters later
-        for route in &self.routes {
-            if route.method == method && self.path_matches(&route.path, path) {
-                return (route.handler)(req).await;
+        match self.find(&method, match_path) {
+            Some((route, params)) => {
+                let catch_all = route.pattern.catch_all(match_path);
+                let response_timeout = route
+                    .response_timeout
+                    .or(self.default_response_timeout)
+                    .or_else(|| deadline::server_timeout(&req));
+                if let Some(matched) = req.extensions().get::<MatchedRoute>() {
+                    let mut info = route.info();
+                    info.response_timeout = response_timeout;
+                    let _ = matched.0.set(info);
+                }
+                if let Some(sunset) = &route.sunset {
+                    let method = route.method.to_string();
+                    route
+                        .deprecation_usage
+                        .record(&method, &route.path, sunset);
+                    if let Some(stats) = req.extensions().get::<Arc<Stats>>() {
+                        stats.deprecated_route_called(&method, &route.path);
+                    }
+                    if sunset.gone_after_sunset && sunset.is_past() {
+                        return Ok(sunset.gone_response());
+                    }
+                }
+                check_body_size(route, &req)?;
+                if let Some(rejection) =
+                    check_content_type(route, &req, self.allow_missing_content_type)
+                {
+                    return Ok(rejection);
+                }
+                let mut context = RequestContext::for_request(&req);
+                context.params = params.into_iter().collect();
+                context.set_catch_all(catch_all);
+                context.set_route(&route.path);
+                if let Some(locales) = &self.locales {
+                    context.set_locale(locales.negotiate(&AcceptLanguage::from_headers(req.headers())));
+                }
+                req.extensions_mut().insert(context);
+                req.extensions_mut().insert(self.query_config);
+                if let Some(cost) = route.quota_cost {
+                    req.extensions_mut().insert(QuotaCost(cost));
+                }
+                let dump_limit = self.dump_limit(route);
+                if let Some(limit) = dump_limit {
+                    req = dump::request(&route.path, req, limit);
+                }
+                if let Some(timing) = req.extensions().get::<ServerTiming>() {
+                    timing.mark_handler_started();
+                }
+                let result =
+                    deadline::run(req, response_timeout, |req| self.call_route(route, req)).await;
+                match dump_limit {
+                    Some(limit) => dump::response(&route.path, result, limit).await,
+                    None => result,
+                }
             }
+            None => match &self.fallback {
+                Some(fallback) => {
+                    let context = RequestContext::for_request(&req);
+                    req.extensions_mut().insert(context);
+                    req.extensions_mut().insert(self.query_config);
+                    if let Some(timing) = req.extensions().get::<ServerTiming>() {
+                        timing.mark_handler_started();
+                    }
+                    // No route, so only the handler is bounded, not its body
+                    let timeout = self
+                        .default_response_timeout
+                        .or_else(|| deadline::server_timeout(&req));
+                    deadline::run(req, timeout, |req| fallback(req)).await
+                }
+                None => Err(ServerError::RouteNotFound {
+                    method,
+                    path: path.into(),
+                }),
+            },
+        }
+    }
+
+    async fn call_route(&self, route: &Route, req: Request<Body>) -> Result<Response> {
+        if route.tags.is_empty() {
+            return (route.handler)(req).await;
         }
 
-        Err(ServerError::RouteNotFound {
-            method: format!("{:?}", method),
-            path: path.to_string(),
+        let stats = req.extensions().get::<Arc<Stats>>().cloned();
+        let layers: Vec<Arc<dyn Layer>> = self
+            .tag_layers
+            .iter()
+            .filter(|(tag, _)| route.has_tag(tag))
+            .map(|(_, layer)| layer.clone())
+            .collect();
+        let result = Next::new(layers, route.handler.clone()).run(req).await;
+        if let Some(stats) = stats {
+            let failed = result
+                .as_ref()
+                .map_or(true, |response| response.status.is_server_error());
+            stats.tagged_request(&route.tags, failed);
+        }
+        result
+    }
+
+    fn dump_limit(&self, route: &Route) -> Option<usize> {
+        if route.sensitive {
+            return None;
+        }
+        route.debug_body_limit.or_else(|| {
+            self.debug_tags
+                .iter()
+                .any(|tag| route.has_tag(tag))
+                .then_some(DEFAULT_DEBUG_BODY_LIMIT)
         })
     }
 
-    fn path_matches(&self, route_path: &str, request_path: &str) -> bool {
-        // Simple exact match for now
-        // TODO: Add support for path parameters like /users/:id
-        route_path == request_path
+    // The highest-ranked match wins; ties go to the earliest registration
+    fn find(&self, method: &Method, path: &str) -> Option<(&Route, Params)> {
+        let request = RequestPath::new(path);
+        let mut best: Option<(&Route, Params, Vec<u8>)> = None;
+
+        for route in &self.routes {
+            if route.method != *method {
+                continue;
+            }
+            let matched = if self.case_insensitive {
+                route.pattern.matches_ignore_case(&request)
+            } else {
+                route.pattern.matches(&request)
+            };
+            if let Some(params) = matched {
+                let rank = route.pattern.rank();
+                if best
+                    .as_ref()
+                    .is_none_or(|(_, _, best_rank)| rank > *best_rank)
+                {
+                    best = Some((route, params, rank));
+                }
+            }
+        }
+
+        best.map(|(route, params, _)| (route, params))
+    }
+}
+
+// Lowercased, without the port or a trailing dot
+fn request_host(req: &Request<Body>) -> Option<String> {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    // `example.com:8080`, `[::1]:8080`; a bare IPv6 literal has no port
+    let host = match host.rsplit_once(':') {
+        Some((name, port))
+            if port.bytes().all(|b| b.is_ascii_digit())
+                && (name.ends_with(']') || !name.contains(':')) =>
+        {
+            name
+        }
+        _ => host,
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+// The 415 response when the route's `consumes` guard rejects the request
+fn check_content_type(route: &Route, req: &Request<Body>, allow_missing: bool) -> Option<Response> {
+    if route.consumes.is_empty() || !has_body(req) {
+        return None;
+    }
+
+    let content_type = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let accepted = match content_type {
+        None => allow_missing,
+        Some(value) => MediaType::parse(value).is_some_and(|media_type| {
+            route
+                .consumes
+                .iter()
+                .filter_map(|allowed| MediaType::parse(allowed))
+                .any(|allowed| allowed.matches(&media_type))
+        }),
+    };
+    if accepted {
+        return None;
+    }
+
+    let accept_header = if *req.method() == HttpMethod::PATCH {
+        "Accept-Patch"
+    } else {
+        "Accept-Post"
+    };
+    Some(
+        Response::new()
+            .status(hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .header(accept_header, route.consumes.join(", "))
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::json!({
+                    "error": format!(
+                        "Unsupported media type: {}",
+                        content_type.unwrap_or("none")
+                    )
+                })
+                .to_string(),
+            ),
+    )
+}
+
+// One segment per '/', counted without splitting the path
+fn check_path_segments(path: &str, max: usize) -> Result<()> {
+    let segments = path.bytes().filter(|&b| b == b'/').count();
+    if segments > max {
+        return Err(ServerError::BadRequest(format!(
+            "path has {} segments, more than the limit of {}",
+            segments, max
+        )));
+    }
+    Ok(())
+}
+
+// Malformed escapes (`%zz`, a truncated `%4`) and escapes that don't decode
+// to UTF-8 are a 400, rather than a 404 for a path no route can match or a
+// query parameter that silently disappears. Both decode as a whole: '/',
+// '&' and '=' are ASCII, so a valid whole means every segment and pair is
+// valid too.
+fn check_percent_encoding(uri: &hyper::Uri) -> Result<()> {
+    let path = uri.path();
+    if path.contains('%') && crate::percent::decode(path).is_none() {
+        return Err(ServerError::BadRequest(
+            "malformed percent-encoding in path".to_string(),
+        ));
+    }
+    if let Some(query) = uri.query().filter(|query| query.contains('%')) {
+        if crate::percent::decode_query_component(query).is_none() {
+            return Err(ServerError::BadRequest(
+                "malformed percent-encoding in query".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn has_body(req: &Request<Body>) -> bool {
+    content_length(req).is_some_and(|length| length > 0)
+        || req.headers().contains_key(hyper::header::TRANSFER_ENCODING)
+}
+
+// Settles the request's `BodyLimit` on the route's own limit before the
+// handler can read anything (see `body::limit_body`), then rejects a
+// declared Content-Length above it
+fn check_body_size(route: &Route, req: &Request<Body>) -> Result<()> {
+    let server_limit = req.extensions().get::<BodyLimit>();
+    if let (Some(limit), Some(route_limit)) = (server_limit, route.max_body_size) {
+        limit.set(route_limit);
+    }
+
+    let limit = route
+        .max_body_size
+        .or_else(|| server_limit.map(BodyLimit::get));
+    match (limit, content_length(req)) {
+        (Some(limit), Some(length)) if length > limit as u64 => {
+            Err(ServerError::PayloadTooLarge { limit })
+        }
+        _ => Ok(()),
+    }
+}
+
+// `router.extend(plugin_routes)` with `Route`s or `(Method, path, handler)`
+// tuples; the same as calling `add` / `add_route` for each in order
+impl Extend<Route> for Router {
+    fn extend<I: IntoIterator<Item = Route>>(&mut self, routes: I) {
+        for route in routes {
+            self.push(route);
+        }
+    }
+}
+
+impl<P, H> Extend<(Method, P, H)> for Router
+where
+    P: Into<String>,
+    H: Handler,
+{
+    fn extend<I: IntoIterator<Item = (Method, P, H)>>(&mut self, routes: I) {
+        for (method, path, handler) in routes {
+            self.add_route(method, path, handler);
+        }
     }
 }
 
@@ -139,4 +1172,50 @@ impl Default for Router {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn ok(_req: Request<Body>) -> Result<Response> {
+        Ok(Response::new())
+    }
+
+    // The pattern `find` picks for GET `path`
+    fn winner(router: &Router, path: &str) -> Option<String> {
+        router
+            .find(&Method::GET, path)
+            .map(|(route, _)| route.path.clone())
+    }
+
+    #[test]
+    fn exact_prefix_beats_an_empty_catch_all() {
+        for router in [
+            Router::new().get("/assets/*path", ok).get("/assets", ok),
+            Router::new().get("/assets", ok).get("/assets/*path", ok),
+        ] {
+            assert_eq!(winner(&router, "/assets").as_deref(), Some("/assets"));
+            assert_eq!(
+                winner(&router, "/assets/site.css").as_deref(),
+                Some("/assets/*path")
+            );
+        }
+
+        let router = Router::new().get("/*rest", ok).get("/:page", ok);
+        assert_eq!(winner(&router, "/about").as_deref(), Some("/:page"));
+        assert_eq!(winner(&router, "/about/team").as_deref(), Some("/*rest"));
+    }
+
+    #[test]
+    fn constrained_beats_unconstrained_beats_catch_all() {
+        let router = Router::new()
+            .get("/users/*rest", ok)
+            .get("/users/:name", ok)
+            .get("/users/:id<int>", ok);
+
+        assert_eq!(winner(&router, "/users/42").as_deref(), Some("/users/:id<int>"));
+        assert_eq!(winner(&router, "/users/ann").as_deref(), Some("/users/:name"));
+        assert_eq!(winner(&router, "/users").as_deref(), Some("/users/*rest"));
+        assert_eq!(winner(&router, "/users/ann/posts").as_deref(), Some("/users/*rest"));
+    }
+}
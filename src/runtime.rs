@@ -0,0 +1,178 @@
+use crate::{Result, Server, ServerError};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tracing::warn;
+
+pub const DEFAULT_THREAD_NAME: &str = "server-worker";
+
+// Settings for the tokio runtime built by `ServerRuntime` /
+// `Server::run_blocking`. `None` keeps tokio's default.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    // Defaults to one per CPU
+    pub worker_threads: Option<usize>,
+    // Threads are named `<prefix>-<n>`
+    pub thread_name_prefix: String,
+    pub max_blocking_threads: Option<usize>,
+    pub thread_stack_size: Option<usize>,
+    // Pins worker thread n to the n-th CPU the process may run on, wrapping
+    // around. Linux only; elsewhere it's logged and ignored.
+    pub pin_workers: bool,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            thread_name_prefix: DEFAULT_THREAD_NAME.to_string(),
+            max_blocking_threads: None,
+            thread_stack_size: None,
+            pin_workers: false,
+        }
+    }
+}
+
+// An owned multi-threaded runtime for launching the server from a plain
+// (non-async) `main`:
+//   let runtime = ServerRuntime::new(RuntimeConfig::default())?;
+//   runtime.handle().spawn(refresh_cache());
+//   runtime.run(server)
+//
+// It must be created and run outside any tokio runtime; doing otherwise
+// returns `ServerError::Runtime` rather than panicking.
+pub struct ServerRuntime {
+    runtime: Runtime,
+}
+
+impl ServerRuntime {
+    pub fn new(config: RuntimeConfig) -> Result<Self> {
+        ensure_outside_runtime()?;
+
+        let mut builder = Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(threads) = config.worker_threads {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = config.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        if let Some(size) = config.thread_stack_size {
+            builder.thread_stack_size(size);
+        }
+
+        let prefix = config.thread_name_prefix;
+        let thread_number = AtomicUsize::new(0);
+        builder.thread_name_fn(move || {
+            format!("{}-{}", prefix, thread_number.fetch_add(1, Ordering::Relaxed))
+        });
+
+        if config.pin_workers {
+            let workers = config
+                .worker_threads
+                .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+            pin_worker_threads(&mut builder, workers);
+        }
+
+        let runtime = builder
+            .build()
+            .map_err(|e| ServerError::Runtime(format!("failed to build runtime: {}", e)))?;
+        Ok(Self { runtime })
+    }
+
+    // For spawning background tasks onto the runtime, before or while the
+    // server runs
+    pub fn handle(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+
+    // Panics if called from within a tokio runtime
+    pub fn block_on<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        self.runtime.block_on(future)
+    }
+
+    pub fn run(self, server: Server) -> Result<()> {
+        ensure_outside_runtime()?;
+        self.runtime.block_on(server.run())
+    }
+
+    pub fn run_with_graceful_shutdown<F>(self, server: Server, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        ensure_outside_runtime()?;
+        self.runtime
+            .block_on(server.run_with_graceful_shutdown(signal))
+    }
+}
+
+fn ensure_outside_runtime() -> Result<()> {
+    if Handle::try_current().is_ok() {
+        return Err(ServerError::Runtime(
+            "called from within a tokio runtime; use `Server::run` there instead".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Workers are the first threads the runtime starts (blocking threads only
+// appear once something calls `spawn_blocking`), so the first `workers`
+// thread starts are the ones pinned.
+#[cfg(target_os = "linux")]
+fn pin_worker_threads(builder: &mut Builder, workers: usize) {
+    let cpus = allowed_cpus();
+    if cpus.is_empty() {
+        warn!("Worker pinning disabled: could not read the CPU affinity mask");
+        return;
+    }
+
+    let cpus = Arc::new(cpus);
+    let started = AtomicUsize::new(0);
+    builder.on_thread_start(move || {
+        let n = started.fetch_add(1, Ordering::Relaxed);
+        if n < workers {
+            let cpu = cpus[n % cpus.len()];
+            if let Err(e) = pin_current_thread(cpu) {
+                warn!("Failed to pin worker thread to CPU {}: {}", cpu, e);
+            }
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_worker_threads(_builder: &mut Builder, _workers: usize) {
+    warn!("Worker pinning is only supported on Linux; ignoring `pin_workers`");
+}
+
+// CPUs in the process's affinity mask, which may be narrower than the
+// machine (taskset, cgroups)
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+    // SAFETY: `cpu_set_t` is plain data and the size passed matches it
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpu: usize) -> std::io::Result<()> {
+    // SAFETY: as above; pid 0 is the calling thread
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -1,156 +1,1726 @@
-use crate::{Result, Router, ServerError};
+use crate::audit::{exact_length, Audit, Auditor, PendingAudit};
+use crate::body::{limit_body, BodyLimit, DEFAULT_MAX_BODY_SIZE};
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, ChaosLayer, Fault};
+use crate::compression::{capture_accept_encoding, Compression};
+use crate::deadline;
+use crate::completion::Completion;
+use crate::digest;
+use crate::connections::IpLimiter;
+use crate::early_hints::EarlyHints;
+use crate::guard::EndpointGuard;
+use crate::handler::RequestParts;
+use crate::log_context::LogContext;
+use crate::normalize::{self, DotSegments};
+use crate::quota;
+use crate::preconditions::{apply_conditional_get, capture_conditions};
+use crate::preflight::Preflight;
+use crate::recover::{self, catch_panic};
+use crate::response::elide_body;
+use crate::rewrite::RewriteTable;
+use crate::router::{MatchedRoute, RouteDiagnostic, RouteInfo};
+use crate::runtime::{RuntimeConfig, ServerRuntime};
+use crate::queue::{QueuePolicy, RequestQueue};
+use crate::stats::Stats;
+use crate::timing::ServerTiming;
+use crate::trap::{self, RequestTraps};
+use crate::validation::ValidationErrors;
+use crate::{Response, Result, Router, ServerError};
+use futures::future::{poll_fn, FutureExt};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Server as HyperServer};
+use serde::Serialize;
 use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tracing::{error, info, warn};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn, Instrument};
+
+pub const ROUTE_HEADER: &str = "X-Route";
+pub const ROUTE_NAME_HEADER: &str = "X-Route-Name";
+
+// Default limit for path + query, in bytes
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+
+// Default size above which a handler's in-memory response body is reported
+pub const DEFAULT_BUFFERED_BODY_LIMIT: usize = 32 * 1024 * 1024;
+
+const OFFENDER_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerConfig {
+    pub max_uri_length: usize,
+    pub dot_segments: DotSegments,
+    pub merge_slashes: bool,
+    pub http2_only: bool,
+    pub http2_initial_stream_window_size: u32,
+    pub http2_initial_connection_window_size: u32,
+    pub http2_max_frame_size: u32,
+    // `None` lets hyper pick the write strategy per transport
+    pub http1_writev: Option<bool>,
+    pub http1_half_close: bool,
+    pub http1_preserve_header_case: bool,
+    pub max_connections_per_ip: Option<usize>,
+    pub max_concurrent_requests: Option<usize>,
+    pub request_queue_depth: usize,
+    pub queue_policy: QueuePolicy,
+    pub bucket_ipv6_by_prefix: bool,
+    pub max_body_size: usize,
+    pub default_headers: Vec<(String, String)>,
+    pub conditional_get: bool,
+    pub shutdown_timeout: Option<Duration>,
+    pub server_timing: bool,
+    pub strict_headers: bool,
+    pub buffered_body_limit: usize,
+    pub strict_buffered_body_limit: bool,
+    pub request_parts: bool,
+    pub early_hints: bool,
+    pub default_charset: Option<String>,
+    pub request_timeout: Option<Duration>,
+    pub route_header: bool,
+    pub strip_response_headers: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            dot_segments: DotSegments::Resolve,
+            merge_slashes: false,
+            http2_only: false, // Allow both HTTP/1.1 and HTTP/2
+            http2_initial_stream_window_size: 1024 * 1024, // 1MB
+            http2_initial_connection_window_size: 1024 * 1024 * 10, // 10MB
+            http2_max_frame_size: 1024 * 64, // 64KB
+            http1_writev: None,
+            http1_half_close: false,
+            http1_preserve_header_case: false,
+            max_connections_per_ip: None,
+            max_concurrent_requests: None,
+            request_queue_depth: 0,
+            queue_policy: QueuePolicy::RejectNewest,
+            bucket_ipv6_by_prefix: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            default_headers: Vec::new(),
+            conditional_get: true,
+            shutdown_timeout: None,
+            server_timing: false,
+            strict_headers: false,
+            buffered_body_limit: DEFAULT_BUFFERED_BODY_LIMIT,
+            strict_buffered_body_limit: false,
+            request_parts: false,
+            early_hints: false,
+            default_charset: None,
+            request_timeout: None,
+            route_header: false,
+            strip_response_headers: Vec::new(),
+        }
+    }
+}
+
+// How `Router::check` diagnostics are treated at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteCheck {
+    Off,
+    Warn,
+    Strict,
+}
+
+// An opt-in operational endpoint (the config dump, the trap API) and the
+// guard its requests must pass
+struct AdminEndpoint {
+    path: String,
+    guard: EndpointGuard,
+}
 
 pub struct Server {
-    router: Arc<Router>,
+    router: Router,
     addr: SocketAddr,
+    config: ServerConfig,
+    debug_config: Option<AdminEndpoint>,
+    route_check: RouteCheck,
+    ip_limiter: Arc<IpLimiter>,
+    stats: Arc<Stats>,
+    compression: Option<Arc<Compression>>,
+    audit: Option<Audit>,
+    request_guard: Option<RequestGuard>,
+    traps: Option<RequestTraps>,
+    trap_endpoint: Option<AdminEndpoint>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosLayer>,
+    router_slot: Arc<RouterSlot>,
+    startup_hooks: Vec<StartupHook>,
+    preflight: Vec<Preflight>,
+    // Set by `from_listener`; otherwise `addr` is bound when the server runs
+    listener: Option<std::net::TcpListener>,
 }
 
+type StartupHook = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+type RequestGuard = Arc<dyn Fn(&Request<Body>) -> Result<()> + Send + Sync>;
+
 impl Server {
     pub fn new(addr: SocketAddr) -> Self {
         Self {
-            router: Arc::new(Router::new()),
+            router: Router::new(),
             addr,
+            config: ServerConfig::default(),
+            debug_config: None,
+            route_check: RouteCheck::Warn,
+            ip_limiter: Arc::new(IpLimiter::new(None, false)),
+            stats: Arc::new(Stats::new()),
+            compression: None,
+            audit: None,
+            request_guard: None,
+            traps: None,
+            trap_endpoint: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            router_slot: Arc::new(RouterSlot::new(RouteCheck::Warn)),
+            startup_hooks: Vec::new(),
+            preflight: Vec::new(),
+            listener: None,
         }
     }
 
+    // Serves on an already bound socket, e.g. one on port 0 for tests, or
+    // one inherited from a process manager
+    pub fn from_listener(listener: std::net::TcpListener) -> Result<Self> {
+        let addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: Some(listener),
+            ..Self::new(addr)
+        })
+    }
+
+    // The address the server listens on; with `from_listener` this is the
+    // port the OS picked, with `new` it's the address as given
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            ip_limiter: self.ip_limiter.clone(),
+            stats: self.stats.clone(),
+            router: self.swappable_router(),
+        }
+    }
+
+    // Live counters, also available to handlers via `RequestExt::stats`
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
     pub fn with_router(mut self, router: Router) -> Self {
-        self.router = Arc::new(router);
+        self.router = router;
         self
     }
 
-    pub async fn run(self) -> Result<()> {
-        // Initialize tracing
-        tracing_subscriber::fmt::init();
+    // Unless `Off`, `Router::check` runs when the server starts, whichever
+    // of `with_router` / `with_route_check` came first. The diagnostics are
+    // logged as warnings, or abort startup under `RouteCheck::Strict`.
+    pub fn with_route_check(mut self, mode: RouteCheck) -> Self {
+        self.route_check = mode;
+        *self.router_slot.route_check.lock().unwrap() = mode;
+        self
+    }
 
-        info!("Starting server on {}", self.addr);
+    // Handle for replacing the routing table while the server runs. The
+    // router passed to `with_router` is generation 0; each successful
+    // `RouterHandle::swap` bumps the generation.
+    pub fn swappable_router(&self) -> RouterHandle {
+        RouterHandle {
+            slot: self.router_slot.clone(),
+            stats: self.stats.clone(),
+        }
+    }
 
-        let router = self.router.clone();
+    // `Router::check` on the current router, whatever the check mode
+    pub fn route_diagnostics(&self) -> Vec<RouteDiagnostic> {
+        self.router.check()
+    }
 
-        // Create the service factory
-        let make_svc = make_service_fn(move |_conn| {
-            let router = router.clone();
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    let router = router.clone();
-                    async move { handle_request(router, req).await }
-                }))
-            }
+    // Requests whose path + query exceed `max` bytes are rejected with 414
+    pub fn with_max_uri_length(mut self, max: usize) -> Self {
+        self.config.max_uri_length = max;
+        self
+    }
+
+    // Forces vectored writes on (`true`) or flattening into one buffer
+    // (`false`); by default hyper decides based on the transport
+    pub fn http1_writev(mut self, enabled: bool) -> Self {
+        self.config.http1_writev = Some(enabled);
+        self
+    }
+
+    // Keep serving after the client shuts down its write side, rather than
+    // treating the read EOF as a closed connection. Off by default.
+    pub fn http1_half_close(mut self, enabled: bool) -> Self {
+        self.config.http1_half_close = enabled;
+        self
+    }
+
+    // Records the case of incoming HTTP/1 header names, so a `Proxy` with
+    // `http1_preserve_header_case` forwards them upstream as received; for
+    // case-sensitive upstreams. hyper keeps the case map private, so headers
+    // set on a `Response` are still sent lowercased. Off by default.
+    pub fn http1_preserve_header_case(mut self, enabled: bool) -> Self {
+        self.config.http1_preserve_header_case = enabled;
+        self
+    }
+
+    // Caps concurrently open connections from a single client IP; excess
+    // connections are closed at accept time. Adjustable later through
+    // `ServerHandle::set_max_connections_per_ip`.
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.config.max_connections_per_ip = Some(limit);
+        self.ip_limiter.set_limit(Some(limit));
+        self
+    }
+
+    // Count IPv6 clients per /64 prefix rather than per address
+    pub fn bucket_ipv6_by_prefix(mut self, enabled: bool) -> Self {
+        self.config.bucket_ipv6_by_prefix = enabled;
+        self.ip_limiter.set_bucket_ipv6(enabled);
+        self
+    }
+
+    // Request bodies larger than this get 413 Payload Too Large, unless the
+    // matched route sets its own `max_body_size`
+    pub fn with_max_body_size(mut self, limit: usize) -> Self {
+        self.config.max_body_size = limit;
+        self
+    }
+
+    // Compresses responses with the registered encoders; see `Compression`
+    // for which responses (SSE, tiny bodies, ...) are left alone
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(Arc::new(compression));
+        self
+    }
+
+    // Captures request and response bodies of matching requests for an
+    // audit sink; see `Audit`
+    pub fn with_audit(mut self, audit: Audit) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    // `.` and `..` segments in request paths are resolved before anything
+    // else sees the path: the request guard, audit and trap filters, layers
+    // and the router all get `/admin` for `/public/../admin`, so a prefix
+    // check can't be sidestepped. `DotSegments::Reject` answers 400 instead.
+    // The path as sent is logged as `raw_path` when it changed.
+    pub fn with_dot_segments(mut self, dot_segments: DotSegments) -> Self {
+        self.config.dot_segments = dot_segments;
+        self
+    }
+
+    // Collapses repeated slashes in request paths, alongside dot-segment
+    // resolution, so `//admin` reaches everything as `/admin`. Unlike
+    // `Router::with_merge_slashes`, which only affects matching, this
+    // changes the path handlers and middleware see. Off by default.
+    pub fn with_merge_slashes(mut self, enabled: bool) -> Self {
+        self.config.merge_slashes = enabled;
+        self
+    }
+
+    // Deep capture of the next request matching an armed trap; see
+    // `RequestTraps`. Keep a clone to arm traps from code.
+    pub fn with_request_traps(mut self, traps: RequestTraps) -> Self {
+        self.traps = Some(traps);
+        self
+    }
+
+    // Serves the request traps at `path` to requests that pass `guard`:
+    // listing, arming and removing them, and reading captures (see
+    // `trap::endpoint`). Installs default `RequestTraps` unless
+    // `with_request_traps` set some.
+    pub fn with_trap_endpoint(mut self, path: impl Into<String>, guard: EndpointGuard) -> Self {
+        self.traps.get_or_insert_with(RequestTraps::new);
+        self.trap_endpoint = Some(AdminEndpoint {
+            path: path.into(),
+            guard,
         });
+        self
+    }
 
-        // Create the server with HTTP/2 support
-        let server = HyperServer::bind(&self.addr)
-            .http2_only(false) // Allow both HTTP/1.1 and HTTP/2
-            .http2_initial_stream_window_size(Some(1024 * 1024)) // 1MB
-            .http2_initial_connection_window_size(Some(1024 * 1024 * 10)) // 10MB
-            .http2_max_frame_size(Some(1024 * 64)) // 64KB
-            .serve(make_svc);
+    // Runs `guard` on every request before anything else, routing
+    // included; an error is answered right away with its status, e.g. to
+    // turn away banned IPs or unknown hosts:
+    //   .with_request_guard(|req| match req.headers().get("Host") {
+    //       Some(host) if host == "api.example.com" => Ok(()),
+    //       _ => Err(ServerError::BadRequest("unknown host".into())),
+    //   })
+    // It sees the request as received, with only `remote_addr` and the
+    // server's own extensions set. Keep it cheap and synchronous; anything
+    // that needs the route or awaits belongs in a `Layer`.
+    pub fn with_request_guard<F>(mut self, guard: F) -> Self
+    where
+        F: Fn(&Request<Body>) -> Result<()> + Send + Sync + 'static,
+    {
+        self.request_guard = Some(Arc::new(guard));
+        self
+    }
 
-        info!("Server running on http://{}", self.addr);
-        info!("HTTP/2 support enabled");
+    // Fault injection for resilience testing; keep a `ChaosLayer::handle`
+    // to change the rules while the server runs
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: ChaosLayer) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
 
-        // Run the server
-        if let Err(e) = server.await {
-            error!("Server error: {}", e);
-            return Err(ServerError::Hyper(e));
+    // Headers added to every response, including error responses, unless the
+    // handler already set a header with the same name. Invalid names or values
+    // make the server fail at startup.
+    pub fn with_default_headers<K, V>(mut self, headers: Vec<(K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.config.default_headers = headers
+            .into_iter()
+            .map(|(k, v)| (k.into(), v.into()))
+            .collect();
+        self
+    }
+
+    // Stamps every routed response with the pattern that matched, for an
+    // edge proxy's metrics: `X-Route: GET /users/:id`, plus
+    // `X-Route-Name: get_user` if the route has a `Router::name`. Responses
+    // no route matched (router 404s, malformed paths) get
+    // `X-Route: unmatched`, so they can be told apart from a handler's 404;
+    // requests refused before routing (414, a full request queue) get
+    // neither. Off by default.
+    pub fn with_route_header(mut self, enabled: bool) -> Self {
+        self.config.route_header = enabled;
+        self
+    }
+
+    // Removes these headers from every response, whoever set them, as the
+    // last step before sending. For an external-facing server sharing a
+    // router with an internal one:
+    //   .strip_response_headers(&[ROUTE_HEADER, ROUTE_NAME_HEADER])
+    // Invalid names make the server fail at startup.
+    pub fn strip_response_headers(mut self, names: &[&str]) -> Self {
+        self.config.strip_response_headers = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    // When enabled (the default), the ETag / Last-Modified of 200 responses
+    // to GET/HEAD are checked against the request's preconditions: a
+    // satisfied If-None-Match / If-Modified-Since becomes 304 Not Modified
+    // without a body, a failed If-Match / If-Unmodified-Since becomes 412.
+    // Handlers can send weak ETags (`W/"..."`, see `Response::etag`) for
+    // equivalent but not byte-identical bodies; they still produce 304s but
+    // never satisfy If-Match.
+    pub fn with_conditional_get(mut self, enabled: bool) -> Self {
+        self.config.conditional_get = enabled;
+        self
+    }
+
+    // Adds a `Server-Timing` header with routing / handler / serialize
+    // durations and any entries handlers recorded; see `ServerTiming`
+    pub fn with_server_timing(mut self, enabled: bool) -> Self {
+        self.config.server_timing = enabled;
+        self
+    }
+
+    // A handler response with a header that can't be sent (invalid name, or
+    // a value with control characters such as `\r\n`) normally goes out
+    // without that header, which is logged. In strict mode it is replaced by
+    // a 500 instead, and the error log names the header.
+    pub fn with_strict_headers(mut self, strict: bool) -> Self {
+        self.config.strict_headers = strict;
+        self
+    }
+
+    // A handler that returns a body held in memory (`text`, `json`, `body`
+    // with bytes) larger than `limit` is logged with its route and counted in
+    // `StatsSnapshot::oversized_response_bodies`; the response still goes
+    // out. Bodies that stream (`channel`, `with_body_from_reader`, files) are
+    // never checked. 32MB by default.
+    pub fn with_buffered_body_limit(mut self, limit: usize) -> Self {
+        self.config.buffered_body_limit = limit;
+        self
+    }
+
+    // Answers 500 instead of sending a response over the buffered body
+    // limit, so oversized bodies show up in development rather than as
+    // memory spikes in production
+    pub fn with_strict_buffered_body_limit(mut self, strict: bool) -> Self {
+        self.config.strict_buffered_body_limit = strict;
+        self
+    }
+
+    // Snapshots each request's method, URI, version and headers into its
+    // extensions before routing (see `RequestParts`). Off by default, since
+    // copying the headers costs an allocation per request.
+    pub fn with_request_parts(mut self, enabled: bool) -> Self {
+        self.config.request_parts = enabled;
+        self
+    }
+
+    // Gives handlers an `EarlyHints` to announce preload links through
+    // (`RequestExt::send_early_hints`); they end up as `Link` headers on the
+    // final response
+    pub fn with_early_hints(mut self, enabled: bool) -> Self {
+        self.config.early_hints = enabled;
+        self
+    }
+
+    // Charset added to the `text/plain` / `text/html` Content-Type that
+    // `Response::text` and `Response::html` set, e.g. `"utf-8"`. Responses
+    // naming a charset themselves (`text_with_charset`, an explicit header)
+    // keep theirs. JSON is UTF-8 by definition and is left alone.
+    pub fn default_charset(mut self, charset: &str) -> Self {
+        self.config.default_charset = Some(charset.to_string());
+        self
+    }
+
+    // Caps requests processed at once, server-wide; what happens to the
+    // rest is up to `request_queue`, and without one they get 503
+    pub fn max_concurrent_requests(mut self, limit: usize) -> Self {
+        self.config.max_concurrent_requests = Some(limit);
+        self
+    }
+
+    // Lets up to `depth` requests beyond `max_concurrent_requests` wait in a
+    // FIFO; `policy` decides what happens when it is full. Time spent queued
+    // is reported as the `queue` wait in `ServerTiming` and in
+    // `StatsSnapshot::queue_wait`. Queued requests count as in flight, so a
+    // graceful shutdown serves them before closing (within
+    // `with_shutdown_timeout`).
+    pub fn request_queue(mut self, depth: usize, policy: QueuePolicy) -> Self {
+        self.config.request_queue_depth = depth;
+        self.config.queue_policy = policy;
+        self
+    }
+
+    // Upper bound on draining in-flight requests after the shutdown signal;
+    // connections still open when it elapses are closed. Unbounded by default.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    // Server-wide response timeout, for routes that set none themselves
+    // (`Route::response_timeout`) and whose router has no
+    // `with_default_response_timeout`; either of those wins over this. It
+    // behaves the same: counted from when the request was received, a 503
+    // while the handler runs and a cut-off body after. Requests no route
+    // matches are bounded only while the fallback handler runs.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = Some(timeout);
+        self
+    }
+
+    // Runs `hook` to completion at startup, before the preflight checks and
+    // before the listener opens; e.g. to fill a connection pool. Hooks run
+    // in the order added, and an error aborts startup.
+    pub fn on_startup<F>(mut self, hook: F) -> Self
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.startup_hooks.push(Box::pin(hook));
+        self
+    }
+
+    // Synthetic requests dispatched in-process, in order, once the startup
+    // hooks are done and before the listener opens:
+    //   .preflight(vec![Preflight::get("/health").expect_status(200)])
+    // Each check's status and duration is logged. If one fails, startup is
+    // aborted with `ServerError::Startup` naming the request and the status
+    // it got, and the address is never bound. The checks go through the
+    // whole request pipeline and count in `Stats` like real traffic.
+    pub fn preflight(mut self, checks: Vec<Preflight>) -> Self {
+        self.preflight.extend(checks);
+        self
+    }
+
+    pub fn with_debug_config_endpoint(mut self, path: impl Into<String>, guard: EndpointGuard) -> Self {
+        self.debug_config = Some(AdminEndpoint {
+            path: path.into(),
+            guard,
+        });
+        self
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // Effective runtime configuration as JSON, as served by the debug endpoint
+    pub fn config_json(&self) -> serde_json::Value {
+        let mut config = serde_json::json!(self.config);
+        // Null when off; codecs without a level show `"level": null`
+        config["compression"] = serde_json::json!(self.compression.as_deref());
+        serde_json::json!({
+            "addr": self.addr.to_string(),
+            "routes": self.router.len(),
+            "rewrites": self.router.rewrite_rules(),
+            "config": config,
+        })
+    }
+
+    fn build_router(&mut self) -> Result<Arc<Router>> {
+        if self.route_check != RouteCheck::Off {
+            let diagnostics = self.router.check();
+            for diagnostic in &diagnostics {
+                warn!("Route check: {}", diagnostic);
+            }
+            if self.route_check == RouteCheck::Strict && !diagnostics.is_empty() {
+                return Err(ServerError::Internal(format!(
+                    "route check failed with {} problem(s)",
+                    diagnostics.len()
+                )));
+            }
         }
 
-        Ok(())
+        let dump = self.config_json();
+        let mut router = std::mem::take(&mut self.router);
+
+        if let Some(info) = router.build_info() {
+            self.stats.set_build_info(info.clone());
+        }
+
+        if let Some(endpoint) = self.debug_config.take() {
+            let dump = Arc::new(dump);
+            let guard = Arc::new(endpoint.guard);
+            let rewrites = router.rewrite_table();
+            router = router.get(endpoint.path, move |req: Request<Body>| {
+                let dump = dump.clone();
+                let guard = guard.clone();
+                let rewrites = rewrites.clone();
+                async move { debug_config_handler(req, &guard, &dump, rewrites.as_deref()) }
+            });
+        }
+
+        if let (Some(endpoint), Some(traps)) = (self.trap_endpoint.take(), &self.traps) {
+            let guard = Arc::new(endpoint.guard);
+            let traps = traps.clone();
+            let handler = move |req: Request<Body>| trap::endpoint(req, traps.clone(), guard.clone());
+            let by_id = format!("{}/:id", endpoint.path.trim_end_matches('/'));
+            router = router
+                .get(endpoint.path.clone(), handler.clone())
+                .post(endpoint.path, handler.clone())
+                .get(by_id.clone(), handler.clone())
+                .delete(by_id, handler);
+        }
+
+        Ok(Arc::new(router))
     }
 
+    pub async fn run(self) -> Result<()> {
+        self.serve(std::future::pending()).await
+    }
+
+    // Builds a runtime from `config` and runs the server on it, for a
+    // non-async `main`. Use `ServerRuntime` directly to spawn other tasks
+    // onto the same runtime or to shut down gracefully.
+    pub fn run_blocking(self, config: RuntimeConfig) -> Result<()> {
+        ServerRuntime::new(config)?.run(self)
+    }
+
+    // When `signal` fires the listening socket is closed right away, before
+    // in-flight requests drain, so a replacement instance can bind the same
+    // address during a rolling restart. Open connections finish their
+    // current requests (bounded by `with_shutdown_timeout`) and are closed
+    // rather than kept alive.
     pub async fn run_with_graceful_shutdown<F>(self, signal: F) -> Result<()>
     where
         F: std::future::Future<Output = ()> + Send + 'static,
     {
-        // Initialize tracing
-        tracing_subscriber::fmt::init();
+        self.serve(signal).await?;
+        info!("Server shutdown gracefully");
+        Ok(())
+    }
+
+    async fn serve<F>(mut self, signal: F) -> Result<()>
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        // Initialize tracing, unless the application (or another server in
+        // this process) already has
+        let _ = tracing_subscriber::fmt::try_init();
+        recover::install_hook();
 
-        info!("Starting server on {} with graceful shutdown", self.addr);
+        info!("Starting server on {}", self.addr);
+        if self.config.max_concurrent_requests.is_none() && self.config.request_queue_depth > 0 {
+            warn!("request_queue has no effect without max_concurrent_requests");
+        }
+
+        let router = self.build_router()?;
+        let router_slot = self.router_slot.clone();
+        router_slot.install_initial(router);
+        let shared = Shared::new(
+            self.config.clone(),
+            self.stats.clone(),
+            self.compression.clone(),
+        )?;
+        let shared = Shared {
+            audit: self.audit.as_ref().map(|audit| audit.start(self.stats.clone())),
+            request_guard: self.request_guard.clone(),
+            traps: self.traps.clone(),
+            ..shared
+        };
+        #[cfg(feature = "chaos")]
+        let shared = Shared {
+            chaos: self.chaos.clone(),
+            ..shared
+        };
+        let shared = Arc::new(shared);
+        let ip_limiter = self.ip_limiter.clone();
+        let shutdown = shared.shutdown.clone();
 
-        let router = self.router.clone();
+        for hook in std::mem::take(&mut self.startup_hooks) {
+            hook.await?;
+        }
+        let (router, _) = router_slot.load();
+        run_preflight(&self.preflight, router, &shared).await?;
+
+        // Periodically summarize clients hitting the per-IP connection limit
+        let summary_limiter = Arc::downgrade(&self.ip_limiter);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(OFFENDER_LOG_INTERVAL);
+            loop {
+                interval.tick().await;
+                match summary_limiter.upgrade() {
+                    Some(limiter) => limiter.log_offenders(5),
+                    None => break,
+                }
+            }
+        });
 
         // Create the service factory
-        let make_svc = make_service_fn(move |_conn| {
-            let router = router.clone();
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let router_slot = router_slot.clone();
+            let shared = shared.clone();
+            let remote_addr = conn.remote_addr();
+            let remote_ip = remote_addr.ip();
+            let guard = ip_limiter.try_acquire(remote_ip);
             async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    let router = router.clone();
-                    async move { handle_request(router, req).await }
+                // Refusing the service makes hyper drop the connection
+                let guard = guard.ok_or_else(|| {
+                    debug!(
+                        "Rejecting connection from {}: per-IP limit reached",
+                        remote_ip
+                    );
+                    std::io::Error::new(
+                        std::io::ErrorKind::ConnectionRefused,
+                        "per-IP connection limit reached",
+                    )
+                })?;
+                let mut connection = shared.stats.connection_opened();
+                Ok::<_, std::io::Error>(service_fn(move |mut req: Request<Body>| {
+                    // Held for the lifetime of the connection
+                    let _guard = &guard;
+                    connection.request_started(req.version());
+                    req.extensions_mut().insert(remote_addr);
+                    // In-flight requests keep the table they started with
+                    let (router, generation) = router_slot.load();
+                    let shared = shared.clone();
+                    async move { handle_request(router, generation, shared, req).await }
                 }))
             }
         });
 
+        // The listener is ours rather than hyper's so it can be dropped the
+        // moment the signal fires, independently of connection draining
+        let signal = signal.shared();
+        let cancel_requests = signal.clone();
+        tokio::spawn(async move {
+            cancel_requests.await;
+            shutdown.cancel();
+        });
+        let listener = match self.listener.take() {
+            Some(listener) => AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)?,
+            None => AddrIncoming::bind(&self.addr)?,
+        };
+        let stop = signal.clone();
+        let incoming = futures::stream::unfold(Some((listener, stop)), |state| async move {
+            let (mut listener, mut stop) = state?;
+            tokio::select! {
+                _ = &mut stop => {
+                    drop(listener);
+                    info!("Shutdown signal received; listener closed");
+                    None
+                }
+                conn = poll_fn(|cx| std::pin::Pin::new(&mut listener).poll_accept(cx)) => {
+                    conn.map(|conn| (conn, Some((listener, stop))))
+                }
+            }
+        });
+
         // Create the server with HTTP/2 support
-        let server = HyperServer::bind(&self.addr)
-            .http2_only(false)
-            .http2_initial_stream_window_size(Some(1024 * 1024))
-            .http2_initial_connection_window_size(Some(1024 * 1024 * 10))
-            .http2_max_frame_size(Some(1024 * 64))
+        let (force_close, executor) = ConnectionExecutor::new();
+        let mut builder = HyperServer::builder(accept::from_stream(incoming));
+        if let Some(writev) = self.config.http1_writev {
+            builder = builder.http1_writev(writev);
+        }
+        let server = builder
+            .executor(executor)
+            .http1_half_close(self.config.http1_half_close)
+            .http1_preserve_header_case(self.config.http1_preserve_header_case)
+            .http2_only(self.config.http2_only)
+            .http2_initial_stream_window_size(Some(self.config.http2_initial_stream_window_size))
+            .http2_initial_connection_window_size(Some(
+                self.config.http2_initial_connection_window_size,
+            ))
+            .http2_max_frame_size(Some(self.config.http2_max_frame_size))
             .serve(make_svc);
 
         info!("Server running on http://{}", self.addr);
         info!("HTTP/2 support enabled");
 
-        // Run the server with graceful shutdown
-        let graceful = server.with_graceful_shutdown(signal);
+        // Run the server until the shutdown signal fires
+        let graceful = server.with_graceful_shutdown(signal.clone());
+        let result = match self.config.shutdown_timeout {
+            Some(timeout) => {
+                let deadline = async {
+                    signal.await;
+                    tokio::time::sleep(timeout).await;
+                };
+                tokio::select! {
+                    result = graceful => result,
+                    _ = deadline => {
+                        warn!(
+                            "Shutdown timeout of {:?} elapsed; closing {} open connection(s)",
+                            timeout,
+                            self.stats.active_connections()
+                        );
+                        let _ = force_close.send(true);
+                        Ok(())
+                    }
+                }
+            }
+            None => graceful.await,
+        };
 
-        if let Err(e) = graceful.await {
+        if let Err(e) = result {
             error!("Server error: {}", e);
             return Err(ServerError::Hyper(e));
         }
 
-        info!("Server shutdown gracefully");
         Ok(())
     }
 }
 
+async fn run_preflight(checks: &[Preflight], router: Arc<Router>, shared: &Arc<Shared>) -> Result<()> {
+    for check in checks {
+        let started = Instant::now();
+        let response = handle_request(router.clone(), 0, shared.clone(), check.request()?)
+            .await
+            .unwrap_or_else(|never| match never {});
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let elapsed = started.elapsed();
+
+        if let Err(failure) = check.verify(status, &body) {
+            error!("Preflight failed after {:.1}ms: {}", elapsed.as_secs_f64() * 1000.0, failure);
+            return Err(ServerError::Startup(failure));
+        }
+        info!(
+            "Preflight {} - {} in {:.1}ms",
+            check.describe(),
+            status.as_u16(),
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+    Ok(())
+}
+
+// Spawns hyper's connection tasks so they can all be dropped at once when
+// the shutdown timeout elapses; left alone they'd outlive the server future
+#[derive(Clone)]
+struct ConnectionExecutor {
+    closed: tokio::sync::watch::Receiver<bool>,
+}
+
+impl ConnectionExecutor {
+    fn new() -> (tokio::sync::watch::Sender<bool>, Self) {
+        let (tx, closed) = tokio::sync::watch::channel(false);
+        (tx, Self { closed })
+    }
+}
+
+impl<F> hyper::rt::Executor<F> for ConnectionExecutor
+where
+    F: std::future::Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        let mut closed = self.closed.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = fut => {}
+                _ = closed.wait_for(|closed| *closed) => {}
+            }
+        });
+    }
+}
+
+// Cloneable handle for adjusting settings of a running server
+#[derive(Clone)]
+pub struct ServerHandle {
+    ip_limiter: Arc<IpLimiter>,
+    stats: Arc<Stats>,
+    router: RouterHandle,
+}
+
+impl ServerHandle {
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    // `None` removes the limit
+    pub fn set_max_connections_per_ip(&self, limit: Option<usize>) {
+        self.ip_limiter.set_limit(limit);
+    }
+
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        self.ip_limiter.limit()
+    }
+
+    // Connections refused by the per-IP limit since startup
+    pub fn rejected_connections(&self) -> u64 {
+        self.ip_limiter.rejected_total()
+    }
+
+    // Hot-swaps the routing table, as `RouterHandle::swap`: open
+    // connections stay up and in-flight requests finish on the router they
+    // started with. Returns the new router generation.
+    pub fn set_router(&self, router: Router) -> Result<u64> {
+        self.router.swap(router)
+    }
+
+    pub fn router(&self) -> &RouterHandle {
+        &self.router
+    }
+}
+
+// The live routing table and its generation
+struct RouterSlot {
+    current: RwLock<(Arc<Router>, u64)>,
+    route_check: Mutex<RouteCheck>,
+}
+
+impl RouterSlot {
+    fn new(route_check: RouteCheck) -> Self {
+        Self {
+            current: RwLock::new((Arc::new(Router::new()), 0)),
+            route_check: Mutex::new(route_check),
+        }
+    }
+
+    fn load(&self) -> (Arc<Router>, u64) {
+        let current = self.current.read().unwrap();
+        (current.0.clone(), current.1)
+    }
+
+    // A router swapped in before the server started wins over the initial one
+    fn install_initial(&self, router: Arc<Router>) {
+        let mut current = self.current.write().unwrap();
+        if current.1 == 0 {
+            current.0 = router;
+        }
+    }
+}
+
+// Replaces the routing table of a running server (see
+// `Server::swappable_router`). Requests already dispatched finish on the
+// router they started with; requests arriving after `swap` returns use the
+// new one. Swapped-in routers are used as given: the debug config endpoint
+// is only added to the initial router.
+#[derive(Clone)]
+pub struct RouterHandle {
+    slot: Arc<RouterSlot>,
+    stats: Arc<Stats>,
+}
+
+impl RouterHandle {
+    pub fn current(&self) -> Arc<Router> {
+        self.slot.load().0
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.slot.load().1
+    }
+
+    // Runs the route conflict checker first, honoring the server's
+    // `RouteCheck` mode: in `Strict` mode a router with problems is rejected
+    // and the current one stays. Returns the new generation.
+    pub fn swap(&self, router: Router) -> Result<u64> {
+        let route_check = *self.slot.route_check.lock().unwrap();
+        if route_check != RouteCheck::Off {
+            let diagnostics = router.check();
+            for diagnostic in &diagnostics {
+                warn!("Route check (swap): {}", diagnostic);
+            }
+            if route_check == RouteCheck::Strict && !diagnostics.is_empty() {
+                return Err(ServerError::Internal(format!(
+                    "route check failed with {} problem(s); router not swapped",
+                    diagnostics.len()
+                )));
+            }
+        }
+
+        if let Some(info) = router.build_info() {
+            self.stats.set_build_info(info.clone());
+        }
+
+        let routes = router.len();
+        let mut current = self.slot.current.write().unwrap();
+        let generation = current.1 + 1;
+        *current = (Arc::new(router), generation);
+        drop(current);
+
+        self.stats.set_router_generation(generation);
+        info!(
+            "Router swapped to generation {} ({} routes)",
+            generation, routes
+        );
+        Ok(generation)
+    }
+}
+
+// Per-server state shared by every connection: the configuration plus values
+// derived from it once at startup
+struct Shared {
+    config: ServerConfig,
+    default_headers: HeaderMap,
+    strip_headers: Vec<HeaderName>,
+    stats: Arc<Stats>,
+    queue: Option<Arc<RequestQueue>>,
+    compression: Option<Arc<Compression>>,
+    audit: Option<Auditor>,
+    request_guard: Option<RequestGuard>,
+    traps: Option<RequestTraps>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosLayer>,
+    // Parent of every request's cancellation token, cancelled when shutdown
+    // begins
+    shutdown: CancellationToken,
+}
+
+impl Shared {
+    fn new(
+        config: ServerConfig,
+        stats: Arc<Stats>,
+        compression: Option<Arc<Compression>>,
+    ) -> Result<Self> {
+        let mut default_headers = HeaderMap::new();
+        for (name, value) in &config.default_headers {
+            default_headers.append(
+                HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from)?,
+                HeaderValue::from_str(value).map_err(http::Error::from)?,
+            );
+        }
+
+        let strip_headers = config
+            .strip_response_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(http::Error::from))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        if let Some(compression) = &compression {
+            stats.register_cache("accept_encoding", compression.cache_counters());
+        }
+
+        let queue = config.max_concurrent_requests.map(|limit| {
+            Arc::new(RequestQueue::new(
+                limit,
+                config.request_queue_depth,
+                config.queue_policy,
+                stats.clone(),
+            ))
+        });
+
+        Ok(Self {
+            config,
+            default_headers,
+            strip_headers,
+            stats,
+            queue,
+            compression,
+            audit: None,
+            request_guard: None,
+            traps: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            shutdown: CancellationToken::new(),
+        })
+    }
+
+    fn finish(&self, mut response: hyper::Response<Body>) -> hyper::Response<Body> {
+        let headers = response.headers_mut();
+        for name in self.default_headers.keys() {
+            if !headers.contains_key(name) {
+                for value in self.default_headers.get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        for name in &self.strip_headers {
+            headers.remove(name);
+        }
+        response
+    }
+}
+
+// Routing, error handling and the rest of the per-request pipeline as a
+// hyper `Service`, for plugging a router into a serving loop of your own.
+// Connection-level features (per-IP limits, graceful shutdown, preflight
+// checks) belong to `Server::run` and don't apply here.
+//
+//     let service = router_service(Arc::new(router));
+//     let make_svc = make_service_fn(move |_conn| {
+//         let service = service.clone();
+//         async move { Ok::<_, Infallible>(service) }
+//     });
+//     hyper::Server::bind(&([127, 0, 0, 1], 8080).into())
+//         .serve(make_svc)
+//         .await
+pub fn router_service(router: Arc<Router>) -> RouterService {
+    recover::install_hook();
+    let shared = Shared::new(ServerConfig::default(), Arc::new(Stats::new()), None)
+        .expect("default configuration has no headers to parse");
+    RouterService {
+        router,
+        shared: Arc::new(shared),
+    }
+}
+
+#[derive(Clone)]
+pub struct RouterService {
+    router: Arc<Router>,
+    shared: Arc<Shared>,
+}
+
+impl RouterService {
+    // Fails if one of `config.default_headers` isn't a valid header
+    pub fn with_config(self, config: ServerConfig) -> Result<Self> {
+        let shared = Shared::new(config, self.shared.stats.clone(), None)?;
+        Ok(Self {
+            shared: Arc::new(shared),
+            ..self
+        })
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Result<Self> {
+        let shared = Shared::new(
+            self.shared.config.clone(),
+            self.shared.stats.clone(),
+            Some(Arc::new(compression)),
+        )?;
+        Ok(Self {
+            shared: Arc::new(shared),
+            ..self
+        })
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.shared.stats.clone()
+    }
+}
+
+impl hyper::service::Service<Request<Body>> for RouterService {
+    type Response = hyper::Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::result::Result<(), Infallible>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        Box::pin(handle_request(
+            self.router.clone(),
+            0,
+            self.shared.clone(),
+            req,
+        ))
+    }
+}
+
 async fn handle_request(
     router: Arc<Router>,
-    req: Request<Body>,
+    router_generation: u64,
+    shared: Arc<Shared>,
+    mut req: Request<Body>,
 ) -> std::result::Result<hyper::Response<Body>, Infallible> {
+    let timing = ServerTiming::new();
+    shared.stats.request_received();
+    req.extensions_mut().insert(shared.stats.clone());
+    req.extensions_mut().insert(timing.clone());
+
+    // Limits apply to the URI as received, before any work proportional to
+    // its length
+    let canonical = check_uri_length(&shared.config, req.uri())
+        .and_then(|()| router.check_path_segments(req.uri().path()))
+        .and_then(|()| canonical_path(&shared.config, req.uri().path()));
+    let mut raw_path = None;
+    if let Ok(Some(path)) = &canonical {
+        raw_path = Some(req.uri().path().to_string());
+        normalize::set_path(&mut req, path);
+    }
+    let log_context = LogContext::new(req.method(), req.uri().path());
+    req.extensions_mut().insert(log_context.clone());
+    if router_generation > 0 {
+        log_context.record("router_generation", router_generation);
+    }
+    if let Some(raw_path) = raw_path {
+        log_context.record("raw_path", raw_path);
+    }
+    if let Err(e) = canonical {
+        let _span = log_context.span().enter();
+        warn!(
+            "{} {} - {} ({}){}",
+            req.method(),
+            req.uri().path(),
+            e.status_code().as_u16(),
+            e,
+            log_context
+        );
+        return Ok(shared.finish(error_response(e)));
+    }
+
+    if let Some(guard) = &shared.request_guard {
+        if let Err(e) = guard(&req) {
+            let _span = log_context.span().enter();
+            warn!(
+                "{} {} - {} rejected by request guard ({}){}",
+                req.method(),
+                req.uri().path(),
+                e.status_code().as_u16(),
+                e,
+                log_context
+            );
+            return Ok(shared.finish(error_response(e)));
+        }
+    }
+
+    let span = log_context.span().clone();
+    let response = dispatch(router, &shared, req, &log_context, &timing)
+        .instrument(span)
+        .await;
+    Ok(shared.finish(response))
+}
+
+async fn dispatch(
+    router: Arc<Router>,
+    shared: &Shared,
+    mut req: Request<Body>,
+    log_context: &LogContext,
+    timing: &ServerTiming,
+) -> hyper::Response<Body> {
+    let config = &shared.config;
     let method = req.method().clone();
-    let path = req.uri().path().to_string();
+    // Cloning the URI only bumps a refcount; the path stays borrowed from it
+    let uri = req.uri().clone();
+    let path = uri.path();
 
-    match router.handle(req).await {
-        Ok(response) => match response.into_hyper_response() {
-            Ok(hyper_response) => {
-                info!("{} {} - 200", method, path);
-                Ok(hyper_response)
-            }
+    // Held until the response is ready; streamed bodies don't keep a slot
+    let _permit = match &shared.queue {
+        Some(queue) => match timing.wait_for("queue", queue.acquire()).await {
+            Ok(permit) => Some(permit),
             Err(e) => {
-                error!("Response conversion error: {}", e);
-                Ok(error_response(e))
+                warn!(
+                    "{} - {} ({}){}",
+                    method,
+                    e.status_code().as_u16(),
+                    e,
+                    log_context
+                );
+                return error_response(e);
             }
         },
+        None => None,
+    };
+
+    let conditions = if config.conditional_get {
+        capture_conditions(&method, req.headers())
+    } else {
+        None
+    };
+
+    let accept_encoding = shared
+        .compression
+        .as_ref()
+        .map(|_| capture_accept_encoding(req.headers()));
+
+    if config.request_parts {
+        let parts = RequestParts::from_request(&req);
+        req.extensions_mut().insert(parts);
+    }
+    let early_hints = config.early_hints.then(EarlyHints::new);
+    if let Some(hints) = &early_hints {
+        req.extensions_mut().insert(hints.clone());
+    }
+
+    let body_limit = BodyLimit::new(config.max_body_size);
+    let req = limit_body(req, &body_limit);
+    let (req, mut audit) = match &shared.audit {
+        Some(auditor) => auditor.begin(req),
+        None => (req, None),
+    };
+    let (mut req, trap) = match &shared.traps {
+        Some(traps) => traps.begin(req),
+        None => (req, None),
+    };
+    req.extensions_mut().insert(body_limit.clone());
+    let matched_route = MatchedRoute::default();
+    req.extensions_mut().insert(matched_route.clone());
+    let cancellation = shared.shutdown.child_token();
+    req.extensions_mut().insert(cancellation.clone());
+    if let Some(timeout) = config.request_timeout {
+        req.extensions_mut().insert(deadline::ServerTimeout(timeout));
+    }
+
+    #[cfg(feature = "chaos")]
+    let fault = shared
+        .chaos
+        .as_ref()
+        .and_then(|chaos| chaos.select(&method, path));
+    #[cfg(feature = "chaos")]
+    if let Some(Fault::Latency(delay)) = &fault {
+        tokio::time::sleep(*delay).await;
+    }
+
+    #[cfg(feature = "chaos")]
+    let handler = async {
+        match &fault {
+            Some(Fault::Status(status)) => Ok(chaos::status_response(*status)),
+            _ => run_handler(&router, req, &method, path).await,
+        }
+    };
+    #[cfg(not(feature = "chaos"))]
+    let handler = run_handler(&router, req, &method, path);
+    // Cancels the token if this future is dropped mid-handler, which is
+    // what happens when the client goes away
+    let cancel_on_drop = cancellation.clone().drop_guard();
+    let handled = handler.await;
+    cancel_on_drop.disarm();
+    let handler_done = Instant::now();
+    record_timings(&shared.stats, timing, handler_done, log_context);
+    let handled = match handled {
+        Ok(response) => check_buffered_body(config, &shared.stats, matched_route.get(), &method, path, response),
+        Err(e) => Err(e),
+    };
+    let handled = match handled {
+        Ok(response) => digest::attach(response).await,
+        Err(e) => Err(e),
+    };
+
+    let result = match handled {
+        Ok(response) => {
+            let route = matched_route.get();
+            let response = match route.and_then(|route| route.cache_ttl) {
+                Some(ttl) => apply_cache_ttl(ttl, response),
+                None => response,
+            };
+            let response = match &conditions {
+                Some(conditions) => apply_conditional_get(&method, conditions, response),
+                None => response,
+            };
+            // Audit the uncompressed body
+            let body_len = response.body.size_hint().exact();
+            let response = match audit.take() {
+                Some(pending) => audit_response(pending, response),
+                None => response,
+            };
+            let response = match (&shared.compression, &accept_encoding) {
+                (Some(compression), Some(accept)) => compression.apply(
+                    &method,
+                    accept,
+                    route.and_then(|route| route.compress),
+                    body_len,
+                    &shared.stats,
+                    response,
+                ),
+                _ => response,
+            };
+            #[cfg(feature = "chaos")]
+            let response = match &fault {
+                Some(fault) => chaos::apply(fault, response),
+                None => response,
+            };
+            Ok(response)
+        }
+        // The handler failed because its body read was cut off
+        Err(_) if body_limit.exceeded() => Err(ServerError::PayloadTooLarge {
+            limit: body_limit.get(),
+        }),
+        Err(e) => Err(e),
+    };
+
+    let handled_ok = result.is_ok();
+    // Successful responses are logged once their body is done (see below)
+    let mut completion = None;
+    let mut response = match result {
+        Ok(mut response) => {
+            if let Some(charset) = &config.default_charset {
+                response.apply_default_charset(charset);
+            }
+            if let Some(hints) = &early_hints {
+                for link in hints.take() {
+                    response = response.append_header("Link", link);
+                }
+            }
+            let callbacks = std::mem::take(&mut response.on_complete);
+            match response.into_hyper_response(config.strict_headers) {
+                Ok(hyper_response) => {
+                    completion = Some(Completion {
+                        callbacks,
+                        started: timing.started(),
+                        access_log: AccessLog {
+                            line: format!(
+                                "{} {} - {}",
+                                method,
+                                path,
+                                hyper_response.status().as_u16()
+                            ),
+                            log_context: log_context.clone(),
+                        },
+                    });
+                    hyper_response
+                }
+                Err(e) => {
+                    error!("Response conversion error: {}", e);
+                    error_response(e)
+                }
+            }
+        }
         Err(e) => {
             let status_code = e.status_code();
             if status_code == hyper::StatusCode::NOT_FOUND {
-                warn!("{} {} - 404", method, path);
+                warn!("{} {} - 404{}", method, path, log_context);
             } else {
-                error!("{} {} - {} ({})", method, path, status_code.as_u16(), e);
+                error!(
+                    "{} {} - {} ({}){}",
+                    method,
+                    path,
+                    status_code.as_u16(),
+                    e,
+                    log_context
+                );
+            }
+            error_response(e)
+        }
+    };
+    if elide_body(&method, &mut response) && handled_ok {
+        debug!(
+            "{} {} - body supplied for a {} response was not sent{}",
+            method,
+            path,
+            response.status().as_u16(),
+            log_context
+        );
+    }
+
+    if let Some(sunset) = matched_route.get().and_then(|route| route.sunset.as_ref()) {
+        sunset.apply_headers(response.headers_mut());
+    }
+    if config.route_header {
+        stamp_route(matched_route.get(), response.headers_mut());
+    }
+    if config.server_timing {
+        if let Ok(value) = HeaderValue::from_str(&timing.header_value(handler_done)) {
+            response
+                .headers_mut()
+                .append(HeaderName::from_static("server-timing"), value);
+        }
+    }
+    // Error responses built above
+    if let Some(pending) = audit {
+        let status = response.status();
+        if let Some(len) = exact_length(status, response.body()) {
+            response
+                .headers_mut()
+                .entry(hyper::header::CONTENT_LENGTH)
+                .or_insert_with(|| HeaderValue::from(len));
+        }
+        let content_type = response
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let content_length = response
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok());
+        let body = std::mem::replace(response.body_mut(), Body::empty());
+        *response.body_mut() =
+            pending.finish(status, content_type.as_deref(), content_length, body);
+    }
+    if let Some(pending) = trap {
+        pending.finish(matched_route.get(), timing, handler_done, &mut response);
+    }
+    // The rest of the response timeout covers writing the body
+    if let Some(timeout) = matched_route.get().and_then(|route| route.response_timeout) {
+        if !response.body().is_end_stream() {
+            if let Some(len) = exact_length(response.status(), response.body()) {
+                response
+                    .headers_mut()
+                    .entry(hyper::header::CONTENT_LENGTH)
+                    .or_insert_with(|| HeaderValue::from(len));
+            }
+            let body = std::mem::replace(response.body_mut(), Body::empty());
+            *response.body_mut() = deadline::limit_body(
+                body,
+                timing.started() + timeout,
+                timeout,
+                cancellation,
+                format!("{} {}", method, path),
+            );
+        }
+    }
+    // Outermost, so it counts what actually reached the connection
+    if let Some(completion) = completion {
+        completion.track(&mut response);
+    }
+    response
+}
+
+// Access-log line of a successful response, written when its body is done
+// so it can carry the bytes sent
+pub(crate) struct AccessLog {
+    line: String,
+    log_context: LogContext,
+}
+
+impl AccessLog {
+    pub(crate) fn write(self, bytes_sent: u64, completed: bool) {
+        self.log_context.record("bytes_sent", bytes_sent);
+        if !completed {
+            self.log_context.record("aborted", true);
+        }
+        let _span = self.log_context.span().enter();
+        info!("{}{}", self.line, self.log_context);
+    }
+}
+
+fn check_uri_length(config: &ServerConfig, uri: &hyper::Uri) -> Result<()> {
+    let length = uri.path_and_query().map_or(0, |pq| pq.as_str().len());
+    if length > config.max_uri_length {
+        return Err(ServerError::UriTooLong {
+            length,
+            limit: config.max_uri_length,
+        });
+    }
+    Ok(())
+}
+
+// The path everything after the server sees, or None if it's the one sent
+fn canonical_path(config: &ServerConfig, path: &str) -> Result<Option<String>> {
+    let resolved = match config.dot_segments {
+        DotSegments::Reject if normalize::has_dot_segments(path) => {
+            return Err(ServerError::BadRequest(
+                "path contains `.` or `..` segments".to_string(),
+            ));
+        }
+        DotSegments::Reject => None,
+        DotSegments::Resolve => normalize::remove_dot_segments(path),
+    };
+    if config.merge_slashes {
+        let current = resolved.as_deref().unwrap_or(path);
+        if current.contains("//") {
+            return Ok(Some(normalize::merge_slashes(current)));
+        }
+    }
+    Ok(resolved)
+}
+
+// Only bodies whose length is known, which are the ones held in memory
+fn check_buffered_body(
+    config: &ServerConfig,
+    stats: &Stats,
+    route: Option<&RouteInfo>,
+    method: &hyper::Method,
+    path: &str,
+    response: Response,
+) -> Result<Response> {
+    let Some(len) = response.body.size_hint().exact() else {
+        return Ok(response);
+    };
+    let limit = config.buffered_body_limit;
+    if len <= limit as u64 {
+        return Ok(response);
+    }
+    stats.oversized_response_body();
+    let route = match route {
+        Some(route) => format!("{} {}", route.method, route.path),
+        None => format!("{} {}", method, path),
+    };
+    warn!(
+        "{} returned a {}-byte body held in memory, over the {}-byte buffered body limit; \
+         stream it with Response::channel or Response::with_body_from_reader",
+        route, len, limit
+    );
+    if config.strict_buffered_body_limit {
+        return Err(ServerError::Internal(format!(
+            "response body of {} bytes exceeds the buffered body limit of {}; use a streaming body",
+            len, limit
+        )));
+    }
+    Ok(response)
+}
+
+// Queue vs handler time, for the access log, the request span and `Stats`
+fn record_timings(stats: &Stats, timing: &ServerTiming, handler_done: Instant, log_context: &LogContext) {
+    let millis = |duration: Duration| format!("{:.3}", duration.as_secs_f64() * 1000.0);
+    let queue = timing.queue_time(handler_done);
+    let handler = timing.handler_time(handler_done);
+
+    log_context.record("queue_ms", millis(queue));
+    if let Some(handler) = handler {
+        log_context.record("handler_ms", millis(handler));
+    }
+    for (layer, waited) in timing.waits() {
+        log_context.record(&format!("wait_{}_ms", layer), millis(waited));
+    }
+    stats.record_timings(queue, handler);
+}
+
+// Runs the router, turning a handler panic into a 500
+async fn run_handler(
+    router: &Router,
+    req: Request<Body>,
+    method: &hyper::Method,
+    path: &str,
+) -> Result<Response> {
+    match catch_panic(router.handle(req)).await {
+        Ok(handled) => handled,
+        Err(report) => {
+            match report.backtrace() {
+                Some(backtrace) => error!(
+                    "{} {} - handler panicked: {}\n{}",
+                    method, path, report, backtrace
+                ),
+                None => error!("{} {} - handler panicked: {}", method, path, report),
             }
-            Ok(error_response(e))
+            Err(ServerError::Internal(
+                "request handler panicked".to_string(),
+            ))
+        }
+    }
+}
+
+// Wrapping the body hides its length, so it's declared up front
+fn audit_response(pending: PendingAudit, mut response: Response) -> Response {
+    if let Some(len) = exact_length(response.status, &response.body) {
+        if !response.has_header("Content-Length") {
+            response = response.header("Content-Length", len.to_string());
         }
     }
+    let content_length = response
+        .header_value("Content-Length")
+        .and_then(|v| v.parse().ok());
+    let body = std::mem::replace(&mut response.body, Body::empty());
+    response.body = pending.finish(
+        response.status,
+        response.header_value("Content-Type"),
+        content_length,
+        body,
+    );
+    response
+}
+
+fn apply_cache_ttl(ttl: Duration, response: Response) -> Response {
+    if !response.status_code().is_success() || response.has_header("Cache-Control") {
+        return response;
+    }
+    response.header("Cache-Control", format!("max-age={}", ttl.as_secs()))
 }
 
-fn error_response(error: ServerError) -> hyper::Response<Body> {
-    let status = error.status_code();
-    let body = Body::from(format!("{{\"error\": \"{}\"}}", error));
+// Rewrite rules are listed with their current hit counts
+fn debug_config_handler(
+    req: Request<Body>,
+    guard: &EndpointGuard,
+    dump: &serde_json::Value,
+    rewrites: Option<&RewriteTable>,
+) -> Result<Response> {
+    if let Some(refusal) = guard.refuse(&req) {
+        return refusal;
+    }
 
-    hyper::Response::builder()
-        .status(status)
+    let mut dump = dump.clone();
+    if let Some(rewrites) = rewrites {
+        dump["rewrites"] = serde_json::to_value(rewrites.info())?;
+    }
+    Ok(Response::new()
         .header("Content-Type", "application/json")
-        .body(body)
-        .unwrap_or_else(|_| {
-            hyper::Response::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from("Internal Server Error"))
-                .unwrap()
-        })
-} 
\ No newline at end of file
+        .body(dump.to_string()))
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    #[serde(serialize_with = "serialize_display")]
+    error: &'a ServerError,
+}
+
+#[derive(Serialize)]
+struct ValidationBody<'a> {
+    errors: &'a ValidationErrors,
+}
+
+// Escapes the Display output as it is written, with no intermediate String
+fn serialize_display<S: serde::Serializer>(
+    error: &&ServerError,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(error)
+}
+
+fn stamp_route(route: Option<&RouteInfo>, headers: &mut HeaderMap) {
+    // From the public constants, parsed (and lowercased) once
+    static NAMES: std::sync::OnceLock<(HeaderName, HeaderName)> = std::sync::OnceLock::new();
+    let (route_header, name_header) = NAMES.get_or_init(|| {
+        let parse = |name: &str| HeaderName::from_bytes(name.as_bytes()).expect("valid header name");
+        (parse(ROUTE_HEADER), parse(ROUTE_NAME_HEADER))
+    });
+
+    let Some(route) = route else {
+        headers.insert(route_header.clone(), HeaderValue::from_static("unmatched"));
+        return;
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{} {}", route.method, route.path)) {
+        headers.insert(route_header.clone(), value);
+    }
+    if let Some(value) = route.name.as_deref().and_then(|name| HeaderValue::from_str(name).ok()) {
+        headers.insert(name_header.clone(), value);
+    }
+}
+
+// Built without a fallible builder, so it can't fail itself. The body is the
+// one allocation; a short path fits without the buffer having to grow.
+pub(crate) fn error_response(error: ServerError) -> hyper::Response<Body> {
+    let mut body = Vec::with_capacity(128);
+    // Writing to a Vec can't fail and a Display impl has nothing to reject
+    let _ = match &error {
+        ServerError::Validation(errors) => serde_json::to_writer(&mut body, &ValidationBody { errors }),
+        _ => serde_json::to_writer(&mut body, &ErrorBody { error: &error }),
+    };
+    let mut response = hyper::Response::new(Body::from(body));
+    *response.status_mut() = error.status_code();
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    if let ServerError::TooManyRequests { reset, limit } = error {
+        quota::rejection_headers(response.headers_mut(), reset, limit);
+    }
+    response
+}
\ No newline at end of file
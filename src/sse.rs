@@ -0,0 +1,192 @@
+use crate::Response;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hyper::{Body, Request};
+use std::fmt::Write;
+use std::pin::Pin;
+use std::time::Duration;
+
+// Idle time after which a keep-alive comment is sent
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+// Server-Sent Events (`text/event-stream`) responses.
+//
+// Reconnection: when the connection drops, the browser's `EventSource`
+// waits for the retry delay (its own default, or the last `retry` the
+// server sent) and reconnects with `Last-Event-ID` set to the last `id` it
+// received. `Sse::resume` hands that id to the stream factory, so give every
+// event an id you can resume from and the client picks up where it left off
+// instead of replaying everything. Events without an id leave the client's
+// last id unchanged.
+//
+// Keep-alive: while no event is sent for `keep_alive`, a comment line is
+// written. Comments are ignored by the client (they don't touch the last
+// event id), but keep proxies and load balancers from closing the idle
+// connection, and make a vanished client show up as a failed write so the
+// stream is dropped. A connection closed anyway is simply retried by the
+// client after the retry delay, so keep `keep_alive` below the shortest idle
+// timeout on the path and `retry` long enough not to hammer the server when
+// it restarts.
+//
+// SSE responses are never compressed.
+#[derive(Debug, Clone)]
+pub struct Sse {
+    keep_alive: Option<Duration>,
+    retry: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Sse {
+    pub fn new() -> Self {
+        Self {
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+            retry: None,
+        }
+    }
+
+    // `None` disables keep-alive comments
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    // Reconnection delay sent to the client before the first event
+    pub fn retry(mut self, delay: Duration) -> Self {
+        self.retry = Some(delay);
+        self
+    }
+
+    pub fn response<S>(self, events: S) -> Response
+    where
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        let initial = self.retry.map(|delay| Event::default().retry(delay).encode());
+        let body = futures::stream::iter(initial).chain(keep_alive(events.boxed(), self.keep_alive));
+
+        Response::new()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            // Stops nginx from buffering the stream
+            .header("X-Accel-Buffering", "no")
+            .body(Body::wrap_stream(body.map(Ok::<_, std::convert::Infallible>)))
+    }
+
+    // Calls `factory` with the request's `Last-Event-ID` (None on the first
+    // connection) to build the event stream, e.g.
+    //   Sse::new().resume(&req, |last_id| events_after(last_id))
+    pub fn resume<F, S>(self, req: &Request<Body>, factory: F) -> Response
+    where
+        F: FnOnce(Option<String>) -> S,
+        S: Stream<Item = Event> + Send + 'static,
+    {
+        self.response(factory(last_event_id(req)))
+    }
+}
+
+impl Default for Sse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The `Last-Event-ID` a reconnecting client sent, if any
+pub fn last_event_id(req: &Request<Body>) -> Option<String> {
+    let value = req.headers().get(LAST_EVENT_ID_HEADER)?.to_str().ok()?;
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: Some(data.into()),
+            ..Self::default()
+        }
+    }
+
+    // Sent back as `Last-Event-ID` when the client reconnects
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    // The event type; the client's `message` handler only sees events
+    // without one
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn retry(mut self, delay: Duration) -> Self {
+        self.retry = Some(delay);
+        self
+    }
+
+    // A comment line, ignored by the client
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    // Multi-line data is sent as one `data:` field per line. Line breaks
+    // in the id, event and comment can't be represented and are removed; an
+    // id containing NUL would be ignored by the client, so it is dropped.
+    pub(crate) fn encode(&self) -> Bytes {
+        let mut out = String::new();
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                let _ = writeln!(out, ": {}", line);
+            }
+        }
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {}", single_line(event));
+        }
+        if let Some(id) = self.id.as_ref().filter(|id| !id.contains('\0')) {
+            let _ = writeln!(out, "id: {}", single_line(id));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry.as_millis());
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                let _ = writeln!(out, "data: {}", line);
+            }
+        }
+        out.push('\n');
+        Bytes::from(out)
+    }
+}
+
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\r\n").flat_map(|line| line.split(['\r', '\n']))
+}
+
+fn single_line(text: &str) -> String {
+    text.chars().filter(|c| *c != '\r' && *c != '\n').collect()
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Event> + Send>>;
+
+// Encodes `events`, inserting a keep-alive comment whenever nothing was sent
+// for `interval`. Ends with the event stream.
+fn keep_alive(events: EventStream, interval: Option<Duration>) -> impl Stream<Item = Bytes> {
+    futures::stream::unfold(events, move |mut events| async move {
+        let next = match interval {
+            Some(interval) => match tokio::time::timeout(interval, events.next()).await {
+                Ok(next) => next.map(|event| event.encode()),
+                Err(_) => Some(Bytes::from_static(b":\n\n")),
+            },
+            None => events.next().await.map(|event| event.encode()),
+        };
+        next.map(|chunk| (chunk, events))
+    })
+}
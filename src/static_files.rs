@@ -0,0 +1,106 @@
+use crate::fs_util;
+use crate::handler::{Handler, RequestContext, RequestExt};
+use crate::{Method, Response, Result, ServerError};
+use hyper::{Body, Request};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+pub const DEFAULT_INDEX_FILE: &str = "index.html";
+
+// Serves files below `root`, addressed by the `path` route parameter, usually
+// the catch-all of `Router::mount_static`: `/assets/*path`.
+//
+// Responses go through `Response::file`, so Range (including multi-range),
+// If-Range and conditional requests work, every file gets an ETag and
+// Last-Modified and Content-Type is guessed from the extension.
+// A directory serves its index file if it has one.
+//
+// Requests are confined to `root` with the checks of `fs_util::safe_join`,
+// plus dotfiles such as `.git`; anything refused is a 404, as are symlinks
+// that resolve outside `root`.
+#[derive(Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: Some(DEFAULT_INDEX_FILE.to_string()),
+        }
+    }
+
+    // File served for a directory; `None` makes directories 404
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(str::to_string);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // The file for decoded request path segments below the root, if it's
+    // allowed: `fs_util::join_segments` rules, and no dotfiles either
+    fn candidate<'a>(&self, segments: impl Iterator<Item = &'a str>) -> Option<PathBuf> {
+        let segments: Vec<&str> = segments.collect();
+        if segments.iter().any(|segment| segment.starts_with('.')) {
+            return None;
+        }
+        fs_util::join_segments(&self.root, segments.into_iter()).ok()
+    }
+
+    async fn resolve(&self, candidate: Option<PathBuf>) -> std::io::Result<Option<PathBuf>> {
+        let Some(mut path) = candidate else {
+            return Ok(None);
+        };
+
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if metadata.is_dir() {
+            let Some(index) = &self.index else {
+                return Ok(None);
+            };
+            path.push(index);
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => {}
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Symlinks may point anywhere; only serve what stays under the root
+        fs_util::resolve_within(&self.root, &path).await
+    }
+
+    async fn serve(&self, req: Request<Body>) -> Result<Response> {
+        // Mounted on a catch-all, segments are taken as the client sent
+        // them; a plain `:path` parameter is split on '/'
+        let candidate = match req.context().and_then(RequestContext::catch_all) {
+            Some(catch_all) => self.candidate(catch_all.segments()),
+            None => self.candidate(req.param("path").unwrap_or("").split('/')),
+        };
+        let Some(path) = self.resolve(candidate).await? else {
+            return Err(ServerError::RouteNotFound {
+                method: Method::from(req.method()),
+                path: req.uri().path().into(),
+            });
+        };
+
+        Response::file(&path, req.headers()).await
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, req: Request<Body>) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        let files = self.clone();
+        Box::pin(async move { files.serve(req).await })
+    }
+}
@@ -0,0 +1,316 @@
+use crate::build_info::BuildInfo;
+use crate::cache::{CacheCounters, CacheStats};
+use hyper::Version;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+// Upper bounds of the latency histogram buckets, in milliseconds. A final
+// bucket counts everything slower.
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+// Server-wide counters. The server inserts an `Arc<Stats>` into every
+// request's extensions so handlers can read them (see `RequestExt::stats`).
+#[derive(Debug, Default)]
+pub struct Stats {
+    active_connections: AtomicUsize,
+    total_connections: AtomicU64,
+    total_requests: AtomicU64,
+    reused_connections: AtomicU64,
+    reused_connection_requests: AtomicU64,
+    // HTTP/1.x, HTTP/2
+    requests_by_version: [AtomicU64; 2],
+    router_generation: AtomicU64,
+    build_info: OnceLock<BuildInfo>,
+    deprecated_routes: Mutex<BTreeMap<String, u64>>,
+    tags: Mutex<BTreeMap<String, TagStats>>,
+    encodings: Mutex<BTreeMap<String, u64>>,
+    caches: Mutex<BTreeMap<String, Arc<CacheCounters>>>,
+    audit_events_dropped: AtomicU64,
+    oversized_response_bodies: AtomicU64,
+    queue_depth: AtomicUsize,
+    queue_rejected: AtomicU64,
+    queue_wait: Histogram,
+    queue_time: Histogram,
+    handler_time: Histogram,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub active_connections: usize,
+    // Connections accepted (and not refused by the per-IP limit)
+    pub total_connections: u64,
+    pub total_requests: u64,
+    // Connections that carried more than one request, through keep-alive
+    // or HTTP/2 streams
+    pub reused_connections: u64,
+    // Requests after the first on their connection; with `total_requests`
+    // this gives the share that didn't need a new connection
+    pub reused_connection_requests: u64,
+    pub http1_requests: u64,
+    pub http2_requests: u64,
+    // Bumped by every `RouterHandle::swap`; 0 is the startup router
+    pub router_generation: u64,
+    pub build_info: Option<BuildInfo>,
+    // Calls per deprecated route, keyed `METHOD /pattern`
+    pub deprecated_routes: BTreeMap<String, u64>,
+    // Requests to tagged routes, per tag (see `Router::tag`)
+    pub tags: BTreeMap<String, TagStats>,
+    // Compressible responses per negotiated `Content-Encoding`, with
+    // `identity` for those sent as-is and `none` for 406s (see `Compression`)
+    pub encodings: BTreeMap<String, u64>,
+    // Internal lookup caches by name, e.g. `accept_encoding` for the
+    // encoding negotiated per `Accept-Encoding` value
+    pub caches: BTreeMap<String, CacheStats>,
+    // Audit events discarded because the sink fell behind
+    pub audit_events_dropped: u64,
+    // Handler responses over `Server::with_buffered_body_limit`
+    pub oversized_response_bodies: u64,
+    // Requests waiting in the `Server::request_queue` right now
+    pub queue_depth: usize,
+    // Requests answered 503 by the queue's overflow policy
+    pub queue_rejected: u64,
+    // Time spent in the request queue, for requests that had to wait
+    pub queue_wait: HistogramSnapshot,
+    // Request received -> handler called (see `ServerTiming`)
+    pub queue_time: HistogramSnapshot,
+    // Handler called -> response ready
+    pub handler_time: HistogramSnapshot,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TagStats {
+    pub requests: u64,
+    // Handler errors and 5xx responses
+    pub errors: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistogramSnapshot {
+    // `counts[i]` is the number of samples <= `bounds_ms[i]` and above the
+    // previous bound; the extra last count is for samples above every bound
+    pub bounds_ms: Vec<u64>,
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum_ms: f64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Connections currently open
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_requests(&self) -> u64 {
+        self.total_requests.load(Ordering::Relaxed)
+    }
+
+    pub fn router_generation(&self) -> u64 {
+        self.router_generation.load(Ordering::Relaxed)
+    }
+
+    pub fn audit_events_dropped(&self) -> u64 {
+        self.audit_events_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            active_connections: self.active_connections(),
+            total_connections: self.total_connections(),
+            total_requests: self.total_requests(),
+            reused_connections: self.reused_connections.load(Ordering::Relaxed),
+            reused_connection_requests: self.reused_connection_requests.load(Ordering::Relaxed),
+            http1_requests: self.requests_by_version[0].load(Ordering::Relaxed),
+            http2_requests: self.requests_by_version[1].load(Ordering::Relaxed),
+            router_generation: self.router_generation(),
+            build_info: self.build_info().cloned(),
+            deprecated_routes: self.deprecated_routes.lock().unwrap().clone(),
+            tags: self.tags.lock().unwrap().clone(),
+            encodings: self.encodings.lock().unwrap().clone(),
+            caches: self
+                .caches
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, counters)| (name.clone(), counters.snapshot()))
+                .collect(),
+            audit_events_dropped: self.audit_events_dropped(),
+            oversized_response_bodies: self.oversized_response_bodies.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth(),
+            queue_rejected: self.queue_rejected.load(Ordering::Relaxed),
+            queue_wait: self.queue_wait.snapshot(),
+            queue_time: self.queue_time.snapshot(),
+            handler_time: self.handler_time.snapshot(),
+        }
+    }
+
+    pub fn build_info(&self) -> Option<&BuildInfo> {
+        self.build_info.get()
+    }
+
+    // First registration wins
+    pub fn set_build_info(&self, info: BuildInfo) {
+        let _ = self.build_info.set(info);
+    }
+
+    pub(crate) fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            stats: self.clone(),
+            requests: 0,
+        }
+    }
+
+    pub(crate) fn set_router_generation(&self, generation: u64) {
+        self.router_generation.store(generation, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request_received(&self) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `handler` is `None` when the request never reached a handler
+    pub(crate) fn record_timings(&self, queue: Duration, handler: Option<Duration>) {
+        self.queue_time.record(queue);
+        if let Some(handler) = handler {
+            self.handler_time.record(handler);
+        }
+    }
+
+    pub(crate) fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub(crate) fn queue_rejected(&self) {
+        self.queue_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_queue_wait(&self, wait: Duration) {
+        self.queue_wait.record(wait);
+    }
+
+    pub(crate) fn audit_event_dropped(&self) {
+        self.audit_events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn oversized_response_body(&self) {
+        self.oversized_response_bodies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn response_encoded(&self, encoding: &str) {
+        let mut counters = self.encodings.lock().unwrap();
+        match counters.get_mut(encoding) {
+            Some(count) => *count += 1,
+            None => {
+                counters.insert(encoding.to_string(), 1);
+            }
+        }
+    }
+
+    // Replaces a cache registered earlier under the same name
+    pub(crate) fn register_cache(&self, name: &str, counters: Arc<CacheCounters>) {
+        self.caches.lock().unwrap().insert(name.to_string(), counters);
+    }
+
+    pub(crate) fn tagged_request(&self, tags: &[String], failed: bool) {
+        let mut counters = self.tags.lock().unwrap();
+        for tag in tags {
+            let counter = counters.entry(tag.clone()).or_default();
+            counter.requests += 1;
+            if failed {
+                counter.errors += 1;
+            }
+        }
+    }
+
+    pub(crate) fn deprecated_route_called(&self, method: &str, path: &str) {
+        let key = format!("{} {}", method, path);
+        *self
+            .deprecated_routes
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(0) += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| micros <= bound * 1000)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds_ms: LATENCY_BUCKETS_MS.to_vec(),
+            counts: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_ms: self.sum_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+// Decrements the active connection count when the connection closes
+pub(crate) struct ConnectionGuard {
+    stats: Arc<Stats>,
+    // Requests seen on this connection so far
+    requests: u64,
+}
+
+impl ConnectionGuard {
+    // Counts a request arriving on this connection. Runs for every request,
+    // so the counters are bumped by 0 or 1 rather than behind branches.
+    pub(crate) fn request_started(&mut self, version: Version) {
+        let earlier = self.requests;
+        self.requests += 1;
+        let stats = &self.stats;
+        stats
+            .reused_connections
+            .fetch_add((earlier == 1) as u64, Ordering::Relaxed);
+        stats
+            .reused_connection_requests
+            .fetch_add((earlier > 0) as u64, Ordering::Relaxed);
+        stats.requests_by_version[(version == Version::HTTP_2) as usize]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
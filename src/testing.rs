@@ -0,0 +1,324 @@
+// Assertion helpers for handler tests, enabled with the `testing` feature.
+// The assertions panic with a descriptive message and return `&Self` so they
+// can be chained; the body accessors consume the response.
+use crate::server::error_response;
+use crate::{Handler, RequestContext, Response};
+use bytes::Bytes;
+use hyper::{Body, Method, Request, StatusCode};
+use serde::Serialize;
+use std::future::{Future, IntoFuture};
+use std::panic::Location;
+use std::pin::Pin;
+
+impl Response {
+    #[track_caller]
+    pub fn assert_status(&self, expected: StatusCode) -> &Self {
+        assert_eq!(
+            self.status, expected,
+            "expected status {}, got {}",
+            expected, self.status
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: &str, expected: &str) -> &Self {
+        match self.header_value(name) {
+            Some(value) => assert_eq!(
+                value, expected,
+                "header `{}`: expected {:?}, got {:?}",
+                name, expected, value
+            ),
+            None => panic!("header `{}` missing, expected {:?}", name, expected),
+        }
+        self
+    }
+
+    pub async fn body_bytes(self) -> Bytes {
+        hyper::body::to_bytes(self.body)
+            .await
+            .expect("failed to read response body")
+    }
+
+    pub async fn body_string(self) -> String {
+        let bytes = self.body_bytes().await;
+        String::from_utf8(bytes.to_vec()).expect("response body is not valid UTF-8")
+    }
+
+    // Compares the body and `expected` as JSON values, so key order and
+    // whitespace don't matter
+    pub async fn assert_json_eq<T: Serialize>(self, expected: &T) {
+        let bytes = self.body_bytes().await;
+        let actual: serde_json::Value =
+            serde_json::from_slice(&bytes).expect("response body is not valid JSON");
+        let expected = serde_json::to_value(expected).expect("expected value is not serializable");
+        assert_eq!(
+            actual, expected,
+            "JSON body mismatch\n  actual: {}\nexpected: {}",
+            actual, expected
+        );
+    }
+}
+
+// Runs one handler directly, without a router or server, and checks the
+// response once awaited:
+//   assert_handler(get_users_handler)
+//       .with_query("page=2")
+//       .expect_status(200)
+//       .expect_json_path("$.success", true)
+//       .await;
+// The request is a GET to `/` unless set otherwise. A handler error is
+// turned into the JSON error response the server would send, so
+// `expect_status(404)` works for `RouteNotFound` too. Every expectation is
+// checked, and a failure panics listing all that failed along with the
+// response.
+#[track_caller]
+pub fn assert_handler<H: Handler>(handler: H) -> HandlerTest {
+    HandlerTest {
+        handler: Box::new(handler),
+        method: Method::GET,
+        path: "/".to_string(),
+        query: None,
+        headers: Vec::new(),
+        body: Bytes::new(),
+        params: Vec::new(),
+        expectations: Vec::new(),
+        caller: Location::caller(),
+    }
+}
+
+pub struct HandlerTest {
+    handler: Box<dyn Handler>,
+    method: Method,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    params: Vec<(String, String)>,
+    expectations: Vec<Expectation>,
+    caller: &'static Location<'static>,
+}
+
+enum Expectation {
+    Status(u16),
+    Header(String, String),
+    JsonPath(String, serde_json::Value),
+    BodyContains(String),
+}
+
+struct Received {
+    status: StatusCode,
+    headers: hyper::HeaderMap,
+    body: Bytes,
+}
+
+impl HandlerTest {
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    // Without the leading `?`
+    pub fn with_query(mut self, query: &str) -> Self {
+        self.query = Some(query.to_string());
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Also sets `Content-Type: application/json`
+    pub fn with_json<T: Serialize>(self, value: &T) -> Self {
+        let body = serde_json::to_vec(value).expect("request body is not serializable");
+        self.with_header("Content-Type", "application/json").with_body(body)
+    }
+
+    // A path parameter, as routing `/users/:id` would capture it
+    pub fn with_param(mut self, name: &str, value: &str) -> Self {
+        self.params.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn expect_status(mut self, status: u16) -> Self {
+        self.expectations.push(Expectation::Status(status));
+        self
+    }
+
+    pub fn expect_header(mut self, name: &str, value: &str) -> Self {
+        self.expectations
+            .push(Expectation::Header(name.to_string(), value.to_string()));
+        self
+    }
+
+    // A dot path into the JSON body: `$.data.items.0.name`, or
+    // `data.items[0].name`; the leading `$.` is optional
+    pub fn expect_json_path<T: Serialize>(mut self, path: &str, expected: T) -> Self {
+        let expected = serde_json::to_value(expected).expect("expected value is not serializable");
+        self.expectations
+            .push(Expectation::JsonPath(path.to_string(), expected));
+        self
+    }
+
+    pub fn expect_body_contains(mut self, needle: &str) -> Self {
+        self.expectations
+            .push(Expectation::BodyContains(needle.to_string()));
+        self
+    }
+
+    fn request(&self) -> Request<Body> {
+        let uri = match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        };
+        let mut builder = Request::builder().method(self.method.clone()).uri(uri);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let mut req = builder
+            .body(Body::from(self.body.clone()))
+            .expect("invalid test request");
+        let mut context = RequestContext::for_request(&req);
+        context.params = self.params.iter().cloned().collect();
+        req.extensions_mut().insert(context);
+        req
+    }
+
+    async fn run(self) {
+        let req = self.request();
+        let response = match self.handler.call(req).await {
+            Ok(response) => response
+                .into_hyper_response(false)
+                .unwrap_or_else(error_response),
+            Err(e) => error_response(e),
+        };
+        let (parts, body) = response.into_parts();
+        let received = Received {
+            status: parts.status,
+            headers: parts.headers,
+            body: hyper::body::to_bytes(body)
+                .await
+                .expect("failed to read response body"),
+        };
+
+        let failures: Vec<String> = self
+            .expectations
+            .iter()
+            .filter_map(|expectation| expectation.check(&received).err())
+            .collect();
+        if !failures.is_empty() {
+            panic!(
+                "{} of {} expectations failed for {} {} (test at {})\n{}\n\nresponse: {}\n{}",
+                failures.len(),
+                self.expectations.len(),
+                self.method,
+                self.path,
+                self.caller,
+                failures.join("\n"),
+                received.status,
+                received.describe_body()
+            );
+        }
+    }
+}
+
+impl IntoFuture for HandlerTest {
+    type Output = ();
+    type IntoFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.run())
+    }
+}
+
+impl Expectation {
+    fn check(&self, received: &Received) -> Result<(), String> {
+        match self {
+            Expectation::Status(expected) if received.status.as_u16() == *expected => Ok(()),
+            Expectation::Status(expected) => Err(format!(
+                "- status: expected {}, got {}",
+                expected, received.status
+            )),
+            Expectation::Header(name, expected) => match received.headers.get(name.as_str()) {
+                Some(value) if value == expected.as_str() => Ok(()),
+                Some(value) => Err(format!(
+                    "- header `{}`: expected {:?}, got {:?}",
+                    name, expected, value
+                )),
+                None => Err(format!("- header `{}`: missing, expected {:?}", name, expected)),
+            },
+            Expectation::JsonPath(path, expected) => {
+                let json: serde_json::Value = serde_json::from_slice(&received.body)
+                    .map_err(|e| format!("- json `{}`: body is not JSON ({})", path, e))?;
+                match json_path(&json, path) {
+                    Ok(actual) if actual == expected => Ok(()),
+                    Ok(actual) => Err(format!(
+                        "- json `{}`:\n    expected: {}\n      actual: {}",
+                        path, expected, actual
+                    )),
+                    Err(missing) => Err(format!("- json `{}`: {}", path, missing)),
+                }
+            }
+            Expectation::BodyContains(needle) => {
+                if String::from_utf8_lossy(&received.body).contains(needle.as_str()) {
+                    Ok(())
+                } else {
+                    Err(format!("- body: does not contain {:?}", needle))
+                }
+            }
+        }
+    }
+}
+
+impl Received {
+    fn describe_body(&self) -> String {
+        match serde_json::from_slice::<serde_json::Value>(&self.body) {
+            Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_default(),
+            Err(_) => String::from_utf8_lossy(&self.body).into_owned(),
+        }
+    }
+}
+
+// Follows `path` through objects (by key) and arrays (by index); on a miss,
+// says which step failed and what was there instead
+fn json_path<'a>(json: &'a serde_json::Value, path: &str) -> Result<&'a serde_json::Value, String> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let steps = path
+        .split(['.', '['])
+        .map(|step| step.trim_end_matches(']'))
+        .filter(|step| !step.is_empty());
+
+    let mut current = json;
+    let mut walked = String::from("$");
+    for step in steps {
+        let next = match current {
+            serde_json::Value::Object(map) => map.get(step).ok_or_else(|| {
+                let keys: Vec<&str> = map.keys().map(String::as_str).collect();
+                format!("no key {:?} in {} (keys: {})", step, walked, keys.join(", "))
+            })?,
+            serde_json::Value::Array(items) => step
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get(index))
+                .ok_or_else(|| {
+                    format!("no index {:?} in {} (length {})", step, walked, items.len())
+                })?,
+            other => return Err(format!("{} is {}, not an object or array", walked, other)),
+        };
+        walked.push('.');
+        walked.push_str(step);
+        current = next;
+    }
+    Ok(current)
+}
@@ -0,0 +1,189 @@
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Phase timings for one request. The server puts a `ServerTiming` in every
+// request's extensions (see `RequestExt::timing`), started when the request
+// was received. Handlers can add their own entries:
+//   let rows = timing.measure("db", query(...)).await;
+//
+// With `Server::with_server_timing(true)` the timings are sent back as a
+// `Server-Timing` header, which browser devtools show per request:
+//   routing   received -> handler called (limits, body setup, matching)
+//   handler   handler called -> handler returned a response
+//   serialize response post-processing and conversion
+//   total     received -> headers ready
+// plus every recorded entry. Streaming bodies are sent after the header, so
+// their transfer time isn't included anywhere.
+//
+// The same split is logged for every request as `queue_ms` (received ->
+// handler called) and `handler_ms` (handler called -> response ready), and
+// fed into the `StatsSnapshot` histograms. Layers that make a request wait,
+// such as limiters, wrap the wait in `wait_for` so it is attributed to them:
+// it shows up as `wait_<layer>_ms` in the log and `<layer>-wait` in the
+// header, and still counts towards the queue time.
+#[derive(Clone)]
+pub struct ServerTiming {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    started: Instant,
+    handler_started: OnceLock<Instant>,
+    entries: Mutex<Vec<TimingEntry>>,
+    waits: Mutex<Vec<(String, Duration)>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimingEntry {
+    pub name: String,
+    pub duration: Duration,
+    pub description: Option<String>,
+}
+
+impl ServerTiming {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                started: Instant::now(),
+                handler_started: OnceLock::new(),
+                entries: Mutex::new(Vec::new()),
+                waits: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    // When the server received the request
+    pub fn started(&self) -> Instant {
+        self.inner.started
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.inner.started.elapsed()
+    }
+
+    pub fn handler_started(&self) -> Option<Instant> {
+        self.inner.handler_started.get().copied()
+    }
+
+    pub fn record(&self, name: &str, duration: Duration) {
+        self.push(name, duration, None);
+    }
+
+    pub fn record_with_description(&self, name: &str, duration: Duration, description: &str) {
+        self.push(name, duration, Some(description.to_string()));
+    }
+
+    // Awaits `future` and records how long it took under `name`
+    pub async fn measure<F>(&self, name: &str, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let start = Instant::now();
+        let output = future.await;
+        self.record(name, start.elapsed());
+        output
+    }
+
+    // Awaits `future` as time spent waiting in `layer` (e.g. "concurrency");
+    // repeated waits in the same layer add up
+    pub async fn wait_for<F>(&self, layer: &str, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        let start = Instant::now();
+        let output = future.await;
+        self.record_wait(layer, start.elapsed());
+        output
+    }
+
+    pub fn record_wait(&self, layer: &str, duration: Duration) {
+        let mut waits = self.inner.waits.lock().unwrap();
+        match waits.iter_mut().find(|(name, _)| name == layer) {
+            Some((_, total)) => *total += duration,
+            None => waits.push((layer.to_string(), duration)),
+        }
+    }
+
+    // Total wait per layer, in the order the layers first waited
+    pub fn waits(&self) -> Vec<(String, Duration)> {
+        self.inner.waits.lock().unwrap().clone()
+    }
+
+    // Received -> handler called, or -> `handler_done` if no handler ran
+    pub(crate) fn queue_time(&self, handler_done: Instant) -> Duration {
+        self.handler_started()
+            .unwrap_or(handler_done)
+            .saturating_duration_since(self.inner.started)
+    }
+
+    pub(crate) fn handler_time(&self, handler_done: Instant) -> Option<Duration> {
+        self.handler_started()
+            .map(|handler_started| handler_done.saturating_duration_since(handler_started))
+    }
+
+    pub fn entries(&self) -> Vec<TimingEntry> {
+        self.inner.entries.lock().unwrap().clone()
+    }
+
+    fn push(&self, name: &str, duration: Duration, description: Option<String>) {
+        self.inner.entries.lock().unwrap().push(TimingEntry {
+            name: name.to_string(),
+            duration,
+            description,
+        });
+    }
+
+    // Set by the router right before the handler runs
+    pub(crate) fn mark_handler_started(&self) {
+        let _ = self.inner.handler_started.set(Instant::now());
+    }
+
+    // `handler_done` is when the handler (or the error path) produced a result
+    pub(crate) fn header_value(&self, handler_done: Instant) -> String {
+        let now = Instant::now();
+        let started = self.inner.started;
+        let mut value = String::new();
+        push_metric(&mut value, "routing", self.queue_time(handler_done), None);
+        if let Some(handler) = self.handler_time(handler_done) {
+            push_metric(&mut value, "handler", handler, None);
+        }
+        push_metric(&mut value, "serialize", now.saturating_duration_since(handler_done), None);
+        for (layer, duration) in self.inner.waits.lock().unwrap().iter() {
+            push_metric(&mut value, &format!("{}-wait", layer), *duration, None);
+        }
+        for entry in self.inner.entries.lock().unwrap().iter() {
+            push_metric(&mut value, &entry.name, entry.duration, entry.description.as_deref());
+        }
+        push_metric(&mut value, "total", now - started, None);
+        value
+    }
+}
+
+// `name;dur=1.234;desc="..."`, durations in milliseconds. Names are tokens,
+// so anything else is replaced with '_'.
+fn push_metric(value: &mut String, name: &str, duration: Duration, description: Option<&str>) {
+    if !value.is_empty() {
+        value.push_str(", ");
+    }
+    value.extend(name.chars().map(|c| {
+        if c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c) {
+            c
+        } else {
+            '_'
+        }
+    }));
+    let _ = write!(value, ";dur={:.3}", duration.as_secs_f64() * 1000.0);
+    if let Some(description) = description {
+        let escaped: String = description
+            .chars()
+            .filter(|c| !c.is_control())
+            .flat_map(|c| match c {
+                '"' | '\\' => vec!['\\', c],
+                _ => vec![c],
+            })
+            .collect();
+        let _ = write!(value, ";desc=\"{}\"", escaped);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::layer::{Layer, Next};
+use crate::{Response, Result};
+use bytes::Bytes;
+use futures::Stream;
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_ENCODING;
+use hyper::{Body, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Rewrites a response body chunk by chunk (see `Response::map_body`).
+// `transform` sees the chunks as the handler produced them, so a pattern can
+// straddle two chunks: hold back a possible partial match and emit it with
+// the next chunk, or from `finish`, which runs once after the last one.
+// Returning an empty chunk is fine; nothing is sent for it.
+pub trait ChunkTransform: Send + 'static {
+    fn transform(&mut self, chunk: Bytes) -> Bytes;
+
+    fn finish(&mut self) -> Bytes {
+        Bytes::new()
+    }
+}
+
+impl<F> ChunkTransform for F
+where
+    F: FnMut(Bytes) -> Bytes + Send + 'static,
+{
+    fn transform(&mut self, chunk: Bytes) -> Bytes {
+        self(chunk)
+    }
+}
+
+// Body rewriting as a `Layer`, e.g. injecting a script tag into HTML pages
+// with a `ChunkTransform` of your own (`InjectScript`):
+//   router.layer_for_tag("pages", BodyTransform::new(|response: &Response| {
+//       let html = response
+//           .header_value("Content-Type")
+//           .is_some_and(|ct| ct.starts_with("text/html"));
+//       html.then(|| InjectScript::before("</body>", SCRIPT))
+//   }))
+//
+// `select` looks at the handler's response (status and headers) and returns
+// the transform to run over its body, or None to pass it through. Responses
+// that already have a `Content-Encoding` are never offered, since their
+// bytes aren't the content itself.
+//
+// Streaming versus buffering: a `ChunkTransform` runs as the body is sent,
+// so memory stays at one chunk, streamed responses keep streaming and the
+// first bytes go out as soon as the handler produces them. The price is that
+// the new length isn't known up front: the response loses its
+// Content-Length and goes out chunked (HTTP/1.1) or without a length
+// (HTTP/2), and the transform has to handle matches across chunk
+// boundaries. `Response::map_body_buffered` reads the whole body first, so
+// it can rewrite it in one piece and send an exact Content-Length, at the
+// cost of holding the body in memory and delaying the response until the
+// handler has produced all of it; keep it to small, fixed bodies.
+//
+// Either way the body changes after the handler set its validators: an
+// ETag computed from the original bytes should be weak (`EntityTag::weak`)
+// or dropped, and a transform that makes the output depend on the request
+// needs the matching `Vary`.
+pub struct BodyTransform {
+    select: SelectFn,
+}
+
+type SelectFn = Arc<dyn Fn(&Response) -> Option<Box<dyn ChunkTransform>> + Send + Sync>;
+
+impl BodyTransform {
+    pub fn new<F, T>(select: F) -> Self
+    where
+        F: Fn(&Response) -> Option<T> + Send + Sync + 'static,
+        T: ChunkTransform,
+    {
+        Self {
+            select: Arc::new(move |response| {
+                select(response).map(|transform| Box::new(transform) as Box<dyn ChunkTransform>)
+            }),
+        }
+    }
+
+    async fn handle(select: SelectFn, req: Request<Body>, next: Next) -> Result<Response> {
+        let response = next.run(req).await?;
+        if response.has_header(CONTENT_ENCODING.as_str()) {
+            return Ok(response);
+        }
+        Ok(match select(&response) {
+            Some(transform) => response.map_boxed_body(transform),
+            None => response,
+        })
+    }
+}
+
+impl Layer for BodyTransform {
+    fn call(&self, req: Request<Body>, next: Next) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
+        Box::pin(Self::handle(self.select.clone(), req, next))
+    }
+}
+
+pub(crate) struct TransformStream {
+    pub(crate) body: Body,
+    pub(crate) transform: Box<dyn ChunkTransform>,
+    pub(crate) finished: bool,
+}
+
+impl Stream for TransformStream {
+    type Item = std::result::Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.finished {
+                return Poll::Ready(None);
+            }
+            match Pin::new(&mut self.body).poll_data(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let chunk = self.transform.transform(chunk);
+                    if !chunk.is_empty() {
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    self.finished = true;
+                    let tail = self.transform.finish();
+                    if !tail.is_empty() {
+                        return Poll::Ready(Some(Ok(tail)));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
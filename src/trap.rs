@@ -0,0 +1,586 @@
+use crate::audit::{exact_length, redact_body, AuditBody, Capture};
+use crate::dump::is_masked;
+use crate::guard::EndpointGuard;
+use crate::query::{from_query, QueryConfig};
+use crate::router::RouteInfo;
+use crate::timing::ServerTiming;
+use crate::{RequestExt, Response, Result, ServerError};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, HeaderMap, Method, Request};
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Traps held at once (armed or holding a capture) by default
+pub const DEFAULT_MAX_TRAPS: usize = 8;
+
+// Bytes of each body kept per capture by default
+pub const DEFAULT_TRAP_BODY_LIMIT: usize = 64 * 1024;
+
+// How long a trap stays armed when the endpoint isn't told otherwise
+pub const DEFAULT_TRAP_TTL: Duration = Duration::from_secs(300);
+
+// One-shot deep capture of a live request, for debugging in production
+// (`Server::with_request_traps`). A trap is armed with a filter; the next
+// request that passes it is recorded in full and the trap is spent:
+//   let traps = RequestTraps::new();
+//   let id = traps.arm(TrapFilter::any().method(Method::POST).path_prefix("/users"), ttl)?;
+//   ...
+//   let captured = traps.get(id);
+//
+// A capture holds the request line and headers, the request body as far
+// as the handler read it, the matched route, the request's timings (queue,
+// handler, each layer's waits and the entries handlers recorded), and the
+// response status, headers and body as sent. Bodies are kept up to
+// `max_body` bytes. Credentials headers are masked as in debug dumps, and
+// the JSON fields named in `redact_fields` are replaced the way `Audit`
+// redacts them.
+//
+// A trap that catches nothing is dropped once its ttl runs out; a capture
+// is kept for the ttl again from when it completed, or until removed. At
+// most `max_traps` are held, armed or captured, which bounds the memory
+// they use. While none is armed, requests only pay for one atomic load.
+//
+// `Server::with_trap_endpoint` serves them over HTTP; see `endpoint`.
+#[derive(Clone)]
+pub struct RequestTraps {
+    inner: Arc<Inner>,
+}
+
+// Which requests spring a trap; `any()` matches every request
+#[derive(Debug, Clone, Default)]
+pub struct TrapFilter {
+    method: Option<Method>,
+    path_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrapInfo {
+    pub id: u64,
+    pub method: Option<String>,
+    pub path_prefix: Option<String>,
+    // `armed`, `capturing` (a request is in flight) or `captured`
+    pub state: &'static str,
+    // Until the trap (or its capture) is dropped
+    pub expires_in_ms: u64,
+    pub capture: Option<CapturedRequest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: CapturedBody,
+    // `METHOD /pattern` of the route that handled it, if one matched
+    pub route: Option<String>,
+    pub route_name: Option<String>,
+    pub timings: CapturedTimings,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: CapturedBody,
+    // False when the client went away before the whole body was sent
+    pub response_complete: bool,
+    // Received -> response body done
+    pub duration_ms: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedBody {
+    pub content_type: Option<String>,
+    // At most `max_body` bytes, as UTF-8 with invalid sequences replaced
+    pub data: String,
+    pub total_bytes: u64,
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedTimings {
+    // Received -> handler called
+    pub queue_ms: f64,
+    // Handler called -> response ready
+    pub handler_ms: Option<f64>,
+    // Time each layer made the request wait (see `ServerTiming::wait_for`)
+    pub waits_ms: Vec<(String, f64)>,
+    // Entries recorded with `ServerTiming::record` / `measure`
+    pub entries: Vec<CapturedTiming>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedTiming {
+    pub name: String,
+    pub ms: f64,
+    pub description: Option<String>,
+}
+
+struct Inner {
+    traps: Mutex<Vec<Trap>>,
+    // Traps still waiting for a request; checked before taking the lock
+    armed: AtomicUsize,
+    next_id: AtomicU64,
+    max_traps: usize,
+    max_body: usize,
+    redact_fields: Vec<String>,
+}
+
+struct Trap {
+    id: u64,
+    filter: TrapFilter,
+    ttl: Duration,
+    expires: Instant,
+    state: State,
+}
+
+enum State {
+    Armed,
+    Capturing,
+    Captured(Box<CapturedRequest>),
+}
+
+// A sprung trap whose response hasn't been sent yet
+pub(crate) struct PendingTrap {
+    id: u64,
+    inner: Arc<Inner>,
+    method: String,
+    uri: String,
+    version: String,
+    request_headers: Vec<(String, String)>,
+    request_content_type: Option<String>,
+    request_capture: Arc<Mutex<Capture>>,
+}
+
+impl TrapFilter {
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    // Matched against the path as sent, before normalization or rewrites
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path_prefix = Some(prefix.into());
+        self
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        self.method.as_ref().is_none_or(|m| m == method)
+            && self
+                .path_prefix
+                .as_deref()
+                .is_none_or(|prefix| path.starts_with(prefix))
+    }
+}
+
+impl RequestTraps {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                traps: Mutex::new(Vec::new()),
+                armed: AtomicUsize::new(0),
+                next_id: AtomicU64::new(1),
+                max_traps: DEFAULT_MAX_TRAPS,
+                max_body: DEFAULT_TRAP_BODY_LIMIT,
+                redact_fields: Vec::new(),
+            }),
+        }
+    }
+
+    // Settings apply to traps armed afterwards, and must be made before
+    // the value is cloned or handed to the server
+    pub fn max_traps(mut self, max: usize) -> Self {
+        self.settings().max_traps = max;
+        self
+    }
+
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.settings().max_body = bytes;
+        self
+    }
+
+    // JSON keys whose values are replaced with `[REDACTED]`, as in
+    // `Audit::redact_fields`
+    pub fn redact_fields(mut self, fields: &[&str]) -> Self {
+        self.settings().redact_fields = fields.iter().map(|f| f.to_ascii_lowercase()).collect();
+        self
+    }
+
+    fn settings(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("RequestTraps configured after being shared")
+    }
+
+    // Arms a trap for the next request passing `filter`. Fails with 503
+    // when `max_traps` are already held.
+    pub fn arm(&self, filter: TrapFilter, ttl: Duration) -> Result<u64> {
+        let mut traps = self.inner.lock();
+        if traps.len() >= self.inner.max_traps {
+            return Err(ServerError::ServiceUnavailable(format!(
+                "{} request traps are already held",
+                traps.len()
+            )));
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        traps.push(Trap {
+            id,
+            filter,
+            ttl,
+            expires: Instant::now() + ttl,
+            state: State::Armed,
+        });
+        self.inner.armed.fetch_add(1, Ordering::Relaxed);
+        Ok(id)
+    }
+
+    // Whether a trap was removed
+    pub fn remove(&self, id: u64) -> bool {
+        let mut traps = self.inner.lock();
+        let Some(index) = traps.iter().position(|trap| trap.id == id) else {
+            return false;
+        };
+        if matches!(traps.remove(index).state, State::Armed) {
+            self.inner.armed.fetch_sub(1, Ordering::Relaxed);
+        }
+        true
+    }
+
+    pub fn get(&self, id: u64) -> Option<TrapInfo> {
+        let traps = self.inner.lock();
+        traps.iter().find(|trap| trap.id == id).map(Trap::info)
+    }
+
+    pub fn list(&self) -> Vec<TrapInfo> {
+        self.inner.lock().iter().map(Trap::info).collect()
+    }
+
+    // Springs the first armed trap `req` passes, teeing its body
+    pub(crate) fn begin(&self, req: Request<Body>) -> (Request<Body>, Option<PendingTrap>) {
+        if self.inner.armed.load(Ordering::Relaxed) == 0 {
+            return (req, None);
+        }
+        let id = {
+            let mut traps = self.inner.lock();
+            let method = req.method();
+            let path = req.uri().path();
+            let trap = traps.iter_mut().find(|trap| {
+                matches!(trap.state, State::Armed) && trap.filter.matches(method, path)
+            });
+            match trap {
+                Some(trap) => {
+                    trap.state = State::Capturing;
+                    self.inner.armed.fetch_sub(1, Ordering::Relaxed);
+                    trap.id
+                }
+                None => return (req, None),
+            }
+        };
+
+        let (parts, body) = req.into_parts();
+        let request_capture = Arc::new(Mutex::new(Capture::default()));
+        let capture = request_capture.clone();
+        let max_body = self.inner.max_body;
+        let body = Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                capture.lock().unwrap().push(chunk, max_body);
+            }
+        }));
+        let pending = PendingTrap {
+            id,
+            inner: self.inner.clone(),
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            version: format!("{:?}", parts.version),
+            request_headers: masked_headers(&parts.headers),
+            request_content_type: content_type(&parts.headers),
+            request_capture,
+        };
+        (Request::from_parts(parts, body), Some(pending))
+    }
+}
+
+impl Default for RequestTraps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inner {
+    // Drops traps and captures past their expiry
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Trap>> {
+        let mut traps = self.traps.lock().unwrap();
+        let now = Instant::now();
+        traps.retain(|trap| {
+            let live = trap.expires > now;
+            if !live && matches!(trap.state, State::Armed) {
+                self.armed.fetch_sub(1, Ordering::Relaxed);
+            }
+            live
+        });
+        traps
+    }
+
+    fn body(&self, capture: Capture, content_type: Option<String>) -> CapturedBody {
+        let mut body = AuditBody {
+            content_type,
+            ..AuditBody::default()
+        };
+        capture.into_body(&mut body);
+        if !self.redact_fields.is_empty() {
+            redact_body(&mut body, &self.redact_fields);
+        }
+        CapturedBody {
+            content_type: body.content_type,
+            data: String::from_utf8_lossy(&body.data).into_owned(),
+            total_bytes: body.total_bytes,
+            truncated: body.truncated,
+        }
+    }
+}
+
+impl Trap {
+    fn info(&self) -> TrapInfo {
+        let (state, capture) = match &self.state {
+            State::Armed => ("armed", None),
+            State::Capturing => ("capturing", None),
+            State::Captured(capture) => ("captured", Some((**capture).clone())),
+        };
+        TrapInfo {
+            id: self.id,
+            method: self.filter.method.as_ref().map(Method::to_string),
+            path_prefix: self.filter.path_prefix.clone(),
+            state,
+            expires_in_ms: self
+                .expires
+                .saturating_duration_since(Instant::now())
+                .as_millis() as u64,
+            capture,
+        }
+    }
+}
+
+impl PendingTrap {
+    // Records the response as it leaves the server and wraps its body; the
+    // capture is stored once the body is done or dropped. `Content-Length`
+    // is declared first, since the wrapped body loses its size hint.
+    pub(crate) fn finish(
+        self,
+        route: Option<&RouteInfo>,
+        timing: &ServerTiming,
+        handler_done: Instant,
+        response: &mut hyper::Response<Body>,
+    ) {
+        if let Some(len) = exact_length(response.status(), response.body()) {
+            response
+                .headers_mut()
+                .entry(hyper::header::CONTENT_LENGTH)
+                .or_insert_with(|| len.into());
+        }
+        let content_length = response
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok());
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let timings = CapturedTimings {
+            queue_ms: millis(timing.queue_time(handler_done)),
+            handler_ms: timing.handler_time(handler_done).map(millis),
+            waits_ms: timing
+                .waits()
+                .into_iter()
+                .map(|(layer, waited)| (layer, millis(waited)))
+                .collect(),
+            entries: timing
+                .entries()
+                .into_iter()
+                .map(|entry| CapturedTiming {
+                    name: entry.name,
+                    ms: millis(entry.duration),
+                    description: entry.description,
+                })
+                .collect(),
+        };
+        let response_part = ResponsePart {
+            route: route.map(|route| format!("{} {}", route.method, route.path)),
+            route_name: route.and_then(|route| route.name.clone()),
+            timings,
+            status: response.status().as_u16(),
+            headers: masked_headers(response.headers()),
+            content_type: content_type(response.headers()),
+            started: timing.started(),
+        };
+        let body = std::mem::replace(response.body_mut(), Body::empty());
+        *response.body_mut() = Body::wrap_stream(TrapBody {
+            body,
+            capture: Capture::default(),
+            content_length,
+            pending: Some((self, response_part)),
+        });
+    }
+
+    fn store(self, response: ResponsePart, body: Capture, complete: bool) {
+        let inner = &self.inner;
+        let request_body = std::mem::take(&mut *self.request_capture.lock().unwrap());
+        let captured = CapturedRequest {
+            method: self.method,
+            uri: self.uri,
+            version: self.version,
+            request_headers: self.request_headers,
+            request_body: inner.body(request_body, self.request_content_type),
+            route: response.route,
+            route_name: response.route_name,
+            timings: response.timings,
+            status: response.status,
+            response_headers: response.headers,
+            response_body: inner.body(body, response.content_type),
+            response_complete: complete,
+            duration_ms: response.started.elapsed().as_secs_f64() * 1000.0,
+        };
+        // Gone if it expired or was removed meanwhile
+        let mut traps = inner.lock();
+        if let Some(trap) = traps.iter_mut().find(|trap| trap.id == self.id) {
+            trap.expires = Instant::now() + trap.ttl;
+            trap.state = State::Captured(Box::new(captured));
+        }
+    }
+}
+
+struct ResponsePart {
+    route: Option<String>,
+    route_name: Option<String>,
+    timings: CapturedTimings,
+    status: u16,
+    headers: Vec<(String, String)>,
+    content_type: Option<String>,
+    started: Instant,
+}
+
+struct TrapBody {
+    body: Body,
+    capture: Capture,
+    content_length: Option<u64>,
+    pending: Option<(PendingTrap, ResponsePart)>,
+}
+
+impl TrapBody {
+    fn store(&mut self, complete: bool) {
+        if let Some((pending, response)) = self.pending.take() {
+            pending.store(response, std::mem::take(&mut self.capture), complete);
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.body.is_end_stream() || self.content_length == Some(self.capture.total)
+    }
+}
+
+impl Stream for TrapBody {
+    type Item = std::result::Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let limit = self.pending.as_ref().map_or(0, |(p, _)| p.inner.max_body);
+                self.capture.push(chunk, limit);
+                if self.is_complete() {
+                    self.store(true);
+                }
+            }
+            Poll::Ready(None) => self.store(true),
+            Poll::Ready(Some(Err(_))) => self.store(false),
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl Drop for TrapBody {
+    fn drop(&mut self) {
+        let complete = self.is_complete();
+        self.store(complete);
+    }
+}
+
+fn masked_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if is_masked(name.as_str()) {
+                crate::dump::MASKED.to_string()
+            } else {
+                value.to_str().unwrap_or("<binary>").to_string()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+#[derive(Deserialize)]
+struct ArmParams {
+    method: Option<String>,
+    prefix: Option<String>,
+    // Seconds
+    ttl: Option<u64>,
+}
+
+// The admin endpoint at `path`, behind `guard`:
+//   GET    path                               every trap, with its capture
+//   POST   path?method=POST&prefix=/users&ttl=60   arm one; answers its id
+//   GET    path/:id                           one trap
+//   DELETE path/:id                           remove it
+pub(crate) async fn endpoint(req: Request<Body>, traps: RequestTraps, guard: Arc<EndpointGuard>) -> Result<Response> {
+    if let Some(refusal) = guard.refuse(&req) {
+        return refusal;
+    }
+    let id = match req.param("id") {
+        Some(id) => Some(
+            id.parse::<u64>()
+                .map_err(|_| ServerError::BadRequest(format!("invalid trap id `{}`", id)))?,
+        ),
+        None => None,
+    };
+    let not_found = || ServerError::RouteNotFound {
+        method: crate::Method::from(req.method()),
+        path: req.uri().path().into(),
+    };
+    match (req.method(), id) {
+        (&Method::GET, None) => Response::new().json(&traps.list()),
+        (&Method::GET, Some(id)) => Response::new().json(&traps.get(id).ok_or_else(not_found)?),
+        (&Method::DELETE, Some(id)) => match traps.remove(id) {
+            true => Ok(Response::new().status(hyper::StatusCode::NO_CONTENT)),
+            false => Err(not_found()),
+        },
+        (&Method::POST, None) => {
+            let params: ArmParams = from_query(req.uri().query().unwrap_or(""), &QueryConfig::flat())?;
+            let mut filter = TrapFilter::any();
+            if let Some(method) = params.method {
+                let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| ServerError::BadRequest(format!("invalid method `{}`", method)))?;
+                filter = filter.method(method);
+            }
+            if let Some(prefix) = params.prefix {
+                filter = filter.path_prefix(prefix);
+            }
+            let ttl = params.ttl.map_or(DEFAULT_TRAP_TTL, Duration::from_secs);
+            let id = traps.arm(filter, ttl)?;
+            Ok(Response::new()
+                .status(hyper::StatusCode::CREATED)
+                .json(&traps.get(id))?)
+        }
+        _ => Err(not_found()),
+    }
+}
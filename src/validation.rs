@@ -0,0 +1,124 @@
+use crate::ServerError;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+// Every problem found with a request's input, by field path, so a client
+// can fix them all in one round trip. Returned as an error it becomes a 422
+// Unprocessable Entity with the body
+//   {"errors": {"email": ["invalid format"], "age": ["must be >= 0"]}}
+//
+// Paths use dots for struct fields and brackets for list indexes, e.g.
+// `items[2].price`. `nest` prefixes the errors of a nested value, so each
+// struct can validate itself and its parent places the result:
+//   let mut errors = ValidationErrors::new();
+//   errors.check(order.email.contains('@'), "email", "invalid format");
+//   for (i, item) in order.items.iter().enumerate() {
+//       errors.nest(format!("items[{}]", i), item.validate());
+//   }
+//   errors.into_result()?;
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct ValidationErrors {
+    fields: BTreeMap<String, Vec<String>>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.fields.entry(path.into()).or_default().push(message.into());
+    }
+
+    // Adds `message` unless `valid`
+    pub fn check(&mut self, valid: bool, path: impl Into<String>, message: impl Into<String>) {
+        if !valid {
+            self.add(path, message);
+        }
+    }
+
+    // Adds the errors of a value found at `prefix`: `name` under `items[2]`
+    // becomes `items[2].name`, `[0]` becomes `items[2][0]`, and an empty
+    // path (an error about the value as a whole) becomes `items[2]`
+    pub fn nest(&mut self, prefix: impl AsRef<str>, errors: impl Into<ValidationErrors>) {
+        let prefix = prefix.as_ref();
+        for (path, messages) in errors.into().fields {
+            let path = join(prefix, &path);
+            self.fields.entry(path).or_default().extend(messages);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    // Number of fields with errors
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&[String]> {
+        self.fields.get(path).map(Vec::as_slice)
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.fields
+            .iter()
+            .map(|(path, messages)| (path.as_str(), messages.as_slice()))
+    }
+
+    // Ok when nothing was added
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+// So `nest` takes a nested `validate()` result directly
+impl From<Result<(), ValidationErrors>> for ValidationErrors {
+    fn from(result: Result<(), ValidationErrors>) -> Self {
+        result.err().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (path, messages) in &self.fields {
+            for message in messages {
+                if !first {
+                    f.write_str("; ")?;
+                }
+                first = false;
+                match path.as_str() {
+                    "" => write!(f, "{}", message)?,
+                    path => write!(f, "{}: {}", path, message)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl From<ValidationErrors> for ServerError {
+    fn from(errors: ValidationErrors) -> Self {
+        ServerError::Validation(errors)
+    }
+}
+
+fn join(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        path.to_string()
+    } else if path.is_empty() || path.starts_with('[') {
+        format!("{}{}", prefix, path)
+    } else {
+        format!("{}.{}", prefix, path)
+    }
+}
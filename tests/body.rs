@@ -0,0 +1,147 @@
+// Request bodies over raw HTTP/1.1 connections: keep-alive after a body the
+// handler ignores, and the size limits.
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::{Response, Result, Router};
+use hyper::{Body, Request};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn ignore_body(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text("ignored"))
+}
+
+// Reads one response with a Content-Length body; None if the connection
+// was closed before one arrived
+async fn read_response(stream: &mut TcpStream) -> Option<(u16, String)> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let mut chunk = [0; 4096];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .expect("no response")
+            .ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let status = head[9..12].parse().unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length").then(|| value.trim().parse().unwrap())
+        })
+        .unwrap_or(0);
+    while buf.len() < head_end + length {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Some((status, String::from_utf8_lossy(&buf[head_end..head_end + length]).into_owned()))
+}
+
+#[tokio::test]
+async fn ignored_bodies_dont_desync_keep_alive() {
+    let app = App::start(|server| {
+        server.with_router(Router::new().post("/ignore", ignore_body).get("/next", ignore_body))
+    })
+    .await;
+    let mut stream = TcpStream::connect(app.addr).await.unwrap();
+
+    // A body the handler never reads, then another request on the same
+    // connection: the body bytes must not be taken for the next request
+    for body in ["hello world", "GET /next HTTP/1.1\r\nHost: x\r\n\r\n"] {
+        let post = format!(
+            "POST /ignore HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(post.as_bytes()).await.unwrap();
+        assert_eq!(read_response(&mut stream).await, Some((200, "ignored".to_string())));
+    }
+    let chunked = "POST /ignore HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n0\r\n\r\n";
+    stream.write_all(chunked.as_bytes()).await.unwrap();
+    assert_eq!(read_response(&mut stream).await, Some((200, "ignored".to_string())));
+
+    stream.write_all(b"GET /next HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+    assert_eq!(read_response(&mut stream).await, Some((200, "ignored".to_string())));
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn unread_body_still_in_flight_closes_the_connection() {
+    let app = App::start(|server| server.with_router(Router::new().post("/ignore", ignore_body))).await;
+    let mut stream = TcpStream::connect(app.addr).await.unwrap();
+
+    // Only part of the declared body is sent before the response: the
+    // connection can't be reused, so it's closed rather than misread
+    stream
+        .write_all(b"POST /ignore HTTP/1.1\r\nHost: x\r\nContent-Length: 100000\r\n\r\npartial")
+        .await
+        .unwrap();
+    assert_eq!(read_response(&mut stream).await, Some((200, "ignored".to_string())));
+    let _ = stream.write_all(b"GET /ignore HTTP/1.1\r\nHost: x\r\n\r\n").await;
+    assert_eq!(read_response(&mut stream).await, None);
+
+    app.stop().await;
+}
+
+async fn body_length(req: Request<Body>) -> Result<Response> {
+    let body = high_performance_webserver::read_body(req).await?;
+    Ok(Response::new().text(body.len().to_string()))
+}
+
+async fn post_body(app: &App, path: &str, len: usize, chunked: bool) -> (u16, String) {
+    let body = if chunked {
+        // No Content-Length: the limit can only be found out while reading
+        let chunks = (0..len).map(|_| Ok::<_, std::io::Error>("x"));
+        Body::wrap_stream(futures::stream::iter(chunks))
+    } else {
+        Body::from("x".repeat(len))
+    };
+    let (status, _, body) = send(Request::post(app.url(path)).body(body).unwrap()).await;
+    (status.as_u16(), String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn route_body_limits_override_the_server_limit() {
+    let app = App::start(|server| {
+        let router = Router::new()
+            .post("/small", body_length)
+            .max_body_size(16)
+            .post("/default", body_length)
+            .post("/upload", body_length)
+            .max_body_size(4096);
+        server.with_router(router).with_max_body_size(256)
+    })
+    .await;
+
+    for chunked in [false, true] {
+        // Below the server limit, above the route's
+        assert_eq!(post_body(&app, "/small", 16, chunked).await, (200, "16".to_string()));
+        assert_eq!(post_body(&app, "/small", 17, chunked).await.0, 413);
+        assert_eq!(post_body(&app, "/small", 200, chunked).await.0, 413);
+
+        assert_eq!(post_body(&app, "/default", 256, chunked).await, (200, "256".to_string()));
+        assert_eq!(post_body(&app, "/default", 257, chunked).await.0, 413);
+
+        // Above the server limit, within the route's
+        assert_eq!(post_body(&app, "/upload", 4096, chunked).await, (200, "4096".to_string()));
+        assert_eq!(post_body(&app, "/upload", 4097, chunked).await.0, 413);
+    }
+
+    app.stop().await;
+}
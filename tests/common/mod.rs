@@ -0,0 +1,82 @@
+// Test servers and clients shared by the integration tests. Each test
+// binary uses a different subset.
+#![allow(dead_code)]
+
+use high_performance_webserver::Server;
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, HeaderMap, Request, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub struct App {
+    pub addr: SocketAddr,
+    pub shutdown: Option<oneshot::Sender<()>>,
+    server: JoinHandle<high_performance_webserver::Result<()>>,
+}
+
+impl App {
+    // A server on an ephemeral port, set up by `configure` and shut down
+    // gracefully by `stop`
+    pub async fn start(configure: impl FnOnce(Server) -> Server) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = configure(Server::from_listener(listener).unwrap());
+        let addr = server.local_addr();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(server.run_with_graceful_shutdown(async {
+            let _ = signal.await;
+        }));
+        let app = Self {
+            addr,
+            shutdown: Some(shutdown),
+            server,
+        };
+        app.wait_until_listening().await;
+        app
+    }
+
+    async fn wait_until_listening(&self) {
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(self.addr).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server on {} never started listening", self.addr);
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub async fn stop(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        tokio::time::timeout(Duration::from_secs(5), self.server)
+            .await
+            .expect("server didn't shut down")
+            .unwrap()
+            .unwrap();
+    }
+}
+
+pub fn http1() -> Client<HttpConnector> {
+    Client::new()
+}
+
+// HTTP/2 with prior knowledge, as there is no TLS to negotiate it over
+pub fn http2() -> Client<HttpConnector> {
+    Client::builder().http2_only(true).build_http()
+}
+
+// Sends `req` over HTTP/1.1 and reads the whole response
+pub async fn send(req: Request<Body>) -> (StatusCode, HeaderMap, Bytes) {
+    let response = http1().request(req).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, headers, body)
+}
@@ -0,0 +1,124 @@
+// Streamed responses through each built-in encoder: every chunk has to reach
+// the client, decodable, before the handler produces the next one.
+#![cfg(all(feature = "gzip", feature = "br", feature = "zstd"))]
+
+mod common;
+
+use bytes::Bytes;
+use common::{http1, App};
+use high_performance_webserver::{Compression, Response, Result, Router};
+use hyper::body::HttpBody;
+use hyper::{Body, Request, StatusCode};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+type Sender = mpsc::Sender<std::result::Result<Bytes, std::io::Error>>;
+
+// As far as `compressed` goes
+fn decode(name: &str, compressed: &[u8]) -> (String, bool) {
+    let mut out = Vec::new();
+    let complete = match name {
+        "gzip" => flate2::read::GzDecoder::new(compressed).read_to_end(&mut out),
+        "br" => brotli::Decompressor::new(compressed, 4096).read_to_end(&mut out),
+        "zstd" => zstd::stream::read::Decoder::new(compressed)
+            .unwrap()
+            .read_to_end(&mut out),
+        _ => unreachable!(),
+    }
+    .is_ok();
+    (String::from_utf8(out).unwrap(), complete)
+}
+
+// Reads body frames until what arrived decodes to `expected`
+async fn read_until(name: &str, body: &mut Body, received: &mut Vec<u8>, expected: &str) {
+    while decode(name, received).0 != expected {
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .unwrap_or_else(|_| panic!("{name}: `{expected}` never arrived"))
+            .expect("body ended early")
+            .unwrap();
+        received.extend_from_slice(&frame);
+    }
+}
+
+#[tokio::test]
+async fn streams_through_every_codec() {
+    let slot: Arc<Mutex<Option<Sender>>> = Arc::default();
+    let handler_slot = slot.clone();
+    let app = App::start(move |server| {
+        let router = Router::new().get("/events", move |_req: Request<Body>| {
+            let slot = handler_slot.clone();
+            async move {
+                let (tx, response) = Response::channel();
+                *slot.lock().unwrap() = Some(tx);
+                Result::Ok(response.header("Content-Type", "text/plain"))
+            }
+        });
+        server
+            .with_router(router)
+            .with_compression(Compression::standard())
+    })
+    .await;
+
+    for name in ["gzip", "br", "zstd"] {
+        let req = Request::get(app.url("/events"))
+            .header("Accept-Encoding", name)
+            .body(Body::empty())
+            .unwrap();
+        let response = http1().request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], name);
+        assert!(!response.headers().contains_key("content-length"));
+        let tx = slot.lock().unwrap().take().unwrap();
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+
+        let mut expected = String::new();
+        for i in 0..3 {
+            let chunk = format!("event {}\n", i);
+            expected.push_str(&chunk);
+            tx.send(Ok(Bytes::from(chunk))).await.unwrap();
+            read_until(name, &mut body, &mut received, &expected).await;
+        }
+        drop(tx);
+        while let Some(frame) = body.data().await {
+            received.extend_from_slice(&frame.unwrap());
+        }
+        assert_eq!(decode(name, &received), (expected, true), "{name}");
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn standard_prefers_zstd_then_br_then_gzip() {
+    let app = App::start(|server| {
+        let router = Router::new().get("/", |_req: Request<Body>| async {
+            Result::Ok(Response::new().text("x".repeat(4096)))
+        });
+        server
+            .with_router(router)
+            .with_compression(Compression::standard())
+    })
+    .await;
+
+    for (accept, expected) in [
+        ("gzip, br, zstd", "zstd"),
+        ("gzip, br", "br"),
+        ("gzip", "gzip"),
+        ("gzip;q=1, zstd;q=0.5", "gzip"),
+    ] {
+        let req = Request::get(app.url("/"))
+            .header("Accept-Encoding", accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = http1().request(req).await.unwrap();
+        assert_eq!(response.headers()["content-encoding"], expected, "{accept}");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(decode(expected, &body), ("x".repeat(4096), true));
+    }
+
+    app.stop().await;
+}
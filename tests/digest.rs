@@ -0,0 +1,90 @@
+// `ContentDigest` and `DigestAlgorithm` against RFC 9530's examples.
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::{read_body, ContentDigest, DigestAlgorithm, Response, Result, Router};
+use hyper::{Body, Request, StatusCode};
+
+// RFC 9530 appendix D: the digests of `{"hello": "world"}`
+const HELLO: &str = r#"{"hello": "world"}"#;
+const HELLO_SHA256: &str = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+const HELLO_SHA512: &str =
+    "sha-512=:WZDPaVn/7XgHaAy8pmojAkGWoRx2UFChF41A2svX+TaPm+AbwAgBWnrIiYllu7BNNyealdVLvRwEmTHWXvJwew==:";
+
+#[test]
+fn digests_match_rfc_9530_examples() {
+    assert_eq!(DigestAlgorithm::Sha256.header_value(HELLO.as_bytes()), HELLO_SHA256);
+    assert_eq!(DigestAlgorithm::Sha512.header_value(HELLO.as_bytes()), HELLO_SHA512);
+    assert_eq!(
+        DigestAlgorithm::Sha256.header_value(b""),
+        "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:"
+    );
+}
+
+async fn echo(req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().body(read_body(req).await?))
+}
+
+async fn signed_app() -> App {
+    App::start(|server| {
+        let router = Router::new()
+            .post("/signed", echo)
+            .tag("signed")
+            .layer_for_tag("signed", ContentDigest::new());
+        server.with_router(router)
+    })
+    .await
+}
+
+fn post(app: &App, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut request = Request::post(app.url("/signed"));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request.body(Body::from(HELLO)).unwrap()
+}
+
+#[tokio::test]
+async fn content_digest_is_checked_against_the_body() {
+    let app = signed_app().await;
+
+    let (status, _, body) = send(post(&app, &[("Content-Digest", HELLO_SHA256)])).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, HELLO);
+
+    // Several algorithms: the strongest is checked
+    let both = format!("{}, {}", HELLO_SHA256, HELLO_SHA512);
+    let (status, _, _) = send(post(&app, &[("Content-Digest", &both)])).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let other = DigestAlgorithm::Sha256.header_value(b"{\"hello\": \"there\"}");
+    let (status, _, body) = send(post(&app, &[("Content-Digest", &other)])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(String::from_utf8_lossy(&body).contains("does not match"));
+
+    // A correct sha-256 doesn't save a wrong sha-512, which is the one checked
+    let wrong_512 = format!("{}, sha-512=:{}:", HELLO_SHA256, "A".repeat(88));
+    let (status, _, _) = send(post(&app, &[("Content-Digest", &wrong_512)])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn want_content_digest_picks_the_highest_weight() {
+    let app = signed_app().await;
+
+    for (want, expected) in [
+        ("sha-256=3, sha-512=10", Some(HELLO_SHA512)),
+        ("sha-256=10, sha-512=3", Some(HELLO_SHA256)),
+        ("sha-512=0, sha-256=1", Some(HELLO_SHA256)),
+        ("md5=10", None),
+    ] {
+        let (status, headers, _) = send(post(&app, &[("Want-Content-Digest", want)])).await;
+        assert_eq!(status, StatusCode::OK);
+        let digest = headers.get("Content-Digest").map(|v| v.to_str().unwrap());
+        assert_eq!(digest, expected, "Want-Content-Digest: {}", want);
+    }
+
+    app.stop().await;
+}
@@ -0,0 +1,156 @@
+// Responses that must not carry content (HEAD, 204, 304) over a raw HTTP/1.1
+// connection: the next response on the connection has to start right after
+// the head, and the framing headers must describe what a GET / 200 would send.
+mod common;
+
+use common::App;
+use high_performance_webserver::{Method, Response, Result, Router, StreamBody};
+use hyper::{Body, Request, StatusCode};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const PAGE: &str = "hello world";
+
+async fn page(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().header("ETag", "\"v1\"").text(PAGE))
+}
+
+async fn stream(_req: Request<Body>) -> Result<Response> {
+    let chunks = futures::stream::iter(["hello", " ", "world"].map(Ok::<_, std::io::Error>));
+    Ok(StreamBody::new(chunks).into())
+}
+
+// A handler that gets the status wrong: a 204 with content
+async fn no_content(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().status(StatusCode::NO_CONTENT).text("oops"))
+}
+
+async fn ping(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text("pong"))
+}
+
+struct Head {
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Sends `request`, then a GET /ping on the same connection, and returns the
+// first response's head along with everything that came between it and the
+// second response
+async fn exchange(app: &App, request: &str) -> (Head, Vec<u8>) {
+    let mut stream = TcpStream::connect(app.addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream
+        .write_all(b"GET /ping HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut buf = Vec::new();
+    let pong = b"\r\n\r\npong";
+    while !buf.ends_with(pong) {
+        let mut chunk = [0; 4096];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .expect("no response")
+            .unwrap();
+        assert!(n > 0, "connection closed: {}", String::from_utf8_lossy(&buf));
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head_end = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let second = head_end
+        + buf[head_end..]
+            .windows(9)
+            .position(|w| w == b"HTTP/1.1 ")
+            .expect("no second response");
+    let text = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = text.lines();
+    let status = lines.next().unwrap()[9..12].parse().unwrap();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect();
+    (Head { status, headers }, buf[head_end..second].to_vec())
+}
+
+async fn app() -> App {
+    App::start(|server| {
+        server.with_router(
+            Router::new()
+                .get("/page", page)
+                .route(Method::HEAD, "/page", page)
+                .get("/stream", stream)
+                .route(Method::HEAD, "/stream", stream)
+                .get("/no-content", no_content)
+                .get("/ping", ping),
+        )
+    })
+    .await
+}
+
+#[tokio::test]
+async fn head_keeps_the_get_framing_without_a_body() {
+    let app = app().await;
+
+    let (head, between) = exchange(&app, "HEAD /page HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert_eq!(head.status, 200);
+    assert_eq!(head.get("content-length"), Some("11"));
+    assert_eq!(head.get("content-type"), Some("text/plain"));
+    assert_eq!(head.get("transfer-encoding"), None);
+    assert!(between.is_empty(), "{:?}", String::from_utf8_lossy(&between));
+
+    // A streamed body of unknown length: no chunked terminator either
+    let (head, between) = exchange(&app, "HEAD /stream HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert_eq!(head.status, 200);
+    assert!(between.is_empty(), "{:?}", String::from_utf8_lossy(&between));
+
+    // The GET it stands for
+    let (head, between) = exchange(&app, "GET /page HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert_eq!(head.get("content-length"), Some("11"));
+    assert_eq!(between, PAGE.as_bytes());
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn no_content_drops_body_and_length() {
+    let app = app().await;
+
+    let (head, between) = exchange(&app, "GET /no-content HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert_eq!(head.status, 204);
+    assert_eq!(head.get("content-length"), None);
+    assert_eq!(head.get("transfer-encoding"), None);
+    assert!(between.is_empty(), "{:?}", String::from_utf8_lossy(&between));
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn not_modified_keeps_length_and_validators() {
+    let app = app().await;
+
+    for method in ["GET", "HEAD"] {
+        let request = format!(
+            "{} /page HTTP/1.1\r\nHost: test\r\nIf-None-Match: \"v1\"\r\n\r\n",
+            method
+        );
+        let (head, between) = exchange(&app, &request).await;
+        assert_eq!(head.status, 304, "{method}");
+        assert_eq!(head.get("etag"), Some("\"v1\""));
+        assert_eq!(head.get("content-length"), Some("11"), "{method}");
+        assert_eq!(head.get("transfer-encoding"), None);
+        assert!(between.is_empty(), "{method}: {:?}", String::from_utf8_lossy(&between));
+    }
+
+    app.stop().await;
+}
@@ -0,0 +1,574 @@
+// Drives the demo application (`example_app`, what the binary serves) over
+// real sockets, with HTTP/1.1 and HTTP/2 clients.
+mod common;
+
+use common::{http1, http2, App};
+use high_performance_webserver::example::{example_app, ApiResponse, User};
+use high_performance_webserver::{
+    read_body, read_json, read_text, BodyError, Compression, DotSegments, Encoder, RequestExt,
+    Response, Result, Router, Server, ServerError,
+};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Version};
+use futures::StreamExt;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+impl App {
+    // The demo app on an ephemeral port, shut down gracefully by `stop`
+    async fn spawn() -> Self {
+        Self::start(|server| server.with_router(example_app())).await
+    }
+}
+
+async fn get(client: &Client<HttpConnector>, url: &str) -> (StatusCode, Version, Value) {
+    let response = client.get(url.parse().unwrap()).await.unwrap();
+    let status = response.status();
+    let version = response.version();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let json = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, version, json)
+}
+
+#[tokio::test]
+async fn serves_json_over_http1_and_http2() {
+    let app = App::spawn().await;
+
+    for (client, expected) in [(http1(), Version::HTTP_11), (http2(), Version::HTTP_2)] {
+        let (status, version, body) = get(&client, &app.url("/health")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version, expected);
+        assert_eq!(body["success"], true);
+
+        let (status, _, body) = get(&client, &app.url("/users")).await;
+        assert_eq!(status, StatusCode::OK);
+        let users: ApiResponse<Vec<User>> = serde_json::from_value(body).unwrap();
+        assert_eq!(users.data.len(), 3);
+
+        let (status, _, body) = get(&client, &app.url("/users/42")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["id"], 42);
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn create_user_checks_content_type() {
+    let app = App::spawn().await;
+    let client = http1();
+
+    let created = Request::builder()
+        .method(Method::POST)
+        .uri(app.url("/users"))
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"name":"New User"}"#))
+        .unwrap();
+    let response = client.request(created).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let wrong_type = Request::builder()
+        .method(Method::POST)
+        .uri(app.url("/users"))
+        .header("Content-Type", "text/plain")
+        .body(Body::from("New User"))
+        .unwrap();
+    let response = client.request(wrong_type).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_routes_are_404() {
+    let app = App::spawn().await;
+
+    for client in [http1(), http2()] {
+        let (status, _, body) = get(&client, &app.url("/nope")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].is_string(), "404 body: {}", body);
+
+        // The id parameter only matches numbers
+        let (status, _, _) = get(&client, &app.url("/users/abc")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn concurrent_requests_get_their_own_responses() {
+    let app = App::spawn().await;
+
+    for client in [http1(), http2()] {
+        let requests = (1..=50u32).map(|id| {
+            let client = client.clone();
+            let url = app.url(&format!("/users/{}", id));
+            tokio::spawn(async move { (id, get(&client, &url).await) })
+        });
+        for request in futures::future::join_all(requests).await {
+            let (id, (status, _, body)) = request.unwrap();
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body["data"]["id"], id);
+        }
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn graceful_shutdown_finishes_in_flight_requests() {
+    let mut app = App::spawn().await;
+    let client = http1();
+
+    // `/async-demo` takes 100ms; shut down while it's being handled
+    let url = app.url("/async-demo");
+    let in_flight = tokio::spawn(async move { get(&client, &url).await });
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    let _ = app.shutdown.take().unwrap().send(());
+
+    let (status, _, body) = in_flight.await.unwrap();
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["success"], true);
+
+    // The listener closes as soon as shutdown begins
+    let addr = app.addr;
+    app.stop().await;
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
+
+// Everything under /admin needs a token, checked by path prefix before
+// routing, the way a gateway-style guard would
+fn admin_guard(req: &Request<Body>) -> Result<()> {
+    if req.uri().path().starts_with("/admin") && req.bearer_token() != Some("letmein") {
+        return Err(ServerError::BadRequest("admin token required".to_string()));
+    }
+    Ok(())
+}
+
+async fn secret(req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text(format!("secret at {}", req.uri().path())))
+}
+
+fn guarded(server: Server) -> Server {
+    let router = Router::new()
+        .get("/admin/users", secret)
+        .get("/public", secret)
+        .get("/etc/passwd", secret);
+    server.with_router(router).with_request_guard(admin_guard)
+}
+
+async fn status_of(url: &str, token: Option<&str>) -> (StatusCode, String) {
+    let mut request = Request::get(url);
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    let response = http1().request(request.body(Body::empty()).unwrap()).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn dot_segments_cant_bypass_prefix_guards() {
+    let app = App::start(guarded).await;
+
+    for path in [
+        "/admin/users",
+        "/admin/../admin/users",
+        "/public/../admin/users",
+        "/./admin/users",
+        "/admin/./users",
+        "/%2e%2e/admin/users",
+        "/public/%2E%2e/admin/users",
+        "/public/.%2e/admin/users",
+    ] {
+        let (status, _) = status_of(&app.url(path), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} without a token", path);
+
+        // The handler sees the resolved path too
+        let (status, body) = status_of(&app.url(path), Some("letmein")).await;
+        assert_eq!(status, StatusCode::OK, "{} with a token", path);
+        assert_eq!(body, "secret at /admin/users");
+    }
+
+    // `..` can't climb above the root
+    let (status, body) = status_of(&app.url("/../../etc/passwd"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "secret at /etc/passwd");
+
+    // A segment that only contains dots is a name, not a dot-segment
+    let (status, _) = status_of(&app.url("/.../admin/users"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn merged_slashes_cant_bypass_prefix_guards() {
+    let app = App::start(|server| guarded(server).with_merge_slashes(true)).await;
+
+    for path in ["//admin/users", "/public/..//admin//users", "///admin/users"] {
+        let (status, _) = status_of(&app.url(path), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{} without a token", path);
+
+        let (status, body) = status_of(&app.url(path), Some("letmein")).await;
+        assert_eq!(status, StatusCode::OK, "{} with a token", path);
+        assert_eq!(body, "secret at /admin/users");
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn strict_mode_rejects_dot_segments() {
+    let app = App::start(|server| guarded(server).with_dot_segments(DotSegments::Reject)).await;
+
+    for path in ["/admin/../admin/users", "/public/%2e%2e/admin/users", "/./public"] {
+        let (status, _) = status_of(&app.url(path), Some("letmein")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+    }
+    let (status, _) = status_of(&app.url("/public"), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn limits_apply_to_the_uri_as_sent() {
+    let app = App::start(guarded).await;
+
+    // Resolves to `/public`, but 15,007 bytes were sent
+    let path = format!("{}/public", "/a/..".repeat(3000));
+    assert!(path.len() > 8 * 1024);
+    let (status, _) = status_of(&app.url(&path), None).await;
+    assert_eq!(status, StatusCode::URI_TOO_LONG);
+
+    // Under the length limit, but 401 segments before resolution
+    let path = format!("{}/public", "/a/..".repeat(200));
+    let (status, body) = status_of(&app.url(&path), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.contains("segments"), "{}", body);
+
+    let (status, _) = status_of(&app.url(&format!("{}/public", "/a/..".repeat(100))), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    app.stop().await;
+}
+
+async fn uris(req: Request<Body>) -> Result<Response> {
+    let raw = req.context().map(|context| context.raw_path().to_string());
+    let parts = req.parts().map(|parts| parts.uri.to_string());
+    Ok(Response::new().text(format!("{} {:?} {:?}", req.uri(), raw, parts)))
+}
+
+#[tokio::test]
+async fn raw_path_and_parts_keep_the_uri_as_sent() {
+    let app = App::start(|server| {
+        server
+            .with_router(Router::new().get("/a/b", uris))
+            .with_request_parts(true)
+    })
+    .await;
+
+    let (status, body) = status_of(&app.url("/a/./b?x=1"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, r#"/a/b?x=1 Some("/a/./b") Some("/a/./b?x=1")"#);
+
+    app.stop().await;
+}
+
+async fn slow(_req: Request<Body>) -> Result<Response> {
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    Ok(Response::new().text("done"))
+}
+
+#[tokio::test]
+async fn route_and_router_timeouts_win_over_the_request_timeout() {
+    let app = App::start(|server| {
+        let api = Router::new()
+            .get("/api/slow", slow)
+            .with_default_response_timeout(Duration::from_secs(5));
+        let router = Router::new()
+            .get("/slow", slow)
+            .get("/export", slow)
+            .response_timeout(Duration::from_secs(5))
+            .fallback_service(slow)
+            .host("api.example.com", api);
+        server
+            .with_router(router)
+            .with_request_timeout(Duration::from_millis(50))
+    })
+    .await;
+
+    let (status, body) = status_of(&app.url("/slow"), None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(body.contains("timed out after 50ms"), "{}", body);
+    let (status, _) = status_of(&app.url("/export"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = status_of(&app.url("/anything-else"), None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    let request = Request::get(app.url("/api/slow"))
+        .header("Host", "api.example.com")
+        .body(Body::empty())
+        .unwrap();
+    let response = http1().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    app.stop().await;
+}
+
+struct Passthrough;
+
+impl Encoder for Passthrough {
+    fn encode(&mut self, chunk: &[u8]) -> std::io::Result<bytes::Bytes> {
+        Ok(bytes::Bytes::copy_from_slice(chunk))
+    }
+
+    fn finish(&mut self) -> std::io::Result<bytes::Bytes> {
+        Ok(bytes::Bytes::new())
+    }
+}
+
+#[test]
+fn config_dump_includes_compression() {
+    let server = Server::new("127.0.0.1:0".parse().unwrap());
+    assert_eq!(server.config_json()["config"]["compression"], Value::Null);
+
+    let compression = Compression::new()
+        .encoding_with_level("Fast", 3, |_| Passthrough)
+        .encoding("plain", || Passthrough)
+        .prefer(&["plain"])
+        .min_size(512)
+        .compress_streams(false)
+        .reject_unacceptable(false);
+    let dump = server.with_compression(compression).config_json();
+    assert_eq!(
+        dump["config"]["compression"],
+        serde_json::json!({
+            "codecs": [
+                { "name": "fast", "level": 3 },
+                { "name": "plain", "level": null },
+            ],
+            "preference": ["plain"],
+            "min_size": 512,
+            "compress_streams": false,
+            "reject_unacceptable": false,
+        })
+    );
+}
+
+async fn echo_json(req: Request<Body>) -> Result<Response> {
+    let value: Value = read_json(req).await?;
+    Ok(Response::new().json_value(value))
+}
+
+async fn echo_text(req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text(read_text(req).await?))
+}
+
+async fn post(app: &App, path: &str, content_type: &str, body: Body) -> (StatusCode, Value) {
+    let request = Request::post(app.url(path))
+        .header("Content-Type", content_type)
+        .body(body)
+        .unwrap();
+    let response = http1().request(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn body_read_failures_get_precise_statuses() {
+    let app = App::start(|server| {
+        let router = Router::new().post("/json", echo_json).post("/text", echo_text);
+        server.with_router(router).with_max_body_size(16)
+    })
+    .await;
+
+    let (status, body) = post(&app, "/json", "application/json", Body::from(r#"{"a":1}"#)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["a"], 1);
+
+    // Declared too large up front, and found too large while reading
+    let (status, _) = post(&app, "/text", "text/plain", Body::from("x".repeat(64))).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let chunks = futures::stream::iter((0..4).map(|_| Ok::<_, std::io::Error>("x".repeat(10))));
+    let (status, _) = post(&app, "/text", "text/plain", Body::wrap_stream(chunks)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let (status, body) = post(&app, "/json", "text/plain", Body::from("{}")).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(body["error"].is_string(), "415 body: {}", body);
+
+    let (status, body) = post(&app, "/json", "application/json", Body::from("{\"a\":")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().contains("JSON"), "400 body: {}", body);
+
+    let (status, body) = post(&app, "/text", "text/plain", Body::from(vec![b'a', 0xff, 0xfe])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].as_str().unwrap().contains("UTF-8"), "400 body: {}", body);
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn malformed_percent_encoding_is_a_bad_request() {
+    let app = App::spawn().await;
+    let client = http1();
+
+    for path in [
+        "/users/%zz",
+        "/users/%4",
+        "/users/%C3%28",
+        "/users?page=%zz",
+        "/users?%zz=1",
+        "/users?name=caf%C3",
+    ] {
+        let (status, _, body) = get(&client, &app.url(path)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        assert!(body["error"].as_str().unwrap().contains("percent-encoding"), "{}: {}", path, body);
+    }
+
+    // Well-formed escapes still route
+    for path in ["/users/%34%32", "/users?name=caf%C3%A9+au+lait"] {
+        let (status, _, _) = get(&client, &app.url(path)).await;
+        assert_eq!(status, StatusCode::OK, "{}", path);
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn body_broken_off_mid_read_is_a_bad_request() {
+    let (mut tx, body) = Body::channel();
+    tx.send_data("partial".into()).await.unwrap();
+    tx.abort();
+
+    let error = read_body(Request::new(body)).await.unwrap_err();
+    assert!(matches!(error, BodyError::Read(_)), "{:?}", error);
+    assert_eq!(ServerError::from(error).status_code(), StatusCode::BAD_REQUEST);
+
+    // Decoding bytes by hand with `?` gets the same status as `read_text`
+    let decoded = String::from_utf8(vec![0xc3]).map_err(ServerError::from);
+    assert_eq!(decoded.unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+}
+
+fn rows(count: usize) -> impl futures::Stream<Item = std::io::Result<Value>> {
+    futures::stream::iter((0..count).map(|id| Ok(serde_json::json!({ "id": id }))))
+}
+
+async fn body_of(app: &App, path: &str) -> (Option<String>, hyper::Result<hyper::body::Bytes>) {
+    let response = http1().get(app.url(path).parse().unwrap()).await.unwrap();
+    let content_type = response.headers().get("Content-Type").map(|v| v.to_str().unwrap().to_string());
+    (content_type, hyper::body::to_bytes(response.into_body()).await)
+}
+
+#[tokio::test]
+async fn json_array_streams_are_valid_json() {
+    let app = App::start(|server| {
+        let router = Router::new()
+            .get("/rows", |_req: Request<Body>| async { Ok(Response::new().json_array_stream(rows(100_000))) })
+            .get("/none", |_req: Request<Body>| async { Ok(Response::new().json_array_stream(rows(0))) })
+            .get("/lines", |_req: Request<Body>| async { Ok(Response::new().ndjson_stream(rows(1000))) })
+            .get("/broken", |_req: Request<Body>| async {
+                let failing = rows(10).chain(futures::stream::once(async {
+                    Err(std::io::Error::other("database went away"))
+                }));
+                Ok(Response::new().json_array_stream(failing))
+            });
+        server.with_router(router)
+    })
+    .await;
+
+    let (content_type, body) = body_of(&app, "/rows").await;
+    assert_eq!(content_type.as_deref(), Some("application/json"));
+    let rows: Vec<Value> = serde_json::from_slice(&body.unwrap()).unwrap();
+    assert_eq!(rows.len(), 100_000);
+    assert!(rows.iter().enumerate().all(|(id, row)| row["id"] == id));
+
+    let (_, body) = body_of(&app, "/none").await;
+    assert_eq!(&body.unwrap()[..], b"[]");
+
+    let (content_type, body) = body_of(&app, "/lines").await;
+    assert_eq!(content_type.as_deref(), Some("application/x-ndjson"));
+    let body = body.unwrap();
+    let lines: Vec<&[u8]> = body.split(|&b| b == b'\n').filter(|line| !line.is_empty()).collect();
+    assert_eq!(lines.len(), 1000);
+    for (id, line) in lines.into_iter().enumerate() {
+        assert_eq!(serde_json::from_slice::<Value>(line).unwrap()["id"], id);
+    }
+
+    // A failure mid-stream cuts the response off instead of closing the
+    // array. Here it's in the first chunk, so the connection may close
+    // before even the head was sent.
+    let response = http1().get(app.url("/broken").parse().unwrap()).await;
+    if let Ok(response) = response {
+        let body = hyper::body::to_bytes(response.into_body()).await;
+        assert!(body.is_err(), "expected an aborted body, got {:?}", body);
+    }
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn json_array_streams_wait_for_slow_clients() {
+    const ROWS: usize = 100_000;
+    let produced = Arc::new(AtomicUsize::new(0));
+    let counter = produced.clone();
+    let app = App::start(move |server| {
+        let router = Router::new().get("/export", move |_req: Request<Body>| {
+            let counter = counter.clone();
+            async move {
+                // About 1KB per item, 100MB in all
+                let items = futures::stream::iter(0..ROWS).map(move |id| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok::<_, std::io::Error>(format!("{}{}", id, "x".repeat(1000)))
+                });
+                Ok(Response::new().json_array_stream(items))
+            }
+        });
+        server.with_router(router)
+    })
+    .await;
+
+    let response = http1().get(app.url("/export").parse().unwrap()).await.unwrap();
+    let mut body = response.into_body();
+    assert!(hyper::body::HttpBody::data(&mut body).await.unwrap().is_ok());
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // Only what fits in the socket buffers was produced, not the whole export
+    let so_far = produced.load(Ordering::Relaxed);
+    assert!(so_far < ROWS / 5, "{} of {} items produced for a stalled client", so_far, ROWS);
+
+    drop(body);
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn route_headers_use_the_public_names() {
+    use high_performance_webserver::server::{ROUTE_HEADER, ROUTE_NAME_HEADER};
+
+    let app = App::start(|server| {
+        let router = Router::new()
+            .get("/users/:id", |_req: Request<Body>| async { Ok(Response::new().text("user")) })
+            .name("get_user");
+        server.with_router(router).with_route_header(true)
+    })
+    .await;
+    let client = http1();
+
+    let response = client.get(app.url("/users/7").parse().unwrap()).await.unwrap();
+    assert_eq!(response.headers()[ROUTE_HEADER], "GET /users/:id");
+    assert_eq!(response.headers()[ROUTE_NAME_HEADER], "get_user");
+
+    let response = client.get(app.url("/nowhere").parse().unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()[ROUTE_HEADER], "unmatched");
+    assert!(!response.headers().contains_key(ROUTE_NAME_HEADER));
+
+    app.stop().await;
+}
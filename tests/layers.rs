@@ -0,0 +1,114 @@
+// Tag layers over a running server: they run after routing, with the route's
+// parameters available, can answer before the handler, and cover merged routers.
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::{Next, RequestExt, Response, Result, Router};
+use hyper::{Body, Request, StatusCode};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// User `n` owns the orders whose id ends in `n`
+fn owns(req: &Request<Body>, order: u64) -> bool {
+    req.headers()
+        .get("x-user")
+        .and_then(|user| user.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|user| order % 10 == user)
+}
+
+async fn check_owner(req: Request<Body>, next: Next) -> Result<Response> {
+    let params = req.route_params().expect("layer ran before routing");
+    let order = params.get("id").and_then(|id| id.parse().ok());
+    if !order.is_some_and(|order| owns(&req, order)) {
+        return Ok(Response::new().status(StatusCode::FORBIDDEN));
+    }
+    next.run(req).await
+}
+
+#[tokio::test]
+async fn ownership_check_rejects_before_the_handler() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = calls.clone();
+    let app = App::start(move |server| {
+        server.with_router(
+            Router::new()
+                .get("/orders/:id", move |req: Request<Body>| {
+                    let calls = handler_calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        Ok(Response::new().text(format!("order {}", req.param("id").unwrap())))
+                    }
+                })
+                .tag("owned")
+                .layer_for_tag("owned", check_owner),
+        )
+    })
+    .await;
+
+    let get = |path: &str, user: Option<&str>| {
+        let mut builder = Request::get(app.url(path));
+        if let Some(user) = user {
+            builder = builder.header("x-user", user);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    for (path, user) in [
+        ("/orders/42", Some("3")),
+        ("/orders/42", None),
+        ("/orders/42", Some("not a user")),
+        ("/orders/abc", Some("2")),
+    ] {
+        let (status, _, _) = send(get(path, user)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{path} as {user:?}");
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    let (status, _, body) = send(get("/orders/42", Some("2"))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"order 42");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    app.stop().await;
+}
+
+async fn ok(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text("ok"))
+}
+
+// Marks the response so the test can see which routes the layer ran for
+async fn mark(req: Request<Body>, next: Next) -> Result<Response> {
+    Ok(next.run(req).await?.header("x-internal", "1"))
+}
+
+#[tokio::test]
+async fn tag_layers_span_merged_routers() {
+    // The layer is registered on one router, the tagged routes live in both
+    let admin = Router::new()
+        .get("/admin/stats", ok)
+        .tag("internal")
+        .get("/admin/ping", ok);
+    let api = Router::new()
+        .get("/api/users", ok)
+        .get("/api/debug", ok)
+        .tag("internal")
+        .layer_for_tag("internal", mark);
+    let router = api.merge(admin).get("/health", ok).tag("public");
+    assert_eq!(router.routes_tagged("internal").count(), 2);
+
+    let app = App::start(|server| server.with_router(router)).await;
+    for (path, layered) in [
+        ("/admin/stats", true),
+        ("/api/debug", true),
+        ("/admin/ping", false),
+        ("/api/users", false),
+        ("/health", false),
+    ] {
+        let req = Request::get(app.url(path)).body(Body::empty()).unwrap();
+        let (status, headers, _) = send(req).await;
+        assert_eq!(status, StatusCode::OK, "{path}");
+        assert_eq!(headers.contains_key("x-internal"), layered, "{path}");
+    }
+
+    app.stop().await;
+}
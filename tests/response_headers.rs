@@ -0,0 +1,51 @@
+// Handler responses carrying a header value that can't go on the wire
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::{Response, Result, Router};
+use hyper::{Body, Request, StatusCode};
+
+async fn injected(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new()
+        .header("X-Good", "kept")
+        .header("X-Injected", "a\r\nSet-Cookie: session=stolen")
+        .text("body"))
+}
+
+fn get(app: &App) -> Request<Body> {
+    Request::get(app.url("/")).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn lenient_mode_drops_only_the_invalid_header() {
+    let app = App::start(|server| server.with_router(Router::new().get("/", injected))).await;
+
+    let (status, headers, body) = send(get(&app)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"body");
+    assert_eq!(headers["x-good"], "kept");
+    assert!(!headers.contains_key("x-injected"));
+    assert!(!headers.contains_key("set-cookie"));
+
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn strict_mode_fails_with_the_header_name() {
+    let app = App::start(|server| {
+        server
+            .with_router(Router::new().get("/", injected))
+            .with_strict_headers(true)
+    })
+    .await;
+
+    let (status, headers, body) = send(get(&app)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(!headers.contains_key("x-good"));
+    assert!(!headers.contains_key("set-cookie"));
+    // The same error is what gets logged
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains("X-Injected"), "{body}");
+
+    app.stop().await;
+}
@@ -0,0 +1,84 @@
+// Startup route checks on conflicting route tables, in either order of
+// `with_router` and `with_route_check`.
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::{
+    Method, Response, Result, RouteCheck, RouteIssue, Router, Server, ServerError,
+};
+use hyper::{Body, Request, StatusCode};
+use std::net::TcpListener;
+
+async fn ok(_req: Request<Body>) -> Result<Response> {
+    Ok(Response::new().text("ok"))
+}
+
+fn conflicting() -> Router {
+    Router::new()
+        .get("/users/:id", ok)
+        .get("/users/:id", ok)
+        .get("/files/*path", ok)
+        .get("/files/*rest", ok)
+}
+
+fn server() -> Server {
+    Server::from_listener(TcpListener::bind("127.0.0.1:0").unwrap()).unwrap()
+}
+
+async fn start(server: Server) -> Result<()> {
+    server.run_with_graceful_shutdown(async {}).await
+}
+
+#[test]
+fn conflicts_are_diagnosed() {
+    let diagnostics = server().with_router(conflicting()).route_diagnostics();
+    assert!(diagnostics
+        .iter()
+        .any(|d| d.method == Method::GET && d.path == "/users/:id" && d.issue == RouteIssue::Duplicate));
+    assert!(diagnostics.iter().any(|d| d.path == "/files/*rest"));
+
+    let clean = Router::new().get("/users/:id", ok).get("/users/me", ok);
+    assert!(server().with_router(clean).route_diagnostics().is_empty());
+}
+
+#[tokio::test]
+async fn strict_check_fails_startup_in_either_order() {
+    let after = server()
+        .with_router(conflicting())
+        .with_route_check(RouteCheck::Strict);
+    let before = server()
+        .with_route_check(RouteCheck::Strict)
+        .with_router(conflicting());
+
+    for server in [after, before] {
+        match start(server).await {
+            Err(ServerError::Internal(message)) => {
+                assert!(message.contains("route check failed"), "{message}")
+            }
+            other => panic!("expected a route check failure, got {:?}", other.err()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn strict_check_passes_a_clean_table() {
+    let app = App::start(|server| {
+        server
+            .with_router(Router::new().get("/users/:id", ok))
+            .with_route_check(RouteCheck::Strict)
+    })
+    .await;
+    let req = Request::get(app.url("/users/1")).body(Body::empty()).unwrap();
+    assert_eq!(send(req).await.0, StatusCode::OK);
+    app.stop().await;
+}
+
+#[tokio::test]
+async fn warn_and_off_still_start() {
+    for mode in [RouteCheck::Warn, RouteCheck::Off] {
+        let app = App::start(|server| server.with_route_check(mode).with_router(conflicting())).await;
+        let req = Request::get(app.url("/users/1")).body(Body::empty()).unwrap();
+        assert_eq!(send(req).await.0, StatusCode::OK);
+        app.stop().await;
+    }
+}
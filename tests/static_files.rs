@@ -0,0 +1,123 @@
+// Path traversal payloads against `fs_util::safe_join` and a mounted
+// `StaticFiles`, which must never serve anything outside its root.
+mod common;
+
+use common::{send, App};
+use high_performance_webserver::fs_util::safe_join;
+use high_performance_webserver::Router;
+use hyper::{Body, Request, StatusCode};
+use std::path::{Path, PathBuf};
+
+const SECRET: &str = "top secret";
+
+// Written as they would appear in a request path, still percent-encoded
+const TRAVERSALS: &[&str] = &[
+    // Plain parent segments
+    "../secret.txt",
+    "../../../../etc/passwd",
+    "files/../../secret.txt",
+    // Encoded dots, in every case mix
+    "%2e%2e/secret.txt",
+    "%2E%2E/secret.txt",
+    ".%2e/secret.txt",
+    "%2e./secret.txt",
+    "files/%2e%2e/%2e%2e/secret.txt",
+    // Encoded separators smuggling a `..` into one segment
+    "..%2Fsecret.txt",
+    "%2e%2e%2fsecret.txt",
+    "files%2F..%2F..%2Fsecret.txt",
+    "%2Fetc%2Fpasswd",
+    // Backslashes, raw and encoded
+    "..%5csecret.txt",
+    "%5c..%5csecret.txt",
+    "files%5C..%5C..%5Csecret.txt",
+    // Windows drives and UNC paths
+    "C:",
+    "C:%5cWindows%5cwin.ini",
+    "c:/windows/win.ini",
+    "%5c%5cserver%5cshare%5csecret.txt",
+    "%5C%5C%3F%5CC:%5Csecret.txt",
+    // NUL bytes
+    "%00",
+    "secret.txt%00.png",
+    "files/ok.txt%00/../../secret.txt",
+    // Overlong UTF-8 encodings of '.' and '/'
+    "%c0%ae%c0%ae/secret.txt",
+    "%c0%ae%c0%ae%c0%afsecret.txt",
+    "%e0%80%ae%e0%80%ae/secret.txt",
+    "%c1%9c..%c1%9csecret.txt",
+    // Broken escapes
+    "%2",
+    "%zz/secret.txt",
+];
+
+// Only as paths for `safe_join`: not valid in a request line
+const RAW_TRAVERSALS: &[&str] = &["..\\secret.txt", "\\\\server\\share\\secret.txt", "files\\..\\..\\secret.txt"];
+
+// `<tmp>/<unique>/public/files/ok.txt`, with `secret.txt` next to `public`
+fn fixture(name: &str) -> PathBuf {
+    let base = std::env::temp_dir().join(format!("hpws-{}-{}", name, std::process::id()));
+    let public = base.join("public");
+    std::fs::create_dir_all(public.join("files")).unwrap();
+    std::fs::write(public.join("files/ok.txt"), "ok").unwrap();
+    std::fs::write(base.join("secret.txt"), SECRET).unwrap();
+    public
+}
+
+fn cleanup(public: &Path) {
+    let _ = std::fs::remove_dir_all(public.parent().unwrap());
+}
+
+#[test]
+fn safe_join_rejects_traversals() {
+    let root = Path::new("/srv/public");
+    for payload in TRAVERSALS.iter().chain(RAW_TRAVERSALS) {
+        let result = safe_join(root, payload);
+        assert!(result.is_err(), "{} was joined as {:?}", payload, result);
+        if let Err(e) = result {
+            assert_eq!(e.status_code(), StatusCode::BAD_REQUEST, "{}", payload);
+        }
+    }
+
+    assert_eq!(safe_join(root, "files/ok.txt").unwrap(), root.join("files/ok.txt"));
+    assert_eq!(safe_join(root, "/files/./a%20b.txt").unwrap(), root.join("files/a b.txt"));
+}
+
+#[tokio::test]
+async fn static_files_refuse_traversals() {
+    let public = fixture("traversal");
+    let router = Router::new().mount_static("/assets", &public);
+
+    // Through the router alone, so `StaticFiles` sees the paths as sent,
+    // and through a server, which also resolves dot-segments up front
+    let app = App::start({
+        let router = router.clone();
+        |server| server.with_router(router)
+    })
+    .await;
+    for payload in TRAVERSALS {
+        let path = format!("/assets/{}", payload);
+
+        let response = router.handle(Request::get(path.as_str()).body(Body::empty()).unwrap()).await;
+        if let Ok(response) = response {
+            let status = response.status_code();
+            assert!(status.is_client_error(), "{} answered {} by the router", path, status);
+        }
+
+        let (status, _, body) = send(Request::get(app.url(&path)).body(Body::empty()).unwrap()).await;
+        assert!(
+            status == StatusCode::NOT_FOUND || status == StatusCode::BAD_REQUEST,
+            "{} answered {}",
+            path,
+            status
+        );
+        assert!(!String::from_utf8_lossy(&body).contains(SECRET), "{} leaked the secret", path);
+    }
+
+    let (status, _, body) = send(Request::get(app.url("/assets/files/ok.txt")).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "ok");
+
+    app.stop().await;
+    cleanup(&public);
+}